@@ -54,13 +54,42 @@ class BarContentView: NSView {
     }
 
     override func mouseDown(with event: NSEvent) {
+        runClickCommand(for: event) { $0.onClick }
+    }
+
+    override func rightMouseDown(with event: NSEvent) {
+        runClickCommand(for: event) { $0.onRightClick }
+    }
+
+    override func otherMouseDown(with event: NSEvent) {
+        guard event.buttonNumber == 2 else { return }
+        runClickCommand(for: event) { $0.onMiddleClick }
+    }
+
+    private func runClickCommand(for event: NSEvent, command: (BarNode) -> String?) {
         let pt = convert(event.locationInWindow, from: nil)
         for (rect, node) in containerRects.reversed() {
-            if rect.contains(pt), let cmd = node.onClick {
+            if rect.contains(pt), let cmd = command(node) {
+                if node.disabled {
+                    return
+                }
+                if let prompt = node.confirm, !confirmClick(prompt) {
+                    return
+                }
+                var environment = ProcessInfo.processInfo.environment
+                environment["RANMA_NAME"] = node.name
+                environment["RANMA_DISPLAY"] = String(node.display)
+                environment["RANMA_LABEL"] = node.label ?? ""
+                let shell = node.onClickShell ?? "/bin/sh"
+                let cwd = node.onClickCwd.map { ($0 as NSString).expandingTildeInPath }
                 DispatchQueue.global(qos: .utility).async {
                     let proc = Process()
-                    proc.executableURL = URL(fileURLWithPath: "/bin/sh")
+                    proc.executableURL = URL(fileURLWithPath: shell)
                     proc.arguments = ["-c", cmd]
+                    proc.environment = environment
+                    if let cwd {
+                        proc.currentDirectoryURL = URL(fileURLWithPath: cwd, isDirectory: true)
+                    }
                     try? proc.run()
                 }
                 return
@@ -68,6 +97,15 @@ class BarContentView: NSView {
         }
     }
 
+    private func confirmClick(_ prompt: String) -> Bool {
+        let alert = NSAlert()
+        alert.messageText = prompt
+        alert.addButton(withTitle: "OK")
+        alert.addButton(withTitle: "Cancel")
+        NSApp.activate(ignoringOtherApps: true)
+        return alert.runModal() == .alertFirstButtonReturn
+    }
+
     override func draw(_ dirtyRect: NSRect) {
         containerRects.removeAll()
         let tree = resolveTree(nodes)
@@ -84,6 +122,43 @@ class BarContentView: NSView {
     // MARK: - Recursive drawing
 
     private func drawEntry(_ entry: TreeEntry, at origin: NSPoint, availableHeight: CGFloat, context: DrawContext) -> CGSize {
+        let node: BarNode
+        switch entry {
+        case .row(let n, _), .column(let n, _), .box(let n, _), .item(let n):
+            node = n
+        }
+        let blendMode = node.style.blendMode.flatMap(cgBlendMode)
+        guard node.removing || blendMode != nil, let cgContext = NSGraphicsContext.current?.cgContext else {
+            return drawEntryContent(entry, at: origin, availableHeight: availableHeight, context: context)
+        }
+        // The subtree is drawn into its own layer so the blend mode and fade apply to it as a whole
+        cgContext.saveGState()
+        if let blendMode {
+            cgContext.setBlendMode(blendMode)
+        }
+        if node.removing {
+            // Nodes pending a delayed removal are drawn faded, children included
+            cgContext.setAlpha(0.4)
+        }
+        cgContext.beginTransparencyLayer(auxiliaryInfo: nil)
+        let size = drawEntryContent(entry, at: origin, availableHeight: availableHeight, context: context)
+        cgContext.endTransparencyLayer()
+        cgContext.restoreGState()
+        return size
+    }
+
+    private func cgBlendMode(_ name: String) -> CGBlendMode? {
+        switch name {
+        case "multiply": return .multiply
+        case "screen": return .screen
+        case "overlay": return .overlay
+        case "darken": return .darken
+        case "lighten": return .lighten
+        default: return nil
+        }
+    }
+
+    private func drawEntryContent(_ entry: TreeEntry, at origin: NSPoint, availableHeight: CGFloat, context: DrawContext) -> CGSize {
         switch entry {
         case .row(let node, let children):
             return drawLayout(node: node, children: children, axis: .horizontal, at: origin, availableHeight: availableHeight, context: context)
@@ -130,7 +205,7 @@ class BarContentView: NSView {
         }
 
         // Draw background/border/shadow
-        drawDecoration(node: node, in: contentRect, hovered: isHovered)
+        drawDecoration(node: node, in: contentRect, hovered: isHovered, imageFill: true)
 
         let pl = CGFloat(node.style.paddingLeft ?? 0)
         let pr = CGFloat(node.style.paddingRight ?? 0)
@@ -160,7 +235,7 @@ class BarContentView: NSView {
                 if index > 0 { cx += gap }
                 let childSize = WindowSizer.measureEntry(child)
                 let childY: CGFloat
-                switch alignItems {
+                switch child.node.style.alignSelf ?? alignItems {
                 case "center": childY = contentY + pb + (innerHeight - childSize.height) / 2
                 case "end":    childY = contentY + pb
                 default:       childY = contentY + pb + innerHeight - childSize.height // start = top
@@ -188,7 +263,7 @@ class BarContentView: NSView {
                 let childSize = WindowSizer.measureEntry(child)
                 cy -= childSize.height
                 let childX: CGFloat
-                switch alignItems {
+                switch child.node.style.alignSelf ?? alignItems {
                 case "center": childX = contentX + pl + (innerWidth - childSize.width) / 2
                 case "end":    childX = contentX + pl + innerWidth - childSize.width
                 default:       childX = contentX + pl
@@ -204,16 +279,35 @@ class BarContentView: NSView {
         return totalSize
     }
 
-    private func drawDecoration(node: BarNode, in rect: NSRect, hovered: Bool = false) {
+    private func backgroundGradient(_ style: NodeStyle) -> NSGradient? {
+        guard let start = style.backgroundGradientStart.flatMap({ NSColor.fromHex($0) }),
+              let end = style.backgroundGradientEnd.flatMap({ NSColor.fromHex($0) })
+        else { return nil }
+        let opacity = CGFloat(style.backgroundOpacity ?? 1)
+        return NSGradient(
+            starting: start.withAlphaComponent(start.alphaComponent * opacity),
+            ending: end.withAlphaComponent(end.alphaComponent * opacity)
+        )
+    }
+
+    /// With `imageFill`, the node's image covers the background inside the
+    /// corners, under the border; containers draw their image this way.
+    private func drawDecoration(node: BarNode, in rect: NSRect, hovered: Bool = false, imageFill: Bool = false) {
+        let fillImage = imageFill ? node.image.flatMap { ImageCache.shared.image(for: $0) } : nil
         let effectiveBg = hovered ? (node.style.hoverBackgroundColor ?? node.style.backgroundColor) : node.style.backgroundColor
+        // A hover color still replaces the gradient while hovered
+        let gradient = hovered && node.style.hoverBackgroundColor != nil ? nil : backgroundGradient(node.style)
         let hasDecoration = effectiveBg != nil
+            || gradient != nil
+            || fillImage != nil
             || node.style.borderColor != nil
             || node.style.shadowColor != nil
+            || node.style.outlineColor != nil
 
         guard hasDecoration else { return }
 
-        let cr = CGFloat(node.style.cornerRadius ?? 0)
-        let path = NSBezierPath(roundedRect: rect, xRadius: cr, yRadius: cr)
+        let radii = CornerRadii(style: node.style)
+        let path = NSBezierPath(roundedRect: rect, radii: radii)
 
         let gfxContext = NSGraphicsContext.current
         if let shadowHex = node.style.shadowColor, let shadowColor = NSColor.fromHex(shadowHex) {
@@ -221,11 +315,19 @@ class BarContentView: NSView {
             let shadow = NSShadow()
             shadow.shadowColor = shadowColor
             shadow.shadowBlurRadius = CGFloat(node.style.shadowRadius ?? 4)
-            shadow.shadowOffset = NSSize(width: 0, height: -1)
+            shadow.shadowOffset = NSSize(
+                width: CGFloat(node.style.shadowOffsetX ?? 0),
+                height: -CGFloat(node.style.shadowOffsetY ?? 0)
+            )
             shadow.set()
         }
 
-        if let bgHex = effectiveBg, let bgColor = NSColor.fromHex(bgHex) {
+        if let gradient {
+            gradient.draw(in: path, angle: CGFloat(node.style.backgroundGradientAngle ?? 0))
+        } else if let bgHex = effectiveBg, var bgColor = NSColor.fromHex(bgHex) {
+            if let opacity = node.style.backgroundOpacity {
+                bgColor = bgColor.withAlphaComponent(bgColor.alphaComponent * CGFloat(opacity))
+            }
             bgColor.setFill()
             path.fill()
         }
@@ -234,12 +336,35 @@ class BarContentView: NSView {
             gfxContext?.restoreGraphicsState()
         }
 
+        if let img = fillImage {
+            let scale = CGFloat(node.imageScale ?? 1.0)
+            let destRect = imageRect(
+                size: NSSize(width: img.size.width * scale, height: img.size.height * scale),
+                in: rect,
+                mode: node.imageContentMode ?? "fill"
+            )
+            NSGraphicsContext.saveGraphicsState()
+            path.addClip()
+            img.draw(in: destRect, from: NSRect(origin: .zero, size: img.size), operation: .sourceOver, fraction: 1.0)
+            NSGraphicsContext.restoreGraphicsState()
+        }
+
         let bw = CGFloat(node.style.borderWidth ?? 0)
         if bw > 0, let borderHex = node.style.borderColor, let borderColor = NSColor.fromHex(borderHex) {
             borderColor.setStroke()
             path.lineWidth = bw
             path.stroke()
         }
+
+        let ow = CGFloat(node.style.outlineWidth ?? 1)
+        if ow > 0, let outlineHex = node.style.outlineColor, let outlineColor = NSColor.fromHex(outlineHex) {
+            let inset = -(CGFloat(node.style.outlineOffset ?? 0) + ow / 2)
+            let outlineRect = rect.insetBy(dx: inset, dy: inset)
+            let outline = NSBezierPath(roundedRect: outlineRect, radii: radii.grown(by: -inset))
+            outlineColor.setStroke()
+            outline.lineWidth = ow
+            outline.stroke()
+        }
     }
 
     // MARK: - Item drawing
@@ -263,6 +388,11 @@ class BarContentView: NSView {
         // Draw background/border/shadow
         drawDecoration(node: node, in: itemRect)
 
+        if node.nodeType == .progress {
+            drawProgress(node, in: itemRect)
+            return totalSize
+        }
+
         // Draw content centered within item
         let font = WindowSizer.fontForNode(node)
         let iconSize = WindowSizer.iconSizeForNode(node)
@@ -271,7 +401,8 @@ class BarContentView: NSView {
         let pr = CGFloat(node.style.paddingRight ?? 0)
 
         let contentWidth = measureContentWidth(node)
-        let contentOffset = (itemWidth - pl - pr - contentWidth) / 2
+        // Content wider than a max_width-capped item starts at the leading edge
+        let contentOffset = max((itemWidth - pl - pr - contentWidth) / 2, 0)
         var currentX = itemX + pl + contentOffset
 
         if let iconName = node.icon,
@@ -308,7 +439,28 @@ class BarContentView: NSView {
             let prevInterpolation = gfxCtx?.imageInterpolation
             gfxCtx?.imageInterpolation = .none
 
-            if let explicitWidth = node.style.width, CGFloat(explicitWidth) > scaledWidth {
+            if let mode = node.imageContentMode, node.style.width?.points != nil || node.style.height?.points != nil {
+                let pt = CGFloat(node.style.paddingTop ?? 0)
+                let pb = CGFloat(node.style.paddingBottom ?? 0)
+                let boxX = currentX - contentOffset
+                let rightEdge = itemX + itemWidth - pr
+                let box = NSRect(
+                    x: boxX,
+                    y: itemY + pb,
+                    width: rightEdge - boxX,
+                    height: itemHeight - pt - pb
+                )
+                let destRect = imageRect(
+                    size: NSSize(width: scaledWidth, height: scaledHeight),
+                    in: box,
+                    mode: mode
+                )
+                NSGraphicsContext.saveGraphicsState()
+                NSBezierPath(rect: box).addClip()
+                img.draw(in: destRect, from: NSRect(origin: .zero, size: img.size), operation: .sourceOver, fraction: 1.0)
+                NSGraphicsContext.restoreGraphicsState()
+                currentX = rightEdge
+            } else if let explicitWidth = node.style.width?.points, explicitWidth > scaledWidth {
                 let tileStartX = currentX - contentOffset
                 let rightEdge = itemX + itemWidth - pr
                 let tileAreaWidth = rightEdge - tileStartX
@@ -352,23 +504,64 @@ class BarContentView: NSView {
 
         if let label = node.label {
             let labelColor = context.hoverLabelColor ?? node.labelColor.flatMap { NSColor.fromHex($0) } ?? .white
-            let attrs: [NSAttributedString.Key: Any] = [
-                .foregroundColor: labelColor,
-                .font: font,
-            ]
+            var attrs = WindowSizer.labelAttributes(node, font: font)
+            attrs[.foregroundColor] = labelColor
             let textSize = (label as NSString).size(withAttributes: attrs)
+            let available = itemX + itemWidth - pr - currentX
             let textRect = NSRect(
                 x: currentX,
                 y: centerY - textSize.height / 2,
-                width: textSize.width,
+                width: min(textSize.width, max(available, 0)),
                 height: textSize.height
             )
-            (label as NSString).draw(in: textRect, withAttributes: attrs)
+            if textRect.width < textSize.width {
+                let paragraph = (attrs[.paragraphStyle] as? NSParagraphStyle)?.mutableCopy() as? NSMutableParagraphStyle
+                    ?? NSMutableParagraphStyle()
+                paragraph.lineBreakMode = lineBreakMode(for: node.style.textTruncation)
+                var truncated = attrs
+                truncated[.paragraphStyle] = paragraph
+                (label as NSString).draw(in: textRect, withAttributes: truncated)
+            } else {
+                (label as NSString).draw(in: textRect, withAttributes: attrs)
+            }
         }
 
         return totalSize
     }
 
+    private func drawProgress(_ node: BarNode, in rect: NSRect) {
+        let pl = CGFloat(node.style.paddingLeft ?? 0)
+        let pr = CGFloat(node.style.paddingRight ?? 0)
+        let height = WindowSizer.progressBarHeight
+        let track = NSRect(
+            x: rect.minX + pl,
+            y: rect.midY - height / 2,
+            width: max(rect.width - pl - pr, 0),
+            height: height
+        )
+        let trackColor = node.style.progressTrackColor.flatMap { NSColor.fromHex($0) }
+            ?? NSColor.white.withAlphaComponent(0.2)
+        trackColor.setFill()
+        NSBezierPath(roundedRect: track, xRadius: height / 2, yRadius: height / 2).fill()
+
+        let fraction = CGFloat(node.value ?? 0) / 100
+        guard fraction > 0 else { return }
+        var fill = track
+        fill.size.width = track.width * fraction
+        let fillColor = node.style.progressColor.flatMap { NSColor.fromHex($0) } ?? .white
+        fillColor.setFill()
+        NSBezierPath(roundedRect: fill, xRadius: height / 2, yRadius: height / 2).fill()
+    }
+
+    private func lineBreakMode(for truncation: String?) -> NSLineBreakMode {
+        switch truncation {
+        case "head": return .byTruncatingHead
+        case "middle": return .byTruncatingMiddle
+        case "none": return .byClipping
+        default: return .byTruncatingTail
+        }
+    }
+
     private func measureContentWidth(_ node: BarNode) -> CGFloat {
         var width: CGFloat = 0
         let font = WindowSizer.fontForNode(node)
@@ -388,8 +581,7 @@ class BarContentView: NSView {
         }
 
         if let label = node.label {
-            let attrs: [NSAttributedString.Key: Any] = [.font: font]
-            let size = (label as NSString).size(withAttributes: attrs)
+            let size = (label as NSString).size(withAttributes: WindowSizer.labelAttributes(node, font: font))
             if width > 0 { width += iconLabelGap }
             width += size.width
         }
@@ -423,6 +615,33 @@ extension NSColor {
     }
 }
 
+// Mirrors NSImageScaling: fit = proportionallyUpOrDown, stretch = axesIndependently,
+// center = none; fill scales proportionally to cover the box and is clipped.
+private func imageRect(size: NSSize, in box: NSRect, mode: String) -> NSRect {
+    guard size.width > 0, size.height > 0 else { return box }
+    let widthRatio = box.width / size.width
+    let heightRatio = box.height / size.height
+    let drawSize: NSSize
+    switch mode {
+    case "stretch":
+        return box
+    case "fit":
+        let ratio = min(widthRatio, heightRatio)
+        drawSize = NSSize(width: size.width * ratio, height: size.height * ratio)
+    case "fill":
+        let ratio = max(widthRatio, heightRatio)
+        drawSize = NSSize(width: size.width * ratio, height: size.height * ratio)
+    default:
+        drawSize = size
+    }
+    return NSRect(
+        x: box.midX - drawSize.width / 2,
+        y: box.midY - drawSize.height / 2,
+        width: drawSize.width,
+        height: drawSize.height
+    )
+}
+
 extension NSImage {
     func tinted(with color: NSColor) -> NSImage {
         let image = self.copy() as! NSImage
@@ -435,3 +654,50 @@ extension NSImage {
         return image
     }
 }
+
+/// Per-corner radii; a corner without its own radius uses `cornerRadius`.
+struct CornerRadii {
+    var topLeft: CGFloat
+    var topRight: CGFloat
+    var bottomLeft: CGFloat
+    var bottomRight: CGFloat
+
+    init(style: NodeStyle) {
+        let all = style.cornerRadius ?? 0
+        topLeft = CGFloat(style.cornerRadiusTopLeft ?? all)
+        topRight = CGFloat(style.cornerRadiusTopRight ?? all)
+        bottomLeft = CGFloat(style.cornerRadiusBottomLeft ?? all)
+        bottomRight = CGFloat(style.cornerRadiusBottomRight ?? all)
+    }
+
+    func grown(by amount: CGFloat) -> CornerRadii {
+        var radii = self
+        radii.topLeft = max(topLeft + amount, 0)
+        radii.topRight = max(topRight + amount, 0)
+        radii.bottomLeft = max(bottomLeft + amount, 0)
+        radii.bottomRight = max(bottomRight + amount, 0)
+        return radii
+    }
+}
+
+extension NSBezierPath {
+    /// Each radius is clamped to half the shorter side, as `xRadius:yRadius:` does.
+    convenience init(roundedRect rect: NSRect, radii: CornerRadii) {
+        self.init()
+        let limit = min(rect.width, rect.height) / 2
+        let tl = min(radii.topLeft, limit)
+        let tr = min(radii.topRight, limit)
+        let bl = min(radii.bottomLeft, limit)
+        let br = min(radii.bottomRight, limit)
+        move(to: NSPoint(x: rect.minX + bl, y: rect.minY))
+        line(to: NSPoint(x: rect.maxX - br, y: rect.minY))
+        appendArc(withCenter: NSPoint(x: rect.maxX - br, y: rect.minY + br), radius: br, startAngle: 270, endAngle: 360)
+        line(to: NSPoint(x: rect.maxX, y: rect.maxY - tr))
+        appendArc(withCenter: NSPoint(x: rect.maxX - tr, y: rect.maxY - tr), radius: tr, startAngle: 0, endAngle: 90)
+        line(to: NSPoint(x: rect.minX + tl, y: rect.maxY))
+        appendArc(withCenter: NSPoint(x: rect.minX + tl, y: rect.maxY - tl), radius: tl, startAngle: 90, endAngle: 180)
+        line(to: NSPoint(x: rect.minX, y: rect.minY + bl))
+        appendArc(withCenter: NSPoint(x: rect.minX + bl, y: rect.minY + bl), radius: bl, startAngle: 180, endAngle: 270)
+        close()
+    }
+}
@@ -30,10 +30,16 @@ final class BarViewModel: StateChangeHandler, @unchecked Sendable {
         }
     }
 
+    func onShutdown() {
+        DispatchQueue.main.async {
+            NSApp.terminate(nil)
+        }
+    }
+
     @MainActor
     private func handleEvent(_ event: StateChangeEvent) {
         switch event {
-        case let .nodeAdded(display, node):
+        case let .nodeAdded(display, node, _):
             nodes[display, default: []].append(node)
             scheduleRefresh(display)
 
@@ -42,6 +48,12 @@ final class BarViewModel: StateChangeHandler, @unchecked Sendable {
             nodes[display] = updated
             scheduleRefresh(display)
 
+        case let .nodeRemoving(display, node):
+            if let idx = nodes[display]?.firstIndex(where: { $0.name == node.name }) {
+                nodes[display]?[idx] = node
+            }
+            scheduleRefresh(display)
+
         case let .nodeUpdated(display, node):
             if let idx = nodes[display]?.firstIndex(where: { $0.name == node.name }) {
                 nodes[display]?[idx] = node
@@ -57,6 +69,13 @@ final class BarViewModel: StateChangeHandler, @unchecked Sendable {
         case let .fullRefresh(display, newNodes):
             nodes[display] = newNodes
             scheduleRefresh(display)
+
+        case let .cleared(display):
+            let cleared = display.map { [$0] } ?? Array(nodes.keys)
+            for id in cleared {
+                nodes[id] = []
+                scheduleRefresh(id)
+            }
         }
     }
 
@@ -47,16 +47,35 @@ class RanmaAppDelegate: NSObject, NSApplicationDelegate {
 
     private func updateDisplayList() {
         let displays = NSScreen.screens.map { screen in
-            DisplayInfo(
+            let notch = notchSize(of: screen)
+            return DisplayInfo(
                 id: screen.displayID,
                 name: screen.localizedName,
-                isMain: screen.displayID == CGMainDisplayID()
+                isMain: screen.displayID == CGMainDisplayID(),
+                width: Float(screen.frame.width),
+                height: Float(screen.frame.height),
+                scale: Float(screen.backingScaleFactor),
+                hasNotch: notch != nil,
+                notchWidth: notch.map { Float($0.width) },
+                notchHeight: notch.map { Float($0.height) }
             )
         }
         setDisplays(displays: displays)
     }
 
+    private func notchSize(of screen: NSScreen) -> CGSize? {
+        guard let left = screen.auxiliaryTopLeftArea,
+              let right = screen.auxiliaryTopRightArea else { return nil }
+        return CGSize(
+            width: screen.frame.width - left.width - right.width,
+            height: screen.safeAreaInsets.top
+        )
+    }
+
     private func defaultSocketPath() -> String {
+        if let path = ProcessInfo.processInfo.environment["RANMA_SOCKET"], !path.isEmpty {
+            return path
+        }
         let uid = getuid()
         let tmpDir = NSTemporaryDirectory()
         return "\(tmpDir)ranma_\(uid).sock"
@@ -70,12 +89,17 @@ private func runInitScript(socketPath: String) {
     }
 
     let initPath: String
-    if let envPath = ProcessInfo.processInfo.environment["RANMA_INIT"] {
-        initPath = (envPath as NSString).expandingTildeInPath
-    } else {
-        initPath = NSString("~/.config/ranma/init").expandingTildeInPath
+    do {
+        guard let path = try initScriptPath() else { return }
+        initPath = path
+    } catch {
+        eprintln("error: \(error)")
+        return
+    }
+    guard FileManager.default.isExecutableFile(atPath: initPath) else {
+        eprintln("error: init script is not executable: \(initPath)")
+        return
     }
-    guard FileManager.default.isExecutableFile(atPath: initPath) else { return }
 
     eprintln("running init: \(initPath)")
     let process = Process()
@@ -1,10 +1,26 @@
 import AppKit
 
+extension Dimension {
+    /// Fixed sizes only; percentages and `fill` size to content until the
+    /// layout resolves them against the parent.
+    var points: CGFloat? {
+        if case .points(let value) = self { return CGFloat(value) }
+        return nil
+    }
+}
+
 indirect enum TreeEntry {
     case row(BarNode, [TreeEntry])
     case column(BarNode, [TreeEntry])
     case box(BarNode, [TreeEntry])
     case item(BarNode)
+
+    var node: BarNode {
+        switch self {
+        case .row(let n, _), .column(let n, _), .box(let n, _), .item(let n):
+            return n
+        }
+    }
 }
 
 enum WindowSizer {
@@ -12,6 +28,8 @@ enum WindowSizer {
     private static let iconLabelGap: CGFloat = 4
     private static let defaultFontSize: CGFloat = 13
     private static let defaultIconSize: CGFloat = 16
+    private static let defaultProgressWidth: CGFloat = 40
+    static let progressBarHeight: CGFloat = 4
 
     static func calculateSize(for nodes: [BarNode]) -> CGSize {
         let tree = resolveTree(nodes)
@@ -79,8 +97,8 @@ enum WindowSizer {
         let contentWidth = pl + innerWidth + pr
         let contentHeight = pt + innerHeight + pb
 
-        let w = node.style.width.map { CGFloat($0) } ?? contentWidth
-        let h = node.style.height.map { CGFloat($0) } ?? contentHeight
+        let w = node.style.width?.points ?? contentWidth
+        let h = node.style.height?.points ?? contentHeight
 
         return CGSize(width: ml + w + mr, height: mt + h + mb)
     }
@@ -91,8 +109,19 @@ enum WindowSizer {
         let mt = CGFloat(node.style.marginTop ?? 0)
         let mb = CGFloat(node.style.marginBottom ?? 0)
 
-        if let w = node.style.width, let h = node.style.height {
-            return CGSize(width: ml + CGFloat(w) + mr, height: mt + CGFloat(h) + mb)
+        if let w = node.style.width?.points, let h = node.style.height?.points {
+            return CGSize(width: ml + w + mr, height: mt + h + mb)
+        }
+
+        // Progress nodes draw only their bar, so icon and label take no space
+        if node.nodeType == .progress {
+            let pl = CGFloat(node.style.paddingLeft ?? 0)
+            let pr = CGFloat(node.style.paddingRight ?? 0)
+            var w = node.style.width?.points ?? (pl + defaultProgressWidth + pr)
+            if let minWidth = node.style.minWidth { w = max(w, CGFloat(minWidth)) }
+            if let maxWidth = node.style.maxWidth { w = min(w, CGFloat(maxWidth)) }
+            let h = node.style.height?.points ?? fontForNode(node).pointSize + 4
+            return CGSize(width: ml + w + mr, height: mt + h + mb)
         }
 
         var contentWidth: CGFloat = 0
@@ -113,34 +142,58 @@ enum WindowSizer {
         }
 
         if let label = node.label {
-            let attrs: [NSAttributedString.Key: Any] = [.font: font]
-            let size = (label as NSString).size(withAttributes: attrs)
+            let size = (label as NSString).size(withAttributes: labelAttributes(node, font: font))
             if contentWidth > 0 { contentWidth += iconLabelGap }
             contentWidth += size.width
         }
 
         let pl = CGFloat(node.style.paddingLeft ?? 0)
         let pr = CGFloat(node.style.paddingRight ?? 0)
-        let w = node.style.width.map { CGFloat($0) } ?? (pl + contentWidth + pr)
+        var w = node.style.width?.points ?? (pl + contentWidth + pr)
+        if let minWidth = node.style.minWidth { w = max(w, CGFloat(minWidth)) }
+        if let maxWidth = node.style.maxWidth { w = min(w, CGFloat(maxWidth)) }
 
-        var contentHeight = font.pointSize + 4
+        var contentHeight = font.pointSize * CGFloat(node.lineHeight ?? 1) + 4
         if let imagePath = node.image,
            let img = ImageCache.shared.image(for: imagePath) {
             let scale = CGFloat(node.imageScale ?? 1.0)
             contentHeight = max(contentHeight, img.size.height * scale)
         }
-        let h = node.style.height.map { CGFloat($0) } ?? contentHeight
+        let h = node.style.height?.points ?? contentHeight
 
         return CGSize(width: ml + w + mr, height: mt + h + mb)
     }
 
     static func fontForNode(_ node: BarNode) -> NSFont {
         let size = CGFloat(node.fontSize ?? Float(defaultFontSize))
-        if let family = node.fontFamily, let font = NSFont(name: family, size: size) {
-            return font
+        let font: NSFont
+        if let family = node.fontFamily, let named = NSFont(name: family, size: size) {
+            font = named
+        } else {
+            font = NSFont.systemFont(ofSize: size, weight: fontWeight(from: node.fontWeight))
+        }
+        guard node.fontStyle == "italic" else { return font }
+        return NSFontManager.shared.convert(font, toHaveTrait: .italicFontMask)
+    }
+
+    /// Font, spacing, line height and decorations, shared by measuring and drawing.
+    static func labelAttributes(_ node: BarNode, font: NSFont) -> [NSAttributedString.Key: Any] {
+        var attrs: [NSAttributedString.Key: Any] = [.font: font]
+        if let spacing = node.letterSpacing {
+            attrs[.kern] = CGFloat(spacing)
+        }
+        if let lineHeight = node.lineHeight {
+            let paragraph = NSMutableParagraphStyle()
+            paragraph.lineHeightMultiple = CGFloat(lineHeight)
+            attrs[.paragraphStyle] = paragraph
+        }
+        if node.underline == true {
+            attrs[.underlineStyle] = NSUnderlineStyle.single.rawValue
+        }
+        if node.strikethrough == true {
+            attrs[.strikethroughStyle] = NSUnderlineStyle.single.rawValue
         }
-        let weight = fontWeight(from: node.fontWeight)
-        return NSFont.systemFont(ofSize: size, weight: weight)
+        return attrs
     }
 
     static func iconSizeForNode(_ node: BarNode) -> CGFloat {
@@ -193,7 +246,7 @@ func collectNodes(from entry: TreeEntry) -> [BarNode] {
 }
 
 func resolveTree(_ nodes: [BarNode]) -> [TreeEntry] {
-    let sorted = nodes.sorted { $0.position < $1.position }
+    let sorted = nodes.filter { !$0.hidden }.sorted { $0.position < $1.position }
     let topLevel = sorted.filter { $0.parent == nil }
 
     func buildEntry(_ node: BarNode) -> TreeEntry {
@@ -213,7 +266,7 @@ func resolveTree(_ nodes: [BarNode]) -> [TreeEntry] {
                 .filter { $0.parent == node.name }
                 .map { buildEntry($0) }
             return .box(node, children)
-        case .item:
+        case .item, .progress:
             return .item(node)
         }
     }
@@ -0,0 +1,178 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+const PHASES: [&str; 4] = ["add", "query", "set", "remove"];
+
+/// One daemon connection reused for every request, so the numbers measure
+/// the daemon rather than connection setup.
+struct Connection {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Connection {
+    fn open(socket_path: &str) -> Result<Self, String> {
+        let writer = UnixStream::connect(socket_path)
+            .map_err(|e| format!("cannot connect to daemon: {e}"))?;
+        let reader = BufReader::new(writer.try_clone().map_err(|e| e.to_string())?);
+        Ok(Self { writer, reader })
+    }
+
+    fn request(&mut self, command: &Value) -> Result<(), String> {
+        let mut payload = serde_json::to_string(command).unwrap();
+        payload.push('\n');
+        self.writer
+            .write_all(payload.as_bytes())
+            .map_err(|e| format!("write error: {e}"))?;
+        let mut response = String::new();
+        match self.reader.read_line(&mut response) {
+            Ok(0) => return Err("connection closed by daemon".to_string()),
+            Ok(_) => {}
+            Err(e) => return Err(format!("read error: {e}")),
+        }
+        let value: Value =
+            serde_json::from_str(&response).map_err(|e| format!("invalid response: {e}"))?;
+        if value["status"] == "error" {
+            return Err(value["message"].as_str().unwrap_or_default().to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Latencies of every request of one phase, across all iterations.
+pub struct PhaseStats {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    latencies: Vec<Duration>,
+}
+
+impl PhaseStats {
+    pub fn ops(&self) -> usize {
+        self.latencies.len()
+    }
+
+    pub fn throughput(&self) -> f64 {
+        self.ops() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Nearest-rank percentile, `p` from 0 to 100.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Runs `iterations` add/query/set/remove cycles over `nodes` hidden items.
+/// Nodes left behind by a failed request are removed before returning.
+pub fn run(socket_path: &str, nodes: usize, iterations: usize) -> Result<Vec<PhaseStats>, String> {
+    let mut conn = Connection::open(socket_path)?;
+    let names: Vec<String> = (0..nodes)
+        .map(|i| format!("ranma-bench-{}-{i}", std::process::id()))
+        .collect();
+    let mut stats: Vec<PhaseStats> = PHASES
+        .iter()
+        .map(|&name| PhaseStats {
+            name,
+            elapsed: Duration::ZERO,
+            latencies: Vec::with_capacity(nodes * iterations),
+        })
+        .collect();
+
+    for iteration in 0..iterations {
+        for phase in &mut stats {
+            let start = Instant::now();
+            for name in &names {
+                let command = match phase.name {
+                    "add" => json!({ "command": "add", "name": name, "hidden": true }),
+                    "query" => json!({ "command": "query", "name": name }),
+                    "set" => json!({
+                        "command": "set",
+                        "name": name,
+                        "properties": { "label": format!("{iteration}") },
+                    }),
+                    _ => json!({ "command": "remove", "name": name }),
+                };
+                let sent = Instant::now();
+                if let Err(e) = conn.request(&command) {
+                    cleanup(&mut conn, &names);
+                    return Err(format!("{} {name}: {e}", phase.name));
+                }
+                phase.latencies.push(sent.elapsed());
+            }
+            phase.elapsed += start.elapsed();
+        }
+    }
+    Ok(stats)
+}
+
+fn cleanup(conn: &mut Connection, names: &[String]) {
+    for name in names {
+        let _ = conn.request(&json!({ "command": "remove", "name": name }));
+    }
+}
+
+pub fn format_report(stats: &[PhaseStats]) -> String {
+    let ms = |d: Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+    let mut out = format!(
+        "{:<8} {:>7} {:>12} {:>10} {:>10} {:>10} {:>10}\n",
+        "phase", "ops", "ops/s", "p50", "p90", "p99", "max"
+    );
+    for phase in stats {
+        out.push_str(&format!(
+            "{:<8} {:>7} {:>12.0} {:>10} {:>10} {:>10} {:>10}\n",
+            phase.name,
+            phase.ops(),
+            phase.throughput(),
+            ms(phase.percentile(50.0)),
+            ms(phase.percentile(90.0)),
+            ms(phase.percentile(99.0)),
+            ms(phase.percentile(100.0)),
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_reuses_one_connection_and_reports_every_phase() {
+        let path =
+            std::env::temp_dir().join(format!("ranma_test_{}_bench.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let daemon = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut served = 0;
+            for line in BufReader::new(&stream).lines() {
+                line.unwrap();
+                (&stream).write_all(b"{\"status\":\"ok\"}\n").unwrap();
+                served += 1;
+            }
+            served
+        });
+
+        let stats = run(&path.to_string_lossy(), 10, 2).unwrap();
+        assert_eq!(daemon.join().unwrap(), 80);
+        let names: Vec<_> = stats.iter().map(|p| p.name).collect();
+        assert_eq!(names, PHASES);
+        for phase in &stats {
+            assert_eq!(phase.ops(), 20);
+            assert!(phase.throughput() > 0.0);
+            assert!(phase.percentile(50.0) <= phase.percentile(100.0));
+        }
+        let report = format_report(&stats);
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.lines().nth(1).unwrap().starts_with("add"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use argh::FromArgs;
+
+use crate::Args;
+
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// A subcommand and its flags, each with the description argh prints.
+struct Subcommand {
+    name: String,
+    description: String,
+    flags: Vec<(String, String)>,
+}
+
+/// The completion script for `shell`, built from argh's own help output so
+/// it always matches the subcommand structs.
+pub fn generate(shell: &str) -> Result<String, String> {
+    let subcommands: Vec<Subcommand> = help_entries(&help(&["help"]), "Commands:")
+        .into_iter()
+        .map(|(name, description)| {
+            let flags = help_entries(&help(&[&name, "--help"]), "Options:")
+                .into_iter()
+                .filter_map(|(flag, description)| {
+                    let long = flag.split(", ").find(|f| f.starts_with("--"))?;
+                    Some((long.to_string(), description))
+                })
+                .collect();
+            Subcommand {
+                name,
+                description,
+                flags,
+            }
+        })
+        .collect();
+    match shell {
+        "bash" => Ok(bash(&subcommands)),
+        "zsh" => Ok(zsh(&subcommands)),
+        "fish" => Ok(fish(&subcommands)),
+        other => Err(format!(
+            "unsupported shell: {other} (expected {})",
+            SHELLS.join(", ")
+        )),
+    }
+}
+
+fn help(args: &[&str]) -> String {
+    match Args::from_args(&["ranma"], args) {
+        Err(exit) => exit.output,
+        Ok(_) => String::new(),
+    }
+}
+
+/// The `(name, description)` rows of one section of a help page, with
+/// wrapped descriptions joined back up.
+fn help_entries(help: &str, section: &str) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for line in help
+        .lines()
+        .skip_while(|line| *line != section)
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+    {
+        let Some(row) = line.strip_prefix("  ") else {
+            continue;
+        };
+        if row.starts_with(' ') {
+            if let Some((_, description)) = entries.last_mut() {
+                if !description.is_empty() {
+                    description.push(' ');
+                }
+                description.push_str(row.trim());
+            }
+            continue;
+        }
+        let (name, description) = row.split_once("  ").unwrap_or((row, ""));
+        entries.push((name.to_string(), description.trim().to_string()));
+    }
+    entries
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+fn bash(subcommands: &[Subcommand]) -> String {
+    let names: Vec<&str> = subcommands.iter().map(|s| s.name.as_str()).collect();
+    let mut out = String::from("_ranma() {\n    local cur=${COMP_WORDS[COMP_CWORD]}\n");
+    out.push_str("    if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    out.push_str(&format!(
+        "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n        return\n    fi\n",
+        names.join(" ")
+    ));
+    out.push_str("    [[ $cur == -* ]] || return\n    case \"${COMP_WORDS[1]}\" in\n");
+    for sub in subcommands {
+        let flags: Vec<&str> = sub.flags.iter().map(|(f, _)| f.as_str()).collect();
+        out.push_str(&format!(
+            "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;\n",
+            sub.name,
+            flags.join(" ")
+        ));
+    }
+    out.push_str("    esac\n}\ncomplete -o default -F _ranma ranma\n");
+    out
+}
+
+fn zsh(subcommands: &[Subcommand]) -> String {
+    let mut out = String::from("#compdef ranma\n\n_ranma() {\n    local -a entries\n");
+    out.push_str("    if (( CURRENT == 2 )); then\n        entries=(\n");
+    for sub in subcommands {
+        let entry = format!("{}:{}", sub.name, sub.description);
+        out.push_str(&format!("            {}\n", quote(&entry)));
+    }
+    out.push_str("        )\n        _describe 'command' entries\n        return\n    fi\n");
+    out.push_str("    case $words[2] in\n");
+    for sub in subcommands {
+        out.push_str(&format!("        {}) entries=(\n", sub.name));
+        for (flag, description) in &sub.flags {
+            out.push_str(&format!(
+                "            {}\n",
+                quote(&format!("{flag}:{description}"))
+            ));
+        }
+        out.push_str("        ) ;;\n");
+    }
+    out.push_str("    esac\n");
+    out.push_str("    if [[ $PREFIX == -* ]]; then\n        _describe 'option' entries\n");
+    out.push_str("    else\n        _files\n    fi\n}\n\n_ranma \"$@\"\n");
+    out
+}
+
+fn fish(subcommands: &[Subcommand]) -> String {
+    let mut out = String::new();
+    for sub in subcommands {
+        out.push_str(&format!(
+            "complete -c ranma -n __fish_use_subcommand -f -a {} -d {}\n",
+            sub.name,
+            quote(&sub.description)
+        ));
+    }
+    for sub in subcommands {
+        let condition = quote(&format!("__fish_seen_subcommand_from {}", sub.name));
+        for (flag, description) in &sub.flags {
+            out.push_str(&format!(
+                "complete -c ranma -n {condition} -l {} -d {}\n",
+                flag.trim_start_matches("--"),
+                quote(description)
+            ));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_cover_every_subcommand_and_flag() {
+        let bash = generate("bash").unwrap();
+        assert!(bash.contains(" add add-tree set toggle "), "{bash}");
+        let set_line = bash.lines().find(|l| l.trim().starts_with("set)")).unwrap();
+        assert!(set_line.contains("--toggle-hidden"));
+        assert!(set_line.contains("--background-gradient-angle"));
+
+        let zsh = generate("zsh").unwrap();
+        assert!(zsh.contains("'swap:exchange the positions of two sibling nodes'"));
+        let fish = generate("fish").unwrap();
+        assert!(
+            fish.contains("complete -c ranma -n '__fish_seen_subcommand_from tree' -l json -d ")
+        );
+
+        assert_eq!(
+            generate("tcsh").unwrap_err(),
+            "unsupported shell: tcsh (expected bash, zsh, fish)"
+        );
+    }
+}
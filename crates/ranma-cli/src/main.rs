@@ -1,14 +1,27 @@
+mod bench;
+mod completion;
+
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
 use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::sync::OnceLock;
 
 use argh::FromArgs;
+use ranma_core::config::{INIT_ENV, SOCKET_ENV, config_init_path};
+use ranma_core::ipc::protocol::PROTOCOL;
+use ranma_core::state::ErrorCode;
 use serde_json::{Value, json};
 
 /// ranma status bar controller
 #[derive(FromArgs)]
 struct Args {
+    /// socket path (default: $RANMA_SOCKET, else $TMPDIR/ranma_<uid>.sock)
+    #[argh(option)]
+    socket: Option<String>,
+
     #[argh(subcommand)]
     command: Command,
 }
@@ -18,12 +31,38 @@ struct Args {
 enum Command {
     Start(StartCmd),
     Add(AddCmd),
+    AddTree(AddTreeCmd),
     Set(SetCmd),
+    Toggle(ToggleCmd),
+    Move(MoveCmd),
+    Rename(RenameCmd),
+    Reset(ResetCmd),
+    Duplicate(DuplicateCmd),
+    Reorder(ReorderCmd),
+    Swap(SwapCmd),
     Remove(RemoveCmd),
+    Clear(ClearCmd),
+    Refresh(RefreshCmd),
     Query(QueryCmd),
     Displays(DisplaysCmd),
+    Exists(ExistsCmd),
+    Export(ExportCmd),
+    Snapshot(SnapshotCmd),
+    Restore(RestoreCmd),
+    Stats(StatsCmd),
+    Count(CountCmd),
+    Styles(StylesCmd),
+    Schema(SchemaCmd),
+    DisplayDefaults(DisplayDefaultsCmd),
+    Reload(ReloadCmd),
+    Shutdown(ShutdownCmd),
+    Ping(PingCmd),
+    Bench(BenchCmd),
+    Batch(BatchCmd),
     Tree(TreeCmd),
+    Watch(WatchCmd),
     Version(VersionCmd),
+    Completion(CompletionCmd),
 }
 
 /// start the ranma server
@@ -34,7 +73,7 @@ struct StartCmd {
     #[argh(option)]
     server_path: Option<String>,
 
-    /// path to init script (overrides ~/.config/ranma/init)
+    /// path to init script (overrides $RANMA_INIT and ~/.config/ranma/init)
     #[argh(option, long = "init")]
     init_script: Option<String>,
 }
@@ -47,7 +86,7 @@ struct AddCmd {
     #[argh(positional)]
     name: String,
 
-    /// node type: item (default), row, column, or box
+    /// node type: item (default), row, column, box, or progress
     #[argh(option, long = "type")]
     node_type: Option<String>,
 
@@ -83,10 +122,26 @@ struct AddCmd {
     #[argh(option)]
     border_width: Option<f32>,
 
-    /// corner radius
+    /// corner radius for all four corners
     #[argh(option)]
     corner_radius: Option<f32>,
 
+    /// top-left corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_top_left: Option<f32>,
+
+    /// top-right corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_top_right: Option<f32>,
+
+    /// bottom-left corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_bottom_left: Option<f32>,
+
+    /// bottom-right corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_bottom_right: Option<f32>,
+
     /// padding left
     #[argh(option)]
     padding_left: Option<f32>,
@@ -123,13 +178,29 @@ struct AddCmd {
     #[argh(option)]
     shadow_radius: Option<f32>,
 
-    /// fixed width
+    /// horizontal shadow offset; positive moves it right
     #[argh(option)]
-    width: Option<f32>,
+    shadow_offset_x: Option<f32>,
 
-    /// container height
+    /// vertical shadow offset; positive moves it down
     #[argh(option)]
-    height: Option<f32>,
+    shadow_offset_y: Option<f32>,
+
+    /// width in points, a percentage of the parent (e.g. 50%), or fill
+    #[argh(option)]
+    width: Option<String>,
+
+    /// height in points, a percentage of the parent (e.g. 50%), or fill
+    #[argh(option)]
+    height: Option<String>,
+
+    /// minimum width in points; narrower content is centered
+    #[argh(option)]
+    min_width: Option<f32>,
+
+    /// maximum width in points; wider content is truncated
+    #[argh(option)]
+    max_width: Option<f32>,
 
     /// item spacing within container
     #[argh(option)]
@@ -175,6 +246,26 @@ struct AddCmd {
     #[argh(option)]
     font_family: Option<String>,
 
+    /// extra space between characters in points (negative tightens)
+    #[argh(option)]
+    letter_spacing: Option<f32>,
+
+    /// line height as a multiple of the font's natural height
+    #[argh(option)]
+    line_height: Option<f32>,
+
+    /// font style: normal or italic
+    #[argh(option)]
+    font_style: Option<String>,
+
+    /// underline the label (true or false)
+    #[argh(option)]
+    underline: Option<bool>,
+
+    /// strike through the label (true or false)
+    #[argh(option)]
+    strikethrough: Option<bool>,
+
     /// notch alignment: left or right (only effective on notched displays)
     #[argh(option)]
     notch_align: Option<String>,
@@ -183,6 +274,10 @@ struct AddCmd {
     #[argh(option)]
     align_items: Option<String>,
 
+    /// cross-axis alignment of this node alone, overriding the parent's align-items: start, center, or end
+    #[argh(option)]
+    align_self: Option<String>,
+
     /// main-axis alignment of children: start, center, or end
     #[argh(option)]
     justify_content: Option<String>,
@@ -199,10 +294,74 @@ struct AddCmd {
     #[argh(option)]
     hover_icon_color: Option<String>,
 
+    /// focus ring color (hex), drawn outside the node without taking space
+    #[argh(option)]
+    outline_color: Option<String>,
+
+    /// focus ring width
+    #[argh(option)]
+    outline_width: Option<f32>,
+
+    /// gap between the node and its focus ring (negative draws inside)
+    #[argh(option)]
+    outline_offset: Option<f32>,
+
+    /// blend mode: normal, multiply, screen, overlay, darken, or lighten
+    #[argh(option)]
+    blend_mode: Option<String>,
+
+    /// where an over-long label is cut: head, middle, tail, or none
+    #[argh(option)]
+    text_truncation: Option<String>,
+
+    /// fill color of a progress node (hex)
+    #[argh(option)]
+    progress_color: Option<String>,
+
+    /// color of a progress node's unfilled track (hex)
+    #[argh(option)]
+    progress_track_color: Option<String>,
+
+    /// background opacity from 0 to 1, applied on top of the color's alpha
+    #[argh(option)]
+    background_opacity: Option<f32>,
+
+    /// gradient start color (hex); drawn instead of --background-color
+    #[argh(option)]
+    background_gradient_start: Option<String>,
+
+    /// gradient end color (hex)
+    #[argh(option)]
+    background_gradient_end: Option<String>,
+
+    /// gradient angle in degrees from 0 to 360 (0 runs left to right)
+    #[argh(option)]
+    background_gradient_angle: Option<f32>,
+
     /// shell command to run on click
     #[argh(option)]
     on_click: Option<String>,
 
+    /// shell command to run on right click
+    #[argh(option)]
+    on_right_click: Option<String>,
+
+    /// shell command to run on middle click
+    #[argh(option)]
+    on_middle_click: Option<String>,
+
+    /// working directory for the on-click command
+    #[argh(option)]
+    on_click_cwd: Option<String>,
+
+    /// shell used to run the on-click command (default /bin/sh)
+    #[argh(option)]
+    on_click_shell: Option<String>,
+
+    /// confirmation prompt shown before the on-click command runs
+    #[argh(option)]
+    confirm: Option<String>,
+
     /// image file path (PNG, JPEG, etc.)
     #[argh(option)]
     image: Option<String>,
@@ -211,13 +370,54 @@ struct AddCmd {
     #[argh(option)]
     image_scale: Option<f32>,
 
+    /// how full a progress node is, from 0 to 100
+    #[argh(option)]
+    value: Option<f32>,
+
+    /// how the image fits an explicit width/height: fit, fill, stretch, center
+    #[argh(option)]
+    image_content_mode: Option<String>,
+
+    /// style template to inherit unset properties from
+    #[argh(option)]
+    template: Option<String>,
+
+    /// start hidden
+    #[argh(switch)]
+    hidden: bool,
+
+    /// start disabled (clicks are ignored)
+    #[argh(switch)]
+    disabled: bool,
+
     /// sort position
     #[argh(option)]
     position: Option<i32>,
 
-    /// target display ID
+    /// place right after this sibling
     #[argh(option)]
-    display: Option<u32>,
+    after: Option<String>,
+
+    /// place right before this sibling
+    #[argh(option)]
+    before: Option<String>,
+
+    /// target display ID or name
+    #[argh(option)]
+    display: Option<String>,
+
+    /// repeating an add with this key within a minute succeeds without adding again
+    #[argh(option)]
+    idempotency_key: Option<String>,
+}
+
+/// add a container and its children from a JSON tree, all or nothing
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-tree")]
+struct AddTreeCmd {
+    /// read the tree from this file instead of stdin
+    #[argh(positional)]
+    file: Option<String>,
 }
 
 /// update node properties
@@ -260,10 +460,26 @@ struct SetCmd {
     #[argh(option)]
     border_width: Option<f32>,
 
-    /// corner radius
+    /// corner radius for all four corners
     #[argh(option)]
     corner_radius: Option<f32>,
 
+    /// top-left corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_top_left: Option<f32>,
+
+    /// top-right corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_top_right: Option<f32>,
+
+    /// bottom-left corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_bottom_left: Option<f32>,
+
+    /// bottom-right corner radius (overrides --corner-radius)
+    #[argh(option)]
+    corner_radius_bottom_right: Option<f32>,
+
     /// padding left
     #[argh(option)]
     padding_left: Option<f32>,
@@ -300,13 +516,29 @@ struct SetCmd {
     #[argh(option)]
     shadow_radius: Option<f32>,
 
-    /// fixed width
+    /// horizontal shadow offset; positive moves it right
+    #[argh(option)]
+    shadow_offset_x: Option<f32>,
+
+    /// vertical shadow offset; positive moves it down
+    #[argh(option)]
+    shadow_offset_y: Option<f32>,
+
+    /// width in points, a percentage of the parent (e.g. 50%), or fill
+    #[argh(option)]
+    width: Option<String>,
+
+    /// height in points, a percentage of the parent (e.g. 50%), or fill
+    #[argh(option)]
+    height: Option<String>,
+
+    /// minimum width in points; narrower content is centered
     #[argh(option)]
-    width: Option<f32>,
+    min_width: Option<f32>,
 
-    /// container height
+    /// maximum width in points; wider content is truncated
     #[argh(option)]
-    height: Option<f32>,
+    max_width: Option<f32>,
 
     /// item spacing within container
     #[argh(option)]
@@ -340,9 +572,9 @@ struct SetCmd {
     #[argh(option)]
     margin_vertical: Option<f32>,
 
-    /// font size (default 12)
+    /// font size, or +=N / -=N to adjust the current size
     #[argh(option)]
-    font_size: Option<f32>,
+    font_size: Option<String>,
 
     /// font weight (light, regular, medium, semibold, bold)
     #[argh(option)]
@@ -352,6 +584,26 @@ struct SetCmd {
     #[argh(option)]
     font_family: Option<String>,
 
+    /// extra space between characters in points, or +=N / -=N to adjust it
+    #[argh(option)]
+    letter_spacing: Option<String>,
+
+    /// line height as a multiple of the font's natural height, or +=N / -=N
+    #[argh(option)]
+    line_height: Option<String>,
+
+    /// font style: normal or italic
+    #[argh(option)]
+    font_style: Option<String>,
+
+    /// underline the label (true or false)
+    #[argh(option)]
+    underline: Option<bool>,
+
+    /// strike through the label (true or false)
+    #[argh(option)]
+    strikethrough: Option<bool>,
+
     /// notch alignment: left or right (only effective on notched displays)
     #[argh(option)]
     notch_align: Option<String>,
@@ -360,6 +612,10 @@ struct SetCmd {
     #[argh(option)]
     align_items: Option<String>,
 
+    /// cross-axis alignment of this node alone, overriding the parent's align-items: start, center, or end
+    #[argh(option)]
+    align_self: Option<String>,
+
     /// main-axis alignment of children: start, center, or end
     #[argh(option)]
     justify_content: Option<String>,
@@ -376,10 +632,74 @@ struct SetCmd {
     #[argh(option)]
     hover_icon_color: Option<String>,
 
+    /// focus ring color (hex), drawn outside the node without taking space
+    #[argh(option)]
+    outline_color: Option<String>,
+
+    /// focus ring width
+    #[argh(option)]
+    outline_width: Option<f32>,
+
+    /// gap between the node and its focus ring (negative draws inside)
+    #[argh(option)]
+    outline_offset: Option<f32>,
+
+    /// blend mode: normal, multiply, screen, overlay, darken, or lighten
+    #[argh(option)]
+    blend_mode: Option<String>,
+
+    /// where an over-long label is cut: head, middle, tail, or none
+    #[argh(option)]
+    text_truncation: Option<String>,
+
+    /// fill color of a progress node (hex)
+    #[argh(option)]
+    progress_color: Option<String>,
+
+    /// color of a progress node's unfilled track (hex)
+    #[argh(option)]
+    progress_track_color: Option<String>,
+
+    /// background opacity from 0 to 1, applied on top of the color's alpha
+    #[argh(option)]
+    background_opacity: Option<f32>,
+
+    /// gradient start color (hex); drawn instead of --background-color
+    #[argh(option)]
+    background_gradient_start: Option<String>,
+
+    /// gradient end color (hex)
+    #[argh(option)]
+    background_gradient_end: Option<String>,
+
+    /// gradient angle in degrees from 0 to 360 (0 runs left to right)
+    #[argh(option)]
+    background_gradient_angle: Option<f32>,
+
     /// shell command to run on click
     #[argh(option)]
     on_click: Option<String>,
 
+    /// shell command to run on right click
+    #[argh(option)]
+    on_right_click: Option<String>,
+
+    /// shell command to run on middle click
+    #[argh(option)]
+    on_middle_click: Option<String>,
+
+    /// working directory for the on-click command
+    #[argh(option)]
+    on_click_cwd: Option<String>,
+
+    /// shell used to run the on-click command (default /bin/sh)
+    #[argh(option)]
+    on_click_shell: Option<String>,
+
+    /// confirmation prompt shown before the on-click command runs
+    #[argh(option)]
+    confirm: Option<String>,
+
     /// image file path (PNG, JPEG, etc.)
     #[argh(option)]
     image: Option<String>,
@@ -388,13 +708,144 @@ struct SetCmd {
     #[argh(option)]
     image_scale: Option<f32>,
 
+    /// how full a progress node is, from 0 to 100
+    #[argh(option)]
+    value: Option<f32>,
+
+    /// how the image fits an explicit width/height: fit, fill, stretch, center
+    #[argh(option)]
+    image_content_mode: Option<String>,
+
+    /// style template to inherit unset properties from
+    #[argh(option)]
+    template: Option<String>,
+
+    /// hide (true) or show (false) the node and its descendants
+    #[argh(option)]
+    hidden: Option<bool>,
+
+    /// flip the current hidden state
+    #[argh(switch)]
+    toggle_hidden: bool,
+
+    /// disable (true) or enable (false) clicks on the node
+    #[argh(option)]
+    disabled: Option<bool>,
+
+    /// flip the current disabled state
+    #[argh(switch)]
+    toggle_disabled: bool,
+
     /// sort position
     #[argh(option)]
     position: Option<i32>,
 
-    /// move to display ID (empty string to reset)
+    /// move to display ID or name (empty string to reset)
     #[argh(option)]
     display: Option<String>,
+
+    /// include the previous values of the changed properties in the response
+    #[argh(switch)]
+    with_old: bool,
+}
+
+/// move a node under another parent
+#[derive(FromArgs)]
+#[argh(subcommand, name = "move")]
+struct MoveCmd {
+    /// node name
+    #[argh(positional)]
+    name: String,
+
+    /// new parent container (omit to move to the top level)
+    #[argh(option)]
+    parent: Option<String>,
+
+    /// sort position among the new siblings (default: after the last one)
+    #[argh(option)]
+    position: Option<i32>,
+}
+
+/// show a hidden node or hide a shown one, keeping its properties
+#[derive(FromArgs)]
+#[argh(subcommand, name = "toggle")]
+struct ToggleCmd {
+    /// node name
+    #[argh(positional)]
+    name: String,
+}
+
+/// rename a node, keeping its children attached
+#[derive(FromArgs)]
+#[argh(subcommand, name = "rename")]
+struct RenameCmd {
+    /// current node name
+    #[argh(positional)]
+    from: String,
+
+    /// new node name
+    #[argh(positional)]
+    to: String,
+}
+
+/// reset a node's styling, or just the listed properties, to defaults
+#[derive(FromArgs)]
+#[argh(subcommand, name = "reset")]
+struct ResetCmd {
+    /// node name
+    #[argh(positional)]
+    name: String,
+
+    /// comma-separated properties to reset (default: all styling)
+    #[argh(positional)]
+    properties: Option<String>,
+}
+
+/// copy a node and its descendants, printing the new names
+#[derive(FromArgs)]
+#[argh(subcommand, name = "duplicate")]
+struct DuplicateCmd {
+    /// node to copy
+    #[argh(positional)]
+    name: String,
+
+    /// name of the copy; descendants are renamed to match
+    #[argh(positional)]
+    new_name: String,
+
+    /// place the copy on this display ID
+    #[argh(option)]
+    display: Option<u32>,
+}
+
+/// set the order of sibling nodes in one step
+#[derive(FromArgs)]
+#[argh(subcommand, name = "reorder")]
+struct ReorderCmd {
+    /// comma-separated sibling names in their new order
+    #[argh(positional)]
+    order: String,
+
+    /// parent container of the siblings (omit for top-level nodes)
+    #[argh(option)]
+    parent: Option<String>,
+
+    /// display ID the siblings are on
+    #[argh(option)]
+    display: u32,
+}
+
+/// exchange the positions of two sibling nodes
+#[derive(FromArgs)]
+#[argh(subcommand, name = "swap")]
+struct SwapCmd {
+    /// first node name
+    #[argh(positional)]
+    a: String,
+
+    /// second node name
+    #[argh(positional)]
+    b: String,
 }
 
 /// remove a node
@@ -404,6 +855,32 @@ struct RemoveCmd {
     /// node name
     #[argh(positional)]
     name: String,
+
+    /// mark the node as removing and remove it after this many milliseconds
+    #[argh(option)]
+    after_ms: Option<u64>,
+
+    /// print the names that would be removed without removing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// remove all nodes
+#[derive(FromArgs)]
+#[argh(subcommand, name = "clear")]
+struct ClearCmd {
+    /// only clear this display ID
+    #[argh(option)]
+    display: Option<u32>,
+}
+
+/// make the bar redraw every node from the daemon's state
+#[derive(FromArgs)]
+#[argh(subcommand, name = "refresh")]
+struct RefreshCmd {
+    /// only refresh this display ID
+    #[argh(option)]
+    display: Option<u32>,
 }
 
 /// query nodes
@@ -414,9 +891,50 @@ struct QueryCmd {
     #[argh(positional)]
     name: Option<String>,
 
-    /// filter by display ID
+    /// filter by display ID or name
     #[argh(option)]
-    display: Option<u32>,
+    display: Option<String>,
+
+    /// look up a node by its stable id
+    #[argh(option)]
+    id: Option<u64>,
+
+    /// only direct children of this node, in position order (an error if it
+    /// doesn't exist; ignored when a name or --id is given)
+    #[argh(option)]
+    parent: Option<String>,
+
+    /// exclude the node with this name
+    #[argh(option)]
+    not_name: Option<String>,
+
+    /// exclude direct children of this node
+    #[argh(option)]
+    not_parent: Option<String>,
+
+    /// exclude nodes of this type (item, row, column, box)
+    #[argh(option)]
+    not_type: Option<String>,
+
+    /// comma-separated key=value conditions every node must meet, e.g. node_type=item,label=
+    #[argh(option, long = "where")]
+    filter: Option<String>,
+
+    /// comma-separated fields to include (name is always included)
+    #[argh(option)]
+    fields: Option<String>,
+
+    /// print one node per line as the server streams them
+    #[argh(switch)]
+    stream: bool,
+
+    /// restrict output to a historical field set (v1 or v2) for older scripts
+    #[argh(option)]
+    compat: Option<String>,
+
+    /// write the result to this file, replacing it only if the query succeeds
+    #[argh(option)]
+    output_file: Option<String>,
 }
 
 /// list connected displays
@@ -424,11 +942,150 @@ struct QueryCmd {
 #[argh(subcommand, name = "displays")]
 struct DisplaysCmd {}
 
+/// exit 0 if a node exists, 1 if it does not
+#[derive(FromArgs)]
+#[argh(subcommand, name = "exists")]
+struct ExistsCmd {
+    /// node name
+    #[argh(positional)]
+    name: String,
+}
+
+/// print commands that rebuild nodes, one JSON object per line
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+struct ExportCmd {
+    /// export only this node and its descendants
+    #[argh(positional)]
+    name: Option<String>,
+
+    /// only nodes on this display ID
+    #[argh(option)]
+    display: Option<u32>,
+
+    /// only properties that were set explicitly, keeping shorthands like padding
+    #[argh(switch)]
+    only_user_set: bool,
+
+    /// write the commands to this file, replacing it only if the export succeeds
+    #[argh(option)]
+    output_file: Option<String>,
+}
+
+/// print the whole bar state as JSON that restore accepts
+#[derive(FromArgs)]
+#[argh(subcommand, name = "snapshot")]
+struct SnapshotCmd {}
+
+/// replace the whole bar state with a snapshot
+#[derive(FromArgs)]
+#[argh(subcommand, name = "restore")]
+struct RestoreCmd {
+    /// read the snapshot from this file instead of stdin
+    #[argh(positional)]
+    file: Option<String>,
+}
+
+/// show per-command counts and handling times
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stats")]
+struct StatsCmd {}
+
+/// print the number of nodes, then the number on each display
+#[derive(FromArgs)]
+#[argh(subcommand, name = "count")]
+struct CountCmd {
+    /// count only this display
+    #[argh(option)]
+    display: Option<u32>,
+}
+
+/// list the defined templates and the properties each one sets
+#[derive(FromArgs)]
+#[argh(subcommand, name = "styles")]
+struct StylesCmd {}
+
+/// list every property set accepts, its type and the node types it applies to
+#[derive(FromArgs)]
+#[argh(subcommand, name = "schema")]
+struct SchemaCmd {
+    /// print the daemon's response as JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+}
+
+/// show or replace the properties new nodes on a display start with
+#[derive(FromArgs)]
+#[argh(subcommand, name = "display-defaults")]
+struct DisplayDefaultsCmd {
+    /// only this display ID (required with --set)
+    #[argh(option)]
+    display: Option<u32>,
+
+    /// replace the defaults with the JSON object of properties in this file, or - for stdin
+    #[argh(option)]
+    set: Option<String>,
+}
+
+/// rerun the init script without restarting the server
+#[derive(FromArgs)]
+#[argh(subcommand, name = "reload")]
+struct ReloadCmd {
+    /// run this script instead of the one the server started with
+    #[argh(option, long = "init")]
+    init_script: Option<String>,
+
+    /// remove every node before running the script
+    #[argh(switch)]
+    clear: bool,
+}
+
+/// stop the server, removing its socket
+#[derive(FromArgs)]
+#[argh(subcommand, name = "shutdown")]
+struct ShutdownCmd {}
+
+/// check that the daemon is running and print its version
+#[derive(FromArgs)]
+#[argh(subcommand, name = "ping")]
+struct PingCmd {}
+
+/// time add/query/set/remove cycles against the daemon
+#[derive(FromArgs)]
+#[argh(subcommand, name = "bench")]
+struct BenchCmd {
+    /// number of nodes per cycle (default 100)
+    #[argh(option, default = "100")]
+    nodes: usize,
+
+    /// number of cycles (default 10)
+    #[argh(option, default = "10")]
+    iterations: usize,
+}
+
+/// apply newline-delimited JSON commands atomically
+#[derive(FromArgs)]
+#[argh(subcommand, name = "batch")]
+struct BatchCmd {
+    /// read commands from this file instead of stdin
+    #[argh(positional)]
+    file: Option<String>,
+}
+
 /// print version
 #[derive(FromArgs)]
 #[argh(subcommand, name = "version")]
 struct VersionCmd {}
 
+/// print a shell completion script
+#[derive(FromArgs)]
+#[argh(subcommand, name = "completion")]
+struct CompletionCmd {
+    /// bash, zsh, or fish
+    #[argh(positional)]
+    shell: String,
+}
+
 /// display node tree
 #[derive(FromArgs)]
 #[argh(subcommand, name = "tree")]
@@ -436,39 +1093,228 @@ struct TreeCmd {
     /// filter by display ID
     #[argh(option)]
     display: Option<u32>,
+
+    /// colorize output: auto (default, only on a terminal), always, or never
+    #[argh(option, default = "String::from(\"auto\")")]
+    color: String,
+
+    /// print the roots as JSON, each node with a children array
+    #[argh(switch)]
+    json: bool,
+}
+
+/// stream state change events
+#[derive(FromArgs)]
+#[argh(subcommand, name = "watch")]
+struct WatchCmd {
+    /// print each event as a raw JSON line
+    #[argh(switch)]
+    json_lines: bool,
+
+    /// replay buffered events after this sequence number before going live
+    #[argh(option)]
+    since_seq: Option<u64>,
+
+    /// comma-separated event kinds to stream (e.g. node_added,node_removed)
+    #[argh(option)]
+    events: Option<String>,
 }
 
 fn main() {
     let args: Args = argh::from_env();
+    let socket_path = args.socket.unwrap_or_else(default_socket_path);
 
     if let Command::Start(cmd) = args.command {
-        exec_server(cmd);
+        exec_server(cmd, &socket_path);
+        return;
+    }
+
+    if let Command::Version(_) = args.command {
+        println!("v{}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    if let Command::Completion(cmd) = args.command {
+        match completion::generate(&cmd.shell) {
+            Ok(script) => print!("{script}"),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Command::Ping(_) = args.command {
+        match ping(&socket_path) {
+            Ok((protocol, version)) => {
+                let version = version.map_or("unknown version".to_string(), |v| format!("v{v}"));
+                println!("{version} (protocol {protocol})");
+                if let Some(warning) = protocol_warning(protocol) {
+                    eprintln!("warning: {warning}");
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Command::Exists(cmd) = args.command {
+        let command = json!({ "command": "exists", "name": cmd.name });
+        match send_command(&socket_path, &command).and_then(|r| exists_status(&r)) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Command::Bench(cmd) = args.command {
+        check_protocol(&socket_path);
+        match bench::run(&socket_path, cmd.nodes, cmd.iterations) {
+            Ok(stats) => print!("{}", bench::format_report(&stats)),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Command::Tree(cmd) = args.command {
+        run_tree(cmd, &socket_path);
+        return;
+    }
+
+    if let Command::Watch(cmd) = args.command {
+        if let Err(e) = run_watch(cmd, &socket_path) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
         return;
     }
 
-    if let Command::Version(_) = args.command {
-        println!("v{}", env!("CARGO_PKG_VERSION"));
-        return;
+    let schema_table = matches!(&args.command, Command::Schema(c) if !c.json);
+    let output_file = match &args.command {
+        Command::Query(c) => c.output_file.clone(),
+        Command::Export(c) => c.output_file.clone(),
+        _ => None,
+    };
+    let command = match args.command {
+        Command::Batch(cmd) => match read_batch(cmd) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Command::Restore(cmd) => match read_snapshot(cmd) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Command::AddTree(cmd) => match read_tree(cmd) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Command::DisplayDefaults(cmd) => match display_defaults_command(cmd) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        command => build_command(command),
+    };
+
+    if output_file.is_some() && command["stream"] == true {
+        eprintln!("error: --output-file cannot be combined with --stream");
+        std::process::exit(1);
     }
 
-    if let Command::Tree(cmd) = args.command {
-        run_tree(cmd);
+    if let Some(path) = output_file {
+        let result = send_command(&socket_path, &command)
+            .map_err(|e| (e, 1))
+            .and_then(|response| write_output(Path::new(&path), &command, &response));
+        if let Err((message, status)) = result {
+            eprintln!("error: {message}");
+            std::process::exit(status);
+        }
         return;
     }
-
-    let command = build_command(args.command);
-
-    let socket_path = default_socket_path();
-    match send_command(&socket_path, &command) {
-        Ok(response) => println!("{response}"),
+    if command["stream"] == true {
+        if let Err(e) = stream_command(&socket_path, &command) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    let response = match send_command(&socket_path, &command) {
+        Ok(response) => response,
         Err(e) => {
             eprintln!("error: {e}");
             std::process::exit(1);
         }
+    };
+    if let Some((message, status)) = error_response(&response) {
+        eprintln!("error: {message}");
+        std::process::exit(status);
     }
+    match command["command"].as_str() {
+        Some("duplicate" | "add_tree") => print_each(&response, "names"),
+        _ if command["dry_run"] == true => print_each(&response, "names"),
+        Some("export") => print_each(&response, "commands"),
+        Some("snapshot") => print_snapshot(&response),
+        Some("count") => match format_count(&response) {
+            Ok(counts) => print!("{counts}"),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Some("add" | "set") => print_node(&response),
+        Some("schema") if schema_table => match format_schema(&response) {
+            Ok(table) => print!("{table}"),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        _ => println!("{response}"),
+    }
+}
+
+/// The message and exit status of an error response, one status per
+/// error code so scripts can tell failures apart.
+fn error_response(response: &str) -> Option<(String, i32)> {
+    let value: Value = serde_json::from_str(response).ok()?;
+    if value["status"] != "error" {
+        return None;
+    }
+    let status = match serde_json::from_value(value["code"].clone()) {
+        Ok(ErrorCode::NotFound) => 3,
+        Ok(ErrorCode::AlreadyExists) => 4,
+        Ok(ErrorCode::InvalidProperty) => 5,
+        Ok(ErrorCode::InvalidValue) => 6,
+        Ok(ErrorCode::Cycle) => 7,
+        Ok(ErrorCode::WrongDisplay) => 8,
+        Ok(ErrorCode::InvalidCommand | ErrorCode::Failed) | Err(_) => 1,
+    };
+    let message = value["message"].as_str().unwrap_or(response).to_string();
+    Some((message, status))
 }
 
-fn exec_server(cmd: StartCmd) {
+fn exec_server(cmd: StartCmd, socket_path: &str) {
     let server_path = cmd.server_path.unwrap_or_else(|| {
         let exe = std::env::current_exe().expect("cannot determine executable path");
         let dir = exe.parent().expect("cannot determine executable directory");
@@ -476,8 +1322,16 @@ fn exec_server(cmd: StartCmd) {
     });
 
     let mut command = std::process::Command::new(&server_path);
-    if let Some(init) = cmd.init_script {
-        command.env("RANMA_INIT", init);
+    command.env(SOCKET_ENV, socket_path);
+    match config_init_path(cmd.init_script.as_deref()) {
+        Ok(Some(init)) => {
+            command.env(INIT_ENV, init);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
     }
     let err = command.exec();
     eprintln!("error: failed to exec {}: {}", server_path, err);
@@ -522,6 +1376,18 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.corner_radius {
                 obj["corner_radius"] = json!(v);
             }
+            if let Some(v) = c.corner_radius_top_left {
+                obj["corner_radius_top_left"] = json!(v);
+            }
+            if let Some(v) = c.corner_radius_top_right {
+                obj["corner_radius_top_right"] = json!(v);
+            }
+            if let Some(v) = c.corner_radius_bottom_left {
+                obj["corner_radius_bottom_left"] = json!(v);
+            }
+            if let Some(v) = c.corner_radius_bottom_right {
+                obj["corner_radius_bottom_right"] = json!(v);
+            }
             if let Some(v) = c.padding_left {
                 obj["padding_left"] = json!(v);
             }
@@ -549,12 +1415,24 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.shadow_radius {
                 obj["shadow_radius"] = json!(v);
             }
+            if let Some(v) = c.shadow_offset_x {
+                obj["shadow_offset_x"] = json!(v);
+            }
+            if let Some(v) = c.shadow_offset_y {
+                obj["shadow_offset_y"] = json!(v);
+            }
             if let Some(v) = c.width {
                 obj["width"] = json!(v);
             }
             if let Some(v) = c.height {
                 obj["height"] = json!(v);
             }
+            if let Some(v) = c.min_width {
+                obj["min_width"] = json!(v);
+            }
+            if let Some(v) = c.max_width {
+                obj["max_width"] = json!(v);
+            }
             if let Some(v) = c.gap {
                 obj["gap"] = json!(v);
             }
@@ -588,12 +1466,30 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.font_family {
                 obj["font_family"] = json!(v);
             }
+            if let Some(v) = c.letter_spacing {
+                obj["letter_spacing"] = json!(v);
+            }
+            if let Some(v) = c.line_height {
+                obj["line_height"] = json!(v);
+            }
+            if let Some(v) = c.font_style {
+                obj["font_style"] = json!(v);
+            }
+            if let Some(v) = c.underline {
+                obj["underline"] = json!(v);
+            }
+            if let Some(v) = c.strikethrough {
+                obj["strikethrough"] = json!(v);
+            }
             if let Some(v) = c.notch_align {
                 obj["notch_align"] = json!(v);
             }
             if let Some(v) = c.align_items {
                 obj["align_items"] = json!(v);
             }
+            if let Some(v) = c.align_self {
+                obj["align_self"] = json!(v);
+            }
             if let Some(v) = c.justify_content {
                 obj["justify_content"] = json!(v);
             }
@@ -606,20 +1502,92 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.hover_icon_color {
                 obj["hover_icon_color"] = json!(v);
             }
+            if let Some(v) = c.outline_color {
+                obj["outline_color"] = json!(v);
+            }
+            if let Some(v) = c.outline_width {
+                obj["outline_width"] = json!(v);
+            }
+            if let Some(v) = c.outline_offset {
+                obj["outline_offset"] = json!(v);
+            }
+            if let Some(v) = c.blend_mode {
+                obj["blend_mode"] = json!(v);
+            }
+            if let Some(v) = c.text_truncation {
+                obj["text_truncation"] = json!(v);
+            }
+            if let Some(v) = c.progress_color {
+                obj["progress_color"] = json!(v);
+            }
+            if let Some(v) = c.progress_track_color {
+                obj["progress_track_color"] = json!(v);
+            }
+            if let Some(v) = c.background_opacity {
+                obj["background_opacity"] = json!(v);
+            }
+            if let Some(v) = c.background_gradient_start {
+                obj["background_gradient_start"] = json!(v);
+            }
+            if let Some(v) = c.background_gradient_end {
+                obj["background_gradient_end"] = json!(v);
+            }
+            if let Some(v) = c.background_gradient_angle {
+                obj["background_gradient_angle"] = json!(v);
+            }
             if let Some(v) = c.on_click {
                 obj["on_click"] = json!(v);
             }
+            if let Some(v) = c.on_right_click {
+                obj["on_right_click"] = json!(v);
+            }
+            if let Some(v) = c.on_middle_click {
+                obj["on_middle_click"] = json!(v);
+            }
+            if let Some(v) = c.on_click_cwd {
+                obj["on_click_cwd"] = json!(v);
+            }
+            if let Some(v) = c.on_click_shell {
+                obj["on_click_shell"] = json!(v);
+            }
+            if let Some(v) = c.confirm {
+                obj["confirm"] = json!(v);
+            }
             if let Some(v) = c.image {
                 obj["image"] = json!(v);
             }
             if let Some(v) = c.image_scale {
                 obj["image_scale"] = json!(v);
             }
+            if let Some(v) = c.value {
+                obj["value"] = json!(v);
+            }
+            if let Some(v) = c.image_content_mode {
+                obj["image_content_mode"] = json!(v);
+            }
+            if let Some(v) = c.template {
+                obj["template"] = json!(v);
+            }
+            if c.hidden {
+                obj["hidden"] = json!(true);
+            }
+            if c.disabled {
+                obj["disabled"] = json!(true);
+            }
             if let Some(v) = c.position {
                 obj["position"] = json!(v);
             }
+            if let Some(v) = c.after {
+                obj["after"] = json!(v);
+            }
+            if let Some(v) = c.before {
+                obj["before"] = json!(v);
+            }
             if let Some(v) = c.display {
-                obj["display"] = json!(v);
+                obj["display"] = display_ref(v);
+            }
+            if let Some(v) = c.idempotency_key {
+                obj["idempotency_key"] = json!(v);
             }
             obj
         }
@@ -652,6 +1620,18 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.corner_radius {
                 properties.insert("corner_radius".into(), v.to_string());
             }
+            if let Some(v) = c.corner_radius_top_left {
+                properties.insert("corner_radius_top_left".into(), v.to_string());
+            }
+            if let Some(v) = c.corner_radius_top_right {
+                properties.insert("corner_radius_top_right".into(), v.to_string());
+            }
+            if let Some(v) = c.corner_radius_bottom_left {
+                properties.insert("corner_radius_bottom_left".into(), v.to_string());
+            }
+            if let Some(v) = c.corner_radius_bottom_right {
+                properties.insert("corner_radius_bottom_right".into(), v.to_string());
+            }
             if let Some(v) = c.padding_left {
                 properties.insert("padding_left".into(), v.to_string());
             }
@@ -679,11 +1659,23 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.shadow_radius {
                 properties.insert("shadow_radius".into(), v.to_string());
             }
+            if let Some(v) = c.shadow_offset_x {
+                properties.insert("shadow_offset_x".into(), v.to_string());
+            }
+            if let Some(v) = c.shadow_offset_y {
+                properties.insert("shadow_offset_y".into(), v.to_string());
+            }
             if let Some(v) = c.width {
-                properties.insert("width".into(), v.to_string());
+                properties.insert("width".into(), v);
             }
             if let Some(v) = c.height {
-                properties.insert("height".into(), v.to_string());
+                properties.insert("height".into(), v);
+            }
+            if let Some(v) = c.min_width {
+                properties.insert("min_width".into(), v.to_string());
+            }
+            if let Some(v) = c.max_width {
+                properties.insert("max_width".into(), v.to_string());
             }
             if let Some(v) = c.gap {
                 properties.insert("gap".into(), v.to_string());
@@ -710,7 +1702,7 @@ fn build_command(cmd: Command) -> Value {
                 properties.insert("margin_vertical".into(), v.to_string());
             }
             if let Some(v) = c.font_size {
-                properties.insert("font_size".into(), v.to_string());
+                properties.insert("font_size".into(), v);
             }
             if let Some(v) = c.font_weight {
                 properties.insert("font_weight".into(), v);
@@ -718,12 +1710,30 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.font_family {
                 properties.insert("font_family".into(), v);
             }
+            if let Some(v) = c.letter_spacing {
+                properties.insert("letter_spacing".into(), v);
+            }
+            if let Some(v) = c.line_height {
+                properties.insert("line_height".into(), v);
+            }
+            if let Some(v) = c.font_style {
+                properties.insert("font_style".into(), v);
+            }
+            if let Some(v) = c.underline {
+                properties.insert("underline".into(), v.to_string());
+            }
+            if let Some(v) = c.strikethrough {
+                properties.insert("strikethrough".into(), v.to_string());
+            }
             if let Some(v) = c.notch_align {
                 properties.insert("notch_align".into(), v);
             }
             if let Some(v) = c.align_items {
                 properties.insert("align_items".into(), v);
             }
+            if let Some(v) = c.align_self {
+                properties.insert("align_self".into(), v);
+            }
             if let Some(v) = c.justify_content {
                 properties.insert("justify_content".into(), v);
             }
@@ -736,15 +1746,84 @@ fn build_command(cmd: Command) -> Value {
             if let Some(v) = c.hover_icon_color {
                 properties.insert("hover_icon_color".into(), v);
             }
+            if let Some(v) = c.outline_color {
+                properties.insert("outline_color".into(), v);
+            }
+            if let Some(v) = c.outline_width {
+                properties.insert("outline_width".into(), v.to_string());
+            }
+            if let Some(v) = c.outline_offset {
+                properties.insert("outline_offset".into(), v.to_string());
+            }
+            if let Some(v) = c.blend_mode {
+                properties.insert("blend_mode".into(), v);
+            }
+            if let Some(v) = c.text_truncation {
+                properties.insert("text_truncation".into(), v);
+            }
+            if let Some(v) = c.progress_color {
+                properties.insert("progress_color".into(), v);
+            }
+            if let Some(v) = c.progress_track_color {
+                properties.insert("progress_track_color".into(), v);
+            }
+            if let Some(v) = c.background_opacity {
+                properties.insert("background_opacity".into(), v.to_string());
+            }
+            if let Some(v) = c.background_gradient_start {
+                properties.insert("background_gradient_start".into(), v);
+            }
+            if let Some(v) = c.background_gradient_end {
+                properties.insert("background_gradient_end".into(), v);
+            }
+            if let Some(v) = c.background_gradient_angle {
+                properties.insert("background_gradient_angle".into(), v.to_string());
+            }
             if let Some(v) = c.on_click {
                 properties.insert("on_click".into(), v);
             }
+            if let Some(v) = c.on_right_click {
+                properties.insert("on_right_click".into(), v);
+            }
+            if let Some(v) = c.on_middle_click {
+                properties.insert("on_middle_click".into(), v);
+            }
+            if let Some(v) = c.on_click_cwd {
+                properties.insert("on_click_cwd".into(), v);
+            }
+            if let Some(v) = c.on_click_shell {
+                properties.insert("on_click_shell".into(), v);
+            }
+            if let Some(v) = c.confirm {
+                properties.insert("confirm".into(), v);
+            }
             if let Some(v) = c.image {
                 properties.insert("image".into(), v);
             }
             if let Some(v) = c.image_scale {
                 properties.insert("image_scale".into(), v.to_string());
             }
+            if let Some(v) = c.value {
+                properties.insert("value".into(), v.to_string());
+            }
+            if let Some(v) = c.image_content_mode {
+                properties.insert("image_content_mode".into(), v);
+            }
+            if let Some(v) = c.template {
+                properties.insert("template".into(), v);
+            }
+            if let Some(v) = c.hidden {
+                properties.insert("hidden".into(), v.to_string());
+            }
+            if c.toggle_hidden {
+                properties.insert("toggle_hidden".into(), String::new());
+            }
+            if let Some(v) = c.disabled {
+                properties.insert("disabled".into(), v.to_string());
+            }
+            if c.toggle_disabled {
+                properties.insert("toggle_disabled".into(), String::new());
+            }
             if let Some(v) = c.position {
                 properties.insert("position".into(), v.to_string());
             }
@@ -755,19 +1834,116 @@ fn build_command(cmd: Command) -> Value {
                 "command": "set",
                 "name": c.name,
                 "properties": properties,
+                "with_old": c.with_old,
             })
         }
-        Command::Remove(c) => json!({ "command": "remove", "name": c.name }),
-        Command::Query(c) => json!({ "command": "query", "name": c.name, "display": c.display }),
+        Command::Move(c) => json!({
+            "command": "move",
+            "name": c.name,
+            "parent": c.parent,
+            "position": c.position,
+        }),
+        Command::Duplicate(c) => json!({
+            "command": "duplicate",
+            "name": c.name,
+            "new_name": c.new_name,
+            "new_display": c.display,
+        }),
+        Command::Toggle(c) => json!({
+            "command": "set",
+            "name": c.name,
+            "properties": { "toggle_hidden": "" },
+        }),
+        Command::Rename(c) => json!({
+            "command": "rename",
+            "from": c.from,
+            "to": c.to,
+        }),
+        Command::Reset(c) => json!({
+            "command": "reset",
+            "name": c.name,
+            "properties": c.properties.map(|p| p.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>()),
+        }),
+        Command::Reorder(c) => json!({
+            "command": "reorder",
+            "parent": c.parent,
+            "display": c.display,
+            "order": c.order.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>(),
+        }),
+        Command::Swap(c) => json!({ "command": "swap", "a": c.a, "b": c.b }),
+        Command::Remove(c) => json!({
+            "command": "remove",
+            "name": c.name,
+            "after_ms": c.after_ms,
+            "dry_run": c.dry_run,
+        }),
+        Command::Clear(c) => json!({
+            "command": "clear",
+            "display": c.display,
+        }),
+        Command::Refresh(c) => json!({
+            "command": "refresh",
+            "display": c.display,
+        }),
+        Command::Query(c) => json!({
+            "command": "query",
+            "id": c.id,
+            "name": c.name,
+            "display": c.display.map(display_ref),
+            "parent": c.parent,
+            "not_name": c.not_name,
+            "not_parent": c.not_parent,
+            "not_type": c.not_type,
+            "filter": c.filter.map(|f| f.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>()),
+            "fields": c.fields.map(|f| f.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>()),
+            "stream": c.stream,
+            "compat": c.compat,
+        }),
         Command::Displays(_) => json!({ "command": "displays" }),
-        Command::Tree(_) | Command::Version(_) => unreachable!(),
+        Command::Export(c) => json!({
+            "command": "export",
+            "name": c.name,
+            "display": c.display,
+            "only_user_set": c.only_user_set,
+        }),
+        Command::Snapshot(_) => json!({ "command": "snapshot" }),
+        Command::Stats(_) => json!({ "command": "stats" }),
+        Command::Count(c) => json!({ "command": "count", "display": c.display }),
+        Command::Styles(_) => json!({ "command": "list_styles" }),
+        Command::Schema(_) => json!({ "command": "schema" }),
+        Command::Shutdown(_) => json!({ "command": "shutdown" }),
+        Command::Reload(c) => json!({
+            "command": "reload_init",
+            "path": c.init_script,
+            "clear": c.clear,
+        }),
+        Command::Tree(_)
+        | Command::Watch(_)
+        | Command::Version(_)
+        | Command::Completion(_)
+        | Command::Ping(_)
+        | Command::Bench(_)
+        | Command::Exists(_)
+        | Command::Batch(_)
+        | Command::Restore(_)
+        | Command::DisplayDefaults(_)
+        | Command::AddTree(_) => unreachable!(),
     }
 }
 
-fn run_tree(cmd: TreeCmd) {
+fn run_tree(cmd: TreeCmd, socket_path: &str) {
+    let color = match cmd.color.as_str() {
+        "always" => true,
+        "never" => false,
+        "auto" => std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        other => {
+            eprintln!("error: invalid --color: {other} (expected auto, always, or never)");
+            std::process::exit(1);
+        }
+    };
+
     let query = json!({ "command": "query", "name": null, "display": cmd.display });
-    let socket_path = default_socket_path();
-    let response = match send_command(&socket_path, &query) {
+    let response = match send_command(socket_path, &query) {
         Ok(r) => r,
         Err(e) => {
             eprintln!("error: {e}");
@@ -798,15 +1974,9 @@ fn run_tree(cmd: TreeCmd) {
         by_display.entry(display_id).or_default().push(node);
     }
 
+    let mut json_roots = Vec::new();
     let mut first = true;
     for (display_id, display_nodes) in &by_display {
-        if !first {
-            println!();
-        }
-        first = false;
-
-        println!("[display {display_id}]");
-
         // Build parent->children map
         let mut children_map: HashMap<String, Vec<&Value>> = HashMap::new();
         let mut roots: Vec<&Value> = Vec::new();
@@ -824,29 +1994,71 @@ fn run_tree(cmd: TreeCmd) {
             roots.push(node);
         }
 
-        // Sort by position
-        let sort_by_position = |a: &&Value, b: &&Value| {
-            let pa = a["position"].as_i64().unwrap_or(0);
-            let pb = b["position"].as_i64().unwrap_or(0);
-            pa.cmp(&pb)
-        };
-
-        roots.sort_by(sort_by_position);
+        roots.sort_by(|a, b| compare_siblings(a, b));
         for children in children_map.values_mut() {
-            children.sort_by(sort_by_position);
+            children.sort_by(|a, b| compare_siblings(a, b));
+        }
+
+        if cmd.json {
+            json_roots.extend(roots.iter().map(|root| nest_node(root, &children_map)));
+            continue;
+        }
+
+        if !first {
+            println!();
         }
+        first = false;
 
+        println!("[display {display_id}]");
         for root in &roots {
-            print_tree_node(root, &children_map, "", true);
+            print_tree_node(root, &children_map, "", true, color);
         }
     }
+    if cmd.json {
+        println!("{:#}", Value::Array(json_roots));
+    }
+}
+
+/// `node` with its descendants nested under `children`, in sibling order.
+fn nest_node(node: &Value, children_map: &HashMap<String, Vec<&Value>>) -> Value {
+    let children = children_map
+        .get(node["name"].as_str().unwrap_or(""))
+        .map(|children| {
+            children
+                .iter()
+                .map(|child| nest_node(child, children_map))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut nested = node.clone();
+    nested["children"] = Value::Array(children);
+    nested
 }
 
-fn format_node_line(node: &Value) -> String {
+/// Orders siblings by position, breaking ties by name so output is reproducible.
+fn compare_siblings(a: &Value, b: &Value) -> Ordering {
+    let pa = a["position"].as_i64().unwrap_or(0);
+    let pb = b["position"].as_i64().unwrap_or(0);
+    let na = a["name"].as_str().unwrap_or("");
+    let nb = b["name"].as_str().unwrap_or("");
+    (pa, na).cmp(&(pb, nb))
+}
+
+/// Formats a tree line; with `color`, the label takes the node's
+/// `label_color` and hidden or removing nodes are dimmed.
+fn format_node_line(node: &Value, color: bool) -> String {
     let name = node["name"].as_str().unwrap_or("?");
     let node_type = node["node_type"].as_str().unwrap_or("item");
 
-    let mut line = format!("{name} ({node_type})");
+    let dim = color && (node["removing"] == true || node["hidden"] == true);
+    let mut line = if dim {
+        // SGR 22 ends faint along with bold, so re-enter faint after the name
+        format!("\x1b[2m\x1b[1m{name}\x1b[22;2m ({node_type})")
+    } else if color {
+        format!("\x1b[1m{name}\x1b[22m ({node_type})")
+    } else {
+        format!("{name} ({node_type})")
+    };
 
     if let Some(icon) = node["icon"].as_str()
         && !icon.is_empty()
@@ -856,19 +2068,38 @@ fn format_node_line(node: &Value) -> String {
     if let Some(label) = node["label"].as_str()
         && !label.is_empty()
     {
-        line.push_str(&format!(" \"{label}\""));
+        match node["label_color"].as_str().and_then(parse_hex_rgb) {
+            Some((r, g, b)) if color => {
+                line.push_str(&format!(" \x1b[38;2;{r};{g};{b}m\"{label}\"\x1b[39m"));
+            }
+            _ => line.push_str(&format!(" \"{label}\"")),
+        }
     }
 
+    if dim {
+        line.push_str("\x1b[0m");
+    }
     line
 }
 
+/// Parses `#RRGGBB` or `#RRGGBBAA`, ignoring alpha.
+fn parse_hex_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#')?;
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
 fn print_tree_node(
     node: &Value,
     children_map: &HashMap<String, Vec<&Value>>,
     prefix: &str,
     is_root: bool,
+    color: bool,
 ) {
-    let line = format_node_line(node);
+    let line = format_node_line(node, color);
 
     if is_root {
         println!("{line}");
@@ -880,7 +2111,7 @@ fn print_tree_node(
         for (i, child) in children.iter().enumerate() {
             let is_last = i == count - 1;
             let connector = if is_last { "└── " } else { "├── " };
-            let child_line = format_node_line(child);
+            let child_line = format_node_line(child, color);
             println!("{prefix}{connector}{child_line}");
 
             let child_prefix = if is_last {
@@ -888,18 +2119,321 @@ fn print_tree_node(
             } else {
                 format!("{prefix}│   ")
             };
-            print_tree_node(child, children_map, &child_prefix, false);
+            print_tree_node(child, children_map, &child_prefix, false, color);
+        }
+    }
+}
+
+fn run_watch(cmd: WatchCmd, socket_path: &str) -> Result<(), String> {
+    let events = cmd.events.map(|e| {
+        e.split(',')
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<_>>()
+    });
+    let command = json!({ "command": "watch", "since_seq": cmd.since_seq, "events": events });
+    let mut stream =
+        UnixStream::connect(socket_path).map_err(|e| format!("cannot connect to daemon: {e}"))?;
+
+    let mut payload = serde_json::to_string(&command).unwrap();
+    payload.push('\n');
+    stream
+        .write_all(payload.as_bytes())
+        .map_err(|e| format!("write error: {e}"))?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| format!("read error: {e}"))?;
+        if cmd.json_lines {
+            println!("{line}");
+            continue;
+        }
+        let event: Value =
+            serde_json::from_str(&line).map_err(|e| format!("invalid event: {e}"))?;
+        if event["status"] == "error" {
+            return Err(event["message"].as_str().unwrap_or_default().into());
+        }
+        println!("{}", format_event_line(&event));
+    }
+    Ok(())
+}
+
+fn format_event_line(event: &Value) -> String {
+    let seq = event["seq"].as_u64().unwrap_or(0);
+    let kind = event["event"].as_str().unwrap_or("?");
+    let subject = event["node"]["name"]
+        .as_str()
+        .or(event["name"].as_str())
+        .map(str::to_string)
+        .or_else(|| event["display"].as_u64().map(|d| format!("display {d}")))
+        .unwrap_or_else(|| "all displays".to_string());
+    format!("{seq} {kind} {subject}")
+}
+
+fn read_batch(cmd: BatchCmd) -> Result<Value, String> {
+    let reader: Box<dyn BufRead> = match cmd.file {
+        Some(path) => Box::new(BufReader::new(
+            std::fs::File::open(&path).map_err(|e| format!("cannot open {path}: {e}"))?,
+        )),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+    parse_batch(reader)
+}
+
+fn parse_batch(reader: impl BufRead) -> Result<Value, String> {
+    let mut commands = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("read error: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command: Value = serde_json::from_str(&line)
+            .map_err(|e| format!("line {}: invalid JSON: {e}", index + 1))?;
+        commands.push(command);
+    }
+    Ok(json!({ "command": "batch", "commands": commands }))
+}
+
+fn read_snapshot(cmd: RestoreCmd) -> Result<Value, String> {
+    let snapshot: Value = match cmd.file {
+        Some(path) => {
+            let file =
+                std::fs::File::open(&path).map_err(|e| format!("cannot open {path}: {e}"))?;
+            serde_json::from_reader(BufReader::new(file))
+        }
+        None => serde_json::from_reader(std::io::stdin().lock()),
+    }
+    .map_err(|e| format!("invalid snapshot JSON: {e}"))?;
+    Ok(json!({ "command": "restore", "snapshot": snapshot }))
+}
+
+fn read_tree(cmd: AddTreeCmd) -> Result<Value, String> {
+    let root: Value = match cmd.file {
+        Some(path) => {
+            let file =
+                std::fs::File::open(&path).map_err(|e| format!("cannot open {path}: {e}"))?;
+            serde_json::from_reader(BufReader::new(file))
+        }
+        None => serde_json::from_reader(std::io::stdin().lock()),
+    }
+    .map_err(|e| format!("invalid tree JSON: {e}"))?;
+    Ok(json!({ "command": "add_tree", "root": root }))
+}
+
+fn display_defaults_command(cmd: DisplayDefaultsCmd) -> Result<Value, String> {
+    let Some(path) = cmd.set else {
+        return Ok(json!({ "command": "get_display_defaults", "display": cmd.display }));
+    };
+    let display = cmd.display.ok_or("--set requires --display")?;
+    let properties: Value = if path == "-" {
+        serde_json::from_reader(std::io::stdin().lock())
+    } else {
+        let file = std::fs::File::open(&path).map_err(|e| format!("cannot open {path}: {e}"))?;
+        serde_json::from_reader(BufReader::new(file))
+    }
+    .map_err(|e| format!("invalid defaults JSON: {e}"))?;
+    Ok(json!({
+        "command": "set_display_defaults",
+        "display": display,
+        "properties": properties,
+    }))
+}
+
+/// Prints the snapshot document, or the error.
+fn print_snapshot(response: &str) {
+    let value: Value = serde_json::from_str(response).unwrap_or_default();
+    if value["status"] != "snapshot" {
+        eprintln!("error: {}", value["message"].as_str().unwrap_or(response));
+        std::process::exit(1);
+    }
+    println!("{:#}", value["snapshot"]);
+}
+
+/// `--display` as a number when it is one, so only names go as strings.
+fn display_ref(value: String) -> Value {
+    match value.parse::<u32>() {
+        Ok(id) => json!(id),
+        Err(_) => json!(value),
+    }
+}
+
+/// The total on the first line, then one `display N: count` line each.
+fn format_count(response: &str) -> Result<String, String> {
+    let value: Value = serde_json::from_str(response).map_err(|e| e.to_string())?;
+    if value["status"] != "count" {
+        return Err(value["message"].as_str().unwrap_or(response).to_string());
+    }
+    let mut out = format!("{}\n", value["total"]);
+    if let Some(per_display) = value["per_display"].as_object() {
+        for (display, count) in per_display {
+            out.push_str(&format!("display {display}: {count}\n"));
+        }
+    }
+    Ok(out)
+}
+
+/// One row per property with its type and the node types it applies to,
+/// `all` when it applies to every type.
+fn format_schema(response: &str) -> Result<String, String> {
+    let value: Value = serde_json::from_str(response).map_err(|e| e.to_string())?;
+    let Some(properties) = value["properties"].as_array() else {
+        return Err(value["message"].as_str().unwrap_or(response).to_string());
+    };
+    let all_types = value["node_types"].as_array().map_or(0, Vec::len);
+    let rows: Vec<[String; 3]> = properties
+        .iter()
+        .map(|p| {
+            let types: Vec<&str> = p["node_types"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            let applies = if types.len() == all_types {
+                "all".to_string()
+            } else {
+                types.join(",")
+            };
+            [
+                p["key"].as_str().unwrap_or_default().to_string(),
+                p["type"].as_str().unwrap_or_default().to_string(),
+                applies,
+            ]
+        })
+        .collect();
+    let header = ["property".to_string(), "type".into(), "node types".into()];
+    let key_width = rows
+        .iter()
+        .chain([&header])
+        .map(|r| r[0].len())
+        .max()
+        .unwrap_or(0);
+    let type_width = rows
+        .iter()
+        .chain([&header])
+        .map(|r| r[1].len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for [key, kind, applies] in std::iter::once(&header).chain(&rows) {
+        out.push_str(&format!(
+            "{key:<key_width$}  {kind:<type_width$}  {applies}\n"
+        ));
+    }
+    Ok(out)
+}
+
+/// Pretty-prints the node an `add` or `set` reports, or the response as is.
+fn print_node(response: &str) {
+    let value: Value = serde_json::from_str(response).unwrap_or_default();
+    if value["status"] == "node_result" {
+        println!("{:#}", value["node"]);
+    } else {
+        println!("{response}");
+    }
+}
+
+fn exists_status(response: &str) -> Result<bool, String> {
+    let value: Value =
+        serde_json::from_str(response).map_err(|e| format!("invalid response: {e}"))?;
+    value["exists"]
+        .as_bool()
+        .ok_or_else(|| value["message"].as_str().unwrap_or(response).to_string())
+}
+
+/// Writes what `command` would print for `response` to `path`. An error
+/// response leaves `path` as it was; otherwise it is replaced atomically.
+/// Writes a successful response to `path`; an error response fails with
+/// the exit status `error_response` gives it.
+fn write_output(path: &Path, command: &Value, response: &str) -> Result<(), (String, i32)> {
+    if let Some(error) = error_response(response) {
+        return Err(error);
+    }
+    let value: Value =
+        serde_json::from_str(response).map_err(|e| (format!("invalid response: {e}"), 1))?;
+    let mut output = String::new();
+    if command["command"] == "export" {
+        for item in value["commands"].as_array().into_iter().flatten() {
+            output.push_str(&format!("{item}\n"));
+        }
+    } else {
+        output.push_str(response);
+        output.push('\n');
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let failed = |e: std::io::Error| (format!("cannot write {}: {e}", path.display()), 1);
+    std::fs::write(&tmp, output).map_err(failed)?;
+    std::fs::rename(&tmp, path).map_err(failed)
+}
+
+/// Prints the array under `key` one entry per line, or the error.
+fn print_each(response: &str, key: &str) {
+    let value: Value = serde_json::from_str(response).unwrap_or_default();
+    match value[key].as_array() {
+        Some(items) => {
+            for item in items {
+                match item.as_str() {
+                    Some(s) => println!("{s}"),
+                    None => println!("{item}"),
+                }
+            }
+        }
+        None => {
+            eprintln!("error: {}", value["message"].as_str().unwrap_or(response));
+            std::process::exit(1);
         }
     }
 }
 
 fn default_socket_path() -> String {
+    if let Some(path) = std::env::var_os(SOCKET_ENV).filter(|path| !path.is_empty()) {
+        return path.to_string_lossy().into_owned();
+    }
     let uid = unsafe { libc::getuid() };
     let tmp = std::env::temp_dir();
     format!("{}/ranma_{uid}.sock", tmp.display())
 }
 
+/// The daemon's protocol, pinged for on the first command of the process.
+/// `None` when the ping itself could not be sent.
+static DAEMON_PROTOCOL: OnceLock<Option<u32>> = OnceLock::new();
+
 fn send_command(socket_path: &str, command: &Value) -> Result<String, String> {
+    check_protocol(socket_path);
+    request(socket_path, command)
+}
+
+fn check_protocol(socket_path: &str) {
+    DAEMON_PROTOCOL.get_or_init(|| {
+        let protocol = ping(socket_path).ok().map(|(protocol, _)| protocol);
+        if let Some(warning) = protocol.and_then(protocol_warning) {
+            eprintln!("warning: {warning}");
+        }
+        protocol
+    });
+}
+
+/// Asks the daemon for its protocol and version. Daemons that predate
+/// `ping` reject it as an unknown command and count as protocol 0 with no
+/// version.
+fn ping(socket_path: &str) -> Result<(u32, Option<String>), String> {
+    let response = request(socket_path, &json!({ "command": "ping" }))?;
+    let value: Value = serde_json::from_str(&response).unwrap_or_default();
+    Ok((
+        value["protocol"].as_u64().unwrap_or(0) as u32,
+        value["version"].as_str().map(str::to_string),
+    ))
+}
+
+fn protocol_warning(daemon: u32) -> Option<String> {
+    (daemon != PROTOCOL).then(|| {
+        format!(
+            "daemon speaks protocol {daemon} but this CLI speaks {PROTOCOL}; restart ranma after upgrading so both match"
+        )
+    })
+}
+
+fn request(socket_path: &str, command: &Value) -> Result<String, String> {
     let mut stream =
         UnixStream::connect(socket_path).map_err(|e| format!("cannot connect to daemon: {e}"))?;
 
@@ -917,3 +2451,263 @@ fn send_command(socket_path: &str, command: &Value) -> Result<String, String> {
 
     Ok(response.trim_end().to_string())
 }
+
+/// Prints streamed lines until the terminating status line.
+fn stream_command(socket_path: &str, command: &Value) -> Result<(), String> {
+    check_protocol(socket_path);
+    let mut stream =
+        UnixStream::connect(socket_path).map_err(|e| format!("cannot connect to daemon: {e}"))?;
+
+    let mut payload = serde_json::to_string(command).unwrap();
+    payload.push('\n');
+    stream
+        .write_all(payload.as_bytes())
+        .map_err(|e| format!("write error: {e}"))?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| format!("read error: {e}"))?;
+        let value: Value =
+            serde_json::from_str(&line).map_err(|e| format!("invalid response: {e}"))?;
+        match value["status"].as_str() {
+            Some("stream_end") => return Ok(()),
+            Some("error") => return Err(value["message"].as_str().unwrap_or_default().into()),
+            _ => println!("{line}"),
+        }
+    }
+    Err("connection closed before end of stream".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_line_names_the_affected_node() {
+        let event =
+            json!({ "seq": 7, "event": "node_updated", "display": 1, "node": { "name": "clock" } });
+        assert_eq!(format_event_line(&event), "7 node_updated clock");
+        let event = json!({ "seq": 8, "event": "cleared", "display": null });
+        assert_eq!(format_event_line(&event), "8 cleared all displays");
+    }
+
+    #[test]
+    fn clear_command_targets_optional_display() {
+        let command = build_command(Command::Clear(ClearCmd { display: Some(2) }));
+        assert_eq!(command, json!({ "command": "clear", "display": 2 }));
+        let command = build_command(Command::Clear(ClearCmd { display: None }));
+        assert_eq!(command, json!({ "command": "clear", "display": null }));
+    }
+
+    #[test]
+    fn toggle_flips_hidden_with_set() {
+        let command = build_command(Command::Toggle(ToggleCmd { name: "vpn".into() }));
+        assert_eq!(
+            command,
+            json!({ "command": "set", "name": "vpn", "properties": { "toggle_hidden": "" } })
+        );
+    }
+
+    #[test]
+    fn error_responses_exit_with_a_status_per_code() {
+        let response = r#"{"status":"error","code":"not_found","message":"node 'x' not found"}"#;
+        assert_eq!(
+            error_response(response),
+            Some(("node 'x' not found".to_string(), 3))
+        );
+        let response = r#"{"status":"error","code":"cycle","message":"loop"}"#;
+        assert_eq!(error_response(response), Some(("loop".to_string(), 7)));
+        let response = r#"{"status":"error","message":"from an older daemon"}"#;
+        assert_eq!(
+            error_response(response),
+            Some(("from an older daemon".to_string(), 1))
+        );
+        assert_eq!(error_response(r#"{"status":"ok"}"#), None);
+    }
+
+    #[test]
+    fn schema_prints_one_aligned_row_per_property() {
+        let response = json!({
+            "status": "schema",
+            "properties": [
+                { "key": "gap", "type": "float", "node_types": ["row", "column", "box"] },
+                { "key": "hidden", "type": "bool", "node_types": ["item", "row", "column", "box", "progress"] },
+            ],
+            "node_types": ["item", "row", "column", "box", "progress"],
+        });
+        assert_eq!(
+            format_schema(&response.to_string()).unwrap(),
+            "property  type   node types\n\
+             gap       float  row,column,box\n\
+             hidden    bool   all\n"
+        );
+        let response = r#"{"status":"error","message":"boom"}"#;
+        assert_eq!(format_schema(response).unwrap_err(), "boom");
+    }
+
+    #[test]
+    fn count_prints_total_then_each_display() {
+        let response = r#"{"status":"count","total":3,"per_display":{"1":2,"2":1}}"#;
+        assert_eq!(
+            format_count(response).unwrap(),
+            "3\ndisplay 1: 2\ndisplay 2: 1\n"
+        );
+        let response = r#"{"status":"error","message":"boom"}"#;
+        assert_eq!(format_count(response).unwrap_err(), "boom");
+    }
+
+    #[test]
+    fn batch_input_skips_blank_lines_and_reports_bad_ones() {
+        let input =
+            "{\"command\":\"add\",\"name\":\"a\"}\n\n{\"command\":\"remove\",\"name\":\"b\"}\n";
+        let command = parse_batch(input.as_bytes()).unwrap();
+        assert_eq!(
+            command,
+            json!({ "command": "batch", "commands": [
+                { "command": "add", "name": "a" },
+                { "command": "remove", "name": "b" },
+            ] })
+        );
+
+        let err = parse_batch("{}\nnot json\n".as_bytes()).unwrap_err();
+        assert!(err.starts_with("line 2:"), "{err}");
+    }
+
+    #[test]
+    fn equal_position_siblings_sort_by_name() {
+        let mut children = [
+            json!({ "name": "b", "position": 1 }),
+            json!({ "name": "c", "position": 0 }),
+            json!({ "name": "a", "position": 1 }),
+        ];
+        children.sort_by(compare_siblings);
+
+        let names: Vec<&str> = children
+            .iter()
+            .map(|n| n["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn json_tree_nests_children() {
+        let bar = json!({ "name": "bar", "node_type": "row" });
+        let clock = json!({ "name": "clock", "parent": "bar" });
+        let date = json!({ "name": "date", "parent": "bar" });
+        let children_map = HashMap::from([("bar".to_string(), vec![&clock, &date])]);
+
+        let nested = nest_node(&bar, &children_map);
+        assert_eq!(nested["node_type"], "row");
+        assert_eq!(nested["children"][1]["name"], "date");
+        assert_eq!(nested["children"][0]["children"], json!([]));
+    }
+
+    #[test]
+    fn tree_line_color_codes_follow_flag() {
+        let node = json!({
+            "name": "clock",
+            "node_type": "item",
+            "label": "12:00",
+            "label_color": "#FF8000",
+            "removing": true,
+        });
+
+        let colored = format_node_line(&node, true);
+        assert_eq!(
+            colored,
+            "\x1b[2m\x1b[1mclock\x1b[22;2m (item) \x1b[38;2;255;128;0m\"12:00\"\x1b[39m\x1b[0m"
+        );
+        let shown = json!({ "name": "clock", "node_type": "item", "label": "12:00" });
+        assert_eq!(
+            format_node_line(&shown, true),
+            "\x1b[1mclock\x1b[22m (item) \"12:00\""
+        );
+
+        let plain = format_node_line(&node, false);
+        assert_eq!(plain, "clock (item) \"12:00\"");
+        assert!(!plain.contains('\x1b'));
+    }
+
+    #[test]
+    fn failed_query_keeps_existing_output_file() {
+        let path =
+            std::env::temp_dir().join(format!("ranma_test_{}_output.json", std::process::id()));
+        std::fs::write(&path, "previous backup\n").unwrap();
+        let query = json!({ "command": "query" });
+
+        let result = write_output(
+            &path,
+            &query,
+            "{\"status\":\"error\",\"code\":\"not_found\",\"message\":\"node 'ghost' not found\"}",
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ("node 'ghost' not found".to_string(), 3)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous backup\n");
+
+        let response = "{\"status\":\"query_result\",\"schema_version\":2,\"nodes\":[]}";
+        write_output(&path, &query, response).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{response}\n")
+        );
+
+        let export = json!({ "command": "export" });
+        let response = "{\"status\":\"export\",\"commands\":[{\"command\":\"add\",\"name\":\"a\"},{\"command\":\"add\",\"name\":\"b\"}]}";
+        write_output(&path, &export, response).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.starts_with("{\"command\":\"add\",\"name\":\"a\"}\n"));
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Serves one connection, answering its single request with `reply`.
+    fn fake_daemon(name: &str, reply: String) -> String {
+        let path =
+            std::env::temp_dir().join(format!("ranma_test_{}_{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            assert_eq!(
+                serde_json::from_str::<Value>(&request).unwrap()["command"],
+                "ping"
+            );
+            (&stream).write_all(reply.as_bytes()).unwrap();
+        });
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn mismatched_protocol_is_warned_about() {
+        let reply = format!(
+            "{{\"status\":\"pong\",\"protocol\":{},\"version\":\"0.0.0\"}}\n",
+            PROTOCOL + 1
+        );
+        let socket = fake_daemon("newer", reply);
+        let (daemon, version) = ping(&socket).unwrap();
+        assert_eq!(daemon, PROTOCOL + 1);
+        assert_eq!(version.as_deref(), Some("0.0.0"));
+        let warning = protocol_warning(daemon).unwrap();
+        assert!(warning.starts_with(&format!("daemon speaks protocol {}", PROTOCOL + 1)));
+
+        let socket = fake_daemon(
+            "older",
+            "{\"status\":\"error\",\"message\":\"invalid command: unknown variant `ping`\"}\n"
+                .to_string(),
+        );
+        assert_eq!(ping(&socket).unwrap(), (0, None));
+        assert!(protocol_warning(0).is_some());
+        assert!(protocol_warning(PROTOCOL).is_none());
+
+        let missing =
+            std::env::temp_dir().join(format!("ranma_test_{}_gone.sock", std::process::id()));
+        assert!(ping(&missing.to_string_lossy()).is_err());
+    }
+}
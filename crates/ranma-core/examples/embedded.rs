@@ -0,0 +1,95 @@
+//! Drives `ranma-core` as a plain Rust library: no IPC socket, no Swift.
+//!
+//! Run with `cargo run -p ranma-core --example embedded`.
+
+use std::sync::Arc;
+
+use ranma_core::bridge::{DisplayInfo, RanmaError, StateChangeEvent, StateChangeHandler};
+use ranma_core::state::{BarNode, NodeType};
+
+struct PrintHandler;
+
+impl StateChangeHandler for PrintHandler {
+    fn on_state_change(&self, event: StateChangeEvent) -> Result<(), RanmaError> {
+        match event {
+            StateChangeEvent::NodeAdded {
+                display,
+                node,
+                index,
+            } => {
+                println!("added {} on display {display} at {index}", node.name)
+            }
+            StateChangeEvent::NodeUpdated { display, node } => {
+                println!(
+                    "updated {} on display {display}: {:?}",
+                    node.name, node.label
+                )
+            }
+            other => println!("{other:?}"),
+        }
+        Ok(())
+    }
+
+    fn on_shutdown(&self) {}
+}
+
+fn main() {
+    ranma_core::register_handler(Arc::new(PrintHandler));
+    ranma_core::set_displays(vec![DisplayInfo {
+        id: 1,
+        name: "Example Display".to_string(),
+        is_main: true,
+        width: 1920.0,
+        height: 1080.0,
+        scale: 1.0,
+        has_notch: false,
+        notch_width: None,
+        notch_height: None,
+    }]);
+
+    let added = ranma_core::get_state().call_blocking(|state| {
+        let mut events = Vec::new();
+        for (name, label) in [("clock", "12:00"), ("battery", "80%")] {
+            let node = BarNode {
+                display: 1,
+                label: Some(label.to_string()),
+                ..BarNode::new(name, NodeType::Item)
+            };
+            let node = match state.add_node(node) {
+                Ok(id) => state.get_node_by_id(id).unwrap(),
+                Err(e) => return (Err(e), events),
+            };
+            events.push(StateChangeEvent::NodeAdded {
+                display: 1,
+                node: state.effective_node(&node),
+                index: state.sibling_index(node.id).unwrap_or_default(),
+            });
+        }
+        (Ok(()), events)
+    });
+    if let Some(Err(e)) = added {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+
+    ranma_core::get_state().call_blocking(|state| {
+        let properties = [("label".to_string(), "12:01".to_string())].into();
+        match state.set_properties("clock", &properties) {
+            Ok(node) => ((), vec![StateChangeEvent::NodeUpdated { display: 1, node }]),
+            Err(e) => {
+                eprintln!("error: {e}");
+                ((), vec![])
+            }
+        }
+    });
+
+    for node in ranma_core::get_nodes_for_display(1) {
+        println!(
+            "{} #{} at {}: {}",
+            node.name,
+            node.id,
+            node.position,
+            node.label.unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::sync::Arc;
+use std::sync::mpsc;
+
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::oneshot;
+
+use crate::bridge::StateChangeEvent;
+use crate::state::BarState;
+
+type Reply = Box<dyn FnOnce() + Send>;
+type Job = Box<dyn FnOnce(&mut BarState) -> (Reply, Vec<StateChangeEvent>) + Send>;
+
+/// Owns the `BarState` on a dedicated thread and applies mutations in the
+/// order they are submitted. Readers share one copy of the state per
+/// mutation, taken on the first read after it, so a run of mutations with no
+/// reads in between copies nothing.
+pub struct StateActor {
+    jobs: mpsc::Sender<Job>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: RwLock<BarState>,
+    /// The state as of the last mutation; `None` until someone reads it.
+    published: Mutex<Option<Arc<BarState>>>,
+}
+
+impl StateActor {
+    /// `record` sees each event before any reader can observe the mutation
+    /// that produced it, so it must not call back into the actor. `on_event`
+    /// runs on a thread of its own, in emission order, so it may.
+    pub fn spawn(
+        state: BarState,
+        record: impl Fn(&StateChangeEvent) + Send + 'static,
+        on_event: impl Fn(StateChangeEvent) + Send + 'static,
+    ) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (events, emitted) = mpsc::channel::<StateChangeEvent>();
+        let shared = Arc::new(Shared {
+            published: Mutex::new(Some(Arc::new(state.clone()))),
+            state: RwLock::new(state),
+        });
+        std::thread::spawn(move || {
+            for event in emitted {
+                on_event(event);
+            }
+        });
+        let owner = shared.clone();
+        std::thread::spawn(move || {
+            for job in receiver {
+                let (reply, produced) = {
+                    let mut state = owner.state.write();
+                    let result = job(&mut state);
+                    for event in &result.1 {
+                        record(event);
+                    }
+                    *owner.published.lock() = None;
+                    result
+                };
+                for event in produced {
+                    let _ = events.send(event);
+                }
+                reply();
+            }
+        });
+        StateActor { jobs, shared }
+    }
+
+    /// Queues a mutation; the receiver resolves once it has been applied and
+    /// its events queued for `on_event`.
+    pub fn submit<R, F>(&self, f: F) -> oneshot::Receiver<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BarState) -> (R, Vec<StateChangeEvent>) + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |state| {
+            let (result, events) = f(state);
+            let reply: Reply = Box::new(move || {
+                let _ = tx.send(result);
+            });
+            (reply, events)
+        });
+        if self.jobs.send(job).is_err() {
+            eprintln!("state actor stopped");
+        }
+        rx
+    }
+
+    /// Applies a mutation from a thread outside the async runtime.
+    pub fn call_blocking<R, F>(&self, f: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BarState) -> (R, Vec<StateChangeEvent>) + Send + 'static,
+    {
+        self.submit(f).blocking_recv().ok()
+    }
+
+    pub fn snapshot(&self) -> Arc<BarState> {
+        if let Some(published) = self.shared.published.lock().clone() {
+            return published;
+        }
+        self.snapshot_with(|| ()).0
+    }
+
+    /// A snapshot together with `read`, called while no mutation can run, so
+    /// whatever `record` has seen matches the snapshot exactly.
+    pub fn snapshot_with<T>(&self, read: impl FnOnce() -> T) -> (Arc<BarState>, T) {
+        // Same lock order as the actor thread: state, then published
+        let state = self.shared.state.read();
+        let value = read();
+        let snapshot = self
+            .shared
+            .published
+            .lock()
+            .get_or_insert_with(|| Arc::new(state.clone()))
+            .clone();
+        (snapshot, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::state::{BarNode, NodeType};
+
+    #[test]
+    fn mutations_apply_and_emit_in_submission_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let actor = StateActor::spawn(
+            BarState::default(),
+            |_| {},
+            move |event| {
+                if let StateChangeEvent::NodeUpdated { node, .. } = event {
+                    sink.lock().push(node.label.unwrap_or_default());
+                }
+            },
+        );
+        actor.call_blocking(|state| {
+            let result = state.add_node(BarNode::new("clock", NodeType::Item));
+            (result, vec![])
+        });
+
+        let pending: Vec<_> = (0..50)
+            .map(|i| {
+                actor.submit(move |state| {
+                    let properties = HashMap::from([("label".to_string(), i.to_string())]);
+                    let node = state.set_properties("clock", &properties).unwrap();
+                    ((), vec![StateChangeEvent::NodeUpdated { display: 0, node }])
+                })
+            })
+            .collect();
+        for rx in pending {
+            rx.blocking_recv().unwrap();
+        }
+
+        let snapshot = actor.snapshot();
+        assert_eq!(snapshot.get_nodes()[0].label.as_deref(), Some("49"));
+        assert!(Arc::ptr_eq(&snapshot, &actor.snapshot()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while seen.lock().len() < 50 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let expected: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        assert_eq!(*seen.lock(), expected);
+    }
+
+    #[test]
+    fn event_handler_may_submit_to_the_actor() {
+        static ACTOR: std::sync::OnceLock<StateActor> = std::sync::OnceLock::new();
+        let (done_tx, done_rx) = mpsc::channel();
+        let done_tx = Mutex::new(done_tx);
+        let actor = ACTOR.get_or_init(|| {
+            StateActor::spawn(
+                BarState::default(),
+                |_| {},
+                move |event| {
+                    if let StateChangeEvent::Cleared { .. } = event {
+                        let count = ACTOR
+                            .get()
+                            .unwrap()
+                            .call_blocking(|state| (state.get_nodes().len(), vec![]));
+                        done_tx.lock().send(count).unwrap();
+                    }
+                },
+            )
+        });
+        actor.call_blocking(|_| ((), vec![StateChangeEvent::Cleared { display: None }]));
+        let count = done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[test]
+    fn snapshot_with_sees_exactly_the_recorded_events() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let recorded = Arc::new(AtomicUsize::new(0));
+        let counter = recorded.clone();
+        let actor = StateActor::spawn(
+            BarState::default(),
+            move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+            |_| {},
+        );
+        let pending: Vec<_> = (0..200)
+            .map(|i| {
+                actor.submit(move |state| {
+                    let node = BarNode::new(format!("n{i}"), NodeType::Item);
+                    state.add_node(node.clone()).unwrap();
+                    (
+                        (),
+                        vec![StateChangeEvent::NodeAdded {
+                            display: 0,
+                            node,
+                            index: i,
+                        }],
+                    )
+                })
+            })
+            .collect();
+        for _ in 0..200 {
+            let (snapshot, seen) = actor.snapshot_with(|| recorded.load(Ordering::SeqCst));
+            assert_eq!(snapshot.get_nodes().len(), seen);
+        }
+        for rx in pending {
+            rx.blocking_recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_do_not_wait_for_running_mutation() {
+        let actor = StateActor::spawn(BarState::default(), |_| {}, |_| {});
+        let (started_tx, started_rx) = mpsc::channel();
+        let slow = actor.submit(move |state| {
+            started_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            let result = state.add_node(BarNode::new("slow", NodeType::Item));
+            (result, vec![])
+        });
+        started_rx.recv().unwrap();
+
+        let start = Instant::now();
+        let snapshot = actor.snapshot();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(snapshot.get_nodes().is_empty());
+
+        slow.blocking_recv().unwrap().unwrap();
+        assert_eq!(actor.snapshot().get_nodes().len(), 1);
+    }
+}
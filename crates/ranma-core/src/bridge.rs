@@ -1,3 +1,8 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
 use crate::state::BarNode;
 
 #[derive(Debug, Clone, uniffi::Record)]
@@ -5,6 +10,28 @@ pub struct DisplayInfo {
     pub id: u32,
     pub name: String,
     pub is_main: bool,
+    /// Frame size in points.
+    pub width: f32,
+    pub height: f32,
+    /// Backing pixels per point, e.g. 2 on Retina displays.
+    pub scale: f32,
+    pub has_notch: bool,
+    /// Size of the camera housing in points; `None` when `has_notch` is false.
+    pub notch_width: Option<f32>,
+    pub notch_height: Option<f32>,
+}
+
+/// The id `value` names: a numeric id as is, otherwise the display whose
+/// name matches, exactly or else ignoring case.
+pub fn find_display(displays: &[DisplayInfo], value: &str) -> Option<u32> {
+    if let Ok(id) = value.parse() {
+        return Some(id);
+    }
+    displays
+        .iter()
+        .find(|d| d.name == value)
+        .or_else(|| displays.iter().find(|d| d.name.eq_ignore_ascii_case(value)))
+        .map(|d| d.id)
 }
 
 #[derive(Debug, uniffi::Enum)]
@@ -12,11 +39,18 @@ pub enum StateChangeEvent {
     NodeAdded {
         display: u32,
         node: BarNode,
+        /// Position among the node's siblings, in layout order.
+        index: u32,
     },
     NodeRemoved {
         display: u32,
         name: String,
     },
+    /// `node` is marked `removing` and will be removed once its delay elapses.
+    NodeRemoving {
+        display: u32,
+        node: BarNode,
+    },
     NodeUpdated {
         display: u32,
         node: BarNode,
@@ -30,6 +64,10 @@ pub enum StateChangeEvent {
         display: u32,
         nodes: Vec<BarNode>,
     },
+    /// All nodes were removed from `display`, or from every display when `None`.
+    Cleared {
+        display: Option<u32>,
+    },
 }
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
@@ -49,4 +87,206 @@ impl From<uniffi::UnexpectedUniFFICallbackError> for RanmaError {
 #[uniffi::export(with_foreign)]
 pub trait StateChangeHandler: Send + Sync {
     fn on_state_change(&self, event: StateChangeEvent) -> Result<(), RanmaError>;
+    /// The server has stopped after a `shutdown` command or a signal and
+    /// saved the bar; the host should quit.
+    fn on_shutdown(&self);
+}
+
+/// Consecutive delivery errors after which a handler is treated as gone.
+pub const HANDLER_FAILURE_LIMIT: u32 = 5;
+
+/// Events kept while no handler is registered; the oldest go first.
+pub const PENDING_EVENT_CAPACITY: usize = 1024;
+
+/// Holds the registered `StateChangeHandler`. A new registration replaces
+/// the old handler, and events that arrive while none is registered are
+/// buffered for the next one.
+#[derive(Default)]
+pub(crate) struct HandlerSlot {
+    inner: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    handler: Option<Arc<dyn StateChangeHandler>>,
+    failures: u32,
+    pending: VecDeque<StateChangeEvent>,
+}
+
+impl HandlerSlot {
+    pub(crate) fn register(&self, handler: Arc<dyn StateChangeHandler>) {
+        let mut slot = self.inner.lock();
+        slot.handler = Some(handler);
+        slot.failures = 0;
+        let pending = std::mem::take(&mut slot.pending);
+        for event in pending {
+            slot.deliver(event);
+        }
+    }
+
+    pub(crate) fn deliver(&self, event: StateChangeEvent) {
+        self.inner.lock().deliver(event);
+    }
+
+    /// Passes a server shutdown on to the host. Returns false when no
+    /// handler is registered to take it.
+    pub(crate) fn shutdown(&self) -> bool {
+        let handler = self.inner.lock().handler.clone();
+        match handler {
+            Some(handler) => {
+                handler.on_shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl SlotState {
+    fn deliver(&mut self, event: StateChangeEvent) {
+        let Some(handler) = &self.handler else {
+            if self.pending.len() == PENDING_EVENT_CAPACITY {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(event);
+            return;
+        };
+        match handler.on_state_change(event) {
+            Ok(()) => self.failures = 0,
+            Err(e) => {
+                self.failures += 1;
+                if self.failures >= HANDLER_FAILURE_LIMIT {
+                    eprintln!(
+                        "state change handler failed {} times in a row, unregistering: {e}",
+                        self.failures
+                    );
+                    self.handler = None;
+                    self.failures = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn displays_are_found_by_id_or_name() {
+        let display = |id, name: &str| DisplayInfo {
+            id,
+            name: name.to_string(),
+            is_main: id == 1,
+            width: 1512.0,
+            height: 982.0,
+            scale: 2.0,
+            has_notch: false,
+            notch_width: None,
+            notch_height: None,
+        };
+        let displays = [
+            display(1, "Built-in Retina Display"),
+            display(7, "DELL U2723QE"),
+        ];
+        assert_eq!(find_display(&displays, "7"), Some(7));
+        assert_eq!(find_display(&displays, "42"), Some(42));
+        assert_eq!(find_display(&displays, "DELL U2723QE"), Some(7));
+        assert_eq!(find_display(&displays, "built-in retina display"), Some(1));
+        assert_eq!(find_display(&displays, "Sidecar"), None);
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        fail: AtomicBool,
+        received: Mutex<Vec<String>>,
+        shut_down: AtomicBool,
+    }
+
+    impl StateChangeHandler for Recorder {
+        fn on_state_change(&self, event: StateChangeEvent) -> Result<(), RanmaError> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(RanmaError::General {
+                    message: "detached".to_string(),
+                });
+            }
+            if let StateChangeEvent::NodeRemoved { name, .. } = event {
+                self.received.lock().push(name);
+            }
+            Ok(())
+        }
+
+        fn on_shutdown(&self) {
+            self.shut_down.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn removed(name: &str) -> StateChangeEvent {
+        StateChangeEvent::NodeRemoved {
+            display: 1,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn failing_handler_is_unregistered_and_events_buffer() {
+        let slot = HandlerSlot::default();
+        slot.register(Arc::new(Recorder {
+            fail: AtomicBool::new(true),
+            ..Recorder::default()
+        }));
+        for i in 0..HANDLER_FAILURE_LIMIT {
+            slot.deliver(removed(&format!("lost{i}")));
+        }
+        assert!(slot.inner.lock().handler.is_none());
+
+        slot.deliver(removed("a"));
+        slot.deliver(removed("b"));
+        assert_eq!(slot.inner.lock().pending.len(), 2);
+
+        let fresh = Arc::new(Recorder::default());
+        slot.register(fresh.clone());
+        slot.deliver(removed("c"));
+        assert_eq!(*fresh.received.lock(), vec!["a", "b", "c"]);
+        assert!(slot.inner.lock().pending.is_empty());
+    }
+
+    #[test]
+    fn shutdown_reaches_the_handler_or_reports_there_is_none() {
+        let slot = HandlerSlot::default();
+        assert!(!slot.shutdown());
+
+        let handler = Arc::new(Recorder::default());
+        slot.register(handler.clone());
+        assert!(slot.shutdown());
+        assert!(handler.shut_down.load(Ordering::SeqCst));
+
+        handler.fail.store(true, Ordering::SeqCst);
+        for _ in 0..HANDLER_FAILURE_LIMIT {
+            slot.deliver(removed("x"));
+        }
+        assert!(!slot.shutdown());
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let slot = HandlerSlot::default();
+        let handler = Arc::new(Recorder {
+            fail: AtomicBool::new(true),
+            ..Recorder::default()
+        });
+        slot.register(handler.clone());
+        for _ in 1..HANDLER_FAILURE_LIMIT {
+            slot.deliver(removed("x"));
+        }
+        handler.fail.store(false, Ordering::SeqCst);
+        slot.deliver(removed("ok"));
+        handler.fail.store(true, Ordering::SeqCst);
+        for _ in 1..HANDLER_FAILURE_LIMIT {
+            slot.deliver(removed("x"));
+        }
+        assert!(slot.inner.lock().handler.is_some());
+    }
 }
@@ -0,0 +1,186 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Path of the init script the server runs on startup.
+pub const INIT_ENV: &str = "RANMA_INIT";
+
+/// Socket the server listens on and the CLI connects to, replacing
+/// `$TMPDIR/ranma_<uid>.sock`.
+pub const SOCKET_ENV: &str = "RANMA_SOCKET";
+
+/// Resolves the init script: `explicit`, else `$RANMA_INIT`, else
+/// `$XDG_CONFIG_HOME/ranma/init`, else `~/.config/ranma/init`. An explicitly
+/// requested script that doesn't exist is an error; `Ok(None)` means no
+/// default script is installed.
+pub fn config_init_path(explicit: Option<&str>) -> Result<Option<PathBuf>, String> {
+    resolve_init_path(explicit, |key| std::env::var_os(key), Path::exists)
+}
+
+fn resolve_init_path(
+    explicit: Option<&str>,
+    var: impl Fn(&str) -> Option<OsString>,
+    exists: impl Fn(&Path) -> bool,
+) -> Result<Option<PathBuf>, String> {
+    let home = var("HOME").map(PathBuf::from);
+    let requested = explicit
+        .map(OsString::from)
+        .or_else(|| var(INIT_ENV))
+        .filter(|path| !path.is_empty());
+    if let Some(requested) = requested {
+        let path = expand_tilde(PathBuf::from(requested), home.as_deref());
+        if !exists(&path) {
+            return Err(format!("init script not found: {}", path.display()));
+        }
+        return Ok(Some(path));
+    }
+    let xdg = var("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("ranma/init"));
+    let default = home.map(|home| home.join(".config/ranma/init"));
+    Ok([xdg, default]
+        .into_iter()
+        .flatten()
+        .find(|path| exists(path)))
+}
+
+/// Runs the init script and waits for it, sending its output to the
+/// server's stderr. Jobs it starts in the background keep running. A failure
+/// carries what the script wrote to stderr.
+pub fn run_init_script(path: &Path) -> Result<(), String> {
+    // A file rather than a pipe, so background jobs holding it open don't
+    // keep the read from finishing.
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    let log_path = std::env::temp_dir().join(format!(
+        "ranma_init_{}_{}.log",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let log = std::fs::File::create(&log_path)
+        .map_err(|e| format!("cannot capture init script output: {e}"))?;
+    let status = Command::new(path)
+        .stdout(std::io::stderr())
+        .stderr(log)
+        .status();
+    let stderr = std::fs::read_to_string(&log_path).unwrap_or_default();
+    let _ = std::fs::remove_file(&log_path);
+    eprint!("{stderr}");
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!(
+            "init script {} failed ({status}): {}",
+            path.display(),
+            stderr.trim_end()
+        )),
+        Err(e) => Err(format!("cannot run init script {}: {e}", path.display())),
+    }
+}
+
+fn expand_tilde(path: PathBuf, home: Option<&Path>) -> PathBuf {
+    match (path.strip_prefix("~"), home) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn resolve(
+        explicit: Option<&str>,
+        vars: &[(&str, &str)],
+        files: &[&str],
+    ) -> Result<Option<PathBuf>, String> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        resolve_init_path(
+            explicit,
+            |key| vars.get(key).map(OsString::from),
+            |path| files.iter().any(|f| Path::new(f) == path),
+        )
+    }
+
+    const HOME: (&str, &str) = ("HOME", "/Users/me");
+    const XDG: (&str, &str) = ("XDG_CONFIG_HOME", "/xdg");
+    const ALL: [&str; 4] = [
+        "/Users/me/.config/ranma/init",
+        "/xdg/ranma/init",
+        "/env/init",
+        "/cli/init",
+    ];
+
+    fn path(p: &str) -> Result<Option<PathBuf>, String> {
+        Ok(Some(PathBuf::from(p)))
+    }
+
+    #[test]
+    fn explicit_path_wins() {
+        let vars = [HOME, XDG, (INIT_ENV, "/env/init")];
+        assert_eq!(resolve(Some("/cli/init"), &vars, &ALL), path("/cli/init"));
+    }
+
+    #[test]
+    fn env_var_beats_config_dirs() {
+        let vars = [HOME, XDG, (INIT_ENV, "/env/init")];
+        assert_eq!(resolve(None, &vars, &ALL), path("/env/init"));
+        let vars = [HOME, (INIT_ENV, "~/bin/init")];
+        assert_eq!(
+            resolve(None, &vars, &["/Users/me/bin/init"]),
+            path("/Users/me/bin/init")
+        );
+    }
+
+    #[test]
+    fn xdg_config_home_beats_home() {
+        assert_eq!(resolve(None, &[HOME, XDG], &ALL), path("/xdg/ranma/init"));
+        assert_eq!(
+            resolve(None, &[HOME, XDG], &["/Users/me/.config/ranma/init"]),
+            path("/Users/me/.config/ranma/init")
+        );
+    }
+
+    #[test]
+    fn home_config_is_the_fallback() {
+        assert_eq!(
+            resolve(None, &[HOME], &ALL),
+            path("/Users/me/.config/ranma/init")
+        );
+        assert_eq!(resolve(None, &[HOME, XDG], &[]), Ok(None));
+    }
+
+    #[test]
+    fn missing_requested_script_is_an_error() {
+        let err = resolve(Some("/nope"), &[HOME], &ALL).unwrap_err();
+        assert_eq!(err, "init script not found: /nope");
+        let err = resolve(None, &[HOME, (INIT_ENV, "/nope")], &ALL).unwrap_err();
+        assert_eq!(err, "init script not found: /nope");
+    }
+
+    #[test]
+    fn failed_init_script_reports_its_stderr() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("ranma_test_{}_init", std::process::id()));
+        std::fs::write(
+            &path,
+            "#!/bin/sh\necho starting\necho 'no such widget' >&2\nexit 3\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = run_init_script(&path).unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "init script {} failed (exit status: 3): no such widget",
+                path.display()
+            )
+        );
+
+        std::fs::write(&path, "#!/bin/sh\necho 'just a warning' >&2\n").unwrap();
+        assert_eq!(run_init_script(&path), Ok(()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
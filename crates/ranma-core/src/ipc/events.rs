@@ -0,0 +1,316 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::bridge::StateChangeEvent;
+
+use super::protocol::NodeDto;
+
+/// Number of recent events kept for `watch --since-seq` replays.
+pub const EVENT_LOG_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventDto {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    NodeAdded {
+        display: u32,
+        node: NodeDto,
+        index: u32,
+    },
+    NodeRemoved {
+        display: u32,
+        name: String,
+    },
+    NodeRemoving {
+        display: u32,
+        node: NodeDto,
+    },
+    NodeUpdated {
+        display: u32,
+        node: NodeDto,
+    },
+    NodeMoved {
+        old_display: u32,
+        new_display: u32,
+        node: NodeDto,
+    },
+    FullRefresh {
+        display: u32,
+        nodes: Vec<NodeDto>,
+    },
+    Cleared {
+        display: Option<u32>,
+    },
+}
+
+/// Names of the event kinds as they appear in the `event` field.
+pub const EVENT_NAMES: [&str; 7] = [
+    "node_added",
+    "node_removed",
+    "node_removing",
+    "node_updated",
+    "node_moved",
+    "full_refresh",
+    "cleared",
+];
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::NodeAdded { .. } => "node_added",
+            EventKind::NodeRemoved { .. } => "node_removed",
+            EventKind::NodeRemoving { .. } => "node_removing",
+            EventKind::NodeUpdated { .. } => "node_updated",
+            EventKind::NodeMoved { .. } => "node_moved",
+            EventKind::FullRefresh { .. } => "full_refresh",
+            EventKind::Cleared { .. } => "cleared",
+        }
+    }
+}
+
+/// Event kinds a watcher asked for; all of them when no list was given.
+pub struct EventFilter(Option<Vec<String>>);
+
+impl EventFilter {
+    pub fn new(events: Option<Vec<String>>) -> Result<Self, String> {
+        if let Some(unknown) = events
+            .iter()
+            .flatten()
+            .find(|name| !EVENT_NAMES.contains(&name.as_str()))
+        {
+            return Err(format!(
+                "unknown event: {} (expected one of {})",
+                unknown,
+                EVENT_NAMES.join(", ")
+            ));
+        }
+        Ok(EventFilter(events))
+    }
+
+    pub fn allows(&self, event: &EventKind) -> bool {
+        self.allows_name(event.name())
+    }
+
+    pub fn allows_name(&self, name: &str) -> bool {
+        self.0
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|n| n == name))
+    }
+}
+
+impl From<&StateChangeEvent> for EventKind {
+    fn from(event: &StateChangeEvent) -> Self {
+        match event {
+            StateChangeEvent::NodeAdded {
+                display,
+                node,
+                index,
+            } => EventKind::NodeAdded {
+                display: *display,
+                node: node.clone().into(),
+                index: *index,
+            },
+            StateChangeEvent::NodeRemoved { display, name } => EventKind::NodeRemoved {
+                display: *display,
+                name: name.clone(),
+            },
+            StateChangeEvent::NodeRemoving { display, node } => EventKind::NodeRemoving {
+                display: *display,
+                node: node.clone().into(),
+            },
+            StateChangeEvent::NodeUpdated { display, node } => EventKind::NodeUpdated {
+                display: *display,
+                node: node.clone().into(),
+            },
+            StateChangeEvent::NodeMoved {
+                old_display,
+                new_display,
+                node,
+            } => EventKind::NodeMoved {
+                old_display: *old_display,
+                new_display: *new_display,
+                node: node.clone().into(),
+            },
+            StateChangeEvent::FullRefresh { display, nodes } => EventKind::FullRefresh {
+                display: *display,
+                nodes: nodes.iter().cloned().map(Into::into).collect(),
+            },
+            StateChangeEvent::Cleared { display } => EventKind::Cleared { display: *display },
+        }
+    }
+}
+
+/// Events to send a watcher before it switches to live delivery.
+#[derive(Debug)]
+pub enum Replay {
+    /// Every event after the requested sequence, in order.
+    Events(Vec<EventDto>),
+    /// The requested sequence is older than the buffer; the watcher needs a
+    /// full refresh instead.
+    Gap,
+}
+
+pub struct Subscription {
+    pub replay: Replay,
+    /// Sequence of the latest event at the time of subscribing.
+    pub last_seq: u64,
+    pub receiver: broadcast::Receiver<EventDto>,
+}
+
+struct Inner {
+    buffer: VecDeque<EventDto>,
+    last_seq: u64,
+}
+
+/// Numbers every state change event, keeps the most recent ones for replays
+/// and fans them out to live watchers.
+pub struct EventLog {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    sender: broadcast::Sender<EventDto>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventLog {
+            inner: Mutex::new(Inner {
+                buffer: VecDeque::with_capacity(capacity),
+                last_seq: 0,
+            }),
+            capacity,
+            sender,
+        }
+    }
+
+    pub fn record(&self, event: &StateChangeEvent) -> u64 {
+        let mut inner = self.inner.lock();
+        inner.last_seq += 1;
+        let dto = EventDto {
+            seq: inner.last_seq,
+            event: event.into(),
+        };
+        if inner.buffer.len() == self.capacity {
+            inner.buffer.pop_front();
+        }
+        inner.buffer.push_back(dto.clone());
+        let _ = self.sender.send(dto);
+        inner.last_seq
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.inner.lock().last_seq
+    }
+
+    /// Subscribes to live events and returns what to replay first. Both are
+    /// taken together, so no event is missed or delivered twice.
+    pub fn subscribe(&self, since_seq: Option<u64>) -> Subscription {
+        let inner = self.inner.lock();
+        let receiver = self.sender.subscribe();
+        let replay = match since_seq {
+            None => Replay::Events(vec![]),
+            Some(seq) => {
+                let oldest = inner.buffer.front().map_or(inner.last_seq + 1, |e| e.seq);
+                if seq.saturating_add(1) < oldest || seq > inner.last_seq {
+                    Replay::Gap
+                } else {
+                    Replay::Events(
+                        inner
+                            .buffer
+                            .iter()
+                            .filter(|e| e.seq > seq)
+                            .cloned()
+                            .collect(),
+                    )
+                }
+            }
+        };
+        Subscription {
+            replay,
+            last_seq: inner.last_seq,
+            receiver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn removed(name: &str) -> StateChangeEvent {
+        StateChangeEvent::NodeRemoved {
+            display: 1,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn resubscribing_replays_missed_events_in_order() {
+        let log = EventLog::new(8);
+        log.record(&removed("a"));
+        log.record(&removed("b"));
+        let last_seen = log.last_seq();
+        log.record(&removed("c"));
+        log.record(&removed("d"));
+
+        let mut subscription = log.subscribe(Some(last_seen));
+        let Replay::Events(events) = subscription.replay else {
+            panic!("expected replay");
+        };
+        let seqs: Vec<u64> = events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+        assert!(matches!(
+            &events[0].event,
+            EventKind::NodeRemoved { name, .. } if name == "c"
+        ));
+
+        log.record(&removed("e"));
+        assert_eq!(subscription.receiver.try_recv().unwrap().seq, 5);
+    }
+
+    #[test]
+    fn filter_passes_only_requested_events() {
+        let filter = EventFilter::new(Some(vec!["node_removed".to_string()])).unwrap();
+        assert!(filter.allows(&(&removed("a")).into()));
+        assert!(!filter.allows(&EventKind::Cleared { display: None }));
+
+        let all = EventFilter::new(None).unwrap();
+        assert!(all.allows(&EventKind::Cleared { display: None }));
+
+        let err = EventFilter::new(Some(vec!["node_renamed".to_string()]))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("unknown event: node_renamed"));
+    }
+
+    #[test]
+    fn event_names_match_serialized_tags() {
+        let event = EventDto {
+            seq: 1,
+            event: (&removed("a")).into(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], event.event.name());
+    }
+
+    #[test]
+    fn sequence_older_than_buffer_is_a_gap() {
+        let log = EventLog::new(2);
+        for name in ["a", "b", "c", "d"] {
+            log.record(&removed(name));
+        }
+
+        assert!(matches!(log.subscribe(Some(1)).replay, Replay::Gap));
+        assert!(matches!(log.subscribe(Some(2)).replay, Replay::Events(ref e) if e.len() == 2));
+        assert!(matches!(log.subscribe(Some(u64::MAX)).replay, Replay::Gap));
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Handling cost of one command type, as reported by `stats`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommandStats {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+/// Per-command counters for everything the server handles over the socket.
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<&'static str, CommandStats>>,
+}
+
+impl Metrics {
+    pub fn record(&self, command: &'static str, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let mut commands = self.commands.lock();
+        let stats = commands.entry(command).or_default();
+        stats.count += 1;
+        stats.total_us = stats.total_us.saturating_add(micros);
+        stats.max_us = stats.max_us.max(micros);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, CommandStats> {
+        self.commands
+            .lock()
+            .iter()
+            .map(|(name, stats)| (name.to_string(), stats.clone()))
+            .collect()
+    }
+}
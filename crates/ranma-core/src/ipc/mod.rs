@@ -1,2 +1,4 @@
+pub mod events;
+pub mod metrics;
 pub mod protocol;
 pub mod server;
@@ -1,9 +1,13 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
-use crate::state::BarNode;
+use super::metrics::CommandStats;
+use crate::bridge::DisplayInfo;
+use crate::state::{BarNode, BarState, Dimension, ErrorCode, NodeType, StateError};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "command", rename_all = "snake_case")]
 #[allow(clippy::large_enum_variant)]
 pub enum Command {
@@ -30,6 +34,14 @@ pub enum Command {
         #[serde(default)]
         corner_radius: Option<f32>,
         #[serde(default)]
+        corner_radius_top_left: Option<f32>,
+        #[serde(default)]
+        corner_radius_top_right: Option<f32>,
+        #[serde(default)]
+        corner_radius_bottom_left: Option<f32>,
+        #[serde(default)]
+        corner_radius_bottom_right: Option<f32>,
+        #[serde(default)]
         padding_left: Option<f32>,
         #[serde(default)]
         padding_right: Option<f32>,
@@ -42,9 +54,17 @@ pub enum Command {
         #[serde(default)]
         shadow_radius: Option<f32>,
         #[serde(default)]
-        width: Option<f32>,
+        shadow_offset_x: Option<f32>,
+        #[serde(default)]
+        shadow_offset_y: Option<f32>,
+        #[serde(default)]
+        width: Option<Dimension>,
         #[serde(default)]
-        height: Option<f32>,
+        height: Option<Dimension>,
+        #[serde(default)]
+        min_width: Option<f32>,
+        #[serde(default)]
+        max_width: Option<f32>,
         #[serde(default)]
         gap: Option<f32>,
         #[serde(default)]
@@ -74,10 +94,22 @@ pub enum Command {
         #[serde(default)]
         font_family: Option<String>,
         #[serde(default)]
+        letter_spacing: Option<f32>,
+        #[serde(default)]
+        line_height: Option<f32>,
+        #[serde(default)]
+        font_style: Option<String>,
+        #[serde(default)]
+        underline: Option<bool>,
+        #[serde(default)]
+        strikethrough: Option<bool>,
+        #[serde(default)]
         notch_align: Option<String>,
         #[serde(default)]
         align_items: Option<String>,
         #[serde(default)]
+        align_self: Option<String>,
+        #[serde(default)]
         justify_content: Option<String>,
         #[serde(default)]
         hover_background_color: Option<String>,
@@ -86,43 +118,582 @@ pub enum Command {
         #[serde(default)]
         hover_icon_color: Option<String>,
         #[serde(default)]
+        outline_color: Option<String>,
+        #[serde(default)]
+        outline_width: Option<f32>,
+        #[serde(default)]
+        outline_offset: Option<f32>,
+        #[serde(default)]
+        blend_mode: Option<String>,
+        #[serde(default)]
+        text_truncation: Option<String>,
+        #[serde(default)]
+        progress_color: Option<String>,
+        #[serde(default)]
+        progress_track_color: Option<String>,
+        #[serde(default)]
+        background_opacity: Option<f32>,
+        #[serde(default)]
+        background_gradient_start: Option<String>,
+        #[serde(default)]
+        background_gradient_end: Option<String>,
+        #[serde(default)]
+        background_gradient_angle: Option<f32>,
+        #[serde(default)]
         on_click: Option<String>,
         #[serde(default)]
+        on_right_click: Option<String>,
+        #[serde(default)]
+        on_middle_click: Option<String>,
+        #[serde(default)]
+        on_click_cwd: Option<String>,
+        #[serde(default)]
+        on_click_shell: Option<String>,
+        #[serde(default)]
+        confirm: Option<String>,
+        #[serde(default)]
         image: Option<String>,
         #[serde(default)]
         image_scale: Option<f32>,
+        /// Fill of a progress node, clamped to 0 to 100.
+        #[serde(default)]
+        value: Option<f32>,
+        #[serde(default)]
+        image_content_mode: Option<String>,
+        #[serde(default)]
+        template: Option<String>,
+        #[serde(default)]
+        hidden: bool,
+        #[serde(default)]
+        disabled: bool,
         #[serde(default)]
         position: Option<i32>,
+        /// Place the node right after this sibling.
         #[serde(default)]
-        display: Option<u32>,
+        after: Option<String>,
+        /// Place the node right before this sibling.
+        #[serde(default)]
+        before: Option<String>,
+        #[serde(default)]
+        display: Option<DisplayRef>,
+        /// A repeated `add` with the same key within `ADD_KEY_TTL` succeeds
+        /// without adding again, so retries are safe.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    /// Adds a node and all of its descendants; if any fails, none are added.
+    AddTree {
+        root: TreeSpec,
     },
     Set {
         name: String,
         properties: HashMap<String, String>,
+        /// Report the values the changed properties had before the update.
+        #[serde(default)]
+        with_old: bool,
+    },
+    /// Reparents a node; without `parent` it moves to the top level.
+    Move {
+        name: String,
+        #[serde(default)]
+        parent: Option<String>,
+        #[serde(default)]
+        position: Option<i32>,
+    },
+    /// Copies a node and its descendants under new names.
+    Duplicate {
+        name: String,
+        new_name: String,
+        #[serde(default)]
+        new_display: Option<u32>,
+    },
+    /// Renames a node and repoints its children at the new name.
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Returns the listed properties of `name` to their defaults, or its
+    /// whole styling when `properties` is omitted.
+    Reset {
+        name: String,
+        #[serde(default)]
+        properties: Option<Vec<String>>,
+    },
+    /// Assigns positions `0..n` to the listed siblings of `parent`.
+    Reorder {
+        #[serde(default)]
+        parent: Option<String>,
+        display: u32,
+        order: Vec<String>,
+    },
+    /// Exchanges the positions of two siblings.
+    Swap {
+        a: String,
+        b: String,
     },
     Remove {
         name: String,
+        /// Keep the node, marked `removing`, for this long before removing it.
+        #[serde(default)]
+        after_ms: Option<u64>,
+        /// Only report the names that would be removed.
+        #[serde(default)]
+        dry_run: bool,
+    },
+    Clear {
+        #[serde(default)]
+        display: Option<u32>,
+    },
+    /// Sends a `FullRefresh` for `display`, or for every display, so a
+    /// frontend can resync without replaying missed events.
+    Refresh {
+        #[serde(default)]
+        display: Option<u32>,
     },
     Query {
+        #[serde(default)]
+        id: Option<u64>,
         #[serde(default)]
         name: Option<String>,
         #[serde(default)]
-        display: Option<u32>,
+        display: Option<DisplayRef>,
+        /// Only direct children of this node, sorted by position; an error
+        /// if it doesn't exist. `id` and `name` take precedence over it.
+        #[serde(default)]
+        parent: Option<String>,
+        /// Exclude nodes with this name.
+        #[serde(default)]
+        not_name: Option<String>,
+        /// Exclude direct children of this node.
+        #[serde(default)]
+        not_parent: Option<String>,
+        /// Exclude nodes of this type, e.g. `item`.
+        #[serde(default)]
+        not_type: Option<String>,
+        /// `key=value` conditions a node must all meet.
+        #[serde(default)]
+        filter: Option<Vec<String>>,
+        #[serde(default)]
+        fields: Option<Vec<String>>,
+        #[serde(default)]
+        stream: bool,
+        /// Restricts nodes to a historical field set, e.g. `v1`.
+        #[serde(default)]
+        compat: Option<String>,
     },
     Displays,
+    /// Whether a node named `name` exists, without returning the node.
+    Exists {
+        name: String,
+    },
+    /// Commands that rebuild the selected nodes, parents first.
+    Export {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        display: Option<u32>,
+        /// Only properties the user set explicitly, shorthands kept.
+        #[serde(default)]
+        only_user_set: bool,
+    },
+    /// The whole state as a document `restore` accepts.
+    Snapshot,
+    /// Replaces the whole state with a `snapshot` result.
+    Restore {
+        snapshot: Value,
+    },
+    /// Reports per-command counts and handling times.
+    Stats,
+    /// Defines `$name`, which color and numeric properties can reference.
+    SetVar {
+        name: String,
+        value: String,
+    },
+    /// Reports how many nodes each display has.
+    Count {
+        #[serde(default)]
+        display: Option<u32>,
+    },
+    /// Reports the daemon's `PROTOCOL` and version.
+    Ping,
+    /// Reports every template and the properties it sets.
+    ListStyles,
+    /// Reports every property key `set` accepts and every node type.
+    Schema,
+    DefineTemplate {
+        name: String,
+        properties: HashMap<String, String>,
+    },
+    /// Replaces the properties nodes added to `display` start with.
+    SetDisplayDefaults {
+        display: u32,
+        properties: HashMap<String, String>,
+    },
+    /// Reports the defaults of `display`, or of every display.
+    GetDisplayDefaults {
+        #[serde(default)]
+        display: Option<u32>,
+    },
+    /// Reruns the init script, `path` or the one found at startup, after
+    /// clearing every node when `clear` is set.
+    ReloadInit {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        clear: bool,
+    },
+    /// Applies mutations in order; if any fails, none of them take effect.
+    Batch {
+        commands: Vec<Command>,
+    },
+    /// Stops the server after answering, removing its socket.
+    Shutdown,
+    /// Streams state change events for the rest of the connection.
+    #[serde(alias = "subscribe")]
+    Watch {
+        #[serde(default)]
+        since_seq: Option<u64>,
+        /// Only stream these event kinds, e.g. `node_added`.
+        #[serde(default)]
+        events: Option<Vec<String>>,
+    },
+}
+
+impl Command {
+    /// Commands that only read state and can be answered from a snapshot.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Query { .. }
+                | Command::Displays
+                | Command::Exists { .. }
+                | Command::Export { .. }
+                | Command::Snapshot
+                | Command::Stats
+                | Command::Count { .. }
+                | Command::Ping
+                | Command::ListStyles
+                | Command::Schema
+                | Command::GetDisplayDefaults { .. }
+                | Command::Remove { dry_run: true, .. }
+        )
+    }
+
+    /// The `command` tag this was sent with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Add { .. } => "add",
+            Command::AddTree { .. } => "add_tree",
+            Command::Set { .. } => "set",
+            Command::Move { .. } => "move",
+            Command::Duplicate { .. } => "duplicate",
+            Command::Rename { .. } => "rename",
+            Command::Reset { .. } => "reset",
+            Command::Reorder { .. } => "reorder",
+            Command::Swap { .. } => "swap",
+            Command::Remove { .. } => "remove",
+            Command::Clear { .. } => "clear",
+            Command::Refresh { .. } => "refresh",
+            Command::Query { .. } => "query",
+            Command::Displays => "displays",
+            Command::Exists { .. } => "exists",
+            Command::Export { .. } => "export",
+            Command::Snapshot => "snapshot",
+            Command::Restore { .. } => "restore",
+            Command::Stats => "stats",
+            Command::SetVar { .. } => "set_var",
+            Command::Count { .. } => "count",
+            Command::Ping => "ping",
+            Command::ListStyles => "list_styles",
+            Command::Schema => "schema",
+            Command::DefineTemplate { .. } => "define_template",
+            Command::SetDisplayDefaults { .. } => "set_display_defaults",
+            Command::GetDisplayDefaults { .. } => "get_display_defaults",
+            Command::ReloadInit { .. } => "reload_init",
+            Command::Batch { .. } => "batch",
+            Command::Shutdown => "shutdown",
+            Command::Watch { .. } => "watch",
+        }
+    }
+}
+
+/// A display given as its numeric id or as a connected display's name,
+/// resolved when the command runs so an unknown name is reported like
+/// `set --display`'s.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DisplayRef {
+    Id(u32),
+    Name(String),
+}
+
+impl DisplayRef {
+    pub fn resolve(&self) -> Result<u32, StateError> {
+        match self {
+            DisplayRef::Id(id) => Ok(*id),
+            DisplayRef::Name(name) => crate::resolve_display(name).ok_or_else(|| {
+                StateError::new(ErrorCode::InvalidValue, format!("unknown display: {name}"))
+            }),
+        }
+    }
+}
+
+/// One node of an `add_tree`: the fields an `add` takes, and the nodes to
+/// add under it. Children get their `parent` from where they are nested.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeSpec {
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+    #[serde(default)]
+    pub children: Vec<TreeSpec>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(tag = "status", rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
 pub enum Response {
     Ok,
-    Error { message: String },
-    QueryResult { nodes: Vec<NodeDto> },
-    DisplayList { displays: Vec<DisplayDto> },
+    /// A `set` whose values the node already had; no event was sent.
+    Unchanged,
+    /// The node as an `add` or `set` left it, with its template applied.
+    NodeResult {
+        node: NodeDto,
+    },
+    #[serde(rename = "ok")]
+    OkWithPrevious {
+        previous: HashMap<String, String>,
+    },
+    #[serde(rename = "ok")]
+    OkWithNames {
+        names: Vec<String>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    QueryResult {
+        schema_version: u32,
+        nodes: Vec<NodeDto>,
+    },
+    #[serde(rename = "query_result")]
+    Projection {
+        schema_version: u32,
+        nodes: Vec<Value>,
+    },
+    DisplayList {
+        displays: Vec<DisplayDto>,
+    },
+    Exists {
+        exists: bool,
+        display: Option<u32>,
+    },
+    Stats {
+        commands: BTreeMap<String, CommandStats>,
+    },
+    BatchResult {
+        results: Vec<Response>,
+    },
+    Pong {
+        protocol: u32,
+        version: String,
+    },
+    Count {
+        total: u32,
+        per_display: BTreeMap<u32, u32>,
+    },
+    Styles {
+        styles: BTreeMap<String, BTreeMap<String, String>>,
+    },
+    Schema {
+        properties: Vec<PropertySchema>,
+        node_types: Vec<&'static str>,
+    },
+    DisplayDefaults {
+        defaults: BTreeMap<u32, BTreeMap<String, String>>,
+    },
+    Export {
+        commands: Vec<Value>,
+    },
+    Snapshot {
+        snapshot: Value,
+    },
+    /// Written as one JSON object per line followed by `StreamEnd`, each
+    /// node built from `state` as it is written.
+    #[serde(skip_serializing)]
+    Stream {
+        state: Arc<BarState>,
+        ids: Vec<u64>,
+        fields: Option<Vec<String>>,
+        schema_version: u32,
+    },
+    StreamEnd {
+        count: usize,
+    },
 }
 
+/// One key `set` accepts, as `schema` reports it.
 #[derive(Debug, Serialize)]
+pub struct PropertySchema {
+    pub key: &'static str,
+    /// From `state::property_type`, e.g. `float` or `enum[start,center,end]`.
+    #[serde(rename = "type")]
+    pub value_type: String,
+    pub node_types: Vec<&'static str>,
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<StateError> for Response {
+    fn from(e: StateError) -> Self {
+        Response::error(e.code, e.message)
+    }
+}
+
+/// Version of the socket protocol. Bumped whenever a command or response
+/// changes in a way an older CLI or daemon would misread; daemons too old
+/// to answer `ping` count as protocol 0.
+pub const PROTOCOL: u32 = 4;
+
+/// Version of the `NodeDto` field set reported in query results.
+pub const QUERY_SCHEMA_VERSION: u32 = 3;
+
+/// Fields of `NodeDto` as of schema version 1.
+const NODE_FIELDS_V1: [&str; 39] = [
+    "name",
+    "node_type",
+    "parent",
+    "position",
+    "display",
+    "display_explicit",
+    "label",
+    "label_color",
+    "icon",
+    "icon_color",
+    "background_color",
+    "border_color",
+    "border_width",
+    "corner_radius",
+    "padding_left",
+    "padding_right",
+    "padding_top",
+    "padding_bottom",
+    "shadow_color",
+    "shadow_radius",
+    "width",
+    "height",
+    "gap",
+    "margin_left",
+    "margin_right",
+    "margin_top",
+    "margin_bottom",
+    "notch_align",
+    "align_items",
+    "justify_content",
+    "hover_background_color",
+    "hover_label_color",
+    "hover_icon_color",
+    "on_click",
+    "image",
+    "image_scale",
+    "font_size",
+    "font_weight",
+    "font_family",
+];
+
+/// Fields of `NodeDto` as of schema version 2.
+const NODE_FIELDS_V2: [&str; 77] = [
+    "id",
+    "name",
+    "node_type",
+    "parent",
+    "position",
+    "display",
+    "display_explicit",
+    "label",
+    "label_display_width",
+    "label_color",
+    "icon",
+    "icon_color",
+    "background_color",
+    "border_color",
+    "border_width",
+    "corner_radius",
+    "corner_radius_top_left",
+    "corner_radius_top_right",
+    "corner_radius_bottom_left",
+    "corner_radius_bottom_right",
+    "padding_left",
+    "padding_right",
+    "padding_top",
+    "padding_bottom",
+    "shadow_color",
+    "shadow_radius",
+    "shadow_offset_x",
+    "shadow_offset_y",
+    "width",
+    "height",
+    "min_width",
+    "max_width",
+    "gap",
+    "margin_left",
+    "margin_right",
+    "margin_top",
+    "margin_bottom",
+    "notch_align",
+    "align_items",
+    "align_self",
+    "justify_content",
+    "hover_background_color",
+    "hover_label_color",
+    "hover_icon_color",
+    "outline_color",
+    "outline_width",
+    "outline_offset",
+    "blend_mode",
+    "text_truncation",
+    "progress_color",
+    "progress_track_color",
+    "background_opacity",
+    "background_gradient_start",
+    "background_gradient_end",
+    "background_gradient_angle",
+    "on_click",
+    "on_right_click",
+    "on_middle_click",
+    "on_click_cwd",
+    "on_click_shell",
+    "confirm",
+    "image",
+    "image_scale",
+    "value",
+    "image_content_mode",
+    "template",
+    "removing",
+    "hidden",
+    "disabled",
+    "font_size",
+    "font_weight",
+    "font_family",
+    "letter_spacing",
+    "line_height",
+    "font_style",
+    "underline",
+    "strikethrough",
+];
+
+/// Fields are only ever added, never renamed or removed. Bumping
+/// `QUERY_SCHEMA_VERSION` freezes the current field set as a new entry in
+/// `compat_fields`.
+#[derive(Debug, Clone, Serialize)]
 pub struct NodeDto {
+    pub id: u64,
     pub name: String,
     pub node_type: String,
     pub parent: Option<String>,
@@ -130,6 +701,9 @@ pub struct NodeDto {
     pub display: u32,
     pub display_explicit: bool,
     pub label: Option<String>,
+    /// Columns the label occupies, counting wide CJK characters and emoji
+    /// as two.
+    pub label_display_width: Option<usize>,
     pub label_color: Option<String>,
     pub icon: Option<String>,
     pub icon_color: Option<String>,
@@ -137,14 +711,23 @@ pub struct NodeDto {
     pub border_color: Option<String>,
     pub border_width: Option<f32>,
     pub corner_radius: Option<f32>,
+    pub corner_radius_top_left: Option<f32>,
+    pub corner_radius_top_right: Option<f32>,
+    pub corner_radius_bottom_left: Option<f32>,
+    pub corner_radius_bottom_right: Option<f32>,
     pub padding_left: Option<f32>,
     pub padding_right: Option<f32>,
     pub padding_top: Option<f32>,
     pub padding_bottom: Option<f32>,
     pub shadow_color: Option<String>,
     pub shadow_radius: Option<f32>,
-    pub width: Option<f32>,
-    pub height: Option<f32>,
+    pub shadow_offset_x: Option<f32>,
+    pub shadow_offset_y: Option<f32>,
+    /// A number of points, or a string such as `50%` or `fill`.
+    pub width: Option<Dimension>,
+    pub height: Option<Dimension>,
+    pub min_width: Option<f32>,
+    pub max_width: Option<f32>,
     pub gap: Option<f32>,
     pub margin_left: Option<f32>,
     pub margin_right: Option<f32>,
@@ -152,33 +735,59 @@ pub struct NodeDto {
     pub margin_bottom: Option<f32>,
     pub notch_align: Option<String>,
     pub align_items: Option<String>,
+    pub align_self: Option<String>,
     pub justify_content: Option<String>,
     pub hover_background_color: Option<String>,
     pub hover_label_color: Option<String>,
     pub hover_icon_color: Option<String>,
+    pub outline_color: Option<String>,
+    pub outline_width: Option<f32>,
+    pub outline_offset: Option<f32>,
+    pub blend_mode: Option<String>,
+    pub text_truncation: Option<String>,
+    pub progress_color: Option<String>,
+    pub progress_track_color: Option<String>,
+    pub background_opacity: Option<f32>,
+    pub background_gradient_start: Option<String>,
+    pub background_gradient_end: Option<String>,
+    pub background_gradient_angle: Option<f32>,
     pub on_click: Option<String>,
+    pub on_right_click: Option<String>,
+    pub on_middle_click: Option<String>,
+    pub on_click_cwd: Option<String>,
+    pub on_click_shell: Option<String>,
+    pub confirm: Option<String>,
     pub image: Option<String>,
     pub image_scale: Option<f32>,
+    pub value: Option<f32>,
+    pub image_content_mode: Option<String>,
+    pub template: Option<String>,
+    pub removing: bool,
+    pub hidden: bool,
+    pub disabled: bool,
     pub font_size: Option<f32>,
     pub font_weight: Option<String>,
     pub font_family: Option<String>,
+    pub letter_spacing: Option<f32>,
+    pub line_height: Option<f32>,
+    pub font_style: Option<String>,
+    pub underline: Option<bool>,
+    pub strikethrough: Option<bool>,
 }
 
 impl From<BarNode> for NodeDto {
     fn from(node: BarNode) -> Self {
+        let label_display_width = node.label_display_width();
         NodeDto {
+            id: node.id,
             name: node.name,
-            node_type: match node.node_type {
-                crate::state::NodeType::Item => "item".to_string(),
-                crate::state::NodeType::Row => "row".to_string(),
-                crate::state::NodeType::Column => "column".to_string(),
-                crate::state::NodeType::Box => "box".to_string(),
-            },
+            node_type: node.node_type.as_str().to_string(),
             parent: node.parent,
             position: node.position,
             display: node.display,
             display_explicit: node.display_explicit,
             label: node.label,
+            label_display_width,
             label_color: node.label_color,
             icon: node.icon,
             icon_color: node.icon_color,
@@ -186,14 +795,22 @@ impl From<BarNode> for NodeDto {
             border_color: node.style.border_color,
             border_width: node.style.border_width,
             corner_radius: node.style.corner_radius,
+            corner_radius_top_left: node.style.corner_radius_top_left,
+            corner_radius_top_right: node.style.corner_radius_top_right,
+            corner_radius_bottom_left: node.style.corner_radius_bottom_left,
+            corner_radius_bottom_right: node.style.corner_radius_bottom_right,
             padding_left: node.style.padding_left,
             padding_right: node.style.padding_right,
             padding_top: node.style.padding_top,
             padding_bottom: node.style.padding_bottom,
             shadow_color: node.style.shadow_color,
             shadow_radius: node.style.shadow_radius,
+            shadow_offset_x: node.style.shadow_offset_x,
+            shadow_offset_y: node.style.shadow_offset_y,
             width: node.style.width,
             height: node.style.height,
+            min_width: node.style.min_width,
+            max_width: node.style.max_width,
             gap: node.style.gap,
             margin_left: node.style.margin_left,
             margin_right: node.style.margin_right,
@@ -201,23 +818,183 @@ impl From<BarNode> for NodeDto {
             margin_bottom: node.style.margin_bottom,
             notch_align: node.style.notch_align,
             align_items: node.style.align_items,
+            align_self: node.style.align_self,
             justify_content: node.style.justify_content,
             hover_background_color: node.style.hover_background_color,
             hover_label_color: node.style.hover_label_color,
             hover_icon_color: node.style.hover_icon_color,
+            outline_color: node.style.outline_color,
+            outline_width: node.style.outline_width,
+            outline_offset: node.style.outline_offset,
+            blend_mode: node.style.blend_mode,
+            text_truncation: node.style.text_truncation,
+            progress_color: node.style.progress_color,
+            progress_track_color: node.style.progress_track_color,
+            background_opacity: node.style.background_opacity,
+            background_gradient_start: node.style.background_gradient_start,
+            background_gradient_end: node.style.background_gradient_end,
+            background_gradient_angle: node.style.background_gradient_angle,
             on_click: node.on_click,
+            on_right_click: node.on_right_click,
+            on_middle_click: node.on_middle_click,
+            on_click_cwd: node.on_click_cwd,
+            on_click_shell: node.on_click_shell,
+            confirm: node.confirm,
             image: node.image,
             image_scale: node.image_scale,
+            value: node.value,
+            image_content_mode: node.image_content_mode,
+            template: node.template,
+            removing: node.removing,
+            hidden: node.hidden,
+            disabled: node.disabled,
             font_size: node.font_size,
             font_weight: node.font_weight,
             font_family: node.font_family,
+            letter_spacing: node.letter_spacing,
+            line_height: node.line_height,
+            font_style: node.font_style,
+            underline: node.underline,
+            strikethrough: node.strikethrough,
         }
     }
 }
 
+impl NodeDto {
+    /// Schema version and field set for a `compat` name such as `v1`.
+    pub fn compat_fields(compat: &str) -> Option<(u32, &'static [&'static str])> {
+        match compat {
+            "v1" => Some((1, &NODE_FIELDS_V1)),
+            "v2" => Some((2, &NODE_FIELDS_V2)),
+            _ => None,
+        }
+    }
+
+    /// Before schema version 3 `width` and `height` were always numbers, so
+    /// relative sizes are reported as null to older schemas.
+    pub fn for_schema(mut self, version: u32) -> Self {
+        if version < 3 {
+            self.width = self.width.filter(|width| width.points().is_some());
+            self.height = self.height.filter(|height| height.points().is_some());
+        }
+        self
+    }
+
+    /// Every field a query result can report.
+    pub fn field_names() -> Vec<String> {
+        let blank = NodeDto::from(BarNode::new("", NodeType::Item));
+        let Ok(Value::Object(map)) = serde_json::to_value(blank) else {
+            unreachable!("NodeDto serializes to an object");
+        };
+        map.into_iter().map(|(key, _)| key).collect()
+    }
+
+    /// Serializes only the requested fields; `name` is always included.
+    pub fn project(&self, fields: &[String]) -> Map<String, Value> {
+        let Ok(Value::Object(mut map)) = serde_json::to_value(self) else {
+            unreachable!("NodeDto serializes to an object");
+        };
+        map.retain(|key, _| key == "name" || fields.iter().any(|f| f == key));
+        map
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct DisplayDto {
     pub id: u32,
     pub name: String,
     pub is_main: bool,
+    pub width: f32,
+    pub height: f32,
+    pub scale: f32,
+    pub has_notch: bool,
+    pub notch_width: Option<f32>,
+    pub notch_height: Option<f32>,
+}
+
+impl From<DisplayInfo> for DisplayDto {
+    fn from(display: DisplayInfo) -> Self {
+        DisplayDto {
+            id: display.id,
+            name: display.name,
+            is_main: display.is_main,
+            width: display.width,
+            height: display.height,
+            scale: display.scale,
+            has_notch: display.has_notch,
+            notch_width: display.notch_width,
+            notch_height: display.notch_height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_may_be_given_as_id_or_name() {
+        let Command::Add { display, .. } =
+            serde_json::from_str(r#"{"command":"add","name":"a","display":"2"}"#).unwrap()
+        else {
+            panic!("expected add");
+        };
+        assert_eq!(display, Some(DisplayRef::Name("2".to_string())));
+        assert_eq!(display.unwrap().resolve().unwrap(), 2);
+        let Command::Query { display, .. } =
+            serde_json::from_str(r#"{"command":"query","display":3}"#).unwrap()
+        else {
+            panic!("expected query");
+        };
+        assert_eq!(display, Some(DisplayRef::Id(3)));
+        let Command::Query { display, .. } =
+            serde_json::from_str(r#"{"command":"query","display":"Nowhere"}"#).unwrap()
+        else {
+            panic!("expected query");
+        };
+        let err = display.unwrap().resolve().unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidValue);
+        assert_eq!(err, "unknown display: Nowhere");
+    }
+
+    #[test]
+    fn project_selects_requested_fields() {
+        let mut node = BarNode::new("clock", NodeType::Item);
+        node.label = Some("12:00".to_string());
+        node.label_color = Some("#ffffff".to_string());
+        node.style.background_color = Some("#000000".to_string());
+
+        let projected = NodeDto::from(node).project(&["label".to_string()]);
+
+        assert_eq!(projected.len(), 2);
+        assert_eq!(projected["name"], "clock");
+        assert_eq!(projected["label"], "12:00");
+        assert!(!projected.contains_key("label_color"));
+        assert!(!projected.contains_key("background_color"));
+    }
+
+    #[test]
+    fn geometry_survives_into_display_dto() {
+        let display = DisplayInfo {
+            id: 1,
+            name: "Built-in Retina Display".to_string(),
+            is_main: true,
+            width: 1512.0,
+            height: 982.0,
+            scale: 2.0,
+            has_notch: true,
+            notch_width: Some(185.0),
+            notch_height: Some(32.0),
+        };
+
+        let dto = DisplayDto::from(display);
+
+        assert_eq!((dto.width, dto.height, dto.scale), (1512.0, 982.0, 2.0));
+        assert!(dto.has_notch);
+        assert_eq!(dto.notch_width, Some(185.0));
+        assert_eq!(dto.notch_height, Some(32.0));
+        let json = serde_json::to_value(&dto).unwrap();
+        assert_eq!(json["notch_width"], 185.0);
+        assert_eq!(json["scale"], 2.0);
+    }
 }
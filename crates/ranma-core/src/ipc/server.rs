@@ -1,14 +1,36 @@
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::UnixListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Notify;
+use tokio::sync::broadcast::error::RecvError;
 
+use crate::actor::StateActor;
 use crate::bridge::StateChangeEvent;
-use crate::state::{BarNode, NodeStyle, NodeType};
-use crate::{get_displays, get_state, main_display_id, notify};
+use crate::config::{config_init_path, run_init_script};
+use crate::state::{
+    BarNode, BarState, ErrorCode, NodeStyle, NodeType, PROPERTY_KEYS, StateError,
+    property_node_types, property_type,
+};
+use crate::{get_displays, get_events, get_metrics, get_state, main_display_id, startup_init_path};
 
-use super::protocol::{Command, DisplayDto, Response};
+use super::events::{EventDto, EventFilter, EventKind, Replay, Subscription};
+use super::metrics::Metrics;
+use super::protocol::{
+    Command, DisplayDto, NodeDto, PROTOCOL, PropertySchema, QUERY_SCHEMA_VERSION, Response,
+    TreeSpec,
+};
 
+/// Woken by a `shutdown` command once its answer is written.
+static SHUTDOWN: Notify = Notify::const_new();
+
+/// Serves `socket_path` until SIGINT, SIGTERM or a `shutdown` command, then
+/// removes the socket.
 pub async fn run(socket_path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if socket_path.exists() {
         std::fs::remove_file(socket_path)?;
@@ -17,13 +39,29 @@ pub async fn run(socket_path: &Path) -> Result<(), Box<dyn std::error::Error + S
     let listener = UnixListener::bind(socket_path)?;
     eprintln!("listening on {}", socket_path.display());
 
+    let result = serve(&listener).await;
+    let _ = std::fs::remove_file(socket_path);
+    eprintln!("stopped listening on {}", socket_path.display());
+    result
+}
+
+async fn serve(listener: &UnixListener) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
     loop {
-        let (stream, _) = listener.accept().await?;
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream).await {
-                eprintln!("connection error: {e}");
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream).await {
+                        eprintln!("connection error: {e}");
+                    }
+                });
             }
-        });
+            _ = interrupt.recv() => return Ok(()),
+            _ = terminate.recv() => return Ok(()),
+            _ = SHUTDOWN.notified() => return Ok(()),
+        }
     }
 }
 
@@ -34,25 +72,227 @@ async fn handle_connection(
     let mut lines = BufReader::new(reader).lines();
 
     while let Some(line) = lines.next_line().await? {
-        let response = handle_command(&line);
-        let mut out = serde_json::to_string(&response)?;
-        out.push('\n');
-        writer.write_all(out.as_bytes()).await?;
+        let response = match serde_json::from_str(&line) {
+            Ok(Command::Watch { since_seq, events }) => {
+                return watch(&mut writer, since_seq, events).await;
+            }
+            Ok(Command::Shutdown) => {
+                write_response(&mut writer, &Response::Ok).await?;
+                SHUTDOWN.notify_one();
+                return Ok(());
+            }
+            Ok(cmd) => handle_command(get_state(), get_metrics(), cmd).await,
+            Err(e) => Response::error(ErrorCode::InvalidCommand, format!("invalid command: {e}")),
+        };
+        write_response(&mut writer, &response).await?;
     }
 
     Ok(())
 }
 
-fn handle_command(input: &str) -> Response {
-    let cmd: Command = match serde_json::from_str(input) {
-        Ok(cmd) => cmd,
-        Err(e) => {
-            return Response::Error {
-                message: format!("invalid command: {e}"),
-            };
+async fn watch<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    since_seq: Option<u64>,
+    events: Option<Vec<String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = match EventFilter::new(events) {
+        Ok(filter) => filter,
+        Err(message) => {
+            let response = Response::error(ErrorCode::InvalidCommand, message);
+            return write_line(writer, &response).await;
+        }
+    };
+    let Subscription {
+        replay,
+        mut last_seq,
+        receiver: mut live,
+    } = get_events().subscribe(since_seq);
+    match replay {
+        Replay::Events(events) => {
+            for event in events.iter().filter(|e| filter.allows(&e.event)) {
+                write_line(writer, event).await?;
+            }
+        }
+        Replay::Gap => last_seq = write_full_refresh(writer, &filter).await?,
+    }
+
+    loop {
+        match live.recv().await {
+            Ok(event) if event.seq > last_seq => {
+                last_seq = event.seq;
+                if filter.allows(&event.event) {
+                    write_line(writer, &event).await?;
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => {
+                last_seq = write_full_refresh(writer, &filter).await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Sends a `cleared` for every display followed by the current nodes of each
+/// one, stamped with the seq of the last event they include, which is
+/// returned.
+async fn write_full_refresh<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    filter: &EventFilter,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let (state, seq) = get_state().snapshot_with(|| get_events().last_seq());
+    if !filter.allows_name("full_refresh") {
+        return Ok(seq);
+    }
+    let cleared = EventDto {
+        seq,
+        event: EventKind::Cleared { display: None },
+    };
+    write_line(writer, &cleared).await?;
+    let mut by_display: BTreeMap<u32, Vec<NodeDto>> = BTreeMap::new();
+    for node in state.get_nodes() {
+        by_display
+            .entry(node.display)
+            .or_default()
+            .push(state.effective_node(&node).into());
+    }
+    for (display, nodes) in by_display {
+        let event = EventDto {
+            seq,
+            event: EventKind::FullRefresh { display, nodes },
+        };
+        write_line(writer, &event).await?;
+    }
+    Ok(seq)
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match response {
+        Response::Stream {
+            state,
+            ids,
+            fields,
+            schema_version,
+        } => {
+            let mut count = 0;
+            for node in ids.iter().filter_map(|id| state.node_ref_by_id(*id)) {
+                let node = NodeDto::from(state.effective_node(node)).for_schema(*schema_version);
+                match fields {
+                    Some(fields) => write_line(writer, &node.project(fields)).await?,
+                    None => write_line(writer, &node).await?,
+                }
+                count += 1;
+            }
+            write_line(writer, &Response::StreamEnd { count }).await
         }
+        _ => write_line(writer, response).await,
+    }
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    value: &impl serde::Serialize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut out = serde_json::to_string(value)?;
+    out.push('\n');
+    writer.write_all(out.as_bytes()).await?;
+    Ok(())
+}
+
+async fn handle_command(actor: &'static StateActor, metrics: &Metrics, cmd: Command) -> Response {
+    let name = cmd.name();
+    let start = Instant::now();
+    let response = dispatch(actor, cmd).await;
+    metrics.record(name, start.elapsed());
+    response
+}
+
+async fn dispatch(actor: &'static StateActor, cmd: Command) -> Response {
+    if let Command::ReloadInit { path, clear } = cmd {
+        return reload_init(actor, path, clear).await;
+    }
+    if cmd.is_read_only() {
+        return read(cmd, &actor.snapshot());
+    }
+    let delayed = match &cmd {
+        Command::Remove {
+            name,
+            after_ms: Some(ms),
+            ..
+        } => Some((name.clone(), *ms)),
+        _ => None,
+    };
+    let result = actor
+        .submit(move |state| {
+            let (response, events) = execute(cmd, state);
+            let pending = delayed.and_then(|(name, ms)| {
+                let (id, token) = state.pending_removal(&name)?;
+                Some((id, token, ms))
+            });
+            ((response, pending), events)
+        })
+        .await;
+    let Ok((response, pending)) = result else {
+        return Response::error(ErrorCode::Failed, "state actor stopped");
     };
+    if let Some((id, token, ms)) = pending {
+        schedule_removal(actor, id, token, ms);
+    }
+    response
+}
 
+/// Runs the init script outside the actor, since the script's own commands
+/// need it.
+async fn reload_init(actor: &'static StateActor, path: Option<String>, clear: bool) -> Response {
+    let script = match path {
+        Some(path) => config_init_path(Some(&path)),
+        None => Ok(startup_init_path()),
+    };
+    let script = match script {
+        Ok(Some(script)) => script,
+        Ok(None) => {
+            return Response::error(ErrorCode::NotFound, "no init script to reload");
+        }
+        Err(message) => return Response::error(ErrorCode::NotFound, message),
+    };
+    if clear {
+        let _ = actor
+            .submit(|state| execute(Command::Clear { display: None }, state))
+            .await;
+    }
+    match tokio::task::spawn_blocking(move || run_init_script(&script)).await {
+        Ok(Ok(())) => Response::Ok,
+        Ok(Err(message)) => Response::error(ErrorCode::Failed, message),
+        Err(e) => Response::error(ErrorCode::Failed, format!("init script task failed: {e}")),
+    }
+}
+
+fn schedule_removal(actor: &'static StateActor, id: u64, token: u64, after_ms: u64) {
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(after_ms)).await;
+        let _ = actor
+            .submit(move |state| match state.finish_removal(id, token) {
+                Some(node) => (
+                    (),
+                    vec![StateChangeEvent::NodeRemoved {
+                        display: node.display,
+                        name: node.name,
+                    }],
+                ),
+                None => ((), vec![]),
+            })
+            .await;
+    });
+}
+
+/// How long an `add` idempotency key is remembered after the add succeeds.
+pub const ADD_KEY_TTL: Duration = Duration::from_secs(60);
+
+fn execute(cmd: Command, state: &mut BarState) -> (Response, Vec<StateChangeEvent>) {
+    let add_user_set = matches!(cmd, Command::Add { .. }).then(|| add_properties(&cmd));
     match cmd {
         Command::Add {
             name,
@@ -66,14 +306,22 @@ fn handle_command(input: &str) -> Response {
             border_color,
             border_width,
             corner_radius,
+            corner_radius_top_left,
+            corner_radius_top_right,
+            corner_radius_bottom_left,
+            corner_radius_bottom_right,
             padding_left,
             padding_right,
             padding_top,
             padding_bottom,
             shadow_color,
             shadow_radius,
+            shadow_offset_x,
+            shadow_offset_y,
             width,
             height,
+            min_width,
+            max_width,
             gap,
             margin_left,
             margin_right,
@@ -88,26 +336,94 @@ fn handle_command(input: &str) -> Response {
             font_size,
             font_weight,
             font_family,
+            letter_spacing,
+            line_height,
+            font_style,
+            underline,
+            strikethrough,
             notch_align,
             align_items,
+            align_self,
             justify_content,
             hover_background_color,
             hover_label_color,
             hover_icon_color,
+            outline_color,
+            outline_width,
+            outline_offset,
+            blend_mode,
+            text_truncation,
+            progress_color,
+            progress_track_color,
+            background_opacity,
+            background_gradient_start,
+            background_gradient_end,
+            background_gradient_angle,
             on_click,
+            on_right_click,
+            on_middle_click,
+            on_click_cwd,
+            on_click_shell,
+            confirm,
             image,
             image_scale,
+            value,
+            image_content_mode,
+            template,
+            hidden,
+            disabled,
             position,
+            after,
+            before,
             display,
+            idempotency_key,
         } => {
+            if let Some(key) = &idempotency_key
+                && let Some(id) = state.added_with_key(key, ADD_KEY_TTL)
+            {
+                let response = match state.node_ref_by_id(id) {
+                    Some(node) => Response::NodeResult {
+                        node: state.effective_node(node).into(),
+                    },
+                    None => Response::error(
+                        ErrorCode::NotFound,
+                        format!("node added with idempotency key '{key}' no longer exists"),
+                    ),
+                };
+                return (response, vec![]);
+            }
+            let anchor = match (after, before) {
+                (Some(_), Some(_)) => {
+                    return (
+                        Response::error(
+                            ErrorCode::InvalidCommand,
+                            "after and before cannot be combined",
+                        ),
+                        vec![],
+                    );
+                }
+                (Some(_), None) | (None, Some(_)) if position.is_some() => {
+                    return (
+                        Response::error(
+                            ErrorCode::InvalidCommand,
+                            "position cannot be combined with after or before",
+                        ),
+                        vec![],
+                    );
+                }
+                (Some(sibling), None) => Some((sibling, true)),
+                (None, Some(sibling)) => Some((sibling, false)),
+                (None, None) => None,
+            };
             let display_explicit = display.is_some();
+            let display = match display.map(|d| d.resolve()).transpose() {
+                Ok(display) => display,
+                Err(e) => return (Response::from(e), vec![]),
+            };
             let display = display.unwrap_or_else(|| {
                 if let Some(ref parent_name) = parent {
-                    let state = get_state().lock();
                     state
-                        .get_nodes()
-                        .iter()
-                        .find(|n| &n.name == parent_name)
+                        .get_node(parent_name)
                         .map(|n| n.display)
                         .unwrap_or_else(main_display_id)
                 } else {
@@ -118,9 +434,11 @@ fn handle_command(input: &str) -> Response {
                 Some("row") => NodeType::Row,
                 Some("column") => NodeType::Column,
                 Some("box") => NodeType::Box,
+                Some("progress") => NodeType::Progress,
                 _ => NodeType::Item,
             };
             let node = BarNode {
+                id: 0,
                 name,
                 node_type: nt,
                 parent,
@@ -131,10 +449,28 @@ fn handle_command(input: &str) -> Response {
                 font_size,
                 font_weight,
                 font_family,
+                letter_spacing,
+                line_height,
+                font_style,
+                underline,
+                strikethrough,
                 on_click,
+                on_right_click,
+                on_middle_click,
+                on_click_cwd,
+                on_click_shell,
+                confirm,
                 image,
                 image_scale,
+                // JSON numbers are finite, so clamping can't fail here
+                value: value.map(|v| v.clamp(0.0, 100.0)),
+                image_content_mode,
+                template,
+                hidden,
+                disabled,
+                removing: false,
                 position: position.unwrap_or(0),
+                position_explicit: position.is_some(),
                 display,
                 display_explicit,
                 style: NodeStyle {
@@ -142,14 +478,22 @@ fn handle_command(input: &str) -> Response {
                     border_color,
                     border_width,
                     corner_radius,
+                    corner_radius_top_left: corner_radius_top_left.or(corner_radius),
+                    corner_radius_top_right: corner_radius_top_right.or(corner_radius),
+                    corner_radius_bottom_left: corner_radius_bottom_left.or(corner_radius),
+                    corner_radius_bottom_right: corner_radius_bottom_right.or(corner_radius),
                     padding_left: padding_left.or(padding_horizontal).or(padding),
                     padding_right: padding_right.or(padding_horizontal).or(padding),
                     padding_top: padding_top.or(padding_vertical).or(padding),
                     padding_bottom: padding_bottom.or(padding_vertical).or(padding),
                     shadow_color,
                     shadow_radius,
+                    shadow_offset_x,
+                    shadow_offset_y,
                     width,
                     height,
+                    min_width,
+                    max_width,
                     gap,
                     margin_left: margin_left.or(margin_horizontal).or(margin),
                     margin_right: margin_right.or(margin_horizontal).or(margin),
@@ -157,94 +501,2109 @@ fn handle_command(input: &str) -> Response {
                     margin_bottom: margin_bottom.or(margin_vertical).or(margin),
                     notch_align,
                     align_items,
+                    align_self,
                     justify_content,
                     hover_background_color,
                     hover_label_color,
                     hover_icon_color,
+                    outline_color,
+                    outline_width,
+                    outline_offset,
+                    blend_mode,
+                    text_truncation,
+                    progress_color,
+                    progress_track_color,
+                    background_opacity,
+                    background_gradient_start,
+                    background_gradient_end,
+                    background_gradient_angle,
                 },
             };
-            let mut state = get_state().lock();
-            match state.add_node(node.clone()) {
-                Ok(()) => {
-                    drop(state);
-                    notify(StateChangeEvent::NodeAdded { display, node });
-                    Response::Ok
-                }
-                Err(message) => Response::Error { message },
+            // Placing beside a sibling may shift the others, so refresh the display
+            let refresh = state.is_removing(&node.name, display) || anchor.is_some();
+            let added = match anchor {
+                Some((sibling, after)) => state.add_node_beside(node.clone(), &sibling, after),
+                None => state.add_node(node.clone()),
+            };
+            if let (Ok(id), Some(properties)) = (&added, &add_user_set) {
+                state.record_user_set(*id, properties);
+            }
+            if let (Ok(id), Some(key)) = (&added, idempotency_key) {
+                state.record_add_key(key, *id);
             }
+            let id = match added {
+                Ok(id) => id,
+                Err(e) => return (e.into(), vec![]),
+            };
+            let node = state.get_node_by_id(id).unwrap_or(BarNode { id, ..node });
+            let node = state.effective_node(&node);
+            let response = Response::NodeResult {
+                node: node.clone().into(),
+            };
+            let event = if refresh {
+                StateChangeEvent::FullRefresh {
+                    display,
+                    nodes: state
+                        .nodes_for_display(display)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|n| state.effective_node(n))
+                        .collect(),
+                }
+            } else {
+                StateChangeEvent::NodeAdded {
+                    display,
+                    node,
+                    index: state.sibling_index(id).unwrap_or_default(),
+                }
+            };
+            (response, vec![event])
         }
-        Command::Set { name, properties } => {
-            let mut state = get_state().lock();
-            let old_display = state
-                .get_nodes()
-                .iter()
-                .find(|n| n.name == name)
-                .map(|n| n.display);
+        Command::Set {
+            name,
+            properties,
+            with_old,
+        } => {
+            let previous = if with_old {
+                state.previous_values(&name, &properties).ok()
+            } else {
+                None
+            };
+            let before = state.get_node(&name);
 
             match state.set_properties(&name, &properties) {
+                Ok(node) if before.as_ref() == Some(&node) => (Response::Unchanged, vec![]),
                 Ok(node) => {
+                    let old = before.map(|n| (n.display, n.parent));
+                    let parent_events = old
+                        .as_ref()
+                        .map(|(_, parent)| reflow_parents(state, parent, &node.parent))
+                        .unwrap_or_default();
+                    let node = state.effective_node(&node);
+                    let response = match previous {
+                        Some(previous) => Response::OkWithPrevious { previous },
+                        None => Response::NodeResult {
+                            node: node.clone().into(),
+                        },
+                    };
                     let new_display = node.display;
-                    drop(state);
-
-                    if let Some(old) = old_display {
+                    let mut events = Vec::new();
+                    if let Some((old, _)) = old {
                         if old != new_display {
-                            notify(StateChangeEvent::NodeMoved {
+                            events.push(StateChangeEvent::NodeMoved {
                                 old_display: old,
                                 new_display,
                                 node,
                             });
                         } else {
-                            notify(StateChangeEvent::NodeUpdated {
+                            events.push(StateChangeEvent::NodeUpdated {
                                 display: new_display,
                                 node,
                             });
                         }
                     }
-                    Response::Ok
+                    events.extend(parent_events);
+                    (response, events)
                 }
-                Err(message) => Response::Error { message },
+                Err(e) => (e.into(), vec![]),
             }
         }
-        Command::Remove { name } => {
-            let mut state = get_state().lock();
-            match state.remove_node(&name) {
+        Command::Move {
+            name,
+            parent,
+            position,
+        } => {
+            let old_parent = state.get_node(&name).and_then(|n| n.parent);
+            match state.move_node(&name, parent.as_deref(), position) {
                 Ok(node) => {
-                    let display = node.display;
-                    drop(state);
-                    notify(StateChangeEvent::NodeRemoved { display, name });
-                    Response::Ok
+                    let mut events = vec![StateChangeEvent::NodeUpdated {
+                        display: node.display,
+                        node: state.effective_node(&node),
+                    }];
+                    events.extend(reflow_parents(state, &old_parent, &node.parent));
+                    (Response::Ok, events)
                 }
-                Err(message) => Response::Error { message },
+                Err(e) => (e.into(), vec![]),
             }
         }
-        Command::Query { name, display } => {
-            let state = get_state().lock();
-            let nodes: Vec<_> = match (name, display) {
-                (Some(name), _) => state
-                    .get_nodes()
+        Command::Duplicate {
+            name,
+            new_name,
+            new_display,
+        } => {
+            let display = new_display.or_else(|| state.get_node(&name).map(|n| n.display));
+            match state.duplicate_subtree(&name, &new_name, new_display) {
+                Ok(names) => {
+                    let display = display.unwrap_or_default();
+                    let nodes = state
+                        .nodes_for_display(display)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|n| state.effective_node(n))
+                        .collect();
+                    (
+                        Response::OkWithNames { names },
+                        vec![StateChangeEvent::FullRefresh { display, nodes }],
+                    )
+                }
+                Err(e) => (e.into(), vec![]),
+            }
+        }
+        Command::SetVar { name, value } => match state.set_variable(&name, &value) {
+            Ok(updated) => {
+                let events = updated
+                    .iter()
+                    .map(|node| StateChangeEvent::NodeUpdated {
+                        display: node.display,
+                        node: state.effective_node(node),
+                    })
+                    .collect();
+                (Response::Ok, events)
+            }
+            Err(e) => (e.into(), vec![]),
+        },
+        Command::Reset { name, properties } => {
+            match state.reset_properties(&name, properties.as_deref()) {
+                Ok(node) => (
+                    Response::Ok,
+                    vec![StateChangeEvent::NodeUpdated {
+                        display: node.display,
+                        node: state.effective_node(&node),
+                    }],
+                ),
+                Err(e) => (e.into(), vec![]),
+            }
+        }
+        Command::Rename { from, to } => match state.rename_node(&from, &to) {
+            Ok(node) => {
+                let display = node.display;
+                let nodes = state
+                    .nodes_for_display(display)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|n| state.effective_node(n))
+                    .collect();
+                (
+                    Response::Ok,
+                    vec![StateChangeEvent::FullRefresh { display, nodes }],
+                )
+            }
+            Err(e) => (e.into(), vec![]),
+        },
+        Command::Reorder {
+            parent,
+            display,
+            order,
+        } => match state.reorder(parent.as_deref(), display, &order) {
+            Ok(()) => {
+                let nodes = state
+                    .nodes_for_display(display)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|n| state.effective_node(n))
+                    .collect();
+                (
+                    Response::Ok,
+                    vec![StateChangeEvent::FullRefresh { display, nodes }],
+                )
+            }
+            Err(e) => (e.into(), vec![]),
+        },
+        Command::Swap { a, b } => match state.swap_positions(&a, &b) {
+            Ok((node, _)) => (Response::Ok, full_refresh(state, vec![node.display])),
+            Err(e) => (e.into(), vec![]),
+        },
+        cmd @ Command::Remove { dry_run: true, .. } => {
+            (read(cmd, &Arc::new(state.clone())), vec![])
+        }
+        Command::Remove {
+            name,
+            after_ms: Some(_),
+            ..
+        } => match state.mark_removing(&name) {
+            Ok((node, _)) => (
+                Response::Ok,
+                vec![StateChangeEvent::NodeRemoving {
+                    display: node.display,
+                    node: state.effective_node(&node),
+                }],
+            ),
+            Err(e) => (e.into(), vec![]),
+        },
+        Command::Remove {
+            name,
+            after_ms: None,
+            ..
+        } => match state.remove_node(&name) {
+            Ok(node) => {
+                let display = node.display;
+                (
+                    Response::Ok,
+                    vec![StateChangeEvent::NodeRemoved { display, name }],
+                )
+            }
+            Err(e) => (e.into(), vec![]),
+        },
+        Command::Clear { display } => {
+            if state.clear(display).is_empty() {
+                (Response::Ok, vec![])
+            } else {
+                (Response::Ok, vec![StateChangeEvent::Cleared { display }])
+            }
+        }
+        Command::Refresh { display } => {
+            let displays = match display {
+                Some(display) => vec![display],
+                None => {
+                    let mut displays = state.displays();
+                    displays.extend(get_displays().iter().map(|d| d.id));
+                    displays
+                }
+            };
+            (Response::Ok, full_refresh(state, displays))
+        }
+        cmd @ (Command::Query { .. }
+        | Command::Displays
+        | Command::Exists { .. }
+        | Command::Export { .. }
+        | Command::Snapshot
+        | Command::Stats
+        | Command::Count { .. }
+        | Command::Ping
+        | Command::ListStyles
+        | Command::Schema
+        | Command::GetDisplayDefaults { .. }) => (read(cmd, &Arc::new(state.clone())), vec![]),
+        Command::AddTree { root } => execute_add_tree(root, state),
+        Command::Restore { snapshot } => match BarState::from_snapshot(snapshot) {
+            Ok(restored) => {
+                let mut displays = state.displays();
+                *state = restored;
+                let known: Vec<u32> = get_displays().iter().map(|d| d.id).collect();
+                let main = main_display_id();
+                if main != 0 {
+                    for display in state.displays() {
+                        if !known.contains(&display) {
+                            state.migrate_nodes(display, main);
+                        }
+                    }
+                }
+                displays.extend(state.displays());
+                (Response::Ok, full_refresh(state, displays))
+            }
+            Err(e) => (
+                Response::error(ErrorCode::InvalidValue, format!("invalid snapshot: {e}")),
+                vec![],
+            ),
+        },
+        Command::Watch { .. } => (
+            Response::error(
+                ErrorCode::InvalidCommand,
+                "watch cannot be combined with other commands",
+            ),
+            vec![],
+        ),
+        Command::ReloadInit { .. } => (
+            Response::error(
+                ErrorCode::InvalidCommand,
+                "reload_init cannot be combined with other commands",
+            ),
+            vec![],
+        ),
+        Command::Shutdown => (
+            Response::error(
+                ErrorCode::InvalidCommand,
+                "shutdown cannot be combined with other commands",
+            ),
+            vec![],
+        ),
+        Command::Batch { commands } => execute_batch(commands, state),
+        Command::DefineTemplate { name, properties } => {
+            match state.define_template(&name, &properties) {
+                Ok(users) => {
+                    let events = users
+                        .iter()
+                        .map(|node| StateChangeEvent::NodeUpdated {
+                            display: node.display,
+                            node: state.effective_node(node),
+                        })
+                        .collect();
+                    (Response::Ok, events)
+                }
+                Err(e) => (e.into(), vec![]),
+            }
+        }
+        Command::SetDisplayDefaults {
+            display,
+            properties,
+        } => match state.set_display_defaults(display, &properties) {
+            Ok(()) => (Response::Ok, vec![]),
+            Err(e) => (e.into(), vec![]),
+        },
+    }
+}
+
+/// Updates for the containers a node left and joined, so both lay out
+/// their children again.
+fn reflow_parents(
+    state: &BarState,
+    old_parent: &Option<String>,
+    new_parent: &Option<String>,
+) -> Vec<StateChangeEvent> {
+    if old_parent == new_parent {
+        return vec![];
+    }
+    [old_parent, new_parent]
+        .into_iter()
+        .flatten()
+        .filter_map(|parent| state.get_node(parent))
+        .map(|container| StateChangeEvent::NodeUpdated {
+            display: container.display,
+            node: state.effective_node(&container),
+        })
+        .collect()
+}
+
+fn execute_batch(
+    commands: Vec<Command>,
+    state: &mut BarState,
+) -> (Response, Vec<StateChangeEvent>) {
+    let before = state.clone();
+    let mut results = Vec::with_capacity(commands.len());
+    let mut events = Vec::new();
+    for (index, cmd) in commands.into_iter().enumerate() {
+        let rejected = match &cmd {
+            Command::Batch { .. }
+            | Command::Watch { .. }
+            | Command::ReloadInit { .. }
+            | Command::Shutdown => Some(format!("{} cannot be batched", cmd.name())),
+            Command::Remove {
+                after_ms: Some(_), ..
+            } => Some("delayed remove cannot be batched".to_string()),
+            cmd if cmd.is_read_only() => Some(format!("{} cannot be batched", cmd.name())),
+            _ => None,
+        };
+        let (response, mut produced) = match rejected {
+            Some(message) => (Response::error(ErrorCode::InvalidCommand, message), vec![]),
+            None => execute(cmd, state),
+        };
+        if let Response::Error { code, message } = response {
+            *state = before;
+            return (
+                Response::error(code, format!("batch command {index}: {message}")),
+                vec![],
+            );
+        }
+        results.push(response);
+        events.append(&mut produced);
+    }
+    (Response::BatchResult { results }, events)
+}
+
+/// Adds `root` and then its descendants, parents first. The nodes go in as
+/// one `FullRefresh` per display rather than an event each.
+fn execute_add_tree(root: TreeSpec, state: &mut BarState) -> (Response, Vec<StateChangeEvent>) {
+    let before = state.clone();
+    let mut pending = vec![(root, None)];
+    let mut names = Vec::new();
+    let mut displays = Vec::new();
+    while let Some((spec, parent)) = pending.pop() {
+        let TreeSpec {
+            mut fields,
+            children,
+        } = spec;
+        let name = fields
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let result = match parent {
+            Some(_) if fields.contains_key("parent") => Err(StateError::new(
+                ErrorCode::InvalidCommand,
+                "a nested node takes its parent from the tree",
+            )),
+            _ if names.contains(&name) => Err(StateError::new(
+                ErrorCode::AlreadyExists,
+                format!("name used twice in tree: {name}"),
+            )),
+            _ => {
+                fields.insert("command".into(), json!("add"));
+                if let Some(parent) = parent {
+                    fields.insert("parent".into(), Value::String(parent));
+                }
+                match serde_json::from_value(Value::Object(fields)) {
+                    Ok(cmd) => match execute(cmd, state) {
+                        (Response::Error { code, message }, _) => {
+                            Err(StateError::new(code, message))
+                        }
+                        _ => Ok(()),
+                    },
+                    Err(e) => Err(StateError::new(
+                        ErrorCode::InvalidCommand,
+                        format!("invalid node: {e}"),
+                    )),
+                }
+            }
+        };
+        if let Err(e) = result {
+            *state = before;
+            let at = if name.is_empty() { "root" } else { &name };
+            return (
+                Response::error(e.code, format!("add_tree {at}: {}", e.message)),
+                vec![],
+            );
+        }
+        displays.extend(state.node_display(&name));
+        // Reversed so children come off the stack in the order given
+        for child in children.into_iter().rev() {
+            pending.push((child, Some(name.clone())));
+        }
+        names.push(name);
+    }
+    (
+        Response::OkWithNames { names },
+        full_refresh(state, displays),
+    )
+}
+
+/// A `FullRefresh` for each of `displays`, once each.
+fn full_refresh(state: &BarState, mut displays: Vec<u32>) -> Vec<StateChangeEvent> {
+    displays.sort_unstable();
+    displays.dedup();
+    displays
+        .into_iter()
+        .map(|display| StateChangeEvent::FullRefresh {
+            display,
+            nodes: state
+                .nodes_for_display(display)
+                .unwrap_or_default()
+                .iter()
+                .map(|n| state.effective_node(n))
+                .collect(),
+        })
+        .collect()
+}
+
+const NODE_TYPES: [&str; 5] = ["item", "row", "column", "box", "progress"];
+
+/// `NodeDto` fields that `export` writes as placement on the `add`, or that
+/// are derived from other fields, rather than as properties.
+const EXPORT_SKIPPED_FIELDS: [&str; 9] = [
+    "id",
+    "name",
+    "node_type",
+    "parent",
+    "position",
+    "display",
+    "display_explicit",
+    "label_display_width",
+    "removing",
+];
+
+/// The fields an `add` set, as `set` would take them.
+fn add_properties(cmd: &Command) -> HashMap<String, String> {
+    let Ok(Value::Object(fields)) = serde_json::to_value(cmd) else {
+        return HashMap::new();
+    };
+    fields
+        .into_iter()
+        .filter(|(key, _)| {
+            !matches!(
+                key.as_str(),
+                "command" | "name" | "node_type" | "after" | "before" | "idempotency_key"
+            )
+        })
+        .filter_map(|(key, value)| property_string(&value).map(|value| (key, value)))
+        .collect()
+}
+
+/// `value` as a `set` property value; `None` for unset fields.
+fn property_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => {
+            let n = n.to_string();
+            Some(n.strip_suffix(".0").unwrap_or(&n).to_string())
+        }
+        Value::Bool(true) => Some("true".to_string()),
+        _ => None,
+    }
+}
+
+/// `add` and `set` commands that recreate `selected` in `state`.
+fn export_commands(state: &BarState, selected: Vec<BarNode>, only_user_set: bool) -> Vec<Value> {
+    let is_root = |n: &BarNode| {
+        n.parent.as_ref().is_none_or(|parent| {
+            !selected
+                .iter()
+                .any(|p| &p.name == parent && p.display == n.display)
+        })
+    };
+    let mut ordered: Vec<&BarNode> = selected.iter().filter(|n| is_root(n)).collect();
+    ordered.sort_by(|a, b| (a.display, a.position, &a.name).cmp(&(b.display, b.position, &b.name)));
+    let mut i = 0;
+    while i < ordered.len() {
+        let parent = ordered[i];
+        let mut children: Vec<&BarNode> = selected
+            .iter()
+            .filter(|n| n.parent.as_ref() == Some(&parent.name) && n.display == parent.display)
+            .collect();
+        children.sort_by(|a, b| (a.position, &a.name).cmp(&(b.position, &b.name)));
+        ordered.extend(children);
+        i += 1;
+    }
+
+    let mut commands = Vec::new();
+    // where `add` would append each node without an explicit position
+    let mut next_position: HashMap<(u32, Option<&String>), i32> = HashMap::new();
+    for node in ordered {
+        let next = next_position
+            .entry((node.display, node.parent.as_ref()))
+            .or_insert(0);
+        let appended = !node.position_explicit && node.position == *next;
+        *next = (*next).max(node.position + 1);
+        let dto = NodeDto::from(node.clone());
+        let mut add = serde_json::json!({
+            "command": "add",
+            "name": node.name,
+            "node_type": dto.node_type,
+        });
+        if let Some(parent) = &node.parent {
+            add["parent"] = parent.clone().into();
+        }
+        if node.display_explicit {
+            add["display"] = node.display.into();
+        }
+        if !appended {
+            add["position"] = node.position.into();
+        }
+        commands.push(add);
+
+        let properties: BTreeMap<String, String> = if only_user_set {
+            state.user_set(node.id)
+        } else {
+            let Ok(Value::Object(fields)) = serde_json::to_value(&dto) else {
+                unreachable!("NodeDto serializes to an object");
+            };
+            fields
+                .into_iter()
+                .filter(|(key, _)| !EXPORT_SKIPPED_FIELDS.contains(&key.as_str()))
+                .filter_map(|(key, value)| property_string(&value).map(|value| (key, value)))
+                .collect()
+        };
+        if !properties.is_empty() {
+            commands.push(serde_json::json!({
+                "command": "set",
+                "name": node.name,
+                "properties": properties,
+            }));
+        }
+    }
+    commands
+}
+
+fn read(cmd: Command, state: &Arc<BarState>) -> Response {
+    match cmd {
+        Command::Query {
+            id,
+            name,
+            display,
+            parent,
+            not_name,
+            not_parent,
+            not_type,
+            filter,
+            fields,
+            stream,
+            compat,
+        } => {
+            if let Some(ref node_type) = not_type
+                && !NODE_TYPES.contains(&node_type.as_str())
+            {
+                return Response::error(
+                    ErrorCode::InvalidValue,
+                    format!(
+                        "invalid node type: {} (expected one of: {})",
+                        node_type,
+                        NODE_TYPES.join(", ")
+                    ),
+                );
+            }
+            let display = match display.map(|d| d.resolve()).transpose() {
+                Ok(display) => display,
+                Err(e) => return e.into(),
+            };
+            // `id` and `name` select nodes on their own, so a parent given
+            // alongside them is neither looked up nor applied
+            let parent = parent.filter(|_| id.is_none() && name.is_none());
+            if let Some(ref parent) = parent
+                && state.get_node(parent).is_none()
+            {
+                return Response::error(
+                    ErrorCode::NotFound,
+                    format!("parent '{}' not found", parent),
+                );
+            }
+            if let Some(ref fields) = fields {
+                let known = NodeDto::field_names();
+                if let Some(unknown) = fields.iter().find(|f| !known.contains(f)) {
+                    return Response::error(
+                        ErrorCode::InvalidProperty,
+                        format!("unknown query field: {}", unknown),
+                    );
+                }
+            }
+            let (schema_version, fields) = match compat.as_deref() {
+                None => (QUERY_SCHEMA_VERSION, fields),
+                Some(compat) => {
+                    let Some((version, allowed)) = NodeDto::compat_fields(compat) else {
+                        return Response::error(
+                            ErrorCode::InvalidValue,
+                            format!("unknown compat schema: {compat}"),
+                        );
+                    };
+                    let fields = match fields {
+                        Some(fields) => fields
+                            .into_iter()
+                            .filter(|f| allowed.contains(&f.as_str()))
+                            .collect(),
+                        None => allowed.iter().map(|f| f.to_string()).collect(),
+                    };
+                    (version, Some(fields))
+                }
+            };
+            let selected: Vec<&BarNode> = match (id, name, parent, display) {
+                (Some(id), ..) => state.node_ref_by_id(id).into_iter().collect(),
+                (None, Some(name), ..) => state.node_refs().filter(|n| n.name == name).collect(),
+                (None, None, Some(parent), display) => state
+                    .children_refs(&parent)
                     .into_iter()
-                    .filter(|n| n.name == name)
-                    .map(Into::into)
+                    .filter(|n| display.is_none_or(|d| n.display == d))
                     .collect(),
-                (None, Some(display)) => state
-                    .get_nodes_for_display(display)
-                    .into_iter()
-                    .map(Into::into)
+                (None, None, None, Some(display)) => state
+                    .nodes_for_display(display)
+                    .unwrap_or_default()
+                    .iter()
                     .collect(),
-                (None, None) => state.get_nodes().into_iter().map(Into::into).collect(),
+                (None, None, None, None) => state.node_refs().collect(),
             };
-            Response::QueryResult { nodes }
+            let selected = match filter {
+                Some(filter) => match state.query_filtered(selected, &filter) {
+                    Ok(nodes) => nodes,
+                    Err(e) => return e.into(),
+                },
+                None => selected,
+            };
+            let selected = selected
+                .into_iter()
+                .filter(|n| not_name.as_ref() != Some(&n.name))
+                .filter(|n| not_parent.is_none() || n.parent != not_parent)
+                .filter(|n| not_type.as_deref() != Some(n.node_type.as_str()));
+            if stream {
+                // Only ids are kept; each node is built as it is written
+                return Response::Stream {
+                    state: state.clone(),
+                    ids: selected.map(|n| n.id).collect(),
+                    fields,
+                    schema_version,
+                };
+            }
+            let nodes =
+                selected.map(|n| NodeDto::from(state.effective_node(n)).for_schema(schema_version));
+            match fields {
+                Some(fields) => Response::Projection {
+                    schema_version,
+                    nodes: nodes.map(|n| Value::Object(n.project(&fields))).collect(),
+                },
+                None => Response::QueryResult {
+                    schema_version,
+                    nodes: nodes.collect(),
+                },
+            }
         }
         Command::Displays => {
-            let displays = get_displays()
-                .into_iter()
-                .map(|d| DisplayDto {
-                    id: d.id,
-                    name: d.name,
-                    is_main: d.is_main,
-                })
-                .collect();
+            let displays = get_displays().into_iter().map(DisplayDto::from).collect();
             Response::DisplayList { displays }
         }
+        Command::Exists { name } => {
+            let display = state.node_display(&name);
+            Response::Exists {
+                exists: display.is_some(),
+                display,
+            }
+        }
+        Command::Export {
+            name,
+            display,
+            only_user_set,
+        } => {
+            let mut selected: Vec<BarNode> = match display {
+                Some(display) => state.get_nodes_for_display(display),
+                None => state.get_nodes(),
+            };
+            if let Some(name) = name {
+                let Some(root) = selected.iter().find(|n| n.name == name).cloned() else {
+                    return Response::error(
+                        ErrorCode::NotFound,
+                        format!("node '{}' not found", name),
+                    );
+                };
+                let mut subtree = vec![root];
+                let mut i = 0;
+                while i < subtree.len() {
+                    let parent = subtree[i].clone();
+                    subtree.extend(
+                        selected
+                            .iter()
+                            .filter(|n| {
+                                n.parent.as_ref() == Some(&parent.name)
+                                    && n.display == parent.display
+                            })
+                            .cloned(),
+                    );
+                    i += 1;
+                }
+                selected = subtree;
+            }
+            Response::Export {
+                commands: export_commands(state, selected, only_user_set),
+            }
+        }
+        Command::Snapshot => Response::Snapshot {
+            snapshot: state.snapshot(),
+        },
+        Command::Stats => Response::Stats {
+            commands: get_metrics().snapshot(),
+        },
+        Command::Remove { name, .. } => match BarState::clone(state).remove_subtree(&name) {
+            Ok((_, names)) => Response::OkWithNames { names },
+            Err(e) => e.into(),
+        },
+        Command::Ping => Response::Pong {
+            protocol: PROTOCOL,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        Command::Count { display } => {
+            let mut per_display = state.node_counts();
+            if let Some(display) = display {
+                let count = per_display.get(&display).copied().unwrap_or(0);
+                per_display = BTreeMap::from([(display, count)]);
+            }
+            Response::Count {
+                total: per_display.values().sum(),
+                per_display,
+            }
+        }
+        Command::ListStyles => Response::Styles {
+            styles: state.template_properties(),
+        },
+        Command::Schema => Response::Schema {
+            properties: PROPERTY_KEYS
+                .iter()
+                .map(|&key| PropertySchema {
+                    key,
+                    value_type: property_type(key),
+                    node_types: property_node_types(key)
+                        .iter()
+                        .map(NodeType::as_str)
+                        .collect(),
+                })
+                .collect(),
+            node_types: NODE_TYPES.to_vec(),
+        },
+        Command::GetDisplayDefaults { display } => {
+            let mut defaults = state.display_defaults();
+            if let Some(display) = display {
+                defaults.retain(|d, _| *d == display);
+            }
+            Response::DisplayDefaults { defaults }
+        }
+        _ => unreachable!("mutating command routed to read"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run(state: &mut BarState, command: Value) -> (Response, Vec<StateChangeEvent>) {
+        execute(serde_json::from_value(command).unwrap(), state)
+    }
+
+    fn node(state: &BarState, name: &str) -> Option<BarNode> {
+        state.get_node(name)
+    }
+
+    #[test]
+    fn clear_emits_single_cleared_event() {
+        let mut state = BarState::default();
+        for i in 0..5 {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": format!("item{i}"), "display": 1 }),
+            );
+        }
+
+        let (response, events) = run(&mut state, json!({ "command": "clear", "display": 1 }));
+
+        assert!(matches!(response, Response::Ok));
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            StateChangeEvent::Cleared { display: Some(1) }
+        ));
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn refresh_sends_full_state_of_requested_displays() {
+        let mut state = BarState::default();
+        for (name, display) in [("a", 1), ("b", 1), ("c", 2)] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "display": display }),
+            );
+        }
+
+        let (response, events) = run(&mut state, json!({ "command": "refresh", "display": 2 }));
+        assert!(matches!(response, Response::Ok));
+        let [StateChangeEvent::FullRefresh { display: 2, nodes }] = events.as_slice() else {
+            panic!("expected one full refresh, got {events:?}");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, "c");
+
+        let (_, events) = run(&mut state, json!({ "command": "refresh" }));
+        let counts: BTreeMap<u32, usize> = events
+            .iter()
+            .map(|event| match event {
+                StateChangeEvent::FullRefresh { display, nodes } => (*display, nodes.len()),
+                other => panic!("expected full refresh, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(counts.len(), events.len());
+        assert_eq!(counts.get(&1), Some(&2));
+        assert_eq!(counts.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn query_fields_must_be_node_fields() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "battery", "label": "80%" }),
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "name": "battery", "fields": ["label", "lable"] }),
+        );
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({
+                "status": "error",
+                "code": "invalid_property",
+                "message": "unknown query field: lable",
+            })
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "name": "battery", "fields": ["label"] }),
+        );
+        let Response::Projection { nodes, .. } = response else {
+            panic!("expected projection");
+        };
+        assert_eq!(nodes, [json!({ "name": "battery", "label": "80%" })]);
+    }
+
+    #[test]
+    fn count_reports_nodes_per_display() {
+        let mut state = BarState::default();
+        for (name, display) in [("a", 1), ("b", 1), ("c", 2)] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "display": display }),
+            );
+        }
+        run(&mut state, json!({ "command": "remove", "name": "c" }));
+
+        let (response, _) = run(&mut state, json!({ "command": "count" }));
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({ "status": "count", "total": 2, "per_display": { "1": 2 } })
+        );
+        let (response, _) = run(&mut state, json!({ "command": "count", "display": 2 }));
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({ "status": "count", "total": 0, "per_display": { "2": 0 } })
+        );
+    }
+
+    #[test]
+    fn list_styles_and_schema_describe_what_set_accepts() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({
+                "command": "define_template",
+                "name": "pill",
+                "properties": { "border_width": "2" },
+            }),
+        );
+
+        let (response, events) = run(&mut state, json!({ "command": "list_styles" }));
+        assert!(events.is_empty());
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({ "status": "styles", "styles": { "pill": { "border_width": "2" } } })
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "schema" }));
+        let Response::Schema {
+            properties,
+            node_types,
+        } = response
+        else {
+            panic!("expected schema");
+        };
+        assert_eq!(properties.len(), PROPERTY_KEYS.len());
+        let property = |key: &str| properties.iter().find(|p| p.key == key).unwrap();
+        assert_eq!(property("padding").value_type, "float");
+        assert_eq!(property("padding").node_types, NODE_TYPES);
+        assert_eq!(property("align_items").value_type, "enum[start,center,end]");
+        assert_eq!(property("align_items").node_types, ["row", "column", "box"]);
+        assert_eq!(property("label_color").value_type, "color");
+        assert_eq!(property("underline").node_types, ["item"]);
+        assert_eq!(node_types, NODE_TYPES);
+    }
+
+    #[test]
+    fn display_defaults_round_trip() {
+        let mut state = BarState::default();
+        let (response, events) = run(
+            &mut state,
+            json!({
+                "command": "set_display_defaults",
+                "display": 2,
+                "properties": { "font_size": "11" },
+            }),
+        );
+        assert!(matches!(response, Response::Ok));
+        assert!(events.is_empty());
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "a", "display": 2 }),
+        );
+        assert_eq!(state.get_node("a").unwrap().font_size, Some(11.0));
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "get_display_defaults", "display": 1 }),
+        );
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({ "status": "display_defaults", "defaults": {} })
+        );
+        let (response, _) = run(&mut state, json!({ "command": "get_display_defaults" }));
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({ "status": "display_defaults", "defaults": { "2": { "font_size": "11" } } })
+        );
+    }
+
+    #[tokio::test]
+    async fn delayed_remove_keeps_node_until_ttl() {
+        let actor: &'static StateActor = Box::leak(Box::new(StateActor::spawn(
+            BarState::default(),
+            |_| {},
+            |_| {},
+        )));
+        let add = json!({ "command": "add", "name": "toast", "display": 1 });
+        dispatch(actor, serde_json::from_value(add).unwrap()).await;
+
+        let remove = json!({ "command": "remove", "name": "toast", "after_ms": 100 });
+        let response = dispatch(actor, serde_json::from_value(remove).unwrap()).await;
+        assert!(matches!(response, Response::Ok));
+
+        let nodes = actor.snapshot().get_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert!(nodes[0].removing);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(actor.snapshot().get_nodes().is_empty());
+    }
+
+    #[test]
+    fn add_after_refreshes_display_in_new_order() {
+        let mut state = BarState::default();
+        for name in ["a", "b"] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "display": 1 }),
+            );
+        }
+
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "add", "name": "c", "after": "a", "display": 1 }),
+        );
+
+        assert!(matches!(response, Response::NodeResult { .. }));
+        let [StateChangeEvent::FullRefresh { display: 1, nodes }] = events.as_slice() else {
+            panic!("expected a single full refresh, got {events:?}");
+        };
+        let names: Vec<&str> = nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "b"]);
+    }
+
+    #[test]
+    fn add_rejects_conflicting_placement() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "a", "display": 1 }),
+        );
+        for placement in [
+            json!({ "after": "a", "before": "a" }),
+            json!({ "after": "a", "position": 3 }),
+            json!({ "before": "a", "position": 0 }),
+        ] {
+            let mut command = json!({ "command": "add", "name": "b", "display": 1 });
+            command
+                .as_object_mut()
+                .unwrap()
+                .extend(placement.as_object().unwrap().clone());
+            let (response, events) = run(&mut state, command);
+            let Response::Error { code, .. } = response else {
+                panic!("expected an error, got {response:?}");
+            };
+            assert_eq!(code, ErrorCode::InvalidCommand);
+            assert!(events.is_empty());
+        }
+        assert!(node(&state, "b").is_none());
+    }
+
+    #[test]
+    fn unknown_display_name_is_an_invalid_value_everywhere() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "a", "display": 1 }),
+        );
+        for command in [
+            json!({ "command": "add", "name": "b", "display": "Nowhere" }),
+            json!({ "command": "set", "name": "a", "properties": { "display": "Nowhere" } }),
+            json!({ "command": "query", "display": "Nowhere" }),
+        ] {
+            let (response, events) = run(&mut state, command);
+            let Response::Error { code, message } = response else {
+                panic!("expected an error, got {response:?}");
+            };
+            assert_eq!(code, ErrorCode::InvalidValue);
+            assert_eq!(message, "unknown display: Nowhere");
+            assert!(events.is_empty());
+        }
+    }
+
+    #[test]
+    fn query_reflects_image_set_at_creation() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({
+                "command": "add",
+                "name": "logo",
+                "image": "/tmp/logo.png",
+                "image_scale": 2.0,
+                "display": 1,
+            }),
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "name": "logo" }));
+        let result = serde_json::to_value(&response).unwrap();
+        assert_eq!(result["nodes"][0]["image"], "/tmp/logo.png");
+        assert_eq!(result["nodes"][0]["image_scale"], 2.0);
+    }
+
+    #[test]
+    fn exists_reports_display_of_present_nodes() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "display": 2 }),
+        );
+
+        let (response, events) = run(&mut state, json!({ "command": "exists", "name": "clock" }));
+        assert!(matches!(
+            response,
+            Response::Exists {
+                exists: true,
+                display: Some(2)
+            }
+        ));
+        assert!(events.is_empty());
+        let (response, _) = run(&mut state, json!({ "command": "exists", "name": "ghost" }));
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({ "status": "exists", "exists": false, "display": null })
+        );
+    }
+
+    #[test]
+    fn add_tree_creates_nested_nodes_in_one_refresh() {
+        let mut state = BarState::default();
+        let tree = json!({
+            "name": "status",
+            "node_type": "row",
+            "display": 1,
+            "children": [
+                {
+                    "name": "net",
+                    "node_type": "column",
+                    "children": [
+                        { "name": "up", "label": "1 KB/s" },
+                        { "name": "down", "label": "2 KB/s" },
+                    ],
+                },
+                { "name": "clock", "label": "12:00" },
+            ],
+        });
+        let (response, events) = run(&mut state, json!({ "command": "add_tree", "root": tree }));
+        let Response::OkWithNames { names } = response else {
+            panic!("expected names, got {response:?}");
+        };
+        assert_eq!(names, ["status", "net", "up", "down", "clock"]);
+        let [StateChangeEvent::FullRefresh { display: 1, nodes }] = events.as_slice() else {
+            panic!("expected one full refresh, got {events:?}");
+        };
+        assert_eq!(nodes.len(), 5);
+        let parent = |name: &str| node(&state, name).unwrap().parent;
+        assert_eq!(parent("status"), None);
+        assert_eq!(parent("net").as_deref(), Some("status"));
+        assert_eq!(parent("up").as_deref(), Some("net"));
+        assert_eq!(parent("clock").as_deref(), Some("status"));
+        assert_eq!(node(&state, "down").unwrap().display, 1);
+        assert!(node(&state, "up").unwrap().position < node(&state, "down").unwrap().position);
+
+        let clash = json!({
+            "name": "panel",
+            "node_type": "row",
+            "display": 1,
+            "children": [{ "name": "cpu" }, { "name": "clock" }],
+        });
+        let (response, events) = run(&mut state, json!({ "command": "add_tree", "root": clash }));
+        let Response::Error { code, message } = response else {
+            panic!("expected error, got {response:?}");
+        };
+        assert_eq!(code, ErrorCode::AlreadyExists);
+        assert!(message.starts_with("add_tree clock: "), "{message}");
+        assert!(events.is_empty());
+        assert!(node(&state, "panel").is_none());
+        assert!(node(&state, "cpu").is_none());
+
+        let repeated = json!({ "name": "a", "node_type": "row", "children": [{ "name": "a" }] });
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "add_tree", "root": repeated }),
+        );
+        assert!(
+            matches!(response, Response::Error { code: ErrorCode::AlreadyExists, message } if message == "add_tree a: name used twice in tree: a")
+        );
+        assert!(node(&state, "a").is_none());
+    }
+
+    #[test]
+    fn add_and_set_report_the_resulting_node() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "bar", "node_type": "row", "display": 2 }),
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "parent": "bar", "label": "12:00" }),
+        );
+        let result = serde_json::to_value(&response).unwrap();
+        assert_eq!(result["status"], "node_result");
+        assert_eq!(result["node"]["name"], "clock");
+        assert_eq!(result["node"]["display"], 2);
+        assert_eq!(result["node"]["display_explicit"], false);
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "set", "name": "clock", "properties": { "font_size": "+=2" } }),
+        );
+        let Response::NodeResult { node } = response else {
+            panic!("expected node result, got {response:?}");
+        };
+        assert_eq!(node.font_size, Some(15.0));
+        assert_eq!(node.label.as_deref(), Some("12:00"));
+    }
+
+    #[test]
+    fn setting_current_values_is_unchanged_and_silent() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "label": "12:00", "display": 1 }),
+        );
+
+        let set = |label: &str| json!({ "command": "set", "name": "clock", "properties": { "label": label } });
+        let (response, events) = run(&mut state, set("12:00"));
+        assert!(matches!(response, Response::Unchanged), "{response:?}");
+        assert!(events.is_empty());
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({ "status": "unchanged" })
+        );
+
+        let (response, events) = run(&mut state, set("12:01"));
+        assert!(matches!(response, Response::NodeResult { .. }));
+        assert!(matches!(
+            events.as_slice(),
+            [StateChangeEvent::NodeUpdated { .. }]
+        ));
+    }
+
+    #[test]
+    fn node_added_reports_index_among_siblings() {
+        let mut state = BarState::default();
+        for command in [
+            json!({ "command": "add", "name": "row", "node_type": "row", "display": 1 }),
+            json!({ "command": "add", "name": "other", "node_type": "row", "display": 1 }),
+            json!({ "command": "add", "name": "a", "parent": "row", "position": 0 }),
+            json!({ "command": "add", "name": "c", "parent": "row", "position": 2 }),
+            json!({ "command": "add", "name": "x", "parent": "other", "position": 0 }),
+        ] {
+            run(&mut state, command);
+        }
+
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "add", "name": "b", "parent": "row", "position": 1 }),
+        );
+        let [StateChangeEvent::NodeAdded { node, index, .. }] = events.as_slice() else {
+            panic!("expected node added, got {events:?}");
+        };
+        assert_eq!((node.name.as_str(), *index), ("b", 1));
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "add", "name": "d", "parent": "row" }),
+        );
+        let [StateChangeEvent::NodeAdded { index, .. }] = events.as_slice() else {
+            panic!("expected node added, got {events:?}");
+        };
+        assert_eq!(*index, 3);
+    }
+
+    #[test]
+    fn query_reports_display_explicit_through_export() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "pinned", "display": 2 }),
+        );
+        run(&mut state, json!({ "command": "add", "name": "floating" }));
+        let explicit = |state: &BarState, name: &str| {
+            let query = serde_json::from_value(json!({ "command": "query", "name": name }));
+            let state = Arc::new(state.clone());
+            let response = serde_json::to_value(read(query.unwrap(), &state)).unwrap();
+            response["nodes"][0]["display_explicit"].clone()
+        };
+        assert_eq!(explicit(&state, "pinned"), true);
+        assert_eq!(explicit(&state, "floating"), false);
+
+        let (response, _) = run(&mut state, json!({ "command": "export" }));
+        let Response::Export { commands } = response else {
+            panic!("expected export, got {response:?}");
+        };
+        let mut copy = BarState::default();
+        run(
+            &mut copy,
+            json!({ "command": "batch", "commands": commands }),
+        );
+        assert_eq!(explicit(&copy, "pinned"), true);
+        assert_eq!(explicit(&copy, "floating"), false);
+    }
+
+    #[test]
+    fn query_by_parent_distinguishes_childless_from_missing() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "row", "node_type": "row", "display": 1 }),
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "parent": "row" }));
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        assert!(nodes.is_empty());
+
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "parent": "row" }),
+        );
+        let (response, _) = run(&mut state, json!({ "command": "query", "parent": "row" }));
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        assert_eq!(nodes.len(), 1);
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "parent": "ghost" }));
+        let Response::Error { code, message } = response else {
+            panic!("expected error, got {response:?}");
+        };
+        assert_eq!(code, ErrorCode::NotFound);
+        assert_eq!(message, "parent 'ghost' not found");
+    }
+
+    #[test]
+    fn query_by_parent_orders_children_and_yields_to_name() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "row", "node_type": "row", "display": 1 }),
+        );
+        for name in ["b", "c"] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "parent": "row" }),
+            );
+        }
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "a", "parent": "row", "before": "b" }),
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "parent": "row" }));
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        let names: Vec<&str> = nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "name": "row", "parent": "ghost" }),
+        );
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, "row");
+    }
+
+    #[test]
+    fn set_with_old_reports_prior_label() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "label": "12:00", "display": 1 }),
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({
+                "command": "set",
+                "name": "clock",
+                "properties": { "label": "12:01" },
+                "with_old": true,
+            }),
+        );
+
+        let result = serde_json::to_value(&response).unwrap();
+        assert_eq!(result["status"], "ok");
+        assert_eq!(result["previous"], json!({ "label": "12:00" }));
+    }
+
+    #[test]
+    fn compat_v1_query_omits_later_fields() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "label": "12:00", "display": 1 }),
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "query" }));
+        let current = serde_json::to_value(&response).unwrap();
+        assert_eq!(current["schema_version"], QUERY_SCHEMA_VERSION);
+        assert!(current["nodes"][0].get("id").is_some());
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "compat": "v1" }));
+        let v1 = serde_json::to_value(&response).unwrap();
+        assert_eq!(v1["schema_version"], 1);
+        let node = v1["nodes"][0].as_object().unwrap();
+        assert_eq!(node["label"], "12:00");
+        for field in [
+            "id",
+            "template",
+            "removing",
+            "on_click_cwd",
+            "image_content_mode",
+        ] {
+            assert!(!node.contains_key(field), "{field} should be omitted");
+        }
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "compat": "v0" }));
+        assert!(matches!(response, Response::Error { .. }));
+    }
+
+    fn updated_names(events: &[StateChangeEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                StateChangeEvent::NodeUpdated { node, .. } => Some(node.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reparent_reflows_old_and_new_containers() {
+        let mut state = BarState::default();
+        for name in ["left", "right"] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "node_type": "row", "display": 1 }),
+            );
+        }
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "parent": "left", "display": 1 }),
+        );
+
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "set", "name": "clock", "properties": { "parent": "right" } }),
+        );
+        assert_eq!(updated_names(&events), vec!["clock", "left", "right"]);
+
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "move", "name": "clock", "parent": "left" }),
+        );
+        assert_eq!(updated_names(&events), vec!["clock", "right", "left"]);
+
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "set", "name": "clock", "properties": { "label": "12:00" } }),
+        );
+        assert_eq!(updated_names(&events), vec!["clock"]);
+    }
+
+    #[test]
+    fn duplicate_reports_created_names_and_refreshes_target_display() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "cpu", "node_type": "row", "display": 1 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "cpu_icon", "parent": "cpu", "display": 1 }),
+        );
+
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "duplicate", "name": "cpu", "new_name": "mem", "new_display": 2 }),
+        );
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            value,
+            json!({ "status": "ok", "names": ["mem", "mem_icon"] })
+        );
+        let [StateChangeEvent::FullRefresh { display: 2, nodes }] = events.as_slice() else {
+            panic!("expected a full refresh of display 2, got {events:?}");
+        };
+        assert_eq!(nodes.len(), 2);
+    }
+
+    #[test]
+    fn rename_refreshes_display_with_children_repointed() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "row", "node_type": "row", "display": 1 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "parent": "row", "display": 1 }),
+        );
+
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "rename", "from": "row", "to": "right" }),
+        );
+        assert!(matches!(response, Response::Ok));
+        let [StateChangeEvent::FullRefresh { display: 1, nodes }] = events.as_slice() else {
+            panic!("expected one full refresh, got {events:?}");
+        };
+        let clock = nodes.iter().find(|n| n.name == "clock").unwrap();
+        assert_eq!(clock.parent.as_deref(), Some("right"));
+        assert!(nodes.iter().all(|n| n.name != "row"));
+
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "rename", "from": "clock", "to": "right" }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+        assert!(events.is_empty());
+    }
+
+    fn queried_names(response: Response) -> Vec<String> {
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        let mut names: Vec<String> = nodes.into_iter().map(|n| n.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn query_negation_filters_combine_with_positive_ones() {
+        let mut state = BarState::default();
+        for (name, display) in [("clock", 1), ("wifi", 1), ("battery", 1), ("other", 2)] {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": name, "display": display }),
+            );
+        }
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "row", "node_type": "row", "display": 1 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "cpu", "parent": "row", "display": 1 }),
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "display": 1, "not_name": "clock" }),
+        );
+        assert_eq!(queried_names(response), ["battery", "cpu", "row", "wifi"]);
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "display": 1, "not_name": "clock", "not_type": "row", "not_parent": "row" }),
+        );
+        assert_eq!(queried_names(response), ["battery", "wifi"]);
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "not_type": "widget" }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn export_only_user_set_keeps_shorthands() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "cpu", "padding": 8, "label": "CPU" }),
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "export", "name": "cpu", "only_user_set": true }),
+        );
+        let Response::Export { commands } = response else {
+            panic!("expected export, got {response:?}");
+        };
+        assert_eq!(
+            commands,
+            vec![
+                json!({ "command": "add", "name": "cpu", "node_type": "item" }),
+                json!({ "command": "set", "name": "cpu", "properties": { "label": "CPU", "padding": "8" } }),
+            ]
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "export", "name": "cpu" }));
+        let Response::Export { commands } = response else {
+            panic!("expected export, got {response:?}");
+        };
+        let properties = commands[1]["properties"].as_object().unwrap();
+        assert_eq!(properties["padding_left"], "8");
+        assert!(!properties.contains_key("padding"));
+    }
+
+    #[test]
+    fn export_replays_into_an_equal_bar() {
+        let mut state = BarState::default();
+        for command in [
+            json!({ "command": "add", "name": "right", "node_type": "row", "display": 1, "gap": 4 }),
+            json!({ "command": "add", "name": "wifi", "parent": "right", "icon": "wifi", "label": "自宅" }),
+            json!({ "command": "add", "name": "clock", "parent": "right", "position": 0, "margin": 2 }),
+            json!({ "command": "set", "name": "clock", "properties": { "margin_left": "6", "hidden": "true" } }),
+        ] {
+            run(&mut state, command);
+        }
+
+        for only_user_set in [false, true] {
+            let (response, _) = run(
+                &mut state,
+                json!({ "command": "export", "only_user_set": only_user_set }),
+            );
+            let Response::Export { commands } = response else {
+                panic!("expected export, got {response:?}");
+            };
+            let mut copy = BarState::default();
+            let (response, _) = run(
+                &mut copy,
+                json!({ "command": "batch", "commands": commands }),
+            );
+            assert!(matches!(response, Response::BatchResult { .. }));
+
+            let snapshot = |state: &BarState| {
+                let mut nodes: Vec<Value> = state
+                    .get_nodes()
+                    .into_iter()
+                    .map(|n| {
+                        let mut value = serde_json::to_value(NodeDto::from(n)).unwrap();
+                        value.as_object_mut().unwrap().remove("id");
+                        value
+                    })
+                    .collect();
+                nodes.sort_by_key(|n| n["name"].as_str().unwrap().to_string());
+                nodes
+            };
+            assert_eq!(
+                snapshot(&copy),
+                snapshot(&state),
+                "only_user_set: {only_user_set}"
+            );
+        }
+    }
+
+    #[test]
+    fn batch_applies_all_commands_and_their_events() {
+        let mut state = BarState::default();
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "batch", "commands": [
+                { "command": "add", "name": "a", "display": 1 },
+                { "command": "add", "name": "b", "display": 1 },
+                { "command": "set", "name": "a", "properties": { "label": "A" } },
+            ] }),
+        );
+        let Response::BatchResult { results } = response else {
+            panic!("expected batch result, got {response:?}");
+        };
+        assert_eq!(results.len(), 3);
+        assert_eq!(events.len(), 3);
+        assert_eq!(node(&state, "a").unwrap().label.as_deref(), Some("A"));
+        assert!(node(&state, "b").is_some());
+    }
+
+    #[test]
+    fn batched_add_inherits_display_from_parent() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "right", "node_type": "row", "display": 2 }),
+        );
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "batch", "commands": [
+                { "command": "add", "name": "clock", "parent": "right" },
+                { "command": "add", "name": "inner", "node_type": "row", "parent": "right" },
+                { "command": "add", "name": "wifi", "parent": "inner" },
+            ] }),
+        );
+        assert!(matches!(response, Response::BatchResult { .. }));
+        for name in ["clock", "inner", "wifi"] {
+            assert_eq!(node(&state, name).unwrap().display, 2, "{name}");
+            assert!(!node(&state, name).unwrap().display_explicit, "{name}");
+        }
+    }
+
+    #[test]
+    fn failed_batch_rolls_back_and_emits_nothing() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "existing", "display": 1 }),
+        );
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "batch", "commands": [
+                { "command": "add", "name": "new", "display": 1 },
+                { "command": "set", "name": "existing", "properties": { "label": "changed" } },
+                { "command": "remove", "name": "missing" },
+            ] }),
+        );
+        let Response::Error { code, message } = response else {
+            panic!("expected error, got {response:?}");
+        };
+        assert_eq!(code, ErrorCode::NotFound);
+        assert!(message.starts_with("batch command 2:"), "{message}");
+        assert!(events.is_empty());
+        assert!(node(&state, "new").is_none());
+        assert_eq!(node(&state, "existing").unwrap().label, None);
+    }
+
+    #[test]
+    fn batch_rejects_reads_and_nested_batches() {
+        let mut state = BarState::default();
+        for inner in [
+            json!({ "command": "query" }),
+            json!({ "command": "batch", "commands": [] }),
+            json!({ "command": "remove", "name": "x", "after_ms": 10 }),
+            json!({ "command": "reload_init" }),
+            json!({ "command": "shutdown" }),
+        ] {
+            let (response, _) = run(
+                &mut state,
+                json!({ "command": "batch", "commands": [inner] }),
+            );
+            assert!(matches!(response, Response::Error { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_removes_the_socket_so_it_can_be_bound_again() {
+        let path =
+            std::env::temp_dir().join(format!("ranma_test_{}_shutdown.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { super::run(&path).await.map_err(|e| e.to_string()) }
+        });
+
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"{\"command\":\"shutdown\"}\n")
+            .await
+            .unwrap();
+        let answer = BufReader::new(reader).lines().next_line().await.unwrap();
+        assert_eq!(answer.as_deref(), Some("{\"status\":\"ok\"}"));
+
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+        drop(UnixListener::bind(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn metrics_count_handled_commands() {
+        let actor: &'static StateActor = Box::leak(Box::new(StateActor::spawn(
+            BarState::default(),
+            |_| {},
+            |_| {},
+        )));
+        let metrics = Metrics::default();
+        for i in 0..5 {
+            let add = json!({ "command": "add", "name": format!("item{i}"), "display": 1 });
+            handle_command(actor, &metrics, serde_json::from_value(add).unwrap()).await;
+        }
+        let query = json!({ "command": "query" });
+        handle_command(actor, &metrics, serde_json::from_value(query).unwrap()).await;
+
+        let stats = metrics.snapshot();
+        let add = &stats["add"];
+        assert_eq!(add.count, 5);
+        assert!(add.max_us <= add.total_us);
+        assert!(add.total_us < 5_000_000);
+        assert_eq!(stats["query"].count, 1);
+        assert!(!stats.contains_key("set"));
+    }
+
+    #[tokio::test]
+    async fn streamed_query_writes_one_node_per_line() {
+        let mut state = BarState::default();
+        for i in 0..3 {
+            run(
+                &mut state,
+                json!({ "command": "add", "name": format!("item{i}"), "display": 1 }),
+            );
+        }
+        let (response, _) = run(&mut state, json!({ "command": "query", "stream": true }));
+        let Response::Stream { ids, .. } = &response else {
+            panic!("expected stream, got {response:?}");
+        };
+        assert_eq!(ids.len(), 3);
+
+        let mut out = Vec::new();
+        write_response(&mut out, &response).await.unwrap();
+
+        let mut count = 0;
+        for line in String::from_utf8(out).unwrap().lines() {
+            let value: Value = serde_json::from_str(line).unwrap();
+            if value["status"] == "stream_end" {
+                assert_eq!(value["count"], 3);
+                break;
+            }
+            assert!(value["name"].is_string());
+            count += 1;
+        }
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn add_accepts_relative_widths() {
+        let mut state = BarState::default();
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "add", "name": "spacer", "width": "fill", "height": 20, "display": 1 }),
+        );
+        assert!(
+            matches!(response, Response::NodeResult { .. }),
+            "{response:?}"
+        );
+        let (response, _) = run(&mut state, json!({ "command": "query", "name": "spacer" }));
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["nodes"][0]["width"], "fill");
+        assert_eq!(value["nodes"][0]["height"], 20.0);
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "query", "name": "spacer", "compat": "v2" }),
+        );
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["schema_version"], 2);
+        assert_eq!(value["nodes"][0]["width"], Value::Null);
+        assert_eq!(value["nodes"][0]["height"], 20.0);
+
+        let bad = json!({ "command": "add", "name": "bad", "width": "150%" });
+        let err = serde_json::from_value::<Command>(bad).unwrap_err();
+        assert!(err.to_string().contains("out of range: 150%"));
+    }
+
+    #[test]
+    fn reset_emits_node_updated() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "background_color": "#000000", "display": 1 }),
+        );
+        let (response, events) = run(&mut state, json!({ "command": "reset", "name": "clock" }));
+        assert!(matches!(response, Response::Ok));
+        let [StateChangeEvent::NodeUpdated { display: 1, node }] = events.as_slice() else {
+            panic!("expected one node update, got {events:?}");
+        };
+        assert_eq!(node.style.background_color, None);
+
+        let (response, events) = run(&mut state, json!({ "command": "reset", "name": "ghost" }));
+        assert!(matches!(response, Response::Error { .. }));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn set_var_updates_referencing_nodes() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "set_var", "name": "accent", "value": "#FF8000" }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "label_color": "$accent", "display": 1 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "plain", "display": 1 }),
+        );
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "set_var", "name": "accent", "value": "#00FF00" }),
+        );
+        assert!(matches!(response, Response::Ok));
+        let [StateChangeEvent::NodeUpdated { display: 1, node }] = events.as_slice() else {
+            panic!("expected one node update, got {events:?}");
+        };
+        assert_eq!(node.name, "clock");
+        assert_eq!(node.label_color.as_deref(), Some("#00FF00"));
+    }
+
+    #[test]
+    fn remove_dry_run_lists_cascade_without_removing() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "outer", "node_type": "row", "display": 1 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "inner", "node_type": "column", "parent": "outer" }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "parent": "inner" }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "other", "display": 1 }),
+        );
+
+        let (response, events) = run(
+            &mut state,
+            json!({ "command": "remove", "name": "outer", "dry_run": true }),
+        );
+        let Response::OkWithNames { names } = response else {
+            panic!("expected names, got {response:?}");
+        };
+        assert_eq!(names, ["outer", "inner", "clock"]);
+        assert!(events.is_empty());
+        assert_eq!(state.get_nodes().len(), 4);
+        assert!(state.get_node("clock").is_some());
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "remove", "name": "ghost", "dry_run": true }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn add_with_repeated_idempotency_key_adds_once() {
+        let mut state = BarState::default();
+        let add = json!({ "command": "add", "name": "clock", "idempotency_key": "k1" });
+        let (first, events) = run(&mut state, add.clone());
+        assert!(matches!(first, Response::NodeResult { .. }));
+        assert_eq!(events.len(), 1);
+        let (second, events) = run(&mut state, add.clone());
+        assert_eq!(
+            serde_json::to_value(&second).unwrap(),
+            serde_json::to_value(&first).unwrap()
+        );
+        assert!(events.is_empty());
+        assert_eq!(state.get_nodes().len(), 1);
+        assert!(
+            !state
+                .user_set(state.get_node("clock").unwrap().id)
+                .contains_key("idempotency_key")
+        );
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "idempotency_key": "k2" }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+
+        let (response, _) = run(
+            &mut state,
+            json!({ "command": "batch", "commands": [
+                { "command": "add", "name": "cpu", "idempotency_key": "k3" },
+                { "command": "remove", "name": "ghost" },
+            ] }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+        assert!(state.added_with_key("k3", ADD_KEY_TTL).is_none());
+
+        run(&mut state, json!({ "command": "remove", "name": "clock" }));
+        let (response, events) = run(&mut state, add);
+        let Response::Error { code, .. } = response else {
+            panic!("expected an error, got {response:?}");
+        };
+        assert_eq!(code, ErrorCode::NotFound);
+        assert!(events.is_empty());
+        assert!(state.added_with_key("k1", Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn snapshot_restores_every_display_losslessly() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "label": "12:00", "display": 2 }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "set_var", "name": "accent", "value": "#ff0000" }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "set", "name": "clock", "properties": { "label_color": "$accent" } }),
+        );
+        let (response, _) = run(&mut state, json!({ "command": "snapshot" }));
+        let Response::Snapshot { snapshot } = response else {
+            panic!("expected snapshot, got {response:?}");
+        };
+
+        let mut restored = BarState::default();
+        run(
+            &mut restored,
+            json!({ "command": "add", "name": "old", "display": 1 }),
+        );
+        let (response, events) = run(
+            &mut restored,
+            json!({ "command": "restore", "snapshot": snapshot }),
+        );
+        assert!(matches!(response, Response::Ok));
+        let refreshed: Vec<(u32, usize)> = events
+            .iter()
+            .map(|e| match e {
+                StateChangeEvent::FullRefresh { display, nodes } => (*display, nodes.len()),
+                other => panic!("expected full refresh, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(refreshed, [(1, 0), (2, 1)]);
+
+        let clock = restored.get_node("clock").unwrap();
+        assert_eq!(clock.id, state.get_node("clock").unwrap().id);
+        assert_eq!(clock.label_color.as_deref(), Some("#FF0000"));
+        assert!(clock.display_explicit);
+        assert!(restored.get_node("old").is_none());
+        assert_eq!(restored.snapshot(), state.snapshot());
+
+        let (response, _) = run(
+            &mut restored,
+            json!({ "command": "restore", "snapshot": { "nodes": 3 } }),
+        );
+        assert!(matches!(response, Response::Error { .. }));
+        assert!(restored.get_node("clock").is_some());
+    }
+
+    #[test]
+    fn query_reports_template_style_with_node_overrides() {
+        let mut state = BarState::default();
+        run(
+            &mut state,
+            json!({ "command": "define_template", "name": "pill", "properties": {
+                "background_color": "#0A0A0F", "corner_radius": "8"
+            } }),
+        );
+        run(
+            &mut state,
+            json!({ "command": "add", "name": "clock", "template": "pill", "corner_radius": 4, "display": 1 }),
+        );
+
+        let (response, _) = run(&mut state, json!({ "command": "query", "name": "clock" }));
+        let Response::QueryResult { nodes, .. } = response else {
+            panic!("expected query result, got {response:?}");
+        };
+        assert_eq!(nodes[0].background_color.as_deref(), Some("#0A0A0F"));
+        assert_eq!(nodes[0].corner_radius, Some(4.0));
+
+        let (_, events) = run(
+            &mut state,
+            json!({ "command": "define_template", "name": "pill", "properties": {
+                "background_color": "#FFFFFF"
+            } }),
+        );
+        let [StateChangeEvent::NodeUpdated { node, .. }] = events.as_slice() else {
+            panic!("expected one node update, got {events:?}");
+        };
+        assert_eq!(node.style.background_color.as_deref(), Some("#FFFFFF"));
+    }
+
+    #[test]
+    fn ping_reports_protocol() {
+        let mut state = BarState::default();
+        let (response, events) = run(&mut state, json!({ "command": "ping" }));
+        let Response::Pong { protocol, .. } = response else {
+            panic!("expected pong, got {response:?}");
+        };
+        assert_eq!(protocol, PROTOCOL);
+        assert!(events.is_empty());
     }
 }
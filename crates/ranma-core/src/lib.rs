@@ -1,53 +1,118 @@
 uniffi::setup_scaffolding!();
 
+pub mod actor;
 pub mod bridge;
+pub mod config;
 pub mod ipc;
+pub mod persist;
 pub mod state;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, OnceLock};
 
 use parking_lot::Mutex;
 
-use bridge::{DisplayInfo, StateChangeEvent, StateChangeHandler};
+use actor::StateActor;
+use bridge::{DisplayInfo, HandlerSlot, RanmaError, StateChangeEvent, StateChangeHandler};
+use ipc::events::{EVENT_LOG_CAPACITY, EventLog};
+use ipc::metrics::Metrics;
+use persist::Persister;
 use state::{BarNode, BarState};
 
-static STATE: OnceLock<Arc<Mutex<BarState>>> = OnceLock::new();
-static HANDLER: OnceLock<Arc<dyn StateChangeHandler>> = OnceLock::new();
+static STATE: OnceLock<StateActor> = OnceLock::new();
+static HANDLER: OnceLock<HandlerSlot> = OnceLock::new();
 static DISPLAYS: OnceLock<Arc<Mutex<Vec<DisplayInfo>>>> = OnceLock::new();
-
-pub(crate) fn get_state() -> &'static Arc<Mutex<BarState>> {
-    STATE.get_or_init(|| Arc::new(Mutex::new(BarState::default())))
+static EVENTS: OnceLock<EventLog> = OnceLock::new();
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+static PERSISTER: OnceLock<Persister> = OnceLock::new();
+static INIT_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// The process-wide state actor the IPC server and the UniFFI exports share.
+/// Mutations submitted here reach the registered `StateChangeHandler`.
+pub fn get_state() -> &'static StateActor {
+    STATE.get_or_init(|| StateActor::spawn(BarState::default(), record, notify))
 }
 
 fn get_displays_store() -> &'static Arc<Mutex<Vec<DisplayInfo>>> {
     DISPLAYS.get_or_init(|| Arc::new(Mutex::new(Vec::new())))
 }
 
-pub fn notify(event: StateChangeEvent) {
-    if let Some(handler) = HANDLER.get() {
-        let _ = handler.on_state_change(event);
+fn get_handler() -> &'static HandlerSlot {
+    HANDLER.get_or_init(HandlerSlot::default)
+}
+
+pub(crate) fn get_events() -> &'static EventLog {
+    EVENTS.get_or_init(|| EventLog::new(EVENT_LOG_CAPACITY))
+}
+
+pub(crate) fn get_metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+fn record(event: &StateChangeEvent) {
+    get_events().record(event);
+    if let Some(persister) = PERSISTER.get() {
+        persister.mark_dirty();
     }
 }
 
+pub fn notify(event: StateChangeEvent) {
+    get_handler().deliver(event);
+}
+
+/// Replaces the current handler and delivers any events buffered while
+/// none was registered.
 #[uniffi::export]
 pub fn register_handler(handler: Arc<dyn StateChangeHandler>) {
-    let _ = HANDLER.set(handler);
+    get_handler().register(handler);
 }
 
 #[uniffi::export]
 pub fn start_server(socket_path: String) {
+    let _ = INIT_PATH.set(config::config_init_path(None).ok().flatten());
+    if let Some(state_path) = persist::state_path() {
+        // An init script builds the bar itself; restoring first would make
+        // its adds collide with the saved nodes
+        if startup_init_path().is_none() {
+            persist::restore(get_state(), &state_path);
+        }
+        let _ = PERSISTER.set(Persister::spawn(get_state(), state_path));
+    }
     let path = socket_path.clone();
     std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().expect("failed to create tokio runtime");
         rt.block_on(async {
-            if let Err(e) = ipc::server::run(Path::new(&path)).await {
-                eprintln!("server error: {e}");
+            match ipc::server::run(Path::new(&path)).await {
+                Ok(()) => {
+                    if let Some(persister) = PERSISTER.get() {
+                        persister.flush();
+                    }
+                    // The signal handlers replaced the default exit, so
+                    // quit here when there is no host to do it
+                    if !get_handler().shutdown() {
+                        std::process::exit(0);
+                    }
+                }
+                Err(e) => eprintln!("server error: {e}"),
             }
         });
     });
 }
 
+/// The init script to run on startup, if one is requested or installed.
+#[uniffi::export]
+pub fn init_script_path() -> Result<Option<String>, RanmaError> {
+    config::config_init_path(None)
+        .map(|path| path.map(|p| p.to_string_lossy().into_owned()))
+        .map_err(|message| RanmaError::General { message })
+}
+
+/// The init script the server found when it started, which `reload_init`
+/// reruns by default.
+pub(crate) fn startup_init_path() -> Option<PathBuf> {
+    INIT_PATH.get().cloned().flatten()
+}
+
 #[uniffi::export]
 pub fn set_displays(displays: Vec<DisplayInfo>) {
     let old_displays = {
@@ -69,16 +134,15 @@ pub fn set_displays(displays: Vec<DisplayInfo>) {
         return;
     }
 
-    let mut events = Vec::new();
-    {
-        let mut state = get_state().lock();
+    get_state().call_blocking(move |state| {
+        let mut events = Vec::new();
 
         for &old_display in &removed {
             for node in state.migrate_nodes(old_display, new_main) {
                 events.push(StateChangeEvent::NodeMoved {
                     old_display,
                     new_display: new_main,
-                    node,
+                    node: state.effective_node(&node),
                 });
             }
         }
@@ -93,15 +157,13 @@ pub fn set_displays(displays: Vec<DisplayInfo>) {
                 events.push(StateChangeEvent::NodeMoved {
                     old_display: display,
                     new_display: new_main,
-                    node,
+                    node: state.effective_node(&node),
                 });
             }
         }
-    }
 
-    for event in events {
-        notify(event);
-    }
+        ((), events)
+    });
 }
 
 #[uniffi::export]
@@ -111,12 +173,27 @@ pub fn get_displays() -> Vec<DisplayInfo> {
 
 #[uniffi::export]
 pub fn get_nodes() -> Vec<BarNode> {
-    get_state().lock().get_nodes()
+    let state = get_state().snapshot();
+    state
+        .get_nodes()
+        .iter()
+        .map(|n| state.effective_node(n))
+        .collect()
 }
 
 #[uniffi::export]
 pub fn get_nodes_for_display(display: u32) -> Vec<BarNode> {
-    get_state().lock().get_nodes_for_display(display)
+    let state = get_state().snapshot();
+    state
+        .get_nodes_for_display(display)
+        .iter()
+        .map(|n| state.effective_node(n))
+        .collect()
+}
+
+/// The display `value` names, as a numeric id or a connected display's name.
+pub fn resolve_display(value: &str) -> Option<u32> {
+    bridge::find_display(&get_displays_store().lock(), value)
 }
 
 pub(crate) fn main_display_id() -> u32 {
@@ -0,0 +1,143 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::actor::StateActor;
+use crate::bridge::StateChangeEvent;
+use crate::state::BarState;
+use crate::{get_displays, main_display_id};
+
+/// Overrides where the bar state is saved; set it empty to disable saving.
+pub const STATE_FILE_ENV: &str = "RANMA_STATE_FILE";
+
+/// How long to wait after a change before writing, so bursts of updates
+/// are saved once.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// `$RANMA_STATE_FILE`, else `$XDG_STATE_HOME/ranma/state.json`, else
+/// `~/.local/state/ranma/state.json`.
+pub fn state_path() -> Option<PathBuf> {
+    resolve_state_path(|key| std::env::var_os(key))
+}
+
+fn resolve_state_path(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    if let Some(path) = var(STATE_FILE_ENV) {
+        return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+    let state_home = match var("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(var("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("ranma/state.json"))
+}
+
+/// Replaces the actor's state with the one saved at `path` and refreshes
+/// every display it has nodes on. A missing or unreadable file is skipped.
+pub(crate) fn restore(actor: &StateActor, path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let loaded = match BarState::load_from(path) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("ignoring saved state: {e}");
+            return;
+        }
+    };
+    let known: Vec<u32> = get_displays().iter().map(|d| d.id).collect();
+    let main = main_display_id();
+    actor.call_blocking(move |state| {
+        *state = loaded;
+        if main != 0 {
+            for display in state.displays() {
+                if !known.contains(&display) {
+                    state.migrate_nodes(display, main);
+                }
+            }
+        }
+        let events = state
+            .displays()
+            .into_iter()
+            .map(|display| StateChangeEvent::FullRefresh {
+                display,
+                nodes: state
+                    .nodes_for_display(display)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|n| state.effective_node(n))
+                    .collect(),
+            })
+            .collect();
+        ((), events)
+    });
+}
+
+/// Saves the actor's latest snapshot to disk shortly after it changes.
+pub(crate) struct Persister {
+    dirty: mpsc::Sender<()>,
+    actor: &'static StateActor,
+    path: PathBuf,
+}
+
+impl Persister {
+    pub(crate) fn spawn(actor: &'static StateActor, path: PathBuf) -> Self {
+        let (dirty, changes) = mpsc::channel();
+        let save_path = path.clone();
+        std::thread::spawn(move || {
+            while changes.recv().is_ok() {
+                std::thread::sleep(SAVE_DELAY);
+                while changes.try_recv().is_ok() {}
+                if let Err(e) = actor.snapshot().save_to(&save_path) {
+                    eprintln!("failed to save state: {e}");
+                }
+            }
+        });
+        Persister { dirty, actor, path }
+    }
+
+    pub(crate) fn mark_dirty(&self) {
+        let _ = self.dirty.send(());
+    }
+
+    /// Saves right away, for changes still waiting out `SAVE_DELAY` when the
+    /// server stops.
+    pub(crate) fn flush(&self) {
+        if let Err(e) = self.actor.snapshot().save_to(&self.path) {
+            eprintln!("failed to save state: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn resolve(vars: &[(&str, &str)]) -> Option<PathBuf> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        resolve_state_path(|key| vars.get(key).map(OsString::from))
+    }
+
+    #[test]
+    fn state_path_precedence() {
+        let home = ("HOME", "/Users/me");
+        let xdg = ("XDG_STATE_HOME", "/tmp/state");
+        let file = (STATE_FILE_ENV, "/etc/ranma.json");
+        assert_eq!(
+            resolve(&[home]),
+            Some(PathBuf::from("/Users/me/.local/state/ranma/state.json"))
+        );
+        assert_eq!(
+            resolve(&[home, xdg]),
+            Some(PathBuf::from("/tmp/state/ranma/state.json"))
+        );
+        assert_eq!(
+            resolve(&[home, xdg, file]),
+            Some(PathBuf::from("/etc/ranma.json"))
+        );
+        assert_eq!(resolve(&[home, (STATE_FILE_ENV, "")]), None);
+        assert_eq!(resolve(&[]), None);
+    }
+}
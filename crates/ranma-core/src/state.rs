@@ -1,46 +1,317 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, uniffi::Enum)]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use unicode_width::UnicodeWidthStr;
+
+/// Font size the frontend renders when `font_size` is unset.
+pub const DEFAULT_FONT_SIZE: f32 = 13.0;
+
+/// The kind of failure an error response reports, so scripts can branch on
+/// it instead of on the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    AlreadyExists,
+    /// A key `set` doesn't take, or one this command can't set.
+    InvalidProperty,
+    /// A value that doesn't parse or is out of range.
+    InvalidValue,
+    Cycle,
+    /// A parent or target on another display than the node.
+    WrongDisplay,
+    /// A malformed command, or one that can't be used where it was sent.
+    InvalidCommand,
+    /// The request was fine but could not be carried out.
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl StateError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        StateError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: String) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+}
+
+/// Failures from value parsing and validation, which report a bare message.
+impl From<String> for StateError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorCode::InvalidValue, message)
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl PartialEq<&str> for StateError {
+    fn eq(&self, other: &&str) -> bool {
+        self.message == *other
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Enum)]
+#[serde(rename_all = "snake_case")]
 pub enum NodeType {
     Item,
     Row,
     Column,
     Box,
+    /// A bar filled to `BarNode::value` percent.
+    Progress,
+}
+
+impl NodeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeType::Item => "item",
+            NodeType::Row => "row",
+            NodeType::Column => "column",
+            NodeType::Box => "box",
+            NodeType::Progress => "progress",
+        }
+    }
+
+    /// Rows, columns and boxes hold children; items and progress bars don't.
+    pub fn is_container(&self) -> bool {
+        matches!(self, NodeType::Row | NodeType::Column | NodeType::Box)
+    }
+}
+
+/// A node's `width` or `height`. Written as points (`24`), a percentage of
+/// the parent (`50%`), or `fill` for the space the siblings leave.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum Dimension {
+    Points(f32),
+    Percent(f32),
+    Fill,
+}
+
+impl Dimension {
+    pub fn parse(key: &str, value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid {}: {}", key, value);
+        if value == "fill" {
+            return Ok(Dimension::Fill);
+        }
+        if let Some(percent) = value.strip_suffix('%') {
+            let percent: f32 = percent.parse().map_err(|_| invalid())?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(format!("{} out of range: {}", key, value));
+            }
+            return Ok(Dimension::Percent(percent));
+        }
+        value.parse().map(Dimension::Points).map_err(|_| invalid())
+    }
+
+    /// The fixed size, if this is one.
+    pub fn points(self) -> Option<f32> {
+        match self {
+            Dimension::Points(points) => Some(points),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dimension::Points(points) => write!(f, "{}", points),
+            Dimension::Percent(percent) => write!(f, "{}%", percent),
+            Dimension::Fill => f.write_str("fill"),
+        }
+    }
+}
+
+// Points stay plain numbers so existing state files and query consumers
+// keep working; percentages and `fill` are strings
+impl Serialize for Dimension {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Dimension::Points(points) => serializer.serialize_f32(*points),
+            other => serializer.collect_str(other),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Dimension {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Points(f32),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Points(points) => Ok(Dimension::Points(points)),
+            Raw::Text(text) => {
+                Dimension::parse("dimension", &text).map_err(serde::de::Error::custom)
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default, uniffi::Record)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, uniffi::Record)]
+#[serde(default)]
 pub struct NodeStyle {
     pub background_color: Option<String>,
+    /// 0.0 to 1.0, multiplied into the background color's alpha.
+    pub background_opacity: Option<f32>,
+    /// A linear gradient drawn instead of `background_color` when both
+    /// ends are set. The angle is in degrees, counterclockwise from
+    /// left-to-right.
+    pub background_gradient_start: Option<String>,
+    pub background_gradient_end: Option<String>,
+    pub background_gradient_angle: Option<f32>,
     pub border_color: Option<String>,
     pub border_width: Option<f32>,
     pub corner_radius: Option<f32>,
+    /// Per-corner radii; `corner_radius` sets all four, and a corner left
+    /// unset falls back to it.
+    pub corner_radius_top_left: Option<f32>,
+    pub corner_radius_top_right: Option<f32>,
+    pub corner_radius_bottom_left: Option<f32>,
+    pub corner_radius_bottom_right: Option<f32>,
     pub padding_left: Option<f32>,
     pub padding_right: Option<f32>,
     pub padding_top: Option<f32>,
     pub padding_bottom: Option<f32>,
     pub shadow_color: Option<String>,
     pub shadow_radius: Option<f32>,
-    pub width: Option<f32>,
-    pub height: Option<f32>,
+    pub shadow_offset_x: Option<f32>,
+    pub shadow_offset_y: Option<f32>,
+    pub width: Option<Dimension>,
+    pub height: Option<Dimension>,
+    /// Bounds on the laid-out width, so an item whose label changes length
+    /// can keep a steady size.
+    pub min_width: Option<f32>,
+    pub max_width: Option<f32>,
     pub gap: Option<f32>,
     pub margin_left: Option<f32>,
     pub margin_right: Option<f32>,
     pub margin_top: Option<f32>,
     pub margin_bottom: Option<f32>,
+    /// `left` or `right`; only honored on displays where `has_notch` is true.
     pub notch_align: Option<String>,
     pub align_items: Option<String>,
+    /// Overrides the parent's `align_items` for this node alone.
+    pub align_self: Option<String>,
     pub justify_content: Option<String>,
     pub hover_background_color: Option<String>,
     pub hover_label_color: Option<String>,
     pub hover_icon_color: Option<String>,
+    /// Drawn outside the node's box and, unlike the border, takes no space.
+    pub outline_color: Option<String>,
+    pub outline_width: Option<f32>,
+    /// Gap between the box and the outline; negative values draw it inside.
+    pub outline_offset: Option<f32>,
+    /// How the node is composited over what lies beneath it.
+    pub blend_mode: Option<String>,
+    /// Which end of an over-long label gives way to an ellipsis.
+    pub text_truncation: Option<String>,
+    /// Fill color of a progress node, and of the unfilled track behind it.
+    pub progress_color: Option<String>,
+    pub progress_track_color: Option<String>,
+}
+
+// Fills every field left unset on `$target` from `$base`
+macro_rules! inherit {
+    ($target:expr, $base:expr, $above:expr, [$($field:ident),* $(,)?]) => {
+        $(
+            if $target.$field.is_none() && $above.is_none_or(|above| above.$field.is_none()) {
+                $target.$field = $base.$field.clone();
+            }
+        )*
+    };
+}
+
+impl NodeStyle {
+    fn inherit(&mut self, base: &NodeStyle) {
+        self.inherit_below(base, None);
+    }
+
+    /// Like `inherit`, but skips fields `above` sets.
+    fn inherit_below(&mut self, base: &NodeStyle, above: Option<&NodeStyle>) {
+        inherit!(
+            self,
+            base,
+            above,
+            [
+                background_color,
+                background_opacity,
+                background_gradient_start,
+                background_gradient_end,
+                background_gradient_angle,
+                border_color,
+                border_width,
+                corner_radius,
+                corner_radius_top_left,
+                corner_radius_top_right,
+                corner_radius_bottom_left,
+                corner_radius_bottom_right,
+                padding_left,
+                padding_right,
+                padding_top,
+                padding_bottom,
+                shadow_color,
+                shadow_radius,
+                shadow_offset_x,
+                shadow_offset_y,
+                width,
+                height,
+                min_width,
+                max_width,
+                gap,
+                margin_left,
+                margin_right,
+                margin_top,
+                margin_bottom,
+                notch_align,
+                align_items,
+                align_self,
+                justify_content,
+                hover_background_color,
+                hover_label_color,
+                hover_icon_color,
+                outline_color,
+                outline_width,
+                outline_offset,
+                blend_mode,
+                text_truncation,
+                progress_color,
+                progress_track_color,
+            ]
+        );
+    }
 }
 
-#[derive(Debug, Clone, uniffi::Record)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
 pub struct BarNode {
+    /// Assigned by `BarState::add_node`; stays the same across renames.
+    pub id: u64,
     pub name: String,
     pub node_type: NodeType,
     pub parent: Option<String>,
     pub position: i32,
+    /// When false, `BarState::add_node` appends the node after its siblings.
+    pub position_explicit: bool,
     pub display: u32,
     pub display_explicit: bool,
     pub style: NodeStyle,
@@ -51,499 +322,5217 @@ pub struct BarNode {
     pub font_size: Option<f32>,
     pub font_weight: Option<String>,
     pub font_family: Option<String>,
+    /// Extra space between characters in points; negative tightens.
+    pub letter_spacing: Option<f32>,
+    /// Multiple of the font's natural line height.
+    pub line_height: Option<f32>,
+    /// One of `FONT_STYLES`.
+    pub font_style: Option<String>,
+    pub underline: Option<bool>,
+    pub strikethrough: Option<bool>,
     pub on_click: Option<String>,
+    /// Run instead of `on_click` for right and middle clicks, with the
+    /// same working directory and shell.
+    pub on_right_click: Option<String>,
+    pub on_middle_click: Option<String>,
+    pub on_click_cwd: Option<String>,
+    pub on_click_shell: Option<String>,
+    /// Prompt the native side shows for confirmation before running `on_click`.
+    pub confirm: Option<String>,
     pub image: Option<String>,
     pub image_scale: Option<f32>,
+    pub image_content_mode: Option<String>,
+    /// How full a progress node is, from 0 to 100.
+    pub value: Option<f32>,
+    pub template: Option<String>,
+    /// Not drawn, along with its descendants.
+    pub hidden: bool,
+    /// Drawn but ignores clicks.
+    pub disabled: bool,
+    /// Set while a delayed removal is pending.
+    pub removing: bool,
+}
+
+impl BarNode {
+    /// Fills unset style and content fields in from `base`.
+    fn inherit(&mut self, base: &BarNode) {
+        self.inherit_below(base, None);
+    }
+
+    /// Like `inherit`, but skips fields `above` sets, so they keep coming
+    /// from it.
+    fn inherit_below(&mut self, base: &BarNode, above: Option<&BarNode>) {
+        self.style
+            .inherit_below(&base.style, above.map(|above| &above.style));
+        inherit!(
+            self,
+            base,
+            above,
+            [
+                label,
+                label_color,
+                icon,
+                icon_color,
+                font_size,
+                font_weight,
+                font_family,
+                letter_spacing,
+                line_height,
+                font_style,
+                underline,
+                strikethrough,
+                on_click,
+                on_right_click,
+                on_middle_click,
+                on_click_cwd,
+                on_click_shell,
+                confirm,
+                image,
+                image_scale,
+                image_content_mode,
+            ]
+        );
+    }
+
+    pub fn new(name: impl Into<String>, node_type: NodeType) -> Self {
+        BarNode {
+            id: 0,
+            name: name.into(),
+            node_type,
+            parent: None,
+            position: 0,
+            position_explicit: false,
+            display: 0,
+            display_explicit: false,
+            style: NodeStyle::default(),
+            label: None,
+            label_color: None,
+            icon: None,
+            icon_color: None,
+            font_size: None,
+            font_weight: None,
+            font_family: None,
+            letter_spacing: None,
+            line_height: None,
+            font_style: None,
+            underline: None,
+            strikethrough: None,
+            on_click: None,
+            on_right_click: None,
+            on_middle_click: None,
+            on_click_cwd: None,
+            on_click_shell: None,
+            confirm: None,
+            image: None,
+            image_scale: None,
+            image_content_mode: None,
+            value: None,
+            template: None,
+            hidden: false,
+            disabled: false,
+            removing: false,
+        }
+    }
+
+    /// Column width of the label, see [`display_width`].
+    pub fn label_display_width(&self) -> Option<usize> {
+        self.label.as_deref().map(display_width)
+    }
+
+    /// Every color field, by property name.
+    fn color_fields(&mut self) -> [(&'static str, &mut Option<String>); 13] {
+        [
+            ("label_color", &mut self.label_color),
+            ("icon_color", &mut self.icon_color),
+            ("background_color", &mut self.style.background_color),
+            ("border_color", &mut self.style.border_color),
+            ("shadow_color", &mut self.style.shadow_color),
+            (
+                "hover_background_color",
+                &mut self.style.hover_background_color,
+            ),
+            ("hover_label_color", &mut self.style.hover_label_color),
+            ("hover_icon_color", &mut self.style.hover_icon_color),
+            ("outline_color", &mut self.style.outline_color),
+            (
+                "background_gradient_start",
+                &mut self.style.background_gradient_start,
+            ),
+            (
+                "background_gradient_end",
+                &mut self.style.background_gradient_end,
+            ),
+            ("progress_color", &mut self.style.progress_color),
+            ("progress_track_color", &mut self.style.progress_track_color),
+        ]
+    }
+
+    /// Current value of a settable property as `set` would accept it; unset
+    /// fields read as an empty string.
+    pub fn property_value(&self, key: &str) -> Option<String> {
+        fn text(value: &Option<String>) -> String {
+            value.clone().unwrap_or_default()
+        }
+        fn number(value: Option<f32>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+        fn flag(value: Option<bool>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+
+        let style = &self.style;
+        Some(match key {
+            "label" => text(&self.label),
+            "label_color" => text(&self.label_color),
+            "icon" => text(&self.icon),
+            "icon_color" => text(&self.icon_color),
+            "font_size" => number(self.font_size),
+            "font_weight" => text(&self.font_weight),
+            "font_family" => text(&self.font_family),
+            "letter_spacing" => number(self.letter_spacing),
+            "line_height" => number(self.line_height),
+            "font_style" => text(&self.font_style),
+            "underline" => flag(self.underline),
+            "strikethrough" => flag(self.strikethrough),
+            "parent" => text(&self.parent),
+            "position" => self.position.to_string(),
+            "display" if self.display_explicit => self.display.to_string(),
+            "display" => String::new(),
+            "background_color" => text(&style.background_color),
+            "background_opacity" => number(style.background_opacity),
+            "background_gradient_start" => text(&style.background_gradient_start),
+            "background_gradient_end" => text(&style.background_gradient_end),
+            "background_gradient_angle" => number(style.background_gradient_angle),
+            "border_color" => text(&style.border_color),
+            "border_width" => number(style.border_width),
+            "corner_radius" => number(style.corner_radius),
+            "corner_radius_top_left" => number(style.corner_radius_top_left),
+            "corner_radius_top_right" => number(style.corner_radius_top_right),
+            "corner_radius_bottom_left" => number(style.corner_radius_bottom_left),
+            "corner_radius_bottom_right" => number(style.corner_radius_bottom_right),
+            "padding_left" => number(style.padding_left),
+            "padding_right" => number(style.padding_right),
+            "padding_top" => number(style.padding_top),
+            "padding_bottom" => number(style.padding_bottom),
+            "shadow_color" => text(&style.shadow_color),
+            "shadow_radius" => number(style.shadow_radius),
+            "shadow_offset_x" => number(style.shadow_offset_x),
+            "shadow_offset_y" => number(style.shadow_offset_y),
+            "width" => style.width.map(|w| w.to_string()).unwrap_or_default(),
+            "height" => style.height.map(|h| h.to_string()).unwrap_or_default(),
+            "min_width" => number(style.min_width),
+            "max_width" => number(style.max_width),
+            "gap" => number(style.gap),
+            "margin_left" => number(style.margin_left),
+            "margin_right" => number(style.margin_right),
+            "margin_top" => number(style.margin_top),
+            "margin_bottom" => number(style.margin_bottom),
+            "notch_align" => text(&style.notch_align),
+            "align_items" => text(&style.align_items),
+            "align_self" => text(&style.align_self),
+            "justify_content" => text(&style.justify_content),
+            "hover_background_color" => text(&style.hover_background_color),
+            "hover_label_color" => text(&style.hover_label_color),
+            "hover_icon_color" => text(&style.hover_icon_color),
+            "outline_color" => text(&style.outline_color),
+            "outline_width" => number(style.outline_width),
+            "outline_offset" => number(style.outline_offset),
+            "blend_mode" => text(&style.blend_mode),
+            "text_truncation" => text(&style.text_truncation),
+            "progress_color" => text(&style.progress_color),
+            "progress_track_color" => text(&style.progress_track_color),
+            "on_click" => text(&self.on_click),
+            "on_right_click" => text(&self.on_right_click),
+            "on_middle_click" => text(&self.on_middle_click),
+            "on_click_cwd" => text(&self.on_click_cwd),
+            "on_click_shell" => text(&self.on_click_shell),
+            "confirm" => text(&self.confirm),
+            "image" => text(&self.image),
+            "image_scale" => number(self.image_scale),
+            "image_content_mode" => text(&self.image_content_mode),
+            "value" => number(self.value),
+            "template" => text(&self.template),
+            "hidden" => self.hidden.to_string(),
+            "disabled" => self.disabled.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// On-disk and `snapshot` form of a `BarState`.
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    nodes: Vec<BarNode>,
+    templates: HashMap<String, BarNode>,
+    #[serde(default)]
+    user_set: HashMap<u64, BTreeMap<String, String>>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    #[serde(default)]
+    var_refs: HashMap<u64, BTreeMap<String, String>>,
+    #[serde(default)]
+    display_defaults: HashMap<u32, BarNode>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct BarState {
     nodes: HashMap<u32, Vec<BarNode>>,
+    templates: HashMap<String, BarNode>,
+    ids: HashMap<u64, String>,
+    next_id: u64,
+    // node id -> token of the latest delayed removal scheduled for it
+    pending_removals: HashMap<u64, u64>,
+    next_removal_token: u64,
+    // node id -> properties as the user last set them, shorthands kept
+    user_set: HashMap<u64, BTreeMap<String, String>>,
+    // node name -> displays with a node of that name, in the order added
+    names: HashMap<String, Vec<u32>>,
+    variables: BTreeMap<String, String>,
+    // node id -> field -> variable the field was set from
+    var_refs: HashMap<u64, BTreeMap<String, String>>,
+    // idempotency key -> node the `add` carrying it created, and when
+    add_keys: HashMap<String, (u64, Instant)>,
+    // display -> properties new nodes there start with
+    display_defaults: HashMap<u32, BarNode>,
 }
 
 impl BarState {
-    pub fn add_node(&mut self, node: BarNode) -> Result<(), String> {
+    /// Adds `node` and returns the id assigned to it.
+    pub fn add_node(&mut self, mut node: BarNode) -> Result<u64, StateError> {
+        // The template's fields show through at draw time, so defaults only
+        // fill in what neither the node nor its template sets
+        if let Some(defaults) = self.display_defaults.get(&node.display) {
+            node.inherit_below(defaults, self.template_for(&node));
+        }
+        let refs = self.resolve_node_variables(&mut node)?;
+        for (key, field) in node.color_fields() {
+            if let Some(value) = field {
+                *field = Some(parse_color(key, value)?);
+            }
+        }
+        if let Some(value) = node.value {
+            node.value = Some(clamp_progress_value(value)?);
+        }
+        Self::validate_node(&node)?;
+        self.validate_template(node.template.as_deref())?;
+
         if let Some(ref parent_name) = node.parent {
-            let parent = self.find_node_ref(parent_name);
+            let parent = self
+                .nodes
+                .get(&node.display)
+                .and_then(|nodes| nodes.iter().find(|n| &n.name == parent_name))
+                .or_else(|| self.find_node_ref(parent_name));
             match parent {
+                Some(p) if p.display != node.display => {
+                    return Err(StateError::new(
+                        ErrorCode::WrongDisplay,
+                        format!(
+                            "parent '{}' is on display {}, but '{}' is on display {}",
+                            parent_name, p.display, node.name, node.display
+                        ),
+                    ));
+                }
                 Some(p) => {
-                    if matches!(p.node_type, NodeType::Item) {
-                        return Err(format!(
-                            "'{}' is an item and cannot have children",
-                            parent_name
+                    if !p.node_type.is_container() {
+                        return Err(StateError::new(
+                            ErrorCode::InvalidValue,
+                            childless_parent(parent_name, &p.node_type),
                         ));
                     }
                 }
-                None => return Err(format!("parent '{}' not found", parent_name)),
+                None => {
+                    return Err(StateError::not_found(format!(
+                        "parent '{}' not found",
+                        parent_name
+                    )));
+                }
+            }
+        }
+
+        // A node still fading out on this display gives way in place; a
+        // container replacement keeps its children
+        let fading = self
+            .nodes
+            .get(&node.display)
+            .and_then(|nodes| nodes.iter().position(|n| n.name == node.name && n.removing));
+        if let Some(pos) = fading {
+            let old = if node.node_type.is_container() {
+                let old = self.nodes.get_mut(&node.display).unwrap().remove(pos);
+                self.forget_node(&old);
+                self.index_remove(&old.name, old.display);
+                old
+            } else {
+                self.remove_subtree_at(node.display, pos).0
+            };
+            if !node.position_explicit {
+                node.position = old.position;
+                node.position_explicit = true;
             }
         }
 
         let display_nodes = self.nodes.entry(node.display).or_default();
         if display_nodes.iter().any(|n| n.name == node.name) {
-            return Err(format!(
-                "node '{}' already exists on display {}",
-                node.name, node.display
+            return Err(StateError::new(
+                ErrorCode::AlreadyExists,
+                format!(
+                    "node '{}' already exists on display {}",
+                    node.name, node.display
+                ),
             ));
         }
+        if !node.position_explicit {
+            node.position = display_nodes
+                .iter()
+                .filter(|n| n.parent == node.parent)
+                .map(|n| n.position + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        self.next_id += 1;
+        node.id = self.next_id;
+        let id = node.id;
+        let (name, display) = (node.name.clone(), node.display);
+        self.ids.insert(id, name.clone());
         display_nodes.push(node);
         display_nodes.sort_by_key(|n| n.position);
-        Ok(())
+        self.index_insert(&name, display);
+        if !refs.is_empty() {
+            self.var_refs.insert(id, refs);
+        }
+        Ok(id)
+    }
+
+    /// Adds `node` immediately after (or before) `sibling`, shifting the
+    /// siblings behind it back by one position.
+    pub fn add_node_beside(
+        &mut self,
+        mut node: BarNode,
+        sibling: &str,
+        after: bool,
+    ) -> Result<u64, StateError> {
+        let anchor = self
+            .find_node_ref(sibling)
+            .ok_or_else(|| StateError::not_found(format!("sibling '{}' not found", sibling)))?;
+        if anchor.parent != node.parent || anchor.display != node.display {
+            return Err(StateError::new(
+                ErrorCode::WrongDisplay,
+                format!(
+                    "'{}' is not a sibling of '{}' (different parent or display)",
+                    sibling, node.name
+                ),
+            ));
+        }
+        let position = anchor.position + i32::from(after);
+        let display = node.display;
+        let parent = node.parent.clone();
+        node.position = position;
+        node.position_explicit = true;
+        let id = self.add_node(node)?;
+
+        let nodes = self.nodes.get_mut(&display).unwrap();
+        for n in nodes.iter_mut() {
+            if n.id != id && n.parent == parent && n.position >= position {
+                n.position += 1;
+            }
+        }
+        nodes.sort_by_key(|n| n.position);
+        Ok(id)
+    }
+
+    pub fn remove_node(&mut self, name: &str) -> Result<BarNode, StateError> {
+        self.remove_subtree(name).map(|(node, _)| node)
+    }
+
+    /// Removes `name` and its descendants, returning the node along with
+    /// the names of everything removed, `name` first.
+    pub fn remove_subtree(&mut self, name: &str) -> Result<(BarNode, Vec<String>), StateError> {
+        let (display, pos) = self.find_node(name)?;
+        Ok(self.remove_subtree_at(display, pos))
     }
 
-    pub fn remove_node(&mut self, name: &str) -> Result<BarNode, String> {
-        for nodes in self.nodes.values_mut() {
-            if let Some(pos) = nodes.iter().position(|n| n.name == name) {
-                let node = nodes.remove(pos);
-                if !matches!(node.node_type, NodeType::Item) {
-                    // Recursively collect all descendant names (transitive closure)
-                    let mut removed_names: std::collections::HashSet<String> =
-                        std::collections::HashSet::new();
-                    removed_names.insert(name.to_string());
-                    loop {
-                        let mut changed = false;
-                        for n in nodes.iter() {
-                            if let Some(ref parent) = n.parent
-                                && removed_names.contains(parent.as_str())
-                                && !removed_names.contains(&n.name)
-                            {
-                                removed_names.insert(n.name.clone());
-                                changed = true;
-                            }
-                        }
-                        if !changed {
-                            break;
-                        }
+    fn remove_subtree_at(&mut self, display: u32, pos: usize) -> (BarNode, Vec<String>) {
+        let node = self.nodes.get_mut(&display).unwrap().remove(pos);
+        self.forget_node(&node);
+        let nodes = self.nodes.get_mut(&display).unwrap();
+        let mut removed = vec![node.name.clone()];
+        if node.node_type.is_container() {
+            // Recursively collect all descendant names (transitive closure)
+            let mut removed_names: std::collections::HashSet<String> =
+                std::collections::HashSet::new();
+            removed_names.insert(node.name.clone());
+            loop {
+                let mut changed = false;
+                for n in nodes.iter() {
+                    if let Some(ref parent) = n.parent
+                        && removed_names.contains(parent.as_str())
+                        && !removed_names.contains(&n.name)
+                    {
+                        removed_names.insert(n.name.clone());
+                        changed = true;
                     }
-                    nodes.retain(|n| !removed_names.contains(&n.name));
                 }
-                return Ok(node);
+                if !changed {
+                    break;
+                }
             }
+            nodes.retain(|n| {
+                let is_removed = removed_names.contains(&n.name);
+                if is_removed {
+                    self.ids.remove(&n.id);
+                    self.pending_removals.remove(&n.id);
+                    self.user_set.remove(&n.id);
+                    self.var_refs.remove(&n.id);
+                    removed.push(n.name.clone());
+                }
+                !is_removed
+            });
+        }
+        for name in &removed {
+            self.index_remove(name, display);
         }
-        Err(format!("node '{}' not found", name))
+        self.prune_empty_displays();
+        (node, removed)
     }
 
-    pub fn set_properties(
+    /// Drops everything kept about `node` outside the node list itself.
+    fn forget_node(&mut self, node: &BarNode) {
+        self.ids.remove(&node.id);
+        self.pending_removals.remove(&node.id);
+        self.user_set.remove(&node.id);
+        self.var_refs.remove(&node.id);
+    }
+
+    /// Marks a node as `removing` and returns it with the token that
+    /// `finish_removal` must present. Scheduling again supersedes the
+    /// earlier token.
+    pub fn mark_removing(&mut self, name: &str) -> Result<(BarNode, u64), StateError> {
+        let (display, idx) = self.find_node(name)?;
+        let node = &mut self.nodes.get_mut(&display).unwrap()[idx];
+        node.removing = true;
+        self.next_removal_token += 1;
+        self.pending_removals
+            .insert(node.id, self.next_removal_token);
+        Ok((node.clone(), self.next_removal_token))
+    }
+
+    /// Completes a delayed removal unless it was superseded or the node was
+    /// re-added in the meantime.
+    pub fn finish_removal(&mut self, id: u64, token: u64) -> Option<BarNode> {
+        if self.pending_removals.get(&id) != Some(&token) {
+            return None;
+        }
+        self.pending_removals.remove(&id);
+        let name = self.ids.get(&id)?.clone();
+        self.remove_node(&name).ok()
+    }
+
+    /// Id and token of the delayed removal pending for `name`, if any.
+    pub fn pending_removal(&self, name: &str) -> Option<(u64, u64)> {
+        let id = self.find_node_ref(name)?.id;
+        self.pending_removals.get(&id).map(|&token| (id, token))
+    }
+
+    /// Id of the node an `add` carrying `key` created within the last
+    /// `ttl`. Older keys are forgotten.
+    pub fn added_with_key(&mut self, key: &str, ttl: Duration) -> Option<u64> {
+        let now = Instant::now();
+        self.add_keys
+            .retain(|_, (_, added)| now.duration_since(*added) < ttl);
+        self.add_keys.get(key).map(|&(id, _)| id)
+    }
+
+    pub fn record_add_key(&mut self, key: String, id: u64) {
+        self.add_keys.insert(key, (id, Instant::now()));
+    }
+
+    /// Display of the node named `name`, without cloning it.
+    pub fn node_display(&self, name: &str) -> Option<u32> {
+        self.find_node_ref(name).map(|n| n.display)
+    }
+
+    pub fn is_removing(&self, name: &str, display: u32) -> bool {
+        self.nodes
+            .get(&display)
+            .is_some_and(|nodes| nodes.iter().any(|n| n.name == name && n.removing))
+    }
+
+    /// Removes every node on `display` (or on all displays when `None`) and
+    /// returns the displays that had nodes.
+    pub fn clear(&mut self, display: Option<u32>) -> Vec<u32> {
+        let removed: Vec<(u32, Vec<BarNode>)> = match display {
+            Some(display) => self
+                .nodes
+                .remove(&display)
+                .map(|nodes| (display, nodes))
+                .into_iter()
+                .collect(),
+            None => self.nodes.drain().collect(),
+        };
+        for (display, nodes) in &removed {
+            for node in nodes {
+                self.ids.remove(&node.id);
+                self.pending_removals.remove(&node.id);
+                self.user_set.remove(&node.id);
+                self.var_refs.remove(&node.id);
+                self.index_remove(&node.name, *display);
+            }
+        }
+        removed.into_iter().map(|(display, _)| display).collect()
+    }
+
+    /// Reparents a node on its display. Without a `position` the node is
+    /// appended after its new siblings.
+    pub fn move_node(
         &mut self,
         name: &str,
-        properties: &HashMap<String, String>,
-    ) -> Result<BarNode, String> {
-        // None = not specified, Some(None) = reset (empty string), Some(Some(id)) = explicit
-        let display_change: Option<Option<u32>> = properties
-            .get("display")
-            .map(|v| {
-                if v.is_empty() {
-                    Ok(None)
-                } else {
-                    v.parse::<u32>()
-                        .map(Some)
-                        .map_err(|_| format!("invalid display: {}", v))
-                }
-            })
-            .transpose()?;
+        parent: Option<&str>,
+        position: Option<i32>,
+    ) -> Result<BarNode, StateError> {
+        let (display, idx) = self.find_node(name)?;
+        if let Some(parent_name) = parent {
+            if parent_name == name {
+                return Err(StateError::new(
+                    ErrorCode::Cycle,
+                    format!("cannot move '{}' into itself", name),
+                ));
+            }
+            self.check_parent(name, parent_name, display)?;
+            self.check_parent_cycle(name, parent_name)?;
+        }
 
-        match display_change {
-            Some(target_opt) => {
-                let (explicit, target_display) = match target_opt {
-                    Some(id) => (true, id),
-                    None => (false, crate::main_display_id()),
-                };
-                let (current_display, idx) = self.find_node(name)?;
-                if target_display != current_display {
-                    let mut node = self.nodes.get_mut(&current_display).unwrap().remove(idx);
-                    node.display = target_display;
-                    node.display_explicit = explicit;
-                    Self::apply_properties(&mut node, properties)?;
-                    let display_nodes = self.nodes.entry(target_display).or_default();
-                    display_nodes.push(node.clone());
-                    display_nodes.sort_by_key(|n| n.position);
-                    return Ok(node);
-                }
-                let nodes = self.nodes.get_mut(&current_display).unwrap();
-                let node = &mut nodes[idx];
-                node.display_explicit = explicit;
-                Self::apply_properties(node, properties)?;
-                let updated = node.clone();
-                nodes.sort_by_key(|n| n.position);
-                Ok(updated)
+        let parent = parent.map(str::to_string);
+        let nodes = self.nodes.get_mut(&display).unwrap();
+        let position = position.unwrap_or_else(|| {
+            nodes
+                .iter()
+                .filter(|n| n.parent == parent && n.name != name)
+                .map(|n| n.position + 1)
+                .max()
+                .unwrap_or(0)
+        });
+        let node = &mut nodes[idx];
+        node.parent = parent;
+        node.position = position;
+        node.position_explicit = true;
+        let moved = node.clone();
+        nodes.sort_by_key(|n| n.position);
+        Ok(moved)
+    }
+
+    /// Gives the listed siblings positions `0..order.len()` in one step.
+    /// Siblings left out follow them, in their current order.
+    pub fn reorder(
+        &mut self,
+        parent: Option<&str>,
+        display: u32,
+        order: &[String],
+    ) -> Result<(), StateError> {
+        let nodes = self.nodes_for_display(display).unwrap_or_default();
+        let mut indices = Vec::with_capacity(order.len());
+        for name in order {
+            let idx = nodes.iter().position(|n| &n.name == name).ok_or_else(|| {
+                StateError::not_found(format!("node '{}' not found on display {}", name, display))
+            })?;
+            if indices.contains(&idx) {
+                return Err(StateError::new(
+                    ErrorCode::InvalidValue,
+                    format!("'{}' is listed more than once", name),
+                ));
             }
-            None => {
-                let (current_display, _idx) = self.find_node(name)?;
-                let nodes = self.nodes.get_mut(&current_display).unwrap();
-                let node = nodes.iter_mut().find(|n| n.name == name).unwrap();
-                Self::apply_properties(node, properties)?;
-                let updated = node.clone();
-                nodes.sort_by_key(|n| n.position);
-                Ok(updated)
+            if nodes[idx].parent.as_deref() != parent {
+                return Err(StateError::new(
+                    ErrorCode::WrongDisplay,
+                    match parent {
+                        Some(parent) => format!("'{}' is not a child of '{}'", name, parent),
+                        None => format!("'{}' is not a top-level node", name),
+                    },
+                ));
             }
+            indices.push(idx);
+        }
+        // `nodes` is sorted by position, so the rest keep their order
+        let rest: Vec<usize> = (0..nodes.len())
+            .filter(|idx| nodes[*idx].parent.as_deref() == parent && !indices.contains(idx))
+            .collect();
+        indices.extend(rest);
+        let Some(nodes) = self.nodes.get_mut(&display) else {
+            return Ok(());
+        };
+        for (position, idx) in indices.into_iter().enumerate() {
+            nodes[idx].position = position as i32;
+            nodes[idx].position_explicit = true;
         }
+        nodes.sort_by_key(|n| n.position);
+        Ok(())
     }
 
-    fn apply_properties(
-        node: &mut BarNode,
-        properties: &HashMap<String, String>,
-    ) -> Result<(), String> {
-        fn optional_str(value: &str) -> Option<String> {
-            if value.is_empty() {
-                None
-            } else {
-                Some(value.to_string())
+    /// Exchanges the positions of two siblings and returns them, `a` first.
+    pub fn swap_positions(&mut self, a: &str, b: &str) -> Result<(BarNode, BarNode), StateError> {
+        if a == b {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                format!("cannot swap '{}' with itself", a),
+            ));
+        }
+        let (display, idx_a) = self.find_node(a)?;
+        let (display_b, idx_b) = self.find_node(b)?;
+        let nodes = self.nodes.get_mut(&display).expect("found node's display");
+        if display != display_b || nodes[idx_a].parent != nodes[idx_b].parent {
+            return Err(StateError::new(
+                ErrorCode::WrongDisplay,
+                format!("'{}' and '{}' are not siblings", a, b),
+            ));
+        }
+        let position_a = nodes[idx_a].position;
+        nodes[idx_a].position = nodes[idx_b].position;
+        nodes[idx_b].position = position_a;
+        nodes[idx_a].position_explicit = true;
+        nodes[idx_b].position_explicit = true;
+        let swapped = (nodes[idx_a].clone(), nodes[idx_b].clone());
+        nodes.sort_by_key(|n| n.position);
+        Ok(swapped)
+    }
+
+    /// Renames a node, pointing its children at the new name. The node keeps
+    /// its id.
+    pub fn rename_node(&mut self, from: &str, to: &str) -> Result<BarNode, StateError> {
+        if to.is_empty() {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                "node name must not be empty",
+            ));
+        }
+        let (display, idx) = self.find_node(from)?;
+        let nodes = self.nodes.get_mut(&display).unwrap();
+        if nodes.iter().any(|n| n.name == to) {
+            return Err(StateError::new(
+                ErrorCode::AlreadyExists,
+                format!("node '{}' already exists on display {}", to, display),
+            ));
+        }
+        nodes[idx].name = to.to_string();
+        let renamed = nodes[idx].clone();
+        // Parents are always on the child's display
+        for node in nodes.iter_mut() {
+            if node.parent.as_deref() == Some(from) {
+                node.parent = Some(to.to_string());
             }
         }
+        self.ids.insert(renamed.id, to.to_string());
+        self.index_remove(from, display);
+        self.index_insert(to, display);
+        Ok(renamed)
+    }
 
-        fn parse_f32(key: &str, value: &str) -> Result<f32, String> {
-            value
-                .parse()
-                .map_err(|_| format!("invalid {}: {}", key, value))
+    /// Copies `name` and its descendants as `new_name`, on `new_display` if
+    /// given. A descendant whose name starts with `name` gets that prefix
+    /// swapped for `new_name`; any other is prefixed with `new_name_`.
+    /// Returns the created names, parents before children.
+    pub fn duplicate_subtree(
+        &mut self,
+        name: &str,
+        new_name: &str,
+        new_display: Option<u32>,
+    ) -> Result<Vec<String>, StateError> {
+        if new_name.is_empty() {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                "node name must not be empty",
+            ));
         }
+        let root = self
+            .find_node_ref(name)
+            .cloned()
+            .ok_or_else(|| StateError::not_found(format!("node '{}' not found", name)))?;
+        let source = self.nodes_for_display(root.display).unwrap_or_default();
+        let display = new_display.unwrap_or(root.display);
 
-        // Pass 1: apply shorthands (all → directional) so specifics can override
-        if let Some(v) = properties.get("padding") {
-            let val = Some(parse_f32("padding", v)?);
-            node.style.padding_left = val;
-            node.style.padding_right = val;
-            node.style.padding_top = val;
-            node.style.padding_bottom = val;
+        let mut copies = vec![root.clone()];
+        let mut i = 0;
+        while i < copies.len() {
+            let parent = copies[i].name.clone();
+            copies.extend(
+                source
+                    .iter()
+                    .filter(|n| n.parent.as_deref() == Some(parent.as_str()))
+                    .cloned(),
+            );
+            i += 1;
         }
-        if let Some(v) = properties.get("padding_horizontal") {
-            let val = Some(parse_f32("padding_horizontal", v)?);
-            node.style.padding_left = val;
-            node.style.padding_right = val;
+
+        let renamed = |old: &str| match old.strip_prefix(name) {
+            _ if old == name => new_name.to_string(),
+            Some(rest) => format!("{new_name}{rest}"),
+            None => format!("{new_name}_{old}"),
+        };
+        let mut names = Vec::with_capacity(copies.len());
+        for copy in &copies {
+            let new = renamed(&copy.name);
+            if names.contains(&new)
+                || self
+                    .nodes
+                    .get(&display)
+                    .is_some_and(|nodes| nodes.iter().any(|n| n.name == new))
+            {
+                return Err(StateError::new(
+                    ErrorCode::AlreadyExists,
+                    format!("node '{}' already exists on display {}", new, display),
+                ));
+            }
+            names.push(new);
         }
-        if let Some(v) = properties.get("padding_vertical") {
-            let val = Some(parse_f32("padding_vertical", v)?);
-            node.style.padding_top = val;
-            node.style.padding_bottom = val;
+
+        let root_parent = root.parent.filter(|parent| {
+            display == root.display
+                || self
+                    .nodes
+                    .get(&display)
+                    .is_some_and(|nodes| nodes.iter().any(|n| &n.name == parent))
+        });
+        for (index, copy) in copies.into_iter().enumerate() {
+            let is_root = index == 0;
+            let user_set = self.user_set.get(&copy.id).cloned();
+            let var_refs = self.var_refs.get(&copy.id).cloned();
+            let node = BarNode {
+                name: names[index].clone(),
+                parent: if is_root {
+                    root_parent.clone()
+                } else {
+                    copy.parent.as_deref().map(renamed)
+                },
+                position_explicit: !is_root,
+                display,
+                display_explicit: copy.display_explicit || new_display.is_some(),
+                removing: false,
+                ..copy
+            };
+            let id = self.add_node(node)?;
+            if let Some(user_set) = user_set {
+                self.user_set.insert(id, user_set);
+            }
+            if let Some(var_refs) = var_refs {
+                self.var_refs.insert(id, var_refs);
+            }
         }
-        if let Some(v) = properties.get("margin") {
-            let val = Some(parse_f32("margin", v)?);
-            node.style.margin_left = val;
-            node.style.margin_right = val;
-            node.style.margin_top = val;
-            node.style.margin_bottom = val;
+        Ok(names)
+    }
+
+    /// Where the node with `id` sits among its siblings (same parent, same
+    /// display) in layout order, so a view can be inserted without
+    /// re-sorting.
+    pub fn sibling_index(&self, id: u64) -> Option<u32> {
+        let (display, idx) = self.locate_id(id)?;
+        let nodes = &self.nodes[&display];
+        let parent = &nodes[idx].parent;
+        let before = nodes[..idx].iter().filter(|n| &n.parent == parent).count();
+        Some(before as u32)
+    }
+
+    pub fn get_node_by_id(&self, id: u64) -> Option<BarNode> {
+        self.node_ref_by_id(id).cloned()
+    }
+
+    pub(crate) fn node_ref_by_id(&self, id: u64) -> Option<&BarNode> {
+        let displays = self.names.get(self.ids.get(&id)?)?;
+        displays
+            .iter()
+            .filter_map(|display| self.nodes.get(display)?.iter().find(|n| n.id == id))
+            .next()
+    }
+
+    pub fn set_properties(
+        &mut self,
+        name: &str,
+        properties: &HashMap<String, String>,
+    ) -> Result<BarNode, StateError> {
+        let resolved = self.resolve_variables(properties)?;
+        let node = self.update_node(name, &resolved)?;
+        self.record_user_set(node.id, properties);
+        self.record_var_refs(node.id, properties);
+        Ok(node)
+    }
+
+    /// Defines `$name` as `value` and re-applies it to every field set
+    /// from it. Returns the nodes that changed; on error nothing changes.
+    pub fn set_variable(&mut self, name: &str, value: &str) -> Result<Vec<BarNode>, StateError> {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                format!("invalid variable name: {:?}", name),
+            ));
         }
-        if let Some(v) = properties.get("margin_horizontal") {
-            let val = Some(parse_f32("margin_horizontal", v)?);
-            node.style.margin_left = val;
-            node.style.margin_right = val;
+        if value.is_empty() {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                "variable value must not be empty",
+            ));
         }
-        if let Some(v) = properties.get("margin_vertical") {
-            let val = Some(parse_f32("margin_vertical", v)?);
-            node.style.margin_top = val;
-            node.style.margin_bottom = val;
+        let mut ids: Vec<u64> = self
+            .var_refs
+            .iter()
+            .filter(|(_, refs)| refs.values().any(|var| var == name))
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        let mut updates = Vec::with_capacity(ids.len());
+        for id in ids {
+            let properties: HashMap<String, String> = self.var_refs[&id]
+                .iter()
+                .filter(|(_, var)| *var == name)
+                .map(|(field, _)| (field.clone(), value.to_string()))
+                .collect();
+            let (display, idx) = self
+                .locate_id(id)
+                .expect("variable reference to a missing node");
+            let mut node = self.nodes[&display][idx].clone();
+            Self::apply_properties(&mut node, &properties)
+                .map_err(|e| format!("${} is used by '{}': {}", name, node.name, e))?;
+            updates.push((display, idx, node, properties));
+        }
+        self.variables.insert(name.to_string(), value.to_string());
+        let mut updated = Vec::with_capacity(updates.len());
+        for (display, idx, node, properties) in updates {
+            self.nodes.get_mut(&display).unwrap()[idx] = node.clone();
+            self.record_user_set(node.id, &properties);
+            updated.push(node);
         }
+        Ok(updated)
+    }
 
-        // Pass 2: apply specific properties (override shorthands)
+    /// `properties` with `$name` references replaced by their values.
+    fn resolve_variables(
+        &self,
+        properties: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, StateError> {
+        properties
+            .iter()
+            .map(|(key, value)| {
+                let value = match variable_ref(key, value) {
+                    Some(var) => self.variable(var)?,
+                    None => value.clone(),
+                };
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+
+    // `add` carries typed numbers, so only its color fields can hold a reference
+    fn resolve_node_variables(
+        &self,
+        node: &mut BarNode,
+    ) -> Result<BTreeMap<String, String>, StateError> {
+        let mut refs = BTreeMap::new();
+        for (key, field) in node.color_fields() {
+            let Some(var) = field.as_deref().and_then(|v| variable_ref(key, v)) else {
+                continue;
+            };
+            let var = var.to_string();
+            *field = Some(self.variable(&var)?);
+            refs.insert(key.to_string(), var);
+        }
+        Ok(refs)
+    }
+
+    fn variable(&self, name: &str) -> Result<String, StateError> {
+        self.variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| StateError::not_found(format!("undefined variable: ${}", name)))
+    }
+
+    fn record_var_refs(&mut self, id: u64, properties: &HashMap<String, String>) {
+        let refs = self.var_refs.entry(id).or_default();
+        for (key, value) in properties {
+            let var = variable_ref(key, value);
+            for field in covered_fields(key) {
+                match var {
+                    Some(var) => refs.insert(field.to_string(), var.to_string()),
+                    None => refs.remove(field),
+                };
+            }
+        }
+        if refs.is_empty() {
+            self.var_refs.remove(&id);
+        }
+    }
+
+    /// Returns properties of `name` to their defaults: the listed keys, or
+    /// with `None` the whole style along with the colors, fonts and image
+    /// sizing. Content, click actions and placement are kept.
+    pub fn reset_properties(
+        &mut self,
+        name: &str,
+        keys: Option<&[String]>,
+    ) -> Result<BarNode, StateError> {
+        let (display, idx) = self.find_node(name)?;
+        let mut node = self.nodes[&display][idx].clone();
+        let fields: Vec<&str> = match keys {
+            Some(keys) => keys.iter().flat_map(|key| covered_fields(key)).collect(),
+            None => STYLE_FIELDS
+                .iter()
+                .chain(&APPEARANCE_FIELDS)
+                .copied()
+                .collect(),
+        };
+        for field in &fields {
+            reset_field(&mut node, field)?;
+        }
+        self.nodes.get_mut(&display).unwrap()[idx] = node.clone();
+        self.forget_user_set(node.id, &fields);
+        if let Some(refs) = self.var_refs.get_mut(&node.id) {
+            refs.retain(|field, _| !fields.contains(&field.as_str()));
+            if refs.is_empty() {
+                self.var_refs.remove(&node.id);
+            }
+        }
+        Ok(node)
+    }
+
+    // A recorded shorthand that is only partly reset is replaced by the
+    // fields it still sets
+    fn forget_user_set(&mut self, id: u64, fields: &[&str]) {
+        let Some(recorded) = self.user_set.get_mut(&id) else {
+            return;
+        };
+        for (key, value) in recorded.clone() {
+            let covered = covered_fields(&key);
+            if !covered.iter().any(|f| fields.contains(f)) {
+                continue;
+            }
+            recorded.remove(&key);
+            for field in covered.into_iter().filter(|f| !fields.contains(f)) {
+                recorded.insert(field.to_string(), value.clone());
+            }
+        }
+        if recorded.is_empty() {
+            self.user_set.remove(&id);
+        }
+    }
+
+    /// Properties explicitly set on the node with `id`, as last written.
+    /// Shorthands such as `padding` are kept rather than expanded, and a
+    /// later shorthand replaces the fields it covers.
+    pub fn user_set(&self, id: u64) -> BTreeMap<String, String> {
+        self.user_set.get(&id).cloned().unwrap_or_default()
+    }
+
+    /// Notes `properties` as explicitly set on the node with `id`.
+    /// Toggles and `+=`/`-=` adjustments are recorded as the value they
+    /// produced; placement keys (`parent`, `position`, `display`) are not
+    /// recorded.
+    pub fn record_user_set(&mut self, id: u64, properties: &HashMap<String, String>) {
+        let Some(node) = self.get_node_by_id(id) else {
+            return;
+        };
+        let mut keys: Vec<&String> = properties.keys().collect();
+        keys.sort_by_key(|key| std::cmp::Reverse(covered_fields(key).len()));
+        let recorded = self.user_set.entry(id).or_default();
+        for key in keys {
+            let raw = &properties[key];
+            let (key, value) = match key.as_str() {
+                "parent" | "position" | "display" => continue,
+                "toggle_hidden" => ("hidden", node.property_value("hidden")),
+                "toggle_disabled" => ("disabled", node.property_value("disabled")),
+                key if raw.starts_with("+=") || raw.starts_with("-=") => {
+                    (key, node.property_value(key))
+                }
+                key if variable_ref(key, raw).is_some() => (
+                    key,
+                    variable_ref(key, raw)
+                        .and_then(|var| self.variables.get(var))
+                        .cloned(),
+                ),
+                key => (key, Some(raw.clone())),
+            };
+            let fields = covered_fields(key);
+            recorded.retain(|other, _| !covered_fields(other).iter().all(|f| fields.contains(f)));
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                recorded.insert(key.to_string(), value);
+            }
+        }
+        if recorded.is_empty() {
+            self.user_set.remove(&id);
+        }
+    }
+
+    fn update_node(
+        &mut self,
+        name: &str,
+        properties: &HashMap<String, String>,
+    ) -> Result<BarNode, StateError> {
+        if let Some(template) = properties.get("template")
+            && !template.is_empty()
+        {
+            self.validate_template(Some(template))?;
+        }
+        if let Some(parent) = properties.get("parent")
+            && !parent.is_empty()
+        {
+            self.check_parent_cycle(name, parent)?;
+        }
+
+        // None = not specified, Some(None) = reset (empty string), Some(Some(id)) = explicit
+        let display_change: Option<Option<u32>> = properties
+            .get("display")
+            .map(|v| {
+                if v.is_empty() {
+                    Ok(None)
+                } else {
+                    crate::resolve_display(v)
+                        .map(Some)
+                        .ok_or_else(|| format!("unknown display: {}", v))
+                }
+            })
+            .transpose()?;
+
+        // Changes go to a copy so a failing property leaves the node, and
+        // the display it is on, untouched
+        let (current_display, idx) = self.find_node(name)?;
+        let mut node = self.nodes[&current_display][idx].clone();
+        if let Some(target_opt) = display_change {
+            let (explicit, target_display) = match target_opt {
+                Some(id) => (true, id),
+                None => (false, crate::main_display_id()),
+            };
+            node.display = target_display;
+            node.display_explicit = explicit;
+        }
+        Self::apply_properties(&mut node, properties)?;
+        if node.display != current_display
+            && self
+                .nodes
+                .get(&node.display)
+                .is_some_and(|nodes| nodes.iter().any(|n| n.name == name))
+        {
+            return Err(StateError::new(
+                ErrorCode::AlreadyExists,
+                format!("node '{}' already exists on display {}", name, node.display),
+            ));
+        }
+        if let Some(parent) = node.parent.as_deref()
+            && (properties.contains_key("parent") || node.display != current_display)
+        {
+            self.check_parent(name, parent, node.display)?;
+        }
+
+        if node.display != current_display {
+            self.nodes.get_mut(&current_display).unwrap().remove(idx);
+            let display_nodes = self.nodes.entry(node.display).or_default();
+            display_nodes.push(node.clone());
+            display_nodes.sort_by_key(|n| n.position);
+            self.index_remove(name, current_display);
+            self.index_insert(name, node.display);
+            self.prune_empty_displays();
+        } else {
+            let nodes = self.nodes.get_mut(&current_display).unwrap();
+            nodes[idx] = node.clone();
+            nodes.sort_by_key(|n| n.position);
+        }
+        Ok(node)
+    }
+
+    /// Errors unless `parent` is a container on `display`, where `name` is.
+    fn check_parent(&self, name: &str, parent: &str, display: u32) -> Result<(), StateError> {
+        let on_display = self
+            .nodes
+            .get(&display)
+            .and_then(|nodes| nodes.iter().find(|n| n.name == parent));
+        let target = match on_display {
+            Some(target) => target,
+            None => {
+                return Err(match self.node_display(parent) {
+                    Some(other) => StateError::new(
+                        ErrorCode::WrongDisplay,
+                        format!(
+                            "parent '{}' is on display {}, but '{}' is on display {}",
+                            parent, other, name, display
+                        ),
+                    ),
+                    None => StateError::not_found(format!("parent '{}' not found", parent)),
+                });
+            }
+        };
+        if !target.node_type.is_container() {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                childless_parent(parent, &target.node_type),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Errors if making `parent` the parent of `name` would put `name` in
+    /// its own ancestor chain.
+    fn check_parent_cycle(&self, name: &str, parent: &str) -> Result<(), StateError> {
+        if parent == name {
+            return Err(StateError::new(
+                ErrorCode::Cycle,
+                format!("cannot set '{}' as its own parent", name),
+            ));
+        }
+        let mut chain = vec![name, parent];
+        let mut current = parent;
+        while let Some(next) = self
+            .find_node_ref(current)
+            .and_then(|n| n.parent.as_deref())
+        {
+            if chain[1..].contains(&next) {
+                break;
+            }
+            chain.push(next);
+            if next == name {
+                return Err(StateError::new(
+                    ErrorCode::Cycle,
+                    format!("would create a cycle: {}", chain.join(" -> ")),
+                ));
+            }
+            current = next;
+        }
+        Ok(())
+    }
+
+    /// Values the properties in `properties` have before `set_properties`
+    /// changes them, keyed so that setting them again undoes the change.
+    /// Shorthands and toggles are reported as the fields they affect.
+    pub fn previous_values(
+        &self,
+        name: &str,
+        properties: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, StateError> {
+        let node = self
+            .find_node_ref(name)
+            .ok_or_else(|| StateError::not_found(format!("node '{}' not found", name)))?;
+        let mut previous = HashMap::new();
+        for key in properties.keys() {
+            let key = key.as_str();
+            let affected = match key {
+                "toggle_hidden" => vec!["hidden"],
+                "toggle_disabled" => vec!["disabled"],
+                _ => covered_fields(key),
+            };
+            for field in affected {
+                if let Some(value) = node.property_value(field) {
+                    previous.insert(field.to_string(), value);
+                }
+            }
+        }
+        Ok(previous)
+    }
+
+    fn apply_properties(
+        node: &mut BarNode,
+        properties: &HashMap<String, String>,
+    ) -> Result<(), StateError> {
+        fn optional_str(value: &str) -> Option<String> {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            }
+        }
+
+        fn optional_color(key: &str, value: &str) -> Result<Option<String>, String> {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                parse_color(key, value).map(Some)
+            }
+        }
+
+        fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+            match value {
+                "true" => Ok(true),
+                "false" | "" => Ok(false),
+                _ => Err(format!("invalid {}: {}", key, value)),
+            }
+        }
+
+        fn optional_bool(key: &str, value: &str) -> Result<Option<bool>, String> {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                parse_bool(key, value).map(Some)
+            }
+        }
+
+        fn parse_f32(key: &str, value: &str) -> Result<f32, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid {}: {}", key, value))
+        }
+
+        // "+=N" / "-=N" adjust the current value; an unset field counts as `base`
+        fn parse_adjustable(
+            key: &str,
+            value: &str,
+            current: Option<f32>,
+            base: f32,
+            non_negative: bool,
+        ) -> Result<f32, String> {
+            let (sign, delta) = if let Some(delta) = value.strip_prefix("+=") {
+                (1.0, delta)
+            } else if let Some(delta) = value.strip_prefix("-=") {
+                (-1.0, delta)
+            } else {
+                return parse_f32(key, value);
+            };
+            let result = current.unwrap_or(base) + sign * parse_f32(key, delta)?;
+            if !result.is_finite() || (non_negative && result < 0.0) {
+                return Err(format!("{} out of range: {}", key, result));
+            }
+            Ok(result)
+        }
+
+        // Only fixed sizes can be adjusted with "+=N" / "-=N"
+        fn parse_dimension(
+            key: &str,
+            value: &str,
+            current: Option<Dimension>,
+        ) -> Result<Dimension, String> {
+            if !value.starts_with("+=") && !value.starts_with("-=") {
+                return Dimension::parse(key, value);
+            }
+            let current = match current {
+                None => None,
+                Some(Dimension::Points(points)) => Some(points),
+                Some(other) => return Err(format!("cannot adjust {} of {}", key, other)),
+            };
+            parse_adjustable(key, value, current, 0.0, true).map(Dimension::Points)
+        }
+
+        for (key, toggle) in [("hidden", "toggle_hidden"), ("disabled", "toggle_disabled")] {
+            if properties.contains_key(key) && properties.contains_key(toggle) {
+                return Err(StateError::new(
+                    ErrorCode::InvalidProperty,
+                    format!("{} and {} cannot be combined", key, toggle),
+                ));
+            }
+        }
+
+        // Pass 1: apply shorthands (all → directional) so specifics can override
+        if let Some(v) = properties.get("padding") {
+            let val = Some(parse_f32("padding", v)?);
+            node.style.padding_left = val;
+            node.style.padding_right = val;
+            node.style.padding_top = val;
+            node.style.padding_bottom = val;
+        }
+        if let Some(v) = properties.get("padding_horizontal") {
+            let val = Some(parse_f32("padding_horizontal", v)?);
+            node.style.padding_left = val;
+            node.style.padding_right = val;
+        }
+        if let Some(v) = properties.get("padding_vertical") {
+            let val = Some(parse_f32("padding_vertical", v)?);
+            node.style.padding_top = val;
+            node.style.padding_bottom = val;
+        }
+        if let Some(v) = properties.get("corner_radius") {
+            let val = Some(parse_adjustable(
+                "corner_radius",
+                v,
+                node.style.corner_radius,
+                0.0,
+                true,
+            )?);
+            node.style.corner_radius = val;
+            node.style.corner_radius_top_left = val;
+            node.style.corner_radius_top_right = val;
+            node.style.corner_radius_bottom_left = val;
+            node.style.corner_radius_bottom_right = val;
+        }
+        if let Some(v) = properties.get("margin") {
+            let val = Some(parse_f32("margin", v)?);
+            node.style.margin_left = val;
+            node.style.margin_right = val;
+            node.style.margin_top = val;
+            node.style.margin_bottom = val;
+        }
+        if let Some(v) = properties.get("margin_horizontal") {
+            let val = Some(parse_f32("margin_horizontal", v)?);
+            node.style.margin_left = val;
+            node.style.margin_right = val;
+        }
+        if let Some(v) = properties.get("margin_vertical") {
+            let val = Some(parse_f32("margin_vertical", v)?);
+            node.style.margin_top = val;
+            node.style.margin_bottom = val;
+        }
+
+        // Pass 2: apply specific properties (override shorthands)
         for (key, value) in properties {
             match key.as_str() {
                 "label" => node.label = optional_str(value),
-                "label_color" => node.label_color = optional_str(value),
-                "icon" => node.icon = optional_str(value),
-                "icon_color" => node.icon_color = optional_str(value),
+                "label_color" => node.label_color = optional_color(key, value)?,
+                "icon" => {
+                    if !value.is_empty() {
+                        validate_icon(value)?;
+                    }
+                    node.icon = optional_str(value);
+                }
+                "icon_color" => node.icon_color = optional_color(key, value)?,
                 "font_weight" => node.font_weight = optional_str(value),
                 "font_family" => node.font_family = optional_str(value),
                 "parent" => node.parent = optional_str(value),
-                "background_color" => node.style.background_color = optional_str(value),
-                "border_color" => node.style.border_color = optional_str(value),
-                "shadow_color" => node.style.shadow_color = optional_str(value),
-                "notch_align" => node.style.notch_align = optional_str(value),
-                "align_items" => node.style.align_items = optional_str(value),
-                "justify_content" => node.style.justify_content = optional_str(value),
-                "hover_background_color" => node.style.hover_background_color = optional_str(value),
-                "hover_label_color" => node.style.hover_label_color = optional_str(value),
-                "hover_icon_color" => node.style.hover_icon_color = optional_str(value),
+                "background_color" => node.style.background_color = optional_color(key, value)?,
+                "border_color" => node.style.border_color = optional_color(key, value)?,
+                "shadow_color" => node.style.shadow_color = optional_color(key, value)?,
+                "notch_align" => {
+                    if !value.is_empty() {
+                        validate_notch_align(value)?;
+                    }
+                    node.style.notch_align = optional_str(value);
+                }
+                "align_items" => {
+                    if !value.is_empty() {
+                        validate_alignment(key, value)?;
+                    }
+                    node.style.align_items = optional_str(value);
+                }
+                "align_self" => {
+                    if !value.is_empty() {
+                        validate_alignment(key, value)?;
+                    }
+                    node.style.align_self = optional_str(value);
+                }
+                "justify_content" => {
+                    if !value.is_empty() {
+                        validate_alignment(key, value)?;
+                    }
+                    node.style.justify_content = optional_str(value);
+                }
+                "hover_background_color" => {
+                    node.style.hover_background_color = optional_color(key, value)?
+                }
+                "hover_label_color" => node.style.hover_label_color = optional_color(key, value)?,
+                "hover_icon_color" => node.style.hover_icon_color = optional_color(key, value)?,
+                "outline_color" => node.style.outline_color = optional_color(key, value)?,
+                "outline_width" => {
+                    node.style.outline_width = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "outline_width",
+                            value,
+                            node.style.outline_width,
+                            0.0,
+                            true,
+                        )?)
+                    };
+                }
+                "outline_offset" => {
+                    node.style.outline_offset = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "outline_offset",
+                            value,
+                            node.style.outline_offset,
+                            0.0,
+                            false,
+                        )?)
+                    };
+                }
+                "blend_mode" => {
+                    if !value.is_empty() {
+                        validate_blend_mode(value)?;
+                    }
+                    node.style.blend_mode = optional_str(value);
+                }
+                "text_truncation" => {
+                    if !value.is_empty() {
+                        validate_text_truncation(value)?;
+                    }
+                    node.style.text_truncation = optional_str(value);
+                }
+                "progress_color" => node.style.progress_color = optional_color(key, value)?,
+                "progress_track_color" => {
+                    node.style.progress_track_color = optional_color(key, value)?
+                }
+                "value" => {
+                    node.value = if value.is_empty() {
+                        None
+                    } else {
+                        let value = parse_adjustable("value", value, node.value, 0.0, false)?;
+                        Some(clamp_progress_value(value)?)
+                    };
+                }
+                "background_opacity" => {
+                    node.style.background_opacity = if value.is_empty() {
+                        None
+                    } else {
+                        let opacity = parse_adjustable(
+                            "background_opacity",
+                            value,
+                            node.style.background_opacity,
+                            1.0,
+                            false,
+                        )?;
+                        validate_background_opacity(opacity)?;
+                        Some(opacity)
+                    };
+                }
+                "background_gradient_start" => {
+                    node.style.background_gradient_start = optional_color(key, value)?
+                }
+                "background_gradient_end" => {
+                    node.style.background_gradient_end = optional_color(key, value)?
+                }
+                "background_gradient_angle" => {
+                    node.style.background_gradient_angle = if value.is_empty() {
+                        None
+                    } else {
+                        let angle = parse_adjustable(
+                            "background_gradient_angle",
+                            value,
+                            node.style.background_gradient_angle,
+                            0.0,
+                            false,
+                        )?;
+                        validate_gradient_angle(angle)?;
+                        Some(angle)
+                    };
+                }
                 "on_click" => node.on_click = optional_str(value),
+                "on_click_cwd" => {
+                    if !value.is_empty() {
+                        validate_on_click_cwd(value)?;
+                    }
+                    node.on_click_cwd = optional_str(value);
+                }
+                "on_click_shell" => node.on_click_shell = optional_str(value),
+                "on_right_click" => node.on_right_click = optional_str(value),
+                "on_middle_click" => node.on_middle_click = optional_str(value),
+                "confirm" => node.confirm = optional_str(value),
                 "image" => node.image = optional_str(value),
+                "template" => node.template = optional_str(value),
+                "hidden" => node.hidden = parse_bool("hidden", value)?,
+                "disabled" => node.disabled = parse_bool("disabled", value)?,
+                "toggle_hidden" => node.hidden = !node.hidden,
+                "toggle_disabled" => node.disabled = !node.disabled,
+                "font_style" => {
+                    if !value.is_empty() {
+                        validate_font_style(value)?;
+                    }
+                    node.font_style = optional_str(value);
+                }
+                "underline" => {
+                    node.underline = optional_bool("underline", value)?;
+                }
+                "strikethrough" => {
+                    node.strikethrough = optional_bool("strikethrough", value)?;
+                }
+                "image_content_mode" => {
+                    if !value.is_empty() {
+                        validate_image_content_mode(value)?;
+                    }
+                    node.image_content_mode = optional_str(value);
+                }
+                "letter_spacing" => {
+                    node.letter_spacing = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "letter_spacing",
+                            value,
+                            node.letter_spacing,
+                            0.0,
+                            false,
+                        )?)
+                    };
+                }
+                "line_height" => {
+                    node.line_height = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "line_height",
+                            value,
+                            node.line_height,
+                            1.0,
+                            true,
+                        )?)
+                    };
+                    validate_line_height(node.line_height)?;
+                }
                 "image_scale" => {
                     node.image_scale = if value.is_empty() {
                         None
                     } else {
-                        Some(parse_f32("image_scale", value)?)
+                        Some(parse_adjustable(
+                            "image_scale",
+                            value,
+                            node.image_scale,
+                            1.0,
+                            true,
+                        )?)
                     };
                 }
                 "border_width" => {
-                    node.style.border_width = Some(parse_f32("border_width", value)?);
+                    node.style.border_width = Some(parse_adjustable(
+                        "border_width",
+                        value,
+                        node.style.border_width,
+                        0.0,
+                        true,
+                    )?);
+                }
+                "corner_radius_top_left" => {
+                    node.style.corner_radius_top_left = Some(parse_adjustable(
+                        "corner_radius_top_left",
+                        value,
+                        node.style
+                            .corner_radius_top_left
+                            .or(node.style.corner_radius),
+                        0.0,
+                        true,
+                    )?);
+                }
+                "corner_radius_top_right" => {
+                    node.style.corner_radius_top_right = Some(parse_adjustable(
+                        "corner_radius_top_right",
+                        value,
+                        node.style
+                            .corner_radius_top_right
+                            .or(node.style.corner_radius),
+                        0.0,
+                        true,
+                    )?);
+                }
+                "corner_radius_bottom_left" => {
+                    node.style.corner_radius_bottom_left = Some(parse_adjustable(
+                        "corner_radius_bottom_left",
+                        value,
+                        node.style
+                            .corner_radius_bottom_left
+                            .or(node.style.corner_radius),
+                        0.0,
+                        true,
+                    )?);
                 }
-                "corner_radius" => {
-                    node.style.corner_radius = Some(parse_f32("corner_radius", value)?);
+                "corner_radius_bottom_right" => {
+                    node.style.corner_radius_bottom_right = Some(parse_adjustable(
+                        "corner_radius_bottom_right",
+                        value,
+                        node.style
+                            .corner_radius_bottom_right
+                            .or(node.style.corner_radius),
+                        0.0,
+                        true,
+                    )?);
                 }
                 "padding_left" => {
-                    node.style.padding_left = Some(parse_f32("padding_left", value)?);
+                    node.style.padding_left = Some(parse_adjustable(
+                        "padding_left",
+                        value,
+                        node.style.padding_left,
+                        0.0,
+                        true,
+                    )?);
                 }
                 "padding_right" => {
-                    node.style.padding_right = Some(parse_f32("padding_right", value)?);
+                    node.style.padding_right = Some(parse_adjustable(
+                        "padding_right",
+                        value,
+                        node.style.padding_right,
+                        0.0,
+                        true,
+                    )?);
                 }
                 "padding_top" => {
-                    node.style.padding_top = Some(parse_f32("padding_top", value)?);
+                    node.style.padding_top = Some(parse_adjustable(
+                        "padding_top",
+                        value,
+                        node.style.padding_top,
+                        0.0,
+                        true,
+                    )?);
                 }
                 "padding_bottom" => {
-                    node.style.padding_bottom = Some(parse_f32("padding_bottom", value)?);
+                    node.style.padding_bottom = Some(parse_adjustable(
+                        "padding_bottom",
+                        value,
+                        node.style.padding_bottom,
+                        0.0,
+                        true,
+                    )?);
                 }
                 "shadow_radius" => {
-                    node.style.shadow_radius = Some(parse_f32("shadow_radius", value)?);
+                    node.style.shadow_radius = Some(parse_adjustable(
+                        "shadow_radius",
+                        value,
+                        node.style.shadow_radius,
+                        0.0,
+                        true,
+                    )?);
+                }
+                "shadow_offset_x" => {
+                    node.style.shadow_offset_x = Some(parse_adjustable(
+                        "shadow_offset_x",
+                        value,
+                        node.style.shadow_offset_x,
+                        0.0,
+                        false,
+                    )?);
+                }
+                "shadow_offset_y" => {
+                    node.style.shadow_offset_y = Some(parse_adjustable(
+                        "shadow_offset_y",
+                        value,
+                        node.style.shadow_offset_y,
+                        0.0,
+                        false,
+                    )?);
                 }
                 "width" => {
                     node.style.width = if value.is_empty() {
                         None
                     } else {
-                        Some(parse_f32("width", value)?)
+                        Some(parse_dimension("width", value, node.style.width)?)
                     };
                 }
                 "height" => {
-                    node.style.height = Some(parse_f32("height", value)?);
+                    node.style.height = Some(parse_dimension("height", value, node.style.height)?);
+                }
+                "min_width" => {
+                    node.style.min_width = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "min_width",
+                            value,
+                            node.style.min_width,
+                            0.0,
+                            true,
+                        )?)
+                    };
+                }
+                "max_width" => {
+                    node.style.max_width = if value.is_empty() {
+                        None
+                    } else {
+                        Some(parse_adjustable(
+                            "max_width",
+                            value,
+                            node.style.max_width,
+                            0.0,
+                            true,
+                        )?)
+                    };
                 }
                 "gap" => {
                     node.style.gap = if value.is_empty() {
                         None
                     } else {
-                        Some(parse_f32("gap", value)?)
+                        Some(parse_adjustable("gap", value, node.style.gap, 0.0, true)?)
                     };
                 }
                 "margin_left" => {
-                    node.style.margin_left = Some(parse_f32("margin_left", value)?);
+                    node.style.margin_left = Some(parse_adjustable(
+                        "margin_left",
+                        value,
+                        node.style.margin_left,
+                        0.0,
+                        false,
+                    )?);
                 }
                 "margin_right" => {
-                    node.style.margin_right = Some(parse_f32("margin_right", value)?);
+                    node.style.margin_right = Some(parse_adjustable(
+                        "margin_right",
+                        value,
+                        node.style.margin_right,
+                        0.0,
+                        false,
+                    )?);
                 }
                 "margin_top" => {
-                    node.style.margin_top = Some(parse_f32("margin_top", value)?);
+                    node.style.margin_top = Some(parse_adjustable(
+                        "margin_top",
+                        value,
+                        node.style.margin_top,
+                        0.0,
+                        false,
+                    )?);
                 }
                 "margin_bottom" => {
-                    node.style.margin_bottom = Some(parse_f32("margin_bottom", value)?);
+                    node.style.margin_bottom = Some(parse_adjustable(
+                        "margin_bottom",
+                        value,
+                        node.style.margin_bottom,
+                        0.0,
+                        false,
+                    )?);
                 }
                 "font_size" => {
-                    node.font_size = Some(parse_f32("font_size", value)?);
+                    node.font_size = Some(parse_adjustable(
+                        "font_size",
+                        value,
+                        node.font_size,
+                        DEFAULT_FONT_SIZE,
+                        true,
+                    )?);
                 }
                 "position" => {
                     node.position = value
                         .parse()
                         .map_err(|_| format!("invalid position: {}", value))?;
+                    node.position_explicit = true;
+                }
+                "display" => {}
+                // Shorthands handled in pass 1
+                "padding" | "padding_horizontal" | "padding_vertical" | "corner_radius"
+                | "margin" | "margin_horizontal" | "margin_vertical" => {}
+                _ => return Err(unknown_property(key)),
+            }
+        }
+        Ok(validate_width_bounds(&node.style)?)
+    }
+
+    /// Stores a named set of properties that nodes referencing it inherit,
+    /// and returns the nodes currently using the template.
+    pub fn define_template(
+        &mut self,
+        name: &str,
+        properties: &HashMap<String, String>,
+    ) -> Result<Vec<BarNode>, StateError> {
+        if name.is_empty() {
+            return Err(StateError::new(
+                ErrorCode::InvalidValue,
+                "template name must not be empty",
+            ));
+        }
+        for key in ["parent", "position", "display", "template"] {
+            if properties.contains_key(key) {
+                return Err(StateError::new(
+                    ErrorCode::InvalidProperty,
+                    format!("'{}' cannot be set on a template", key),
+                ));
+            }
+        }
+        let mut template = BarNode::new(name, NodeType::Item);
+        Self::apply_properties(&mut template, properties)?;
+        self.templates.insert(name.to_string(), template);
+        Ok(self
+            .nodes
+            .values()
+            .flatten()
+            .filter(|n| n.template.as_deref() == Some(name))
+            .cloned()
+            .collect())
+    }
+
+    /// Every template by name, with the properties it sets as `set` would
+    /// accept them.
+    pub fn template_properties(&self) -> BTreeMap<String, BTreeMap<String, String>> {
+        self.templates
+            .iter()
+            .map(|(name, template)| (name.clone(), changed_properties(template)))
+            .collect()
+    }
+
+    /// Replaces the properties nodes added to `display` start with; nodes
+    /// already there are left alone. Empty `properties` clear the defaults.
+    pub fn set_display_defaults(
+        &mut self,
+        display: u32,
+        properties: &HashMap<String, String>,
+    ) -> Result<(), StateError> {
+        for key in ["parent", "position", "display", "template"] {
+            if properties.contains_key(key) {
+                return Err(StateError::new(
+                    ErrorCode::InvalidProperty,
+                    format!("'{}' cannot be a display default", key),
+                ));
+            }
+        }
+        if properties.is_empty() {
+            self.display_defaults.remove(&display);
+            return Ok(());
+        }
+        let mut defaults = BarNode::new("", NodeType::Item);
+        Self::apply_properties(&mut defaults, properties)?;
+        // Only what `inherit` carries reaches new nodes, e.g. not `hidden`
+        let mut carried = BarNode::new("", NodeType::Item);
+        carried.inherit(&defaults);
+        let carried = changed_properties(&carried);
+        if let Some(key) = changed_properties(&defaults)
+            .into_keys()
+            .find(|key| !carried.contains_key(key))
+        {
+            return Err(StateError::new(
+                ErrorCode::InvalidProperty,
+                format!("'{}' cannot be a display default", key),
+            ));
+        }
+        self.display_defaults.insert(display, defaults);
+        Ok(())
+    }
+
+    /// The defaults of every display that has some, as `set` would take them.
+    pub fn display_defaults(&self) -> BTreeMap<u32, BTreeMap<String, String>> {
+        self.display_defaults
+            .iter()
+            .map(|(display, defaults)| (*display, changed_properties(defaults)))
+            .collect()
+    }
+
+    fn validate_template(&self, template: Option<&str>) -> Result<(), StateError> {
+        match template {
+            Some(name) if !self.templates.contains_key(name) => Err(StateError::not_found(
+                format!("template '{}' not found", name),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Style of `node` with unset fields filled in from its template.
+    pub fn effective_style(&self, node: &BarNode) -> NodeStyle {
+        let mut style = node.style.clone();
+        if let Some(template) = self.template_for(node) {
+            style.inherit(&template.style);
+        }
+        style
+    }
+
+    /// `node` with unset properties filled in from its template.
+    pub fn effective_node(&self, node: &BarNode) -> BarNode {
+        let mut resolved = node.clone();
+        if let Some(template) = self.template_for(node) {
+            resolved.inherit(template);
+        }
+        resolved
+    }
+
+    fn template_for(&self, node: &BarNode) -> Option<&BarNode> {
+        node.template
+            .as_deref()
+            .and_then(|name| self.templates.get(name))
+    }
+
+    fn validate_node(node: &BarNode) -> Result<(), String> {
+        if let Some(ref icon) = node.icon {
+            validate_icon(icon)?;
+        }
+        if let Some(ref cwd) = node.on_click_cwd {
+            validate_on_click_cwd(cwd)?;
+        }
+        if let Some(ref mode) = node.image_content_mode {
+            validate_image_content_mode(mode)?;
+        }
+        if let Some(ref style) = node.font_style {
+            validate_font_style(style)?;
+        }
+        if let Some(ref align) = node.style.notch_align {
+            validate_notch_align(align)?;
+        }
+        for (key, align) in [
+            ("align_items", &node.style.align_items),
+            ("align_self", &node.style.align_self),
+            ("justify_content", &node.style.justify_content),
+        ] {
+            if let Some(align) = align {
+                validate_alignment(key, align)?;
+            }
+        }
+        if let Some(width) = node.style.outline_width
+            && !(width.is_finite() && width >= 0.0)
+        {
+            return Err(format!("outline_width out of range: {}", width));
+        }
+        if let Some(offset) = node.style.outline_offset
+            && !offset.is_finite()
+        {
+            return Err(format!("outline_offset out of range: {}", offset));
+        }
+        if let Some(ref mode) = node.style.blend_mode {
+            validate_blend_mode(mode)?;
+        }
+        if let Some(ref mode) = node.style.text_truncation {
+            validate_text_truncation(mode)?;
+        }
+        validate_line_height(node.line_height)?;
+        if let Some(opacity) = node.style.background_opacity {
+            validate_background_opacity(opacity)?;
+        }
+        if let Some(angle) = node.style.background_gradient_angle {
+            validate_gradient_angle(angle)?;
+        }
+        validate_width_bounds(&node.style)?;
+        Ok(())
+    }
+
+    fn find_node(&self, name: &str) -> Result<(u32, usize), StateError> {
+        let display = self
+            .names
+            .get(name)
+            .and_then(|displays| displays.first())
+            .copied()
+            .ok_or_else(|| StateError::not_found(format!("node '{}' not found", name)))?;
+        let pos = self.nodes[&display]
+            .iter()
+            .position(|n| n.name == name)
+            .expect("name index out of sync with nodes");
+        Ok((display, pos))
+    }
+
+    fn locate_id(&self, id: u64) -> Option<(u32, usize)> {
+        let name = self.ids.get(&id)?;
+        self.names.get(name)?.iter().find_map(|&display| {
+            let pos = self.nodes[&display].iter().position(|n| n.id == id)?;
+            Some((display, pos))
+        })
+    }
+
+    fn find_node_ref(&self, name: &str) -> Option<&BarNode> {
+        let (display, pos) = self.find_node(name).ok()?;
+        Some(&self.nodes[&display][pos])
+    }
+
+    fn index_insert(&mut self, name: &str, display: u32) {
+        self.names
+            .entry(name.to_string())
+            .or_default()
+            .push(display);
+    }
+
+    fn index_remove(&mut self, name: &str, display: u32) {
+        if let Some(displays) = self.names.get_mut(name) {
+            displays.retain(|&d| d != display);
+            if displays.is_empty() {
+                self.names.remove(name);
+            }
+        }
+    }
+
+    /// The node named `name`; when several displays have one, the one
+    /// added first.
+    pub fn get_node(&self, name: &str) -> Option<BarNode> {
+        self.find_node_ref(name).cloned()
+    }
+
+    pub fn migrate_nodes(&mut self, from_display: u32, to_display: u32) -> Vec<BarNode> {
+        let Some(nodes) = self.nodes_for_display(from_display) else {
+            return vec![];
+        };
+
+        // Pinned nodes stay, and so do nodes whose name is taken on the
+        // target display
+        let taken = self.nodes_for_display(to_display).unwrap_or_default();
+        let mut staying_names: std::collections::HashSet<String> = nodes
+            .iter()
+            .filter(|n| n.display_explicit || taken.iter().any(|t| t.name == n.name))
+            .map(|n| n.name.clone())
+            .collect();
+
+        loop {
+            let mut changed = false;
+            for node in nodes.iter() {
+                if staying_names.contains(&node.name) {
+                    continue;
+                }
+                if let Some(ref parent) = node.parent
+                    && staying_names.contains(parent)
+                {
+                    staying_names.insert(node.name.clone());
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut migrate = vec![];
+        let nodes = self
+            .nodes
+            .get_mut(&from_display)
+            .expect("display checked above");
+        nodes.retain(|node| {
+            if staying_names.contains(&node.name) {
+                true
+            } else {
+                migrate.push(node.clone());
+                false
+            }
+        });
+
+        for node in &mut migrate {
+            node.display = to_display;
+        }
+        for node in &migrate {
+            self.index_remove(&node.name, from_display);
+            self.index_insert(&node.name, to_display);
+        }
+
+        let target = self.nodes.entry(to_display).or_default();
+        target.extend(migrate.iter().cloned());
+        target.sort_by_key(|n| n.position);
+        self.prune_empty_displays();
+
+        migrate
+    }
+
+    /// Drops display entries left without nodes so buckets don't pile up as
+    /// displays come and go.
+    fn prune_empty_displays(&mut self) {
+        self.nodes.retain(|_, nodes| !nodes.is_empty());
+    }
+
+    pub fn get_nodes(&self) -> Vec<BarNode> {
+        self.node_refs().cloned().collect()
+    }
+
+    /// Borrowed form of `get_nodes`.
+    pub(crate) fn node_refs(&self) -> impl Iterator<Item = &BarNode> {
+        self.nodes.values().flatten()
+    }
+
+    /// Displays that currently have nodes.
+    pub fn displays(&self) -> Vec<u32> {
+        let mut displays: Vec<u32> = self.nodes.keys().copied().collect();
+        displays.sort_unstable();
+        displays
+    }
+
+    /// Number of nodes on each display that has any.
+    pub fn node_counts(&self) -> BTreeMap<u32, u32> {
+        self.nodes
+            .iter()
+            .filter(|(_, nodes)| !nodes.is_empty())
+            .map(|(display, nodes)| (*display, nodes.len() as u32))
+            .collect()
+    }
+
+    fn saved(&self) -> SavedState {
+        SavedState {
+            nodes: self
+                .nodes
+                .values()
+                .flatten()
+                .filter(|n| !n.removing)
+                .cloned()
+                .collect(),
+            templates: self.templates.clone(),
+            user_set: self.user_set.clone(),
+            variables: self.variables.clone(),
+            var_refs: self.var_refs.clone(),
+            display_defaults: self.display_defaults.clone(),
+        }
+    }
+
+    /// Nodes with every field, templates, variables and user-set properties
+    /// as one JSON document that `from_snapshot` reads back. Nodes pending
+    /// a delayed removal are left out.
+    pub fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(self.saved()).expect("saved state serializes")
+    }
+
+    /// Rebuilds a state from `snapshot` output. Node ids are kept.
+    pub fn from_snapshot(snapshot: serde_json::Value) -> Result<BarState, String> {
+        let saved = serde_json::from_value(snapshot).map_err(|e| e.to_string())?;
+        BarState::from_saved(saved)
+    }
+
+    /// Writes the `snapshot` as JSON. The file is replaced atomically.
+    pub fn save_to(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(&self.saved()).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("cannot create {}: {e}", dir.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, json).map_err(|e| format!("cannot write {}: {e}", tmp.display()))?;
+        std::fs::rename(&tmp, path).map_err(|e| format!("cannot write {}: {e}", path.display()))
+    }
+
+    /// Reads a state written by `save_to`. Node ids are kept.
+    pub fn load_from(path: &Path) -> Result<BarState, String> {
+        let json =
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| e.to_string())
+            .and_then(BarState::from_saved)
+            .map_err(|e| format!("invalid state file {}: {e}", path.display()))
+    }
+
+    fn from_saved(saved: SavedState) -> Result<BarState, String> {
+        let mut state = BarState {
+            templates: saved.templates,
+            user_set: saved.user_set,
+            variables: saved.variables,
+            var_refs: saved.var_refs,
+            display_defaults: saved.display_defaults,
+            ..BarState::default()
+        };
+        for node in saved.nodes {
+            if state.ids.insert(node.id, node.name.clone()).is_some() {
+                return Err(format!("duplicate node id {}", node.id));
+            }
+            state.next_id = state.next_id.max(node.id);
+            state.index_insert(&node.name, node.display);
+            state.nodes.entry(node.display).or_default().push(node);
+        }
+        for nodes in state.nodes.values_mut() {
+            nodes.sort_by_key(|n| n.position);
+        }
+        state.check_tree()?;
+        let ids = &state.ids;
+        state.user_set.retain(|id, _| ids.contains_key(id));
+        state.var_refs.retain(|id, _| ids.contains_key(id));
+        Ok(state)
+    }
+
+    /// Checks loaded nodes for what `add_node` guarantees one at a time:
+    /// names are unique on their display, and each parent is a container on
+    /// the same display that doesn't lead back to the node.
+    fn check_tree(&self) -> Result<(), String> {
+        for (&display, nodes) in &self.nodes {
+            let mut by_name: HashMap<&str, &BarNode> = HashMap::new();
+            for node in nodes {
+                if by_name.insert(&node.name, node).is_some() {
+                    return Err(format!(
+                        "node '{}' appears twice on display {}",
+                        node.name, display
+                    ));
+                }
+            }
+            for node in nodes {
+                let Some(parent_name) = node.parent.as_deref() else {
+                    continue;
+                };
+                let Some(parent) = by_name.get(parent_name) else {
+                    return Err(match self.names.get(parent_name).and_then(|d| d.first()) {
+                        Some(other) => format!(
+                            "parent '{}' is on display {}, but '{}' is on display {}",
+                            parent_name, other, node.name, display
+                        ),
+                        None => format!("parent '{}' of '{}' not found", parent_name, node.name),
+                    });
+                };
+                if !parent.node_type.is_container() {
+                    return Err(childless_parent(parent_name, &parent.node_type));
+                }
+                let mut chain = vec![node.name.as_str()];
+                let mut current = parent_name;
+                while chain.len() <= nodes.len() {
+                    chain.push(current);
+                    if current == node.name {
+                        return Err(format!("parent cycle: {}", chain.join(" -> ")));
+                    }
+                    match by_name.get(current).and_then(|n| n.parent.as_deref()) {
+                        Some(next) => current = next,
+                        None => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_nodes_for_display(&self, display: u32) -> Vec<BarNode> {
+        self.nodes.get(&display).cloned().unwrap_or_default()
+    }
+
+    /// Borrowed form of `get_nodes_for_display` for callers that only read.
+    pub(crate) fn nodes_for_display(&self, display: u32) -> Option<&[BarNode]> {
+        self.nodes.get(&display).map(Vec::as_slice)
+    }
+
+    /// The `nodes` that meet every `key=value` condition in `filter`, with
+    /// templates applied. Keys are `name`, `node_type`, `display`, or any
+    /// property `set` reports; an empty value matches an unset property.
+    pub fn query_filtered<B: Borrow<BarNode>>(
+        &self,
+        nodes: Vec<B>,
+        filter: &[String],
+    ) -> Result<Vec<B>, StateError> {
+        let blank = BarNode::new("", NodeType::Item);
+        let conditions = filter
+            .iter()
+            .map(|condition| {
+                let (key, value) = condition.split_once('=').ok_or_else(|| {
+                    StateError::new(
+                        ErrorCode::InvalidValue,
+                        format!("invalid filter: {} (expected key=value)", condition),
+                    )
+                })?;
+                let key = key.trim();
+                if !matches!(key, "name" | "node_type" | "display")
+                    && blank.property_value(key).is_none()
+                {
+                    return Err(StateError::new(
+                        ErrorCode::InvalidProperty,
+                        format!("unknown filter key: {}", key),
+                    ));
+                }
+                // Colors compare in the form `set` stores them, so `red`
+                // matches `#ff0000`
+                let color = property_type(key) == "color";
+                let value = if color && !value.is_empty() {
+                    parse_color(key, value)?
+                } else {
+                    value.to_string()
+                };
+                Ok((key, value, color))
+            })
+            .collect::<Result<Vec<_>, StateError>>()?;
+
+        Ok(nodes
+            .into_iter()
+            .filter(|node| {
+                let node = self.effective_node(node.borrow());
+                conditions.iter().all(|(key, value, color)| {
+                    let actual = match *key {
+                        "name" => Some(node.name.clone()),
+                        "node_type" => Some(node.node_type.as_str().to_string()),
+                        "display" => Some(node.display.to_string()),
+                        key if *color => node
+                            .property_value(key)
+                            .map(|actual| parse_color(key, &actual).unwrap_or(actual)),
+                        key => node.property_value(key),
+                    };
+                    actual.as_deref() == Some(value.as_str())
+                })
+            })
+            .collect())
+    }
+
+    /// Direct children of every node named `parent`, sorted by position.
+    pub fn get_children(&self, parent: &str) -> Vec<BarNode> {
+        self.children_refs(parent).into_iter().cloned().collect()
+    }
+
+    /// Borrowed form of `get_children`.
+    pub(crate) fn children_refs(&self, parent: &str) -> Vec<&BarNode> {
+        let Some(displays) = self.names.get(parent) else {
+            return vec![];
+        };
+        let mut children: Vec<&BarNode> = displays
+            .iter()
+            .filter_map(|display| self.nodes.get(display))
+            .flatten()
+            .filter(|n| n.parent.as_deref() == Some(parent))
+            .collect();
+        children.sort_by_key(|n| n.position);
+        children
+    }
+}
+
+/// Every `NodeStyle` field, by property key.
+const STYLE_FIELDS: [&str; 43] = [
+    "background_color",
+    "background_opacity",
+    "background_gradient_start",
+    "background_gradient_end",
+    "background_gradient_angle",
+    "border_color",
+    "border_width",
+    "corner_radius",
+    "corner_radius_top_left",
+    "corner_radius_top_right",
+    "corner_radius_bottom_left",
+    "corner_radius_bottom_right",
+    "padding_left",
+    "padding_right",
+    "padding_top",
+    "padding_bottom",
+    "shadow_color",
+    "shadow_radius",
+    "shadow_offset_x",
+    "shadow_offset_y",
+    "width",
+    "height",
+    "min_width",
+    "max_width",
+    "gap",
+    "margin_left",
+    "margin_right",
+    "margin_top",
+    "margin_bottom",
+    "notch_align",
+    "align_items",
+    "align_self",
+    "justify_content",
+    "hover_background_color",
+    "hover_label_color",
+    "hover_icon_color",
+    "outline_color",
+    "outline_width",
+    "outline_offset",
+    "blend_mode",
+    "text_truncation",
+    "progress_color",
+    "progress_track_color",
+];
+
+/// Node fields outside `NodeStyle` that only affect how content looks.
+const APPEARANCE_FIELDS: [&str; 12] = [
+    "label_color",
+    "icon_color",
+    "font_size",
+    "font_weight",
+    "font_family",
+    "letter_spacing",
+    "line_height",
+    "font_style",
+    "underline",
+    "strikethrough",
+    "image_scale",
+    "image_content_mode",
+];
+
+fn reset_field(node: &mut BarNode, field: &str) -> Result<(), StateError> {
+    macro_rules! reset_style {
+        ([$($field:ident),* $(,)?]) => {
+            match field {
+                $(stringify!($field) => {
+                    node.style.$field = None;
+                    return Ok(());
+                })*
+                _ => {}
+            }
+        };
+    }
+    reset_style!([
+        background_color,
+        background_opacity,
+        background_gradient_start,
+        background_gradient_end,
+        background_gradient_angle,
+        border_color,
+        border_width,
+        corner_radius,
+        corner_radius_top_left,
+        corner_radius_top_right,
+        corner_radius_bottom_left,
+        corner_radius_bottom_right,
+        padding_left,
+        padding_right,
+        padding_top,
+        padding_bottom,
+        shadow_color,
+        shadow_radius,
+        shadow_offset_x,
+        shadow_offset_y,
+        width,
+        height,
+        min_width,
+        max_width,
+        gap,
+        margin_left,
+        margin_right,
+        margin_top,
+        margin_bottom,
+        notch_align,
+        align_items,
+        align_self,
+        justify_content,
+        hover_background_color,
+        hover_label_color,
+        hover_icon_color,
+        outline_color,
+        outline_width,
+        outline_offset,
+        blend_mode,
+        text_truncation,
+        progress_color,
+        progress_track_color,
+    ]);
+    match field {
+        "label" => node.label = None,
+        "label_color" => node.label_color = None,
+        "icon" => node.icon = None,
+        "icon_color" => node.icon_color = None,
+        "font_size" => node.font_size = None,
+        "font_weight" => node.font_weight = None,
+        "font_family" => node.font_family = None,
+        "letter_spacing" => node.letter_spacing = None,
+        "line_height" => node.line_height = None,
+        "font_style" => node.font_style = None,
+        "underline" => node.underline = None,
+        "strikethrough" => node.strikethrough = None,
+        "on_click" => node.on_click = None,
+        "on_right_click" => node.on_right_click = None,
+        "on_middle_click" => node.on_middle_click = None,
+        "on_click_cwd" => node.on_click_cwd = None,
+        "on_click_shell" => node.on_click_shell = None,
+        "confirm" => node.confirm = None,
+        "image" => node.image = None,
+        "image_scale" => node.image_scale = None,
+        "image_content_mode" => node.image_content_mode = None,
+        "value" => node.value = None,
+        "template" => node.template = None,
+        "hidden" => node.hidden = false,
+        "disabled" => node.disabled = false,
+        "parent" | "position" | "display" => {
+            return Err(StateError::new(
+                ErrorCode::InvalidProperty,
+                format!("{} cannot be reset; use set or move", field),
+            ));
+        }
+        _ => return Err(unknown_property(field)),
+    }
+    Ok(())
+}
+
+/// Every key `set` accepts, shorthands included.
+pub const PROPERTY_KEYS: [&str; 79] = [
+    "label",
+    "label_color",
+    "icon",
+    "icon_color",
+    "font_size",
+    "font_weight",
+    "font_family",
+    "letter_spacing",
+    "line_height",
+    "font_style",
+    "underline",
+    "strikethrough",
+    "parent",
+    "position",
+    "display",
+    "background_color",
+    "background_opacity",
+    "background_gradient_start",
+    "background_gradient_end",
+    "background_gradient_angle",
+    "border_color",
+    "border_width",
+    "corner_radius",
+    "corner_radius_top_left",
+    "corner_radius_top_right",
+    "corner_radius_bottom_left",
+    "corner_radius_bottom_right",
+    "padding",
+    "padding_horizontal",
+    "padding_vertical",
+    "padding_left",
+    "padding_right",
+    "padding_top",
+    "padding_bottom",
+    "shadow_color",
+    "shadow_radius",
+    "shadow_offset_x",
+    "shadow_offset_y",
+    "width",
+    "height",
+    "min_width",
+    "max_width",
+    "gap",
+    "margin",
+    "margin_horizontal",
+    "margin_vertical",
+    "margin_left",
+    "margin_right",
+    "margin_top",
+    "margin_bottom",
+    "notch_align",
+    "align_items",
+    "align_self",
+    "justify_content",
+    "hover_background_color",
+    "hover_label_color",
+    "hover_icon_color",
+    "outline_color",
+    "outline_width",
+    "outline_offset",
+    "blend_mode",
+    "text_truncation",
+    "progress_color",
+    "progress_track_color",
+    "on_click",
+    "on_right_click",
+    "on_middle_click",
+    "on_click_cwd",
+    "on_click_shell",
+    "confirm",
+    "image",
+    "image_scale",
+    "image_content_mode",
+    "value",
+    "template",
+    "hidden",
+    "disabled",
+    "toggle_hidden",
+    "toggle_disabled",
+];
+
+/// The value `set` expects for `key`: `color`, `float`, `int`, `dimension`,
+/// `bool`, `flag`, `string`, `int|string` for an id or a name, or
+/// `enum[a,b]` for a fixed set.
+pub fn property_type(key: &str) -> String {
+    let values: &[&str] = match key {
+        "font_style" => &FONT_STYLES,
+        "notch_align" => &NOTCH_ALIGNS,
+        "align_items" | "align_self" | "justify_content" => &ALIGNMENTS,
+        "blend_mode" => &BLEND_MODES,
+        "text_truncation" => &TEXT_TRUNCATIONS,
+        "image_content_mode" => &IMAGE_CONTENT_MODES,
+        _ => {
+            let kind = match key {
+                "background_gradient_start" | "background_gradient_end" => "color",
+                key if key.ends_with("_color") => "color",
+                "width" | "height" => "dimension",
+                "position" => "int",
+                "display" => "int|string",
+                "hidden" | "disabled" | "underline" | "strikethrough" => "bool",
+                "toggle_hidden" | "toggle_disabled" => "flag",
+                key if key.starts_with("padding")
+                    || key.starts_with("margin")
+                    || key.starts_with("corner_radius") =>
+                {
+                    "float"
+                }
+                "border_width"
+                | "shadow_radius"
+                | "shadow_offset_x"
+                | "shadow_offset_y"
+                | "min_width"
+                | "max_width"
+                | "gap"
+                | "outline_width"
+                | "outline_offset"
+                | "font_size"
+                | "letter_spacing"
+                | "line_height"
+                | "image_scale"
+                | "value"
+                | "background_opacity"
+                | "background_gradient_angle" => "float",
+                _ => "string",
+            };
+            return kind.to_string();
+        }
+    };
+    format!("enum[{}]", values.join(","))
+}
+
+/// Node types `key` has an effect on.
+pub fn property_node_types(key: &str) -> &'static [NodeType] {
+    match key {
+        "gap" | "align_items" | "justify_content" => {
+            &[NodeType::Row, NodeType::Column, NodeType::Box]
+        }
+        "value" | "progress_color" | "progress_track_color" => &[NodeType::Progress],
+        "label" | "label_color" | "icon" | "icon_color" | "font_size" | "font_weight"
+        | "font_family" | "letter_spacing" | "line_height" | "font_style" | "underline"
+        | "strikethrough" | "hover_label_color" | "hover_icon_color" | "text_truncation" => {
+            &[NodeType::Item]
+        }
+        "image" | "image_scale" | "image_content_mode" => &[
+            NodeType::Item,
+            NodeType::Row,
+            NodeType::Column,
+            NodeType::Box,
+        ],
+        _ => &[
+            NodeType::Item,
+            NodeType::Row,
+            NodeType::Column,
+            NodeType::Box,
+            NodeType::Progress,
+        ],
+    }
+}
+
+/// Properties of `node` that differ from a new item's, as `set` would take
+/// them.
+fn changed_properties(node: &BarNode) -> BTreeMap<String, String> {
+    let blank = BarNode::new(node.name.clone(), NodeType::Item);
+    PROPERTY_KEYS
+        .iter()
+        .filter_map(|key| Some((*key, node.property_value(key)?)))
+        .filter(|(key, value)| blank.property_value(key).as_ref() != Some(value))
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+}
+
+fn unknown_property(key: &str) -> StateError {
+    let message = match suggest_property(key) {
+        Some(known) => format!("unknown property: {} (did you mean '{}'?)", key, known),
+        None => format!("unknown property: {}", key),
+    };
+    StateError::new(ErrorCode::InvalidProperty, message)
+}
+
+/// The known key closest to `key`, if it is close enough to be a typo.
+fn suggest_property(key: &str) -> Option<&'static str> {
+    let limit = (key.chars().count() / 3).max(1);
+    PROPERTY_KEYS
+        .iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein distance between `a` and `b`, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Properties whose values may be a `$name` variable reference.
+const VARIABLE_KEYS: [&str; 50] = [
+    "label_color",
+    "icon_color",
+    "background_color",
+    "border_color",
+    "shadow_color",
+    "hover_background_color",
+    "hover_label_color",
+    "hover_icon_color",
+    "outline_color",
+    "progress_color",
+    "progress_track_color",
+    "border_width",
+    "corner_radius",
+    "corner_radius_top_left",
+    "corner_radius_top_right",
+    "corner_radius_bottom_left",
+    "corner_radius_bottom_right",
+    "shadow_radius",
+    "shadow_offset_x",
+    "shadow_offset_y",
+    "width",
+    "height",
+    "min_width",
+    "max_width",
+    "gap",
+    "outline_width",
+    "outline_offset",
+    "font_size",
+    "letter_spacing",
+    "line_height",
+    "image_scale",
+    "value",
+    "background_opacity",
+    "background_gradient_start",
+    "background_gradient_end",
+    "background_gradient_angle",
+    "padding",
+    "padding_horizontal",
+    "padding_vertical",
+    "padding_left",
+    "padding_right",
+    "padding_top",
+    "padding_bottom",
+    "margin",
+    "margin_horizontal",
+    "margin_vertical",
+    "margin_left",
+    "margin_right",
+    "margin_top",
+    "margin_bottom",
+];
+
+/// The variable `value` names when `key` accepts one, e.g. `accent` for
+/// `$accent`.
+fn variable_ref<'a>(key: &str, value: &'a str) -> Option<&'a str> {
+    value
+        .strip_prefix('$')
+        .filter(|_| VARIABLE_KEYS.contains(&key))
+}
+
+/// Fields a property sets: the directional fields for a shorthand such as
+/// `padding`, otherwise just `key`.
+fn covered_fields(key: &str) -> Vec<&str> {
+    match key {
+        "padding" => vec![
+            "padding_left",
+            "padding_right",
+            "padding_top",
+            "padding_bottom",
+        ],
+        "padding_horizontal" => vec!["padding_left", "padding_right"],
+        "padding_vertical" => vec!["padding_top", "padding_bottom"],
+        "corner_radius" => vec![
+            "corner_radius",
+            "corner_radius_top_left",
+            "corner_radius_top_right",
+            "corner_radius_bottom_left",
+            "corner_radius_bottom_right",
+        ],
+        "margin" => vec!["margin_left", "margin_right", "margin_top", "margin_bottom"],
+        "margin_horizontal" => vec!["margin_left", "margin_right"],
+        "margin_vertical" => vec!["margin_top", "margin_bottom"],
+        _ => vec![key],
+    }
+}
+
+fn validate_on_click_cwd(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("on_click_cwd must not be empty".to_string());
+    }
+    Ok(())
+}
+
+// SF Symbol names are dot-separated lowercase tokens, e.g. `battery.100.bolt`
+fn validate_icon(value: &str) -> Result<(), String> {
+    let valid = value.split('.').all(|token| {
+        !token.is_empty()
+            && token
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    });
+    if !valid {
+        return Err(format!(
+            "invalid icon: {:?} (SF Symbol names are dot-separated lowercase tokens, e.g. wifi or battery.100)",
+            value
+        ));
+    }
+    Ok(())
+}
+
+/// `value` as a hex color the frontend can parse. Takes a named color, or
+/// `#RGB`, `#RRGGBB` or `#RRGGBBAA` with the `#` optional, and returns `#`
+/// followed by uppercase hex, the short form expanded to `#RRGGBB`.
+pub fn parse_color(key: &str, value: &str) -> Result<String, String> {
+    if let Some(hex) = resolve_color(value) {
+        return Ok(hex.to_string());
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !matches!(hex.len(), 3 | 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "invalid color for {}: {} (expected #RGB, #RRGGBB or #RRGGBBAA)",
+            key, value
+        ));
+    }
+    let hex = hex.to_ascii_uppercase();
+    if hex.len() == 3 {
+        let expanded: String = hex.chars().flat_map(|c| [c, c]).collect();
+        return Ok(format!("#{expanded}"));
+    }
+    Ok(format!("#{hex}"))
+}
+
+/// Standard CSS color names and their hex values.
+const NAMED_COLORS: [(&str, &str); 32] = [
+    ("aqua", "#00FFFF"),
+    ("black", "#000000"),
+    ("blue", "#0000FF"),
+    ("brown", "#A52A2A"),
+    ("coral", "#FF7F50"),
+    ("crimson", "#DC143C"),
+    ("cyan", "#00FFFF"),
+    ("fuchsia", "#FF00FF"),
+    ("gold", "#FFD700"),
+    ("gray", "#808080"),
+    ("green", "#008000"),
+    ("grey", "#808080"),
+    ("indigo", "#4B0082"),
+    ("lime", "#00FF00"),
+    ("magenta", "#FF00FF"),
+    ("maroon", "#800000"),
+    ("navy", "#000080"),
+    ("olive", "#808000"),
+    ("orange", "#FFA500"),
+    ("pink", "#FFC0CB"),
+    ("purple", "#800080"),
+    ("red", "#FF0000"),
+    ("salmon", "#FA8072"),
+    ("silver", "#C0C0C0"),
+    ("skyblue", "#87CEEB"),
+    ("teal", "#008080"),
+    ("tomato", "#FF6347"),
+    ("transparent", "#00000000"),
+    ("turquoise", "#40E0D0"),
+    ("violet", "#EE82EE"),
+    ("white", "#FFFFFF"),
+    ("yellow", "#FFFF00"),
+];
+
+/// Maps a CSS color name (case-insensitive) to its canonical hex value.
+pub fn resolve_color(value: &str) -> Option<&'static str> {
+    NAMED_COLORS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+        .map(|(_, hex)| *hex)
+}
+
+fn validate_line_height(line_height: Option<f32>) -> Result<(), String> {
+    match line_height {
+        Some(value) if !(value.is_finite() && value >= 0.0) => {
+            Err(format!("line_height out of range: {}", value))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Accepted values for `image_content_mode`.
+pub const IMAGE_CONTENT_MODES: [&str; 4] = ["fit", "fill", "stretch", "center"];
+
+fn validate_image_content_mode(value: &str) -> Result<(), String> {
+    if !IMAGE_CONTENT_MODES.contains(&value) {
+        return Err(format!(
+            "invalid image_content_mode: {} (expected one of {})",
+            value,
+            IMAGE_CONTENT_MODES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Accepted values for `font_style`.
+pub const FONT_STYLES: [&str; 2] = ["normal", "italic"];
+
+fn validate_font_style(value: &str) -> Result<(), String> {
+    if !FONT_STYLES.contains(&value) {
+        return Err(format!(
+            "invalid font_style: {} (expected one of {})",
+            value,
+            FONT_STYLES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Accepted values for `notch_align`.
+pub const NOTCH_ALIGNS: [&str; 2] = ["left", "right"];
+
+fn validate_notch_align(value: &str) -> Result<(), String> {
+    if !NOTCH_ALIGNS.contains(&value) {
+        return Err(format!(
+            "invalid notch_align: {} (expected one of {})",
+            value,
+            NOTCH_ALIGNS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Accepted values for `align_items`, `align_self` and `justify_content`.
+pub const ALIGNMENTS: [&str; 3] = ["start", "center", "end"];
+
+fn validate_alignment(key: &str, value: &str) -> Result<(), String> {
+    if !ALIGNMENTS.contains(&value) {
+        return Err(format!(
+            "invalid {}: {} (expected one of {})",
+            key,
+            value,
+            ALIGNMENTS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn validate_width_bounds(style: &NodeStyle) -> Result<(), String> {
+    for (key, width) in [
+        ("min_width", style.min_width),
+        ("max_width", style.max_width),
+    ] {
+        if let Some(width) = width
+            && !(width.is_finite() && width >= 0.0)
+        {
+            return Err(format!("{} out of range: {}", key, width));
+        }
+    }
+    if let (Some(min), Some(max)) = (style.min_width, style.max_width)
+        && min > max
+    {
+        return Err(format!("min_width {} exceeds max_width {}", min, max));
+    }
+    Ok(())
+}
+
+fn validate_background_opacity(value: f32) -> Result<(), String> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!(
+            "background_opacity out of range: {} (expected 0 to 1)",
+            value
+        ));
+    }
+    Ok(())
+}
+
+fn validate_gradient_angle(value: f32) -> Result<(), String> {
+    if !(0.0..=360.0).contains(&value) {
+        return Err(format!(
+            "background_gradient_angle out of range: {} (expected 0 to 360)",
+            value
+        ));
+    }
+    Ok(())
+}
+
+/// Accepted values for `blend_mode`.
+pub const BLEND_MODES: [&str; 6] = [
+    "normal", "multiply", "screen", "overlay", "darken", "lighten",
+];
+
+fn validate_blend_mode(value: &str) -> Result<(), String> {
+    if !BLEND_MODES.contains(&value) {
+        return Err(format!(
+            "invalid blend_mode: {} (expected one of {})",
+            value,
+            BLEND_MODES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn childless_parent(parent_name: &str, node_type: &NodeType) -> String {
+    let kind = match node_type {
+        NodeType::Progress => "a progress node",
+        _ => "an item",
+    };
+    format!("'{}' is {} and cannot have children", parent_name, kind)
+}
+
+/// Clamps a progress `value` into 0 to 100.
+fn clamp_progress_value(value: f32) -> Result<f32, String> {
+    if value.is_nan() {
+        return Err(format!("value out of range: {}", value));
+    }
+    Ok(value.clamp(0.0, 100.0))
+}
+
+/// Accepted values for `text_truncation`.
+pub const TEXT_TRUNCATIONS: [&str; 4] = ["head", "middle", "tail", "none"];
+
+fn validate_text_truncation(value: &str) -> Result<(), String> {
+    if !TEXT_TRUNCATIONS.contains(&value) {
+        return Err(format!(
+            "invalid text_truncation: {} (expected one of {})",
+            value,
+            TEXT_TRUNCATIONS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Terminal-style column width of `text`: East Asian wide and fullwidth
+/// characters and emoji take two columns, combining marks, variation
+/// selectors and joiners take none.
+pub fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_node(name: &str, node_type: NodeType, parent: Option<&str>, display: u32) -> BarNode {
+        BarNode {
+            parent: parent.map(|s| s.to_string()),
+            display,
+            ..BarNode::new(name, node_type)
+        }
+    }
+
+    #[test]
+    fn duplicate_copies_subtree_with_renamed_children() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("right", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("cpu", NodeType::Row, Some("right"), 1))
+            .unwrap();
+        state
+            .add_node(BarNode {
+                label: Some("42%".into()),
+                ..make_node("cpu_label", NodeType::Item, Some("cpu"), 1)
+            })
+            .unwrap();
+        state
+            .add_node(make_node("graph", NodeType::Item, Some("cpu"), 1))
+            .unwrap();
+
+        let names = state.duplicate_subtree("cpu", "gpu", None).unwrap();
+        assert_eq!(names, vec!["gpu", "gpu_label", "gpu_graph"]);
+        let nodes = state.get_nodes_for_display(1);
+        let find = |name: &str| nodes.iter().find(|n| n.name == name).unwrap();
+        assert_eq!(find("gpu").parent.as_deref(), Some("right"));
+        assert_eq!(find("gpu").position, find("cpu").position + 1);
+        assert_eq!(find("gpu_label").parent.as_deref(), Some("gpu"));
+        assert_eq!(find("gpu_label").label.as_deref(), Some("42%"));
+        assert_eq!(find("gpu_graph").parent.as_deref(), Some("gpu"));
+        assert_eq!(find("cpu_label").parent.as_deref(), Some("cpu"));
+
+        let err = state.duplicate_subtree("cpu", "gpu", None).unwrap_err();
+        assert_eq!(err, "node 'gpu' already exists on display 1");
+
+        let names = state.duplicate_subtree("cpu", "cpu", Some(2)).unwrap();
+        assert_eq!(names, vec!["cpu", "cpu_label", "cpu_graph"]);
+        let copy = state.get_nodes_for_display(2);
+        let root = copy.iter().find(|n| n.name == "cpu").unwrap();
+        assert_eq!(root.parent, None);
+        assert!(root.display_explicit);
+    }
+
+    #[test]
+    fn children_are_sorted_by_position() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        for (name, position) in [("c", 2), ("a", 0), ("b", 1)] {
+            let mut node = make_node(name, NodeType::Item, Some("row"), 1);
+            node.position = position;
+            node.position_explicit = true;
+            state.add_node(node).unwrap();
+        }
+        state
+            .add_node(make_node("other", NodeType::Item, None, 1))
+            .unwrap();
+
+        let names: Vec<String> = state
+            .get_children("row")
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert!(state.get_children("a").is_empty());
+        assert!(state.get_children("ghost").is_empty());
+    }
+
+    #[test]
+    fn borrowed_display_nodes_match_clones() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("clock", NodeType::Item, Some("row"), 1))
+            .unwrap();
+
+        let borrowed = state.nodes_for_display(1).unwrap();
+        // Same storage, so nothing was copied
+        assert_eq!(borrowed.as_ptr(), state.nodes[&1].as_ptr());
+        let names: Vec<&str> = borrowed.iter().map(|n| n.name.as_str()).collect();
+        let cloned: Vec<String> = state
+            .get_nodes_for_display(1)
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        assert_eq!(names, cloned);
+        assert!(state.nodes_for_display(2).is_none());
+    }
+
+    fn assert_index_matches(state: &BarState) {
+        let mut expected: HashMap<String, Vec<u32>> = HashMap::new();
+        for (&display, nodes) in &state.nodes {
+            for node in nodes {
+                expected.entry(node.name.clone()).or_default().push(display);
+            }
+        }
+        let mut actual = state.names.clone();
+        for displays in expected.values_mut().chain(actual.values_mut()) {
+            displays.sort_unstable();
+        }
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn name_index_tracks_many_nodes() {
+        let mut state = BarState::default();
+        for display in 1..=3 {
+            for group in 0..10 {
+                let row = format!("row{group}");
+                state
+                    .add_node(make_node(&row, NodeType::Row, None, display))
+                    .unwrap();
+                for item in 0..20 {
+                    state
+                        .add_node(make_node(
+                            &format!("item{group}_{item}"),
+                            NodeType::Item,
+                            Some(&row),
+                            display,
+                        ))
+                        .unwrap();
+                }
+            }
+        }
+        assert_eq!(state.get_nodes().len(), 630);
+        assert_index_matches(&state);
+
+        for group in 0..10 {
+            let name = format!("item{group}_0");
+            let props = HashMap::from([("label".to_string(), group.to_string())]);
+            let node = state.set_properties(&name, &props).unwrap();
+            assert_eq!(state.get_node(&name).unwrap().id, node.id);
+        }
+
+        state.rename_node("row0", "first").unwrap();
+        state.remove_node("row1").unwrap();
+        state.remove_node("item2_5").unwrap();
+        let to_display = HashMap::from([
+            ("display".to_string(), "4".to_string()),
+            ("parent".to_string(), String::new()),
+        ]);
+        let moved = state.set_properties("item3_3", &to_display).unwrap();
+        assert_eq!(moved.display, 4);
+        assert_eq!(state.names["item3_3"], vec![2, 3, 4]);
+        state.migrate_nodes(2, 1);
+        state.clear(Some(3));
+        assert_index_matches(&state);
+
+        assert_eq!(state.names["row1"], vec![1]);
+        assert_eq!(state.get_node("first").unwrap().display, 1);
+        for node in state.get_nodes() {
+            assert!(state.find_node(&node.name).is_ok(), "{}", node.name);
+        }
+    }
+
+    #[test]
+    fn changing_display_keeps_names_unique_and_parents_local() {
+        let mut state = BarState::default();
+        for display in [1, 2] {
+            state
+                .add_node(make_node("clock", NodeType::Item, None, display))
+                .unwrap();
+            state
+                .add_node(make_node("row", NodeType::Row, None, display))
+                .unwrap();
+        }
+        state
+            .add_node(make_node("cpu", NodeType::Item, Some("row"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("solo", NodeType::Row, None, 3))
+            .unwrap();
+
+        let set = |state: &mut BarState, name: &str, props: &[(&str, &str)]| {
+            let props = props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            state.set_properties(name, &props)
+        };
+        let err = set(&mut state, "clock", &[("display", "2")]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::AlreadyExists);
+        assert_eq!(state.names["clock"], vec![1, 2]);
+
+        let err = set(&mut state, "cpu", &[("display", "3")]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::WrongDisplay);
+        let err = set(&mut state, "cpu", &[("parent", "clock")]).unwrap_err();
+        assert_eq!(err, "'clock' is an item and cannot have children");
+        let err = set(&mut state, "cpu", &[("parent", "ghost")]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        let moved = set(&mut state, "cpu", &[("display", "3"), ("parent", "solo")]).unwrap();
+        assert_eq!(moved.display, 3);
+
+        state.migrate_nodes(2, 1);
+        assert_eq!(state.names["clock"], vec![1, 2]);
+        state.remove_node("clock").unwrap();
+        assert_eq!(state.node_display("clock"), Some(2));
+    }
+
+    #[test]
+    fn from_snapshot_rejects_trees_add_would_refuse() {
+        let mut state = BarState::default();
+        for (name, node_type, parent, display) in [
+            ("row", NodeType::Row, None, 1),
+            ("a", NodeType::Row, Some("row"), 1),
+            ("b", NodeType::Row, Some("a"), 1),
+            ("leaf", NodeType::Item, Some("b"), 1),
+            ("clock", NodeType::Item, None, 1),
+            ("far", NodeType::Row, None, 2),
+        ] {
+            state
+                .add_node(make_node(name, node_type, parent, display))
+                .unwrap();
+        }
+        let snapshot = state.snapshot();
+        BarState::from_snapshot(snapshot.clone()).unwrap();
+
+        let broken = |name: &str, key: &str, value: serde_json::Value| {
+            let mut snapshot = snapshot.clone();
+            let nodes = snapshot["nodes"].as_array_mut().unwrap();
+            let node = nodes.iter_mut().find(|n| n["name"] == name).unwrap();
+            node[key] = value;
+            BarState::from_snapshot(snapshot).unwrap_err()
+        };
+        assert_eq!(
+            broken("clock", "name", "leaf".into()),
+            "node 'leaf' appears twice on display 1"
+        );
+        assert_eq!(
+            broken("leaf", "parent", "ghost".into()),
+            "parent 'ghost' of 'leaf' not found"
+        );
+        assert_eq!(
+            broken("leaf", "parent", "far".into()),
+            "parent 'far' is on display 2, but 'leaf' is on display 1"
+        );
+        assert_eq!(
+            broken("leaf", "parent", "clock".into()),
+            "'clock' is an item and cannot have children"
+        );
+        assert_eq!(
+            broken("a", "parent", "b".into()),
+            "parent cycle: a -> b -> a"
+        );
+        assert_eq!(
+            broken("row", "parent", "row".into()),
+            "parent cycle: row -> row"
+        );
+    }
+
+    #[test]
+    fn saved_state_round_trips() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        let clock = state
+            .add_node(BarNode {
+                label: Some("12:00".into()),
+                ..make_node("clock", NodeType::Item, Some("row"), 1)
+            })
+            .unwrap();
+        state
+            .add_node(make_node("toast", NodeType::Item, None, 2))
+            .unwrap();
+        state.mark_removing("toast").unwrap();
+        let props = HashMap::from([("background_color".to_string(), "#112233".to_string())]);
+        state.define_template("pill", &props).unwrap();
+
+        let path = std::env::temp_dir()
+            .join(format!("ranma-state-{}", std::process::id()))
+            .join("state.json");
+        state.save_to(&path).unwrap();
+        let mut loaded = BarState::load_from(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(loaded.displays(), vec![1]);
+        let restored = loaded.get_node_by_id(clock).unwrap();
+        assert_eq!(restored.parent.as_deref(), Some("row"));
+        assert_eq!(restored.label.as_deref(), Some("12:00"));
+        assert!(loaded.templates.contains_key("pill"));
+        let id = loaded
+            .add_node(make_node("new", NodeType::Item, None, 1))
+            .unwrap();
+        assert!(id > clock);
+    }
+
+    #[test]
+    fn load_rejects_corrupt_file() {
+        let path = std::env::temp_dir().join(format!("ranma-corrupt-{}.json", std::process::id()));
+        std::fs::write(&path, "{ not json").unwrap();
+        let result = BarState::load_from(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().starts_with("invalid state file"));
+    }
+
+    #[test]
+    fn remove_item_node() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+
+        let removed = state.remove_node("item1").unwrap();
+        assert_eq!(removed.name, "item1");
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn remove_nonexistent_node() {
+        let mut state = BarState::default();
+        let result = state.remove_node("ghost");
+        assert!(result.is_err());
+        assert!(result.unwrap_err().message.contains("not found"));
+    }
+
+    #[test]
+    fn remove_container_without_children() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .unwrap();
+
+        let removed = state.remove_node("row1").unwrap();
+        assert_eq!(removed.name, "row1");
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn remove_container_with_direct_children() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("child1", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("child2", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+
+        let removed = state.remove_node("row1").unwrap();
+        assert_eq!(removed.name, "row1");
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn remove_container_with_deep_nesting() {
+        let mut state = BarState::default();
+        // root -> col1 -> row1 -> item1
+        state
+            .add_node(make_node("root", NodeType::Column, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("col1", NodeType::Column, Some("root"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("row1", NodeType::Row, Some("col1"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("item1", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+
+        let removed = state.remove_node("root").unwrap();
+        assert_eq!(removed.name, "root");
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn remove_container_leaves_unrelated_nodes() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("child1", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("unrelated", NodeType::Item, None, 1))
+            .unwrap();
+
+        state.remove_node("row1").unwrap();
+
+        let remaining: Vec<String> = state.get_nodes().into_iter().map(|n| n.name).collect();
+        assert_eq!(remaining, vec!["unrelated"]);
+    }
+
+    #[test]
+    fn remove_item_does_not_cascade() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("sibling1", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("sibling2", NodeType::Item, Some("row1"), 1))
+            .unwrap();
+
+        state.remove_node("sibling1").unwrap();
+
+        let remaining: Vec<String> = state.get_nodes().into_iter().map(|n| n.name).collect();
+        assert!(remaining.contains(&"row1".to_string()));
+        assert!(remaining.contains(&"sibling2".to_string()));
+        assert_eq!(remaining.len(), 2);
+    }
+
+    #[test]
+    fn specific_corners_override_corner_radius() {
+        let mut state = BarState::default();
+        let id = state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([
+            ("corner_radius".to_string(), "8".to_string()),
+            ("corner_radius_bottom_left".to_string(), "0".to_string()),
+            ("corner_radius_bottom_right".to_string(), "0".to_string()),
+        ]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.style.corner_radius, Some(8.0));
+        assert_eq!(node.style.corner_radius_top_left, Some(8.0));
+        assert_eq!(node.style.corner_radius_top_right, Some(8.0));
+        assert_eq!(node.style.corner_radius_bottom_left, Some(0.0));
+        assert_eq!(node.style.corner_radius_bottom_right, Some(0.0));
+
+        let props = HashMap::from([("corner_radius_top_left".to_string(), "+=4".to_string())]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.style.corner_radius_top_left, Some(12.0));
+
+        let props = HashMap::from([("corner_radius".to_string(), "6".to_string())]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.style.corner_radius_bottom_left, Some(6.0));
+        assert_eq!(node.style.corner_radius_top_left, Some(6.0));
+        assert_eq!(
+            state.user_set(id).into_iter().collect::<Vec<_>>(),
+            [("corner_radius".to_string(), "6".to_string())]
+        );
+
+        let keys = vec!["corner_radius".to_string()];
+        let node = state.reset_properties("clock", Some(&keys)).unwrap();
+        assert_eq!(node.style.corner_radius, None);
+        assert_eq!(node.style.corner_radius_bottom_right, None);
+    }
+
+    #[test]
+    fn user_set_keeps_shorthands_and_latest_overrides() {
+        let mut state = BarState::default();
+        let id = state
+            .add_node(make_node("cpu", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, props: &[(&str, &str)]| {
+            let props = props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            state.set_properties("cpu", &props).unwrap();
+        };
+        let recorded = |state: &BarState| -> Vec<(String, String)> {
+            state.user_set(id).into_iter().collect()
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        set(&mut state, &[("padding", "8"), ("padding_left", "4")]);
+        assert_eq!(
+            recorded(&state),
+            pairs(&[("padding", "8"), ("padding_left", "4")])
+        );
+
+        set(&mut state, &[("padding_horizontal", "2")]);
+        assert_eq!(
+            recorded(&state),
+            pairs(&[("padding", "8"), ("padding_horizontal", "2")])
+        );
+
+        set(&mut state, &[("padding", "3"), ("label", "CPU")]);
+        assert_eq!(
+            recorded(&state),
+            pairs(&[("label", "CPU"), ("padding", "3")])
+        );
+
+        set(&mut state, &[("label", "")]);
+        assert_eq!(recorded(&state), pairs(&[("padding", "3")]));
+
+        set(&mut state, &[("font_size", "12"), ("toggle_hidden", "")]);
+        set(&mut state, &[("font_size", "+=2")]);
+        assert_eq!(
+            recorded(&state),
+            pairs(&[("font_size", "14"), ("hidden", "true"), ("padding", "3")])
+        );
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("a", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("b", NodeType::Row, Some("a"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("c", NodeType::Row, Some("b"), 1))
+            .unwrap();
+        let parent = |p: &str| HashMap::from([("parent".to_string(), p.to_string())]);
+
+        let err = state.set_properties("a", &parent("c")).unwrap_err();
+        assert_eq!(err, "would create a cycle: a -> c -> b -> a");
+        let err = state.set_properties("a", &parent("a")).unwrap_err();
+        assert_eq!(err, "cannot set 'a' as its own parent");
+        assert_eq!(state.find_node_ref("a").unwrap().parent, None);
+
+        state.set_properties("c", &parent("a")).unwrap();
+        assert_eq!(
+            state.find_node_ref("c").unwrap().parent.as_deref(),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn errors_carry_a_code_for_their_category() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        fn code<T: fmt::Debug>(result: Result<T, StateError>) -> ErrorCode {
+            result.unwrap_err().code
+        }
+
+        let parent = |p: &str| HashMap::from([("parent".to_string(), p.to_string())]);
+        assert_eq!(code(state.remove_node("ghost")), ErrorCode::NotFound);
+        assert_eq!(
+            code(state.add_node(make_node("row", NodeType::Row, None, 1))),
+            ErrorCode::AlreadyExists
+        );
+        assert_eq!(
+            code(state.set_properties("row", &parent("row"))),
+            ErrorCode::Cycle
+        );
+        assert_eq!(
+            code(state.add_node(make_node("clock", NodeType::Item, Some("row"), 2))),
+            ErrorCode::WrongDisplay
+        );
+        let props = |k: &str, v: &str| HashMap::from([(k.to_string(), v.to_string())]);
+        assert_eq!(
+            code(state.set_properties("row", &props("lable", "x"))),
+            ErrorCode::InvalidProperty
+        );
+        assert_eq!(
+            code(state.set_properties("row", &props("font_size", "big"))),
+            ErrorCode::InvalidValue
+        );
+    }
+
+    #[test]
+    fn add_rejects_parent_on_another_display() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+
+        let err = state
+            .add_node(make_node("clock", NodeType::Item, Some("row"), 2))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "parent 'row' is on display 1, but 'clock' is on display 2"
+        );
+        assert!(state.get_nodes_for_display(2).is_empty());
+
+        state
+            .add_node(make_node("row", NodeType::Row, None, 2))
+            .unwrap();
+        state
+            .add_node(make_node("clock", NodeType::Item, Some("row"), 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn relative_increment() {
+        let mut state = BarState::default();
+        let mut node = make_node("item1", NodeType::Item, None, 1);
+        node.style.padding_left = Some(4.0);
+        state.add_node(node).unwrap();
+
+        let props = HashMap::from([("padding_left".to_string(), "+=2".to_string())]);
+        let updated = state.set_properties("item1", &props).unwrap();
+        assert_eq!(updated.style.padding_left, Some(6.0));
+    }
+
+    #[test]
+    fn relative_decrement() {
+        let mut state = BarState::default();
+        let mut node = make_node("item1", NodeType::Item, None, 1);
+        node.font_size = Some(14.0);
+        state.add_node(node).unwrap();
+
+        let props = HashMap::from([("font_size".to_string(), "-=1.5".to_string())]);
+        let updated = state.set_properties("item1", &props).unwrap();
+        assert_eq!(updated.font_size, Some(12.5));
+    }
+
+    #[test]
+    fn relative_adjustment_of_unset_field() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+
+        let props = HashMap::from([
+            ("font_size".to_string(), "+=1".to_string()),
+            ("margin_left".to_string(), "-=3".to_string()),
+        ]);
+        let updated = state.set_properties("item1", &props).unwrap();
+        assert_eq!(updated.font_size, Some(DEFAULT_FONT_SIZE + 1.0));
+        assert_eq!(updated.style.margin_left, Some(-3.0));
+    }
+
+    #[test]
+    fn relative_adjustment_rejects_negative_result() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+
+        let props = HashMap::from([("border_width".to_string(), "-=1".to_string())]);
+        let err = state.set_properties("item1", &props).unwrap_err();
+        assert!(err.message.contains("out of range"));
+    }
+
+    #[test]
+    fn on_click_cwd_and_shell_round_trip() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+
+        let props = HashMap::from([
+            ("on_click_cwd".to_string(), "~/src".to_string()),
+            ("on_click_shell".to_string(), "/bin/zsh".to_string()),
+        ]);
+        let updated = state.set_properties("item1", &props).unwrap();
+        assert_eq!(updated.on_click_cwd.as_deref(), Some("~/src"));
+        assert_eq!(updated.on_click_shell.as_deref(), Some("/bin/zsh"));
+
+        let props = HashMap::from([
+            ("on_click_cwd".to_string(), String::new()),
+            ("on_click_shell".to_string(), String::new()),
+        ]);
+        let updated = state.set_properties("item1", &props).unwrap();
+        assert_eq!(updated.on_click_cwd, None);
+        assert_eq!(updated.on_click_shell, None);
+    }
+
+    #[test]
+    fn confirm_prompt_round_trip() {
+        let mut state = BarState::default();
+        let node = BarNode {
+            on_click: Some("pmset sleepnow".into()),
+            confirm: Some("Sleep now?".into()),
+            ..make_node("sleep", NodeType::Item, None, 1)
+        };
+        state.add_node(node).unwrap();
+        assert_eq!(state.get_nodes()[0].confirm.as_deref(), Some("Sleep now?"));
+
+        let props = HashMap::from([("confirm".to_string(), String::new())]);
+        let updated = state.set_properties("sleep", &props).unwrap();
+        assert_eq!(updated.confirm, None);
+    }
+
+    #[test]
+    fn click_commands_are_set_per_button() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("volume", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([
+            ("on_click".to_string(), "open-mixer".to_string()),
+            ("on_right_click".to_string(), "mute".to_string()),
+            ("on_middle_click".to_string(), "reset-volume".to_string()),
+        ]);
+        let node = state.set_properties("volume", &props).unwrap();
+        assert_eq!(node.on_click.as_deref(), Some("open-mixer"));
+        assert_eq!(node.on_right_click.as_deref(), Some("mute"));
+        assert_eq!(node.on_middle_click.as_deref(), Some("reset-volume"));
+
+        let props = HashMap::from([("on_right_click".to_string(), String::new())]);
+        let node = state.set_properties("volume", &props).unwrap();
+        assert_eq!(node.on_right_click, None);
+        assert_eq!(node.on_middle_click.as_deref(), Some("reset-volume"));
+        assert_eq!(
+            node.property_value("on_middle_click").as_deref(),
+            Some("reset-volume")
+        );
+    }
+
+    #[test]
+    fn blank_on_click_cwd_is_rejected() {
+        let mut state = BarState::default();
+        let mut node = make_node("item1", NodeType::Item, None, 1);
+        node.on_click_cwd = Some(String::new());
+        assert!(state.add_node(node).is_err());
+
+        state
+            .add_node(make_node("item2", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([("on_click_cwd".to_string(), "  ".to_string())]);
+        let err = state.set_properties("item2", &props).unwrap_err();
+        assert!(err.message.contains("on_click_cwd"));
+    }
+
+    #[test]
+    fn toggle_flips_hidden_and_disabled() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let toggle = HashMap::from([
+            ("toggle_hidden".to_string(), String::new()),
+            ("toggle_disabled".to_string(), String::new()),
+        ]);
+
+        let node = state.set_properties("clock", &toggle).unwrap();
+        assert!(node.hidden);
+        assert!(node.disabled);
+
+        let node = state.set_properties("clock", &toggle).unwrap();
+        assert!(!node.hidden);
+        assert!(!node.disabled);
+
+        let props = HashMap::from([
+            ("hidden".to_string(), "true".to_string()),
+            ("toggle_hidden".to_string(), String::new()),
+        ]);
+        let err = state.set_properties("clock", &props).unwrap_err();
+        assert_eq!(err, "hidden and toggle_hidden cannot be combined");
+    }
+
+    #[test]
+    fn reset_restores_defaults_for_listed_properties() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([
+            ("label".to_string(), "12:00".to_string()),
+            ("padding".to_string(), "4".to_string()),
+            ("height".to_string(), "20".to_string()),
+            ("label_color".to_string(), "#FF0000".to_string()),
+        ]);
+        let id = state.set_properties("clock", &props).unwrap().id;
+
+        let keys = vec!["padding_left".to_string(), "height".to_string()];
+        let node = state.reset_properties("clock", Some(&keys)).unwrap();
+        assert_eq!(node.style.padding_left, None);
+        assert_eq!(node.style.padding_right, Some(4.0));
+        assert_eq!(node.style.height, None);
+        assert_eq!(node.label_color.as_deref(), Some("#FF0000"));
+        let recorded = state.user_set(id);
+        assert!(!recorded.contains_key("padding") && !recorded.contains_key("height"));
+        assert_eq!(recorded["padding_right"], "4");
+
+        let node = state.reset_properties("clock", None).unwrap();
+        assert_eq!(node.style.padding_right, None);
+        assert_eq!(node.label_color, None);
+        assert_eq!(node.label.as_deref(), Some("12:00"));
+        assert_eq!(node.position, 0);
+        assert_eq!(state.user_set(id).keys().collect::<Vec<_>>(), ["label"]);
+
+        let keys = vec!["display".to_string()];
+        let err = state.reset_properties("clock", Some(&keys)).unwrap_err();
+        assert_eq!(err, "display cannot be reset; use set or move");
+        let keys = vec!["label".to_string(), "sparkle".to_string()];
+        let err = state.reset_properties("clock", Some(&keys)).unwrap_err();
+        assert_eq!(err, "unknown property: sparkle");
+        assert_eq!(
+            state.get_node("clock").unwrap().label.as_deref(),
+            Some("12:00")
+        );
+    }
+
+    #[test]
+    fn variables_resolve_and_propagate() {
+        let mut state = BarState::default();
+        let err = state.set_properties("ghost", &HashMap::new()).unwrap_err();
+        assert_eq!(err, "node 'ghost' not found");
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([("label_color".to_string(), "$accent".to_string())]);
+        let err = state.set_properties("clock", &props).unwrap_err();
+        assert_eq!(err, "undefined variable: $accent");
+
+        assert!(state.set_variable("accent", "#FF8000").unwrap().is_empty());
+        state.set_variable("$pad", "4").unwrap();
+        let props = HashMap::from([
+            ("label_color".to_string(), "$accent".to_string()),
+            ("padding".to_string(), "$pad".to_string()),
+            ("label".to_string(), "$5".to_string()),
+        ]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.label_color.as_deref(), Some("#FF8000"));
+        assert_eq!(node.style.padding_top, Some(4.0));
+        assert_eq!(node.label.as_deref(), Some("$5"));
+        assert_eq!(state.user_set(node.id)["label_color"], "#FF8000");
+
+        let mut badge = make_node("badge", NodeType::Item, None, 1);
+        badge.style.background_color = Some("$accent".into());
+        state.add_node(badge).unwrap();
+
+        let updated = state.set_variable("accent", "#00FF00").unwrap();
+        let names: Vec<&str> = updated.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["clock", "badge"]);
+        assert_eq!(updated[0].label_color.as_deref(), Some("#00FF00"));
+        assert_eq!(
+            state
+                .get_node("badge")
+                .unwrap()
+                .style
+                .background_color
+                .as_deref(),
+            Some("#00FF00")
+        );
+
+        let updated = state.set_variable("pad", "6").unwrap();
+        assert_eq!(updated[0].style.padding_left, Some(6.0));
+
+        let err = state.set_variable("pad", "wide").unwrap_err();
+        assert!(
+            err.message
+                .starts_with("$pad is used by 'clock': invalid padding_"),
+            "{err}"
+        );
+        assert_eq!(
+            state.get_node("clock").unwrap().style.padding_left,
+            Some(6.0)
+        );
+
+        let props = HashMap::from([("label_color".to_string(), "#123456".to_string())]);
+        state.set_properties("clock", &props).unwrap();
+        let updated = state.set_variable("accent", "#FFFFFF").unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].name, "badge");
+    }
+
+    #[test]
+    fn previous_values_capture_state_before_set() {
+        let mut state = BarState::default();
+        let node = BarNode {
+            label: Some("old".into()),
+            ..make_node("clock", NodeType::Item, None, 1)
+        };
+        state.add_node(node).unwrap();
+
+        let props = HashMap::from([
+            ("label".to_string(), "new".to_string()),
+            ("padding_horizontal".to_string(), "4".to_string()),
+        ]);
+        let previous = state.previous_values("clock", &props).unwrap();
+        state.set_properties("clock", &props).unwrap();
+
+        assert_eq!(previous["label"], "old");
+        assert_eq!(previous["padding_left"], "");
+        assert_eq!(previous["padding_right"], "");
+        assert_eq!(previous.len(), 3);
+    }
+
+    #[test]
+    fn clear_single_display() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("item2", NodeType::Item, None, 2))
+            .unwrap();
+
+        assert_eq!(state.clear(Some(1)), vec![1]);
+        let remaining: Vec<String> = state.get_nodes().into_iter().map(|n| n.name).collect();
+        assert_eq!(remaining, vec!["item2"]);
+        assert!(state.clear(Some(1)).is_empty());
+    }
+
+    #[test]
+    fn clear_all_displays() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("item2", NodeType::Item, None, 2))
+            .unwrap();
+
+        let mut cleared = state.clear(None);
+        cleared.sort();
+        assert_eq!(cleared, vec![1, 2]);
+        assert!(state.get_nodes().is_empty());
+    }
+
+    #[test]
+    fn display_cycling_leaves_no_empty_entries() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("pinned", NodeType::Item, None, 1))
+            .unwrap();
+        for cycle in 0..10 {
+            let external = 100 + cycle;
+            state
+                .add_node(make_node("clock", NodeType::Item, None, external))
+                .unwrap();
+            state.migrate_nodes(external, 1);
+            let props = HashMap::from([("display".to_string(), external.to_string())]);
+            state.set_properties("clock", &props).unwrap();
+            state.migrate_nodes(2, 1);
+            state.remove_node("clock").unwrap();
+        }
+
+        assert_eq!(state.nodes.len(), 1);
+        assert!(state.nodes.values().all(|nodes| !nodes.is_empty()));
+    }
+
+    #[test]
+    fn failed_set_leaves_node_on_its_display_unchanged() {
+        let mut state = BarState::default();
+        state
+            .add_node(BarNode {
+                display_explicit: true,
+                ..make_node("clock", NodeType::Item, None, 1)
+            })
+            .unwrap();
+        let before = state.get_node("clock").unwrap();
+
+        let props = HashMap::from([
+            ("display".to_string(), "2".to_string()),
+            ("label".to_string(), "12:00".to_string()),
+            ("padding_left".to_string(), "wide".to_string()),
+        ]);
+        assert!(state.set_properties("clock", &props).is_err());
+        let props = HashMap::from([
+            ("display".to_string(), String::new()),
+            ("padding".to_string(), "4".to_string()),
+            ("padding_left".to_string(), "wide".to_string()),
+        ]);
+        assert!(state.set_properties("clock", &props).is_err());
+
+        let after = state.get_node("clock").unwrap();
+        assert_eq!(after.display, 1);
+        assert!(after.display_explicit);
+        assert_eq!(after.label, before.label);
+        assert_eq!(after.style.padding_right, None);
+        assert_eq!(state.displays(), [1]);
+        assert_eq!(state.names["clock"], [1]);
+    }
+
+    #[test]
+    fn move_node_reparents_and_appends() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("left", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("right", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("existing", NodeType::Item, Some("right"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("clock", NodeType::Item, Some("left"), 1))
+            .unwrap();
+
+        let moved = state.move_node("clock", Some("right"), None).unwrap();
+        assert_eq!(moved.parent.as_deref(), Some("right"));
+        assert_eq!(moved.position, 1);
+
+        let moved = state.move_node("clock", None, Some(5)).unwrap();
+        assert_eq!(moved.parent, None);
+        assert_eq!(moved.position, 5);
+    }
+
+    #[test]
+    fn move_node_rejects_invalid_parents() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("outer", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("inner", NodeType::Column, Some("outer"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("item", NodeType::Item, Some("inner"), 1))
+            .unwrap();
+        state
+            .add_node(make_node("elsewhere", NodeType::Row, None, 2))
+            .unwrap();
+
+        let err = |parent| {
+            state
+                .clone()
+                .move_node("outer", Some(parent), None)
+                .unwrap_err()
+        };
+        assert_eq!(err("ghost"), "parent 'ghost' not found");
+        assert_eq!(err("item"), "'item' is an item and cannot have children");
+        assert_eq!(
+            err("elsewhere"),
+            "parent 'elsewhere' is on display 2, but 'outer' is on display 1"
+        );
+        assert_eq!(
+            err("inner"),
+            "would create a cycle: outer -> inner -> outer"
+        );
+        assert_eq!(err("outer"), "cannot move 'outer' into itself");
+
+        // An existing cycle elsewhere must not hang the ancestor walk
+        for (name, parent) in [("outer", "inner"), ("inner", "outer")] {
+            let nodes = state.nodes.get_mut(&1).unwrap();
+            nodes.iter_mut().find(|n| n.name == name).unwrap().parent = Some(parent.into());
+        }
+        state
+            .add_node(make_node("loose", NodeType::Item, None, 1))
+            .unwrap();
+        state.move_node("loose", Some("outer"), None).unwrap();
+    }
+
+    #[test]
+    fn reorder_assigns_positions_in_list_order() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        for name in ["a", "b", "c"] {
+            state
+                .add_node(make_node(name, NodeType::Item, Some("row"), 1))
+                .unwrap();
+        }
+
+        let order = ["c", "a", "b"].map(String::from);
+        state.reorder(Some("row"), 1, &order).unwrap();
+
+        let children: Vec<(String, i32)> = state
+            .get_nodes_for_display(1)
+            .into_iter()
+            .filter(|n| n.parent.is_some())
+            .map(|n| (n.name, n.position))
+            .collect();
+        assert_eq!(
+            children,
+            vec![("c".into(), 0), ("a".into(), 1), ("b".into(), 2)]
+        );
+    }
+
+    #[test]
+    fn reorder_moves_unlisted_siblings_after_listed_ones() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        for name in ["a", "b", "c", "d"] {
+            state
+                .add_node(make_node(name, NodeType::Item, Some("row"), 1))
+                .unwrap();
+        }
+
+        state
+            .reorder(Some("row"), 1, &["c".into(), "a".into()])
+            .unwrap();
+
+        let children: Vec<(String, i32)> = state
+            .get_children("row")
+            .into_iter()
+            .map(|n| (n.name, n.position))
+            .collect();
+        assert_eq!(
+            children,
+            vec![
+                ("c".into(), 0),
+                ("a".into(), 1),
+                ("b".into(), 2),
+                ("d".into(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn reorder_rejects_missing_or_foreign_nodes() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        state
+            .add_node(make_node("a", NodeType::Item, Some("row"), 1))
+            .unwrap();
+
+        let err = state
+            .reorder(Some("row"), 1, &["a".into(), "ghost".into()])
+            .unwrap_err();
+        assert_eq!(err, "node 'ghost' not found on display 1");
+        let err = state
+            .reorder(Some("row"), 1, &["a".into(), "row".into()])
+            .unwrap_err();
+        assert_eq!(err, "'row' is not a child of 'row'");
+        let err = state.reorder(None, 1, &["a".into()]).unwrap_err();
+        assert_eq!(err, "'a' is not a top-level node");
+    }
+
+    #[test]
+    fn node_inherits_unset_fields_from_template() {
+        let mut state = BarState::default();
+        let properties = HashMap::from([
+            ("background_color".to_string(), "#000000".to_string()),
+            ("corner_radius".to_string(), "8".to_string()),
+            ("label_color".to_string(), "#ffffff".to_string()),
+        ]);
+        state.define_template("pill", &properties).unwrap();
+
+        let node = BarNode {
+            template: Some("pill".into()),
+            label_color: Some("#ff0000".into()),
+            ..make_node("item1", NodeType::Item, None, 1)
+        };
+        state.add_node(node.clone()).unwrap();
+
+        let resolved = state.effective_node(&node);
+        assert_eq!(resolved.style.background_color.as_deref(), Some("#000000"));
+        assert_eq!(resolved.style.corner_radius, Some(8.0));
+        assert_eq!(resolved.label_color.as_deref(), Some("#ff0000"));
+        assert_eq!(state.effective_style(&node).corner_radius, Some(8.0));
+    }
+
+    #[test]
+    fn unknown_template_is_rejected() {
+        let mut state = BarState::default();
+        let node = BarNode {
+            template: Some("missing".into()),
+            ..make_node("item1", NodeType::Item, None, 1)
+        };
+        assert_eq!(
+            state.add_node(node).unwrap_err(),
+            "template 'missing' not found"
+        );
+    }
+
+    #[test]
+    fn node_is_found_by_id_after_rename() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("bar", NodeType::Row, None, 1))
+            .unwrap();
+        let id = state
+            .add_node(make_node("clock", NodeType::Item, Some("bar"), 1))
+            .unwrap();
+
+        state.rename_node("clock", "time").unwrap();
+        state.rename_node("bar", "topbar").unwrap();
+
+        let node = state.get_node_by_id(id).unwrap();
+        assert_eq!(node.name, "time");
+        assert_eq!(node.parent.as_deref(), Some("topbar"));
+
+        state.remove_node("topbar").unwrap();
+        assert!(state.get_node_by_id(id).is_none());
+    }
+
+    #[test]
+    fn rename_repoints_children_on_its_own_display_only() {
+        let mut state = BarState::default();
+        for display in [1, 2] {
+            state
+                .add_node(make_node("row", NodeType::Row, None, display))
+                .unwrap();
+        }
+        let near = state
+            .add_node(make_node("a", NodeType::Item, Some("row"), 1))
+            .unwrap();
+        let far = state
+            .add_node(make_node("b", NodeType::Item, Some("row"), 2))
+            .unwrap();
+
+        state.rename_node("row", "group").unwrap();
+
+        let parent = |id| state.get_node_by_id(id).unwrap().parent;
+        assert_eq!(parent(near).as_deref(), Some("group"));
+        assert_eq!(parent(far).as_deref(), Some("row"));
+        assert_eq!(state.node_display("row"), Some(2));
+    }
+
+    #[test]
+    fn icon_accepts_sf_symbol_names() {
+        let mut state = BarState::default();
+        let node = BarNode {
+            icon: Some("battery.100.bolt".into()),
+            ..make_node("battery", NodeType::Item, None, 1)
+        };
+        state.add_node(node).unwrap();
+
+        let props = HashMap::from([("icon".to_string(), "wifi".to_string())]);
+        let node = state.set_properties("battery", &props).unwrap();
+        assert_eq!(node.icon.as_deref(), Some("wifi"));
+    }
+
+    #[test]
+    fn icon_with_spaces_is_rejected() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("battery", NodeType::Item, None, 1))
+            .unwrap();
+
+        let props = HashMap::from([("icon".to_string(), "battery 100".to_string())]);
+        let err = state.set_properties("battery", &props).unwrap_err();
+        assert!(err.message.starts_with("invalid icon: \"battery 100\""));
+
+        let node = BarNode {
+            icon: Some("Wifi..fill".into()),
+            ..make_node("wifi", NodeType::Item, None, 1)
+        };
+        assert!(state.add_node(node).is_err());
+    }
+
+    #[test]
+    fn outline_round_trip() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("focus", NodeType::Item, None, 1))
+            .unwrap();
+
+        let props = HashMap::from([
+            ("outline_color".to_string(), "#0A84FFCC".to_string()),
+            ("outline_width".to_string(), "2".to_string()),
+            ("outline_offset".to_string(), "-1".to_string()),
+        ]);
+        let node = state.set_properties("focus", &props).unwrap();
+        assert_eq!(node.style.outline_color.as_deref(), Some("#0A84FFCC"));
+        assert_eq!(node.style.outline_width, Some(2.0));
+        assert_eq!(node.style.outline_offset, Some(-1.0));
+        assert_eq!(node.style.border_width, None);
+
+        let props = HashMap::from([
+            ("outline_color".to_string(), String::new()),
+            ("outline_width".to_string(), String::new()),
+            ("outline_offset".to_string(), String::new()),
+        ]);
+        let node = state.set_properties("focus", &props).unwrap();
+        assert_eq!(node.style.outline_color, None);
+        assert_eq!(node.style.outline_width, None);
+        assert_eq!(node.style.outline_offset, None);
+    }
+
+    #[test]
+    fn parse_color_accepts_hex_forms_only() {
+        assert_eq!(parse_color("label_color", "#fc0").unwrap(), "#FFCC00");
+        assert_eq!(parse_color("label_color", "#FFCC00").unwrap(), "#FFCC00");
+        assert_eq!(parse_color("label_color", "#ffcc00").unwrap(), "#FFCC00");
+        assert_eq!(parse_color("label_color", "FFCC0080").unwrap(), "#FFCC0080");
+        assert_eq!(parse_color("label_color", "Yellow").unwrap(), "#FFFF00");
+        for value in ["#GGGGGG", "reddd", "#12345", "#FFCC00F", "", "#"] {
+            assert_eq!(
+                parse_color("border_color", value).unwrap_err(),
+                format!(
+                    "invalid color for border_color: {value} (expected #RGB, #RRGGBB or #RRGGBBAA)"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn every_color_property_is_validated() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let mut node = state.get_node("clock").unwrap();
+        let keys: Vec<&str> = node.color_fields().iter().map(|(key, _)| *key).collect();
+        for key in keys {
+            let props = HashMap::from([(key.to_string(), "#GGGGGG".to_string())]);
+            let err = state.set_properties("clock", &props).unwrap_err();
+            assert!(
+                err.message
+                    .starts_with(&format!("invalid color for {key}: ")),
+                "{err}"
+            );
+            let props = HashMap::from([(key.to_string(), "#0af".to_string())]);
+            let node = state.set_properties("clock", &props).unwrap();
+            assert_eq!(node.property_value(key).as_deref(), Some("#00AAFF"));
+        }
+
+        let props = HashMap::from([("label_color".to_string(), "Orange".to_string())]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.label_color.as_deref(), Some("#FFA500"));
+
+        let mut node = make_node("cpu", NodeType::Item, None, 1);
+        node.label_color = Some("#abc".into());
+        state.add_node(node).unwrap();
+        assert_eq!(
+            state.get_node("cpu").unwrap().label_color.as_deref(),
+            Some("#AABBCC")
+        );
+        let mut node = make_node("mem", NodeType::Item, None, 1);
+        node.style.hover_icon_color = Some("tealish".into());
+        assert_eq!(
+            state.add_node(node).unwrap_err(),
+            "invalid color for hover_icon_color: tealish (expected #RGB, #RRGGBB or #RRGGBBAA)"
+        );
+    }
+
+    #[test]
+    fn template_properties_lists_only_what_each_template_sets() {
+        let mut state = BarState::default();
+        let props = HashMap::from([
+            ("background_color".to_string(), "#0a0a0fdd".to_string()),
+            ("padding".to_string(), "4".to_string()),
+            ("hidden".to_string(), "true".to_string()),
+        ]);
+        state.define_template("pill", &props).unwrap();
+        state.define_template("plain", &HashMap::new()).unwrap();
+
+        let templates = state.template_properties();
+        assert_eq!(templates.keys().collect::<Vec<_>>(), ["pill", "plain"]);
+        let pill: Vec<(&str, &str)> = templates["pill"]
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            pill,
+            [
+                ("background_color", "#0A0A0FDD"),
+                ("hidden", "true"),
+                ("padding_bottom", "4"),
+                ("padding_left", "4"),
+                ("padding_right", "4"),
+                ("padding_top", "4"),
+            ]
+        );
+        assert!(templates["plain"].is_empty());
+    }
+
+    #[test]
+    fn query_filtered_requires_every_condition() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .unwrap();
+        for (name, label) in [("wifi", Some("on")), ("vpn", None), ("cpu", None)] {
+            let mut node = make_node(name, NodeType::Item, Some("row1"), 1);
+            node.label = label.map(String::from);
+            state.add_node(node).unwrap();
+        }
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 2))
+            .unwrap();
+        let names = |filter: &[&str]| -> Result<Vec<String>, StateError> {
+            let filter: Vec<String> = filter.iter().map(|c| c.to_string()).collect();
+            let mut names: Vec<String> = state
+                .query_filtered(state.get_nodes(), &filter)?
+                .into_iter()
+                .map(|n| n.name)
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+
+        assert_eq!(
+            names(&["node_type=item", "label="]).unwrap(),
+            ["clock", "cpu", "vpn"]
+        );
+        assert_eq!(
+            names(&["node_type=item", "label=", "parent=row1"]).unwrap(),
+            ["cpu", "vpn"]
+        );
+        assert_eq!(names(&["display=2"]).unwrap(), ["clock"]);
+        assert_eq!(names(&[]).unwrap().len(), 5);
+        let err = names(&["lable="]).unwrap_err();
+        assert_eq!(err, "unknown filter key: lable");
+        assert_eq!(err.code, ErrorCode::InvalidProperty);
+        let err = names(&["label"]).unwrap_err();
+        assert_eq!(err, "invalid filter: label (expected key=value)");
+        assert_eq!(err.code, ErrorCode::InvalidValue);
+    }
+
+    #[test]
+    fn query_filtered_compares_colors_by_value() {
+        let mut state = BarState::default();
+        let mut wifi = make_node("wifi", NodeType::Item, None, 1);
+        wifi.label_color = Some("#ff0000".into());
+        state.add_node(wifi).unwrap();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let names = |condition: &str| -> Result<Vec<String>, StateError> {
+            let nodes = state.query_filtered(state.get_nodes(), &[condition.to_string()])?;
+            Ok(nodes.into_iter().map(|n| n.name).collect())
+        };
+
+        for condition in ["label_color=red", "label_color=#ff0000", "label_color=F00"] {
+            assert_eq!(names(condition).unwrap(), ["wifi"], "{condition}");
+        }
+        assert_eq!(names("label_color=").unwrap(), ["clock"]);
+        assert!(names("label_color=blue").unwrap().is_empty());
+        let err = names("label_color=reddish").unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidValue);
+    }
+
+    #[test]
+    fn letter_spacing_may_be_negative() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, key: &str, value: &str| {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            state.set_properties("clock", &props)
+        };
+
+        assert_eq!(
+            set(&mut state, "letter_spacing", "-0.5")
+                .unwrap()
+                .letter_spacing,
+            Some(-0.5)
+        );
+        assert_eq!(
+            set(&mut state, "letter_spacing", "-=1")
+                .unwrap()
+                .letter_spacing,
+            Some(-1.5)
+        );
+        assert_eq!(
+            set(&mut state, "line_height", "+=0.25")
+                .unwrap()
+                .line_height,
+            Some(1.25)
+        );
+        assert_eq!(
+            set(&mut state, "line_height", "-=2").unwrap_err(),
+            "line_height out of range: -0.75"
+        );
+        assert_eq!(
+            set(&mut state, "line_height", "-1").unwrap_err(),
+            "line_height out of range: -1"
+        );
+        state
+            .reset_properties("clock", Some(&["letter_spacing".to_string()]))
+            .unwrap();
+        let node = state.get_node("clock").unwrap();
+        assert_eq!((node.letter_spacing, node.line_height), (None, Some(1.25)));
+    }
+
+    #[test]
+    fn font_style_and_decorations_set_and_clear() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, key: &str, value: &str| {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            state.set_properties("clock", &props)
+        };
+
+        let node = set(&mut state, "font_style", "italic").unwrap();
+        assert_eq!(node.font_style.as_deref(), Some("italic"));
+        assert_eq!(
+            set(&mut state, "font_style", "oblique").unwrap_err(),
+            "invalid font_style: oblique (expected one of normal, italic)"
+        );
+        assert_eq!(
+            set(&mut state, "underline", "true").unwrap().underline,
+            Some(true)
+        );
+        let node = set(&mut state, "strikethrough", "false").unwrap();
+        assert_eq!(node.strikethrough, Some(false));
+        assert_eq!(node.property_value("strikethrough").unwrap(), "false");
+        assert_eq!(
+            set(&mut state, "underline", "yes").unwrap_err(),
+            "invalid underline: yes"
+        );
+        assert_eq!(set(&mut state, "underline", "").unwrap().underline, None);
+
+        let mut italic = make_node("date", NodeType::Item, None, 1);
+        italic.font_style = Some("slanted".into());
+        assert!(state.add_node(italic).is_err());
+    }
+
+    #[test]
+    fn schema_types_match_what_set_accepts() {
+        let mut state = BarState::default();
+        for node_type in [
+            NodeType::Item,
+            NodeType::Row,
+            NodeType::Column,
+            NodeType::Box,
+            NodeType::Progress,
+        ] {
+            state
+                .add_node(make_node(node_type.as_str(), node_type, None, 1))
+                .unwrap();
+        }
+        let set = |state: &mut BarState, name: &str, key: &str, value: &str| {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            state.set_properties(name, &props)
+        };
+        for key in PROPERTY_KEYS {
+            let kind = property_type(key);
+            let (accepted, rejected): (Vec<&str>, &[&str]) = match kind.as_str() {
+                "color" => (vec!["#ff0000", "red"], &["reddish"]),
+                "float" => (vec!["1", "0.5"], &["wide"]),
+                "int" => (vec!["1"], &["0.5", "wide"]),
+                "int|string" => (vec!["1"], &["Nowhere"]),
+                "dimension" => (vec!["1.5", "fill", "50%"], &["wide"]),
+                "bool" => (vec!["true", "false"], &["yes"]),
+                "flag" | "string" => (vec![], &[]),
+                kind => {
+                    let values = kind.strip_prefix("enum[").unwrap().trim_end_matches(']');
+                    (values.split(',').collect(), &["bogus"])
+                }
+            };
+            for node_type in property_node_types(key) {
+                for value in &accepted {
+                    if let Err(e) = set(&mut state, node_type.as_str(), key, value) {
+                        panic!("{key}={value} on {node_type:?}: {e}");
+                    }
+                }
+                for value in rejected {
+                    assert!(
+                        set(&mut state, node_type.as_str(), key, value).is_err(),
+                        "{key}={value} on {node_type:?} should be rejected"
+                    );
                 }
-                "display" => {}
-                // Shorthands handled in pass 1
-                "padding" | "padding_horizontal" | "padding_vertical" | "margin"
-                | "margin_horizontal" | "margin_vertical" => {}
-                _ => return Err(format!("unknown property: {}", key)),
             }
         }
-        Ok(())
+        assert_eq!(property_type("display"), "int|string");
+        assert_eq!(property_node_types("image").len(), 4);
     }
 
-    fn find_node(&self, name: &str) -> Result<(u32, usize), String> {
-        for (&display, nodes) in &self.nodes {
-            if let Some(pos) = nodes.iter().position(|n| n.name == name) {
-                return Ok((display, pos));
-            }
-        }
-        Err(format!("node '{}' not found", name))
+    #[test]
+    fn shadow_offsets_accept_negative_values() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("pill", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([
+            ("shadow_offset_x".to_string(), "-2".to_string()),
+            ("shadow_offset_y".to_string(), "3".to_string()),
+        ]);
+        let node = state.set_properties("pill", &props).unwrap();
+        assert_eq!(
+            (node.style.shadow_offset_x, node.style.shadow_offset_y),
+            (Some(-2.0), Some(3.0))
+        );
+        assert_eq!(
+            node.property_value("shadow_offset_x").as_deref(),
+            Some("-2")
+        );
+
+        let props = HashMap::from([("shadow_offset_y".to_string(), "-=4".to_string())]);
+        let node = state.set_properties("pill", &props).unwrap();
+        assert_eq!(node.style.shadow_offset_y, Some(-1.0));
     }
 
-    fn find_node_ref(&self, name: &str) -> Option<&BarNode> {
-        for nodes in self.nodes.values() {
-            if let Some(node) = nodes.iter().find(|n| n.name == name) {
-                return Some(node);
-            }
+    #[test]
+    fn swap_exchanges_sibling_positions() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("row", NodeType::Row, None, 1))
+            .unwrap();
+        for name in ["wifi", "vpn", "battery"] {
+            state
+                .add_node(make_node(name, NodeType::Item, Some("row"), 1))
+                .unwrap();
         }
-        None
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+
+        let (a, b) = state.swap_positions("wifi", "battery").unwrap();
+        assert_eq!((a.position, b.position), (2, 0));
+        let order: Vec<String> = state
+            .get_children("row")
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        assert_eq!(order, ["battery", "vpn", "wifi"]);
+
+        let err = state.swap_positions("wifi", "clock").unwrap_err();
+        assert_eq!(err, "'wifi' and 'clock' are not siblings");
+        assert_eq!(err.code, ErrorCode::WrongDisplay);
+        assert!(state.swap_positions("wifi", "wifi").is_err());
+        assert!(state.swap_positions("wifi", "nope").is_err());
     }
 
-    pub fn migrate_nodes(&mut self, from_display: u32, to_display: u32) -> Vec<BarNode> {
-        let Some(nodes) = self.nodes.get_mut(&from_display) else {
-            return vec![];
-        };
+    #[test]
+    fn display_defaults_fill_in_what_new_nodes_leave_unset() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("before", NodeType::Item, None, 2))
+            .unwrap();
+        let props = HashMap::from([
+            ("font_size".to_string(), "11".to_string()),
+            ("label_color".to_string(), "#ccc".to_string()),
+            ("padding".to_string(), "2".to_string()),
+        ]);
+        state.set_display_defaults(2, &props).unwrap();
 
-        let mut staying_names: std::collections::HashSet<String> = nodes
-            .iter()
-            .filter(|n| n.display_explicit)
-            .map(|n| n.name.clone())
-            .collect();
+        let mut clock = make_node("clock", NodeType::Item, None, 2);
+        clock.font_size = Some(14.0);
+        state.add_node(clock).unwrap();
+        state
+            .add_node(make_node("other", NodeType::Item, None, 1))
+            .unwrap();
 
-        loop {
-            let mut changed = false;
-            for node in nodes.iter() {
-                if staying_names.contains(&node.name) {
-                    continue;
-                }
-                if let Some(ref parent) = node.parent
-                    && staying_names.contains(parent)
-                {
-                    staying_names.insert(node.name.clone());
-                    changed = true;
-                }
-            }
-            if !changed {
-                break;
-            }
+        let clock = state.get_node("clock").unwrap();
+        assert_eq!(clock.font_size, Some(14.0));
+        assert_eq!(clock.label_color.as_deref(), Some("#CCCCCC"));
+        assert_eq!(clock.style.padding_left, Some(2.0));
+        assert_eq!(state.get_node("before").unwrap().font_size, None);
+        assert_eq!(state.get_node("other").unwrap().font_size, None);
+        assert_eq!(state.display_defaults()[&2]["font_size"], "11");
+
+        let props = HashMap::from([("position".to_string(), "1".to_string())]);
+        assert!(state.set_display_defaults(2, &props).is_err());
+        for (key, value) in [("hidden", "true"), ("disabled", "true"), ("value", "50")] {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            let err = state.set_display_defaults(2, &props).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidProperty, "{key}");
         }
+        assert_eq!(state.display_defaults()[&2]["font_size"], "11");
+        state.set_display_defaults(2, &HashMap::new()).unwrap();
+        assert!(state.display_defaults().is_empty());
+    }
 
-        let mut migrate = vec![];
-        nodes.retain(|node| {
-            if staying_names.contains(&node.name) {
-                true
-            } else {
-                migrate.push(node.clone());
-                false
-            }
-        });
+    #[test]
+    fn display_defaults_sit_below_the_template() {
+        let mut state = BarState::default();
+        let template = HashMap::from([("label_color".to_string(), "red".to_string())]);
+        state.define_template("pill", &template).unwrap();
+        let defaults = HashMap::from([
+            ("label_color".to_string(), "#ccc".to_string()),
+            ("font_size".to_string(), "11".to_string()),
+        ]);
+        state.set_display_defaults(1, &defaults).unwrap();
 
-        for node in &mut migrate {
-            node.display = to_display;
-        }
+        let mut clock = make_node("clock", NodeType::Item, None, 1);
+        clock.template = Some("pill".into());
+        state.add_node(clock).unwrap();
 
-        if nodes.is_empty() {
-            self.nodes.remove(&from_display);
-        }
+        let clock = state.effective_node(&state.get_node("clock").unwrap());
+        assert_eq!(clock.label_color.as_deref(), Some("#FF0000"));
+        assert_eq!(clock.font_size, Some(11.0));
+    }
 
-        let target = self.nodes.entry(to_display).or_default();
-        target.extend(migrate.iter().cloned());
-        target.sort_by_key(|n| n.position);
+    #[test]
+    fn align_self_accepts_only_start_center_end() {
+        let mut state = BarState::default();
+        let mut node = make_node("badge", NodeType::Item, None, 1);
+        node.style.align_self = Some("top".into());
+        assert_eq!(
+            state.add_node(node).unwrap_err(),
+            "invalid align_self: top (expected one of start, center, end)"
+        );
 
-        migrate
+        state
+            .add_node(make_node("badge", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, value: &str| {
+            let props = HashMap::from([("align_self".to_string(), value.to_string())]);
+            state.set_properties("badge", &props)
+        };
+        assert_eq!(
+            set(&mut state, "start")
+                .unwrap()
+                .style
+                .align_self
+                .as_deref(),
+            Some("start")
+        );
+        assert!(set(&mut state, "stretch").is_err());
+        assert_eq!(set(&mut state, "").unwrap().style.align_self, None);
     }
 
-    pub fn get_nodes(&self) -> Vec<BarNode> {
-        self.nodes.values().flatten().cloned().collect()
+    #[test]
+    fn invalid_outline_is_rejected() {
+        let mut state = BarState::default();
+        let mut node = make_node("focus", NodeType::Item, None, 1);
+        node.style.outline_color = Some("bleu".into());
+        assert!(state.add_node(node).is_err());
+        let mut node = make_node("focus", NodeType::Item, None, 1);
+        node.style.outline_width = Some(-1.0);
+        assert!(state.add_node(node).is_err());
+
+        state
+            .add_node(make_node("focus", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([("outline_color".to_string(), "#12345".to_string())]);
+        let err = state.set_properties("focus", &props).unwrap_err();
+        assert!(
+            err.message
+                .starts_with("invalid color for outline_color: #12345")
+        );
+        let props = HashMap::from([("outline_width".to_string(), "-=1".to_string())]);
+        let err = state.set_properties("focus", &props).unwrap_err();
+        assert!(err.message.contains("out of range"));
     }
 
-    pub fn get_nodes_for_display(&self, display: u32) -> Vec<BarNode> {
-        self.nodes.get(&display).cloned().unwrap_or_default()
+    #[test]
+    fn image_content_mode_accepts_known_modes() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("logo", NodeType::Item, None, 1))
+            .unwrap();
+        for mode in IMAGE_CONTENT_MODES {
+            let props = HashMap::from([("image_content_mode".to_string(), mode.to_string())]);
+            let node = state.set_properties("logo", &props).unwrap();
+            assert_eq!(node.image_content_mode.as_deref(), Some(mode));
+        }
+        let props = HashMap::from([("image_content_mode".to_string(), String::new())]);
+        assert!(
+            state
+                .set_properties("logo", &props)
+                .unwrap()
+                .image_content_mode
+                .is_none()
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn invalid_image_content_mode_is_rejected() {
+        let mut state = BarState::default();
+        let node = BarNode {
+            image_content_mode: Some("zoom".into()),
+            ..make_node("logo", NodeType::Item, None, 1)
+        };
+        assert!(state.add_node(node).is_err());
 
-    fn make_node(name: &str, node_type: NodeType, parent: Option<&str>, display: u32) -> BarNode {
-        BarNode {
-            name: name.to_string(),
-            node_type,
-            parent: parent.map(|s| s.to_string()),
-            position: 0,
-            display,
-            display_explicit: false,
-            style: NodeStyle::default(),
-            label: None,
-            label_color: None,
-            icon: None,
-            icon_color: None,
-            font_size: None,
-            font_weight: None,
-            font_family: None,
-            on_click: None,
-            image: None,
-            image_scale: None,
+        state
+            .add_node(make_node("logo", NodeType::Item, None, 1))
+            .unwrap();
+        let props = HashMap::from([("image_content_mode".to_string(), "zoom".to_string())]);
+        let err = state.set_properties("logo", &props).unwrap_err();
+        assert!(err.message.starts_with("invalid image_content_mode: zoom"));
+    }
+
+    #[test]
+    fn display_width_counts_wide_characters_twice() {
+        assert_eq!(display_width("12:30"), 5);
+        assert_eq!(display_width("東京"), 4);
+        assert_eq!(display_width("ｶﾅ"), 2);
+        assert_eq!(display_width("🔋 80%"), 6);
+        assert_eq!(display_width("👍🏽"), 2);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("क्ष"), 2);
+        assert_eq!(display_width("\u{1F9CB}"), 2);
+
+        let node = BarNode {
+            label: Some("晴れ 21°".to_string()),
+            ..BarNode::new("weather", NodeType::Item)
+        };
+        assert_eq!(node.label_display_width(), Some(8));
+        assert_eq!(
+            BarNode::new("empty", NodeType::Item).label_display_width(),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_property_suggests_near_misses() {
+        let mut state = BarState::default();
+        state
+            .add_node(make_node("clock", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, key: &str| {
+            let props = HashMap::from([(key.to_string(), "4".to_string())]);
+            state.set_properties("clock", &props).unwrap_err()
+        };
+
+        assert_eq!(
+            set(&mut state, "paddng"),
+            "unknown property: paddng (did you mean 'padding'?)"
+        );
+        assert_eq!(
+            set(&mut state, "backgroud_color"),
+            "unknown property: backgroud_color (did you mean 'background_color'?)"
+        );
+        assert_eq!(set(&mut state, "sparkle"), "unknown property: sparkle");
+        assert_eq!(set(&mut state, "xyz"), "unknown property: xyz");
+    }
+
+    #[test]
+    fn every_suggested_property_is_accepted() {
+        for key in PROPERTY_KEYS {
+            let mut node = BarNode::new("clock", NodeType::Item);
+            let props = HashMap::from([(key.to_string(), String::new())]);
+            if let Err(e) = BarState::apply_properties(&mut node, &props) {
+                assert!(!e.message.starts_with("unknown property"), "{key}: {e}");
+            }
         }
     }
 
     #[test]
-    fn remove_item_node() {
+    fn min_width_may_not_exceed_max_width() {
         let mut state = BarState::default();
         state
-            .add_node(make_node("item1", NodeType::Item, None, 1))
+            .add_node(make_node("clock", NodeType::Item, None, 1))
             .unwrap();
+        let set = |state: &mut BarState, props: &[(&str, &str)]| {
+            let props = props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            state.set_properties("clock", &props)
+        };
 
-        let removed = state.remove_node("item1").unwrap();
-        assert_eq!(removed.name, "item1");
-        assert!(state.get_nodes().is_empty());
+        let node = set(&mut state, &[("min_width", "60"), ("max_width", "80")]).unwrap();
+        assert_eq!(node.style.min_width, Some(60.0));
+        assert_eq!(node.style.max_width, Some(80.0));
+        assert_eq!(
+            set(&mut state, &[("min_width", "+=30")]).unwrap_err(),
+            "min_width 90 exceeds max_width 80"
+        );
+        assert_eq!(state.get_node("clock").unwrap().style.min_width, Some(60.0));
+        let node = set(&mut state, &[("min_width", "90"), ("max_width", "")]).unwrap();
+        assert_eq!(node.style.max_width, None);
+        assert!(set(&mut state, &[("max_width", "-1")]).is_err());
+
+        let mut node = make_node("cpu", NodeType::Item, None, 1);
+        node.style.min_width = Some(50.0);
+        node.style.max_width = Some(40.0);
+        assert!(state.add_node(node).is_err());
     }
 
     #[test]
-    fn remove_nonexistent_node() {
+    fn background_gradient_is_validated() {
         let mut state = BarState::default();
-        let result = state.remove_node("ghost");
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("not found"));
+        state
+            .add_node(make_node("pill", NodeType::Item, None, 1))
+            .unwrap();
+        let set = |state: &mut BarState, key: &str, value: &str| {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            state.set_properties("pill", &props)
+        };
+
+        set(&mut state, "background_gradient_start", "#0A84FF").unwrap();
+        set(&mut state, "background_gradient_end", "#5E5CE6CC").unwrap();
+        let node = set(&mut state, "background_gradient_angle", "90").unwrap();
+        assert_eq!(node.style.background_gradient_angle, Some(90.0));
+        assert_eq!(
+            set(&mut state, "background_gradient_angle", "+=300").unwrap_err(),
+            "background_gradient_angle out of range: 390 (expected 0 to 360)"
+        );
+        assert!(set(&mut state, "background_gradient_angle", "-1").is_err());
+        assert!(
+            set(&mut state, "background_gradient_end", "purpleish")
+                .unwrap_err()
+                .message
+                .starts_with("invalid color for background_gradient_end: purpleish")
+        );
+        let node = state.get_node("pill").unwrap();
+        assert_eq!(node.style.background_gradient_angle, Some(90.0));
+        assert_eq!(
+            node.style.background_gradient_end.as_deref(),
+            Some("#5E5CE6CC")
+        );
+
+        let mut node = make_node("tag", NodeType::Item, None, 1);
+        node.style.background_gradient_start = Some("redish".into());
+        assert!(state.add_node(node).is_err());
     }
 
     #[test]
-    fn remove_container_without_children() {
+    fn background_opacity_must_be_between_zero_and_one() {
         let mut state = BarState::default();
         state
-            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .add_node(make_node("clock", NodeType::Item, None, 1))
             .unwrap();
+        let set = |state: &mut BarState, value: &str| {
+            let props = HashMap::from([("background_opacity".to_string(), value.to_string())]);
+            state.set_properties("clock", &props)
+        };
 
-        let removed = state.remove_node("row1").unwrap();
-        assert_eq!(removed.name, "row1");
-        assert!(state.get_nodes().is_empty());
+        let node = set(&mut state, "0.5").unwrap();
+        assert_eq!(node.style.background_opacity, Some(0.5));
+        let node = set(&mut state, "-=0.5").unwrap();
+        assert_eq!(node.style.background_opacity, Some(0.0));
+        assert_eq!(
+            set(&mut state, "1.5").unwrap_err(),
+            "background_opacity out of range: 1.5 (expected 0 to 1)"
+        );
+        assert!(set(&mut state, "-=0.1").is_err());
+        let node = set(&mut state, "").unwrap();
+        assert_eq!(node.style.background_opacity, None);
+
+        let mut node = make_node("cpu", NodeType::Item, None, 1);
+        node.style.background_opacity = Some(-0.2);
+        assert!(state.add_node(node).is_err());
     }
 
     #[test]
-    fn remove_container_with_direct_children() {
+    fn blend_mode_accepts_known_values() {
         let mut state = BarState::default();
         state
-            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .add_node(make_node("badge", NodeType::Item, None, 1))
             .unwrap();
+        for mode in BLEND_MODES {
+            let props = HashMap::from([("blend_mode".to_string(), mode.to_string())]);
+            let node = state.set_properties("badge", &props).unwrap();
+            assert_eq!(node.style.blend_mode.as_deref(), Some(mode));
+        }
+        let props = HashMap::from([("blend_mode".to_string(), String::new())]);
+        let node = state.set_properties("badge", &props).unwrap();
+        assert_eq!(node.style.blend_mode, None);
+    }
+
+    #[test]
+    fn width_accepts_points_percent_and_fill() {
+        let mut state = BarState::default();
         state
-            .add_node(make_node("child1", NodeType::Item, Some("row1"), 1))
+            .add_node(make_node("spacer", NodeType::Item, None, 1))
             .unwrap();
+        for (value, expected) in [
+            ("24", Dimension::Points(24.0)),
+            ("+=6", Dimension::Points(30.0)),
+            ("50%", Dimension::Percent(50.0)),
+            ("fill", Dimension::Fill),
+        ] {
+            let props = HashMap::from([("width".to_string(), value.to_string())]);
+            let node = state.set_properties("spacer", &props).unwrap();
+            assert_eq!(node.style.width, Some(expected));
+        }
+        assert_eq!(
+            state.get_node("spacer").unwrap().property_value("width"),
+            Some("fill".to_string())
+        );
+
+        for (value, message) in [
+            ("+=4", "cannot adjust width of fill"),
+            ("120%", "width out of range: 120%"),
+            ("wide", "invalid width: wide"),
+        ] {
+            let props = HashMap::from([("width".to_string(), value.to_string())]);
+            assert_eq!(state.set_properties("spacer", &props).unwrap_err(), message);
+        }
+    }
+
+    #[test]
+    fn dimension_serializes_points_as_numbers() {
+        let dims = vec![
+            Dimension::Points(12.5),
+            Dimension::Percent(50.0),
+            Dimension::Fill,
+        ];
+        let value = serde_json::to_value(&dims).unwrap();
+        assert_eq!(value, serde_json::json!([12.5, "50%", "fill"]));
+        let back: Vec<Dimension> = serde_json::from_value(value).unwrap();
+        assert_eq!(back, dims);
+        assert!(serde_json::from_value::<Dimension>(serde_json::json!("tall")).is_err());
+    }
+
+    #[test]
+    fn progress_value_is_clamped_and_takes_no_children() {
+        let mut state = BarState::default();
         state
-            .add_node(make_node("child2", NodeType::Item, Some("row1"), 1))
+            .add_node(make_node("cpu", NodeType::Progress, None, 1))
             .unwrap();
+        let set = |state: &mut BarState, key: &str, value: &str| {
+            let props = HashMap::from([(key.to_string(), value.to_string())]);
+            state.set_properties("cpu", &props)
+        };
 
-        let removed = state.remove_node("row1").unwrap();
-        assert_eq!(removed.name, "row1");
-        assert!(state.get_nodes().is_empty());
+        assert_eq!(set(&mut state, "value", "42.5").unwrap().value, Some(42.5));
+        assert_eq!(set(&mut state, "value", "+=70").unwrap().value, Some(100.0));
+        assert_eq!(set(&mut state, "value", "-5").unwrap().value, Some(0.0));
+        assert_eq!(
+            set(&mut state, "value", "NaN").unwrap_err(),
+            "value out of range: NaN"
+        );
+        assert!(set(&mut state, "value", "full").is_err());
+        assert_eq!(set(&mut state, "value", "").unwrap().value, None);
+        assert!(set(&mut state, "progress_color", "#30D158").is_ok());
+        assert!(
+            set(&mut state, "progress_track_color", "grayish")
+                .unwrap_err()
+                .message
+                .starts_with("invalid color for progress_track_color: grayish")
+        );
+
+        let err = state
+            .add_node(make_node("core0", NodeType::Item, Some("cpu"), 1))
+            .unwrap_err();
+        assert_eq!(err, "'cpu' is a progress node and cannot have children");
+
+        let mut node = make_node("battery", NodeType::Progress, None, 1);
+        node.value = Some(120.0);
+        let id = state.add_node(node).unwrap();
+        assert_eq!(state.get_node_by_id(id).unwrap().value, Some(100.0));
+        let mut node = make_node("disk", NodeType::Progress, None, 1);
+        node.value = Some(f32::NAN);
+        assert_eq!(state.add_node(node).unwrap_err(), "value out of range: NaN");
     }
 
     #[test]
-    fn remove_container_with_deep_nesting() {
+    fn text_truncation_rejects_unknown_modes() {
         let mut state = BarState::default();
-        // root -> col1 -> row1 -> item1
         state
-            .add_node(make_node("root", NodeType::Column, None, 1))
+            .add_node(make_node("title", NodeType::Item, None, 1))
+            .unwrap();
+        for mode in TEXT_TRUNCATIONS {
+            let props = HashMap::from([("text_truncation".to_string(), mode.to_string())]);
+            let node = state.set_properties("title", &props).unwrap();
+            assert_eq!(node.style.text_truncation.as_deref(), Some(mode));
+        }
+        let props = HashMap::from([("text_truncation".to_string(), "start".to_string())]);
+        assert_eq!(
+            state.set_properties("title", &props).unwrap_err(),
+            "invalid text_truncation: start (expected one of head, middle, tail, none)"
+        );
+        assert_eq!(
+            state
+                .get_node("title")
+                .unwrap()
+                .style
+                .text_truncation
+                .as_deref(),
+            Some("none")
+        );
+    }
+
+    #[test]
+    fn invalid_blend_mode_is_rejected() {
+        let mut state = BarState::default();
+        let mut node = make_node("badge", NodeType::Item, None, 1);
+        node.style.blend_mode = Some("dissolve".into());
+        let err = state.add_node(node).unwrap_err();
+        assert!(err.message.starts_with("invalid blend_mode: dissolve"));
+
+        state
+            .add_node(make_node("badge", NodeType::Item, None, 1))
             .unwrap();
+        let props = HashMap::from([("blend_mode".to_string(), "hue".to_string())]);
+        let err = state.set_properties("badge", &props).unwrap_err();
+        assert!(err.message.starts_with("invalid blend_mode: hue"));
+        assert_eq!(state.get_node("badge").unwrap().style.blend_mode, None);
+    }
+
+    #[test]
+    fn invalid_notch_align_is_rejected() {
+        let mut state = BarState::default();
+        let mut node = make_node("clock", NodeType::Item, None, 1);
+        node.style.notch_align = Some("center".into());
+        assert!(state.add_node(node).is_err());
+
         state
-            .add_node(make_node("col1", NodeType::Column, Some("root"), 1))
+            .add_node(make_node("clock", NodeType::Item, None, 1))
             .unwrap();
+        let props = HashMap::from([("notch_align".to_string(), "left".to_string())]);
+        let node = state.set_properties("clock", &props).unwrap();
+        assert_eq!(node.style.notch_align.as_deref(), Some("left"));
+        let props = HashMap::from([("notch_align".to_string(), "middle".to_string())]);
+        let err = state.set_properties("clock", &props).unwrap_err();
+        assert!(err.message.starts_with("invalid notch_align: middle"));
+    }
+
+    #[test]
+    fn add_after_sibling_lands_immediately_after_it() {
+        let mut state = BarState::default();
+        for name in ["first", "second", "third"] {
+            state
+                .add_node(make_node(name, NodeType::Item, None, 1))
+                .unwrap();
+        }
+
         state
-            .add_node(make_node("row1", NodeType::Row, Some("col1"), 1))
+            .add_node_beside(make_node("new", NodeType::Item, None, 1), "first", true)
             .unwrap();
         state
-            .add_node(make_node("item1", NodeType::Item, Some("row1"), 1))
+            .add_node_beside(make_node("head", NodeType::Item, None, 1), "first", false)
             .unwrap();
 
-        let removed = state.remove_node("root").unwrap();
-        assert_eq!(removed.name, "root");
-        assert!(state.get_nodes().is_empty());
+        let names: Vec<String> = state
+            .get_nodes_for_display(1)
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        assert_eq!(names, vec!["head", "first", "new", "second", "third"]);
     }
 
     #[test]
-    fn remove_container_leaves_unrelated_nodes() {
+    fn add_beside_non_sibling_is_rejected() {
         let mut state = BarState::default();
         state
-            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .add_node(make_node("row", NodeType::Row, None, 1))
             .unwrap();
         state
-            .add_node(make_node("child1", NodeType::Item, Some("row1"), 1))
+            .add_node(make_node("child", NodeType::Item, Some("row"), 1))
             .unwrap();
-        state
-            .add_node(make_node("unrelated", NodeType::Item, None, 1))
+
+        let err = state
+            .add_node_beside(make_node("new", NodeType::Item, None, 1), "child", true)
+            .unwrap_err();
+        assert!(err.message.contains("not a sibling"));
+        assert_eq!(err.code, ErrorCode::WrongDisplay);
+        let err = state
+            .add_node_beside(make_node("new", NodeType::Item, None, 1), "ghost", true)
+            .unwrap_err();
+        assert_eq!(err, "sibling 'ghost' not found");
+    }
+
+    #[test]
+    fn delayed_removal_completes_with_matching_token() {
+        let mut state = BarState::default();
+        let id = state
+            .add_node(make_node("toast", NodeType::Item, None, 1))
             .unwrap();
 
-        state.remove_node("row1").unwrap();
+        let (node, first) = state.mark_removing("toast").unwrap();
+        assert!(node.removing);
+        let (_, second) = state.mark_removing("toast").unwrap();
 
-        let remaining: Vec<String> = state.get_nodes().into_iter().map(|n| n.name).collect();
-        assert_eq!(remaining, vec!["unrelated"]);
+        assert!(state.finish_removal(id, first).is_none());
+        assert_eq!(state.get_nodes().len(), 1);
+        assert_eq!(state.finish_removal(id, second).unwrap().name, "toast");
+        assert!(state.get_nodes().is_empty());
     }
 
     #[test]
-    fn remove_item_does_not_cascade() {
+    fn re_adding_cancels_delayed_removal() {
+        let mut state = BarState::default();
+        let old_id = state
+            .add_node(make_node("toast", NodeType::Item, None, 1))
+            .unwrap();
+        let (_, token) = state.mark_removing("toast").unwrap();
+
+        let new_id = state
+            .add_node(make_node("toast", NodeType::Item, None, 1))
+            .unwrap();
+
+        assert!(state.finish_removal(old_id, token).is_none());
+        let nodes = state.get_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, new_id);
+        assert!(!nodes[0].removing);
+    }
+
+    #[test]
+    fn re_adding_replaces_only_the_fading_node_on_its_display() {
         let mut state = BarState::default();
         state
-            .add_node(make_node("row1", NodeType::Row, None, 1))
+            .add_node(make_node("group", NodeType::Row, None, 1))
             .unwrap();
         state
-            .add_node(make_node("sibling1", NodeType::Item, Some("row1"), 1))
+            .add_node(make_node("child", NodeType::Item, Some("group"), 1))
             .unwrap();
         state
-            .add_node(make_node("sibling2", NodeType::Item, Some("row1"), 1))
+            .add_node(make_node("group", NodeType::Row, None, 2))
             .unwrap();
+        state.mark_removing("group").unwrap();
 
-        state.remove_node("sibling1").unwrap();
+        state
+            .add_node(make_node("group", NodeType::Row, None, 2))
+            .unwrap_err();
+        let id = state
+            .add_node(make_node("group", NodeType::Row, None, 1))
+            .unwrap();
 
-        let remaining: Vec<String> = state.get_nodes().into_iter().map(|n| n.name).collect();
-        assert!(remaining.contains(&"row1".to_string()));
-        assert!(remaining.contains(&"sibling2".to_string()));
-        assert_eq!(remaining.len(), 2);
+        let group = state.get_node_by_id(id).unwrap();
+        assert_eq!(group.display, 1);
+        assert!(!group.removing);
+        let children = state.get_children("group");
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].display, 1);
+        assert_eq!(state.nodes_for_display(2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn nodes_without_position_keep_creation_order() {
+        let mut state = BarState::default();
+        for name in ["zeta", "alpha", "mid"] {
+            state
+                .add_node(make_node(name, NodeType::Item, None, 1))
+                .unwrap();
+        }
+        let explicit = BarNode {
+            position: 1,
+            position_explicit: true,
+            ..make_node("pinned", NodeType::Item, None, 1)
+        };
+        state.add_node(explicit).unwrap();
+
+        let nodes = state.get_nodes_for_display(1);
+        let order: Vec<(&str, i32)> = nodes
+            .iter()
+            .map(|n| (n.name.as_str(), n.position))
+            .collect();
+        assert_eq!(
+            order,
+            vec![("zeta", 0), ("alpha", 1), ("pinned", 1), ("mid", 2)]
+        );
     }
 }